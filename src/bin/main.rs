@@ -6,10 +6,10 @@ use async_trait::async_trait;
 use axum::{
     Router,
     body::Body,
-    extract::{Query, State},
+    extract::{Path, Query, State},
     http::StatusCode,
     response::{IntoResponse, Response},
-    routing::get,
+    routing::{get, post},
 };
 
 use eve::esi;
@@ -17,12 +17,12 @@ use eve::sde;
 use eve::{
     AllAssetsDb, AssetItem, AssetName, CharacterAssetsDb, CharacterId, DogmaAttribute,
     DogmaAttributeConcise, DogmaAttributeId, DynamicItem, DynamicsDb, ItemId, ItemType,
-    MarketGroup, MarketGroupId, Station, StationId, TypeId,
+    MarketGroup, MarketGroupId, RegionId, Station, StationId, TypeId,
 };
-use eve::{Ratelimit, RatelimitGroup, RatelimitedClient};
+use eve::{ClientMetrics, Ratelimit, RatelimitGroup, RatelimitedClient};
 use oauth2::{
-    self, AuthUrl, AuthorizationCode, ClientId, CsrfToken, EndpointNotSet, EndpointSet,
-    PkceCodeChallenge, PkceCodeVerifier, RedirectUrl, Scope, TokenUrl,
+    self, AuthUrl, AuthorizationCode, ClientId, CsrfToken, DeviceAuthorizationUrl, EndpointNotSet,
+    EndpointSet, PkceCodeChallenge, PkceCodeVerifier, RedirectUrl, Scope, TokenResponse, TokenUrl,
 };
 use oauth2::{
     StandardDeviceAuthorizationResponse,
@@ -41,34 +41,49 @@ use tokio::sync::mpsc;
 use tokio::sync::{Mutex, RwLock};
 use tokio::time::{Duration as TokioDuration, interval};
 use tower_sessions::{MemoryStore, Session, SessionManagerLayer};
+use utoipa::{OpenApi, ToSchema};
 
 // Import our processing modules
 use eve::AppContext;
 use eve::handlers;
 use eve::saga::assets;
 use eve::saga::market::{self, MarketResolutionSaga};
-use eve::{CharacterClient, CharacterManager, OauthConfig};
+use eve::saga::stats::FetchStatsSnapshot;
+use eve::{CharacterClient, CharacterManager, Config, OauthConfig};
+
+// Hard cap on a single assets resolution run
+const ASSETS_SAGA_DEADLINE: Duration = Duration::from_secs(30 * 60);
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    env_logger::init();
-
-    let ratelimit_group = RatelimitGroup::new(vec![
-        Ratelimit::new(Duration::from_secs(1), 2),
-        Ratelimit::new(Duration::from_secs(60), 120),
-    ]);
+    // tracing events are forwarded to `log`, filtered with RUST_LOG (e.g. `eve=debug` for
+    // every ESI request)
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
 
-    let port = 8080;
+    let config = Config::load()?;
+    let port = config.port;
 
-    let http_client = Arc::new(RatelimitedClient::new(ratelimit_group));
+    let mut client_builder = RatelimitedClient::builder();
+    if let Some(user_agent) = &config.user_agent {
+        client_builder = client_builder.user_agent(user_agent);
+    }
+    let mut http_client = client_builder
+        .build(config.ratelimit_group())
+        .context("can't build the http client")?
+        .with_base_url(&config.esi_base_url)
+        .with_retry_after(true)
+        .with_etag_cache(true);
+    if let Some(date) = &config.esi_compatibility_date {
+        http_client = http_client.with_compatibility_date(date);
+    }
+    let http_client = Arc::new(http_client);
     let oauth_config = OauthConfig {
-        client_id: ClientId::new("49f3698f399f4870afaf1f632592abe0".to_string()),
+        client_id: ClientId::new(config.client_id.clone()),
         auth_url: AuthUrl::new("https://login.eveonline.com/v2/oauth/authorize".to_string())
             .context("invalid auth url")?,
         token_url: TokenUrl::new("https://login.eveonline.com/v2/oauth/token".to_string())
             .context("invalid token url")?,
-        redirect_url: RedirectUrl::new(format!("http://localhost:{port}/auth/callback"))
-            .context("invalid redirect url")?,
+        redirect_url: RedirectUrl::new(config.redirect_url()).context("invalid redirect url")?,
     };
 
     let oauth2_client = Arc::new(
@@ -79,13 +94,7 @@ async fn main() -> Result<()> {
     );
 
     let context = Arc::new(
-        AppContext::with_client(
-            http_client.clone(),
-            oauth2_client.clone(),
-            "/Users/maxim/data/eve/sqlite-latest.sqlite",
-            "/Users/maxim/data/eve/",
-        )
-        .await?,
+        AppContext::with_client(http_client.clone(), oauth2_client.clone(), config).await?,
     );
 
     let dynamics_stats = {
@@ -110,18 +119,56 @@ async fn main() -> Result<()> {
         loop {
             interval.tick().await;
 
-            println!("tick");
+            let (errors_remain, errors_reset_in) = stats_context.http_client.error_budget();
+            println!(
+                "tick: esi permits available {}/{}, error budget {} (resets in {:?}), circuit {:?}",
+                stats_context.available_esi_permits(),
+                stats_context.esi_concurrency,
+                errors_remain,
+                errors_reset_in,
+                stats_context.http_client.circuit_state()
+            );
         }
     });
 
-    let context_clone = context.clone();
-    tokio::spawn(async move {
-        println!("starting market orders resolution");
-        match start_market_orders_resolution_system(context_clone).await {
-            Ok(_) => println!("market orders resolution completed"),
-            Err(e) => println!("market orders resolution failed: {}", e),
+    let autosave_task = context
+        .config
+        .autosave_interval()
+        .map(|interval| context.clone().spawn_autosave(interval));
+
+    let market_targets = context.config.market_targets.clone();
+
+    match context.config.market_refresh_interval() {
+        Some(interval) => {
+            println!("starting market orders refresher, interval {:?}", interval);
+            market::refresher::spawn_market_refresher(
+                context.clone(),
+                market::refresher::MarketRefresherConfig {
+                    interval,
+                    jitter: context.config.market_refresh_jitter(),
+                    targets: market_targets,
+                    workers_count: context.config.market_workers.max(1),
+                },
+            );
         }
-    });
+        None => {
+            let context_clone = context.clone();
+            let workers_count = context.config.market_workers;
+            tokio::spawn(async move {
+                println!("starting market orders resolution");
+                match start_market_orders_resolution_system(
+                    context_clone,
+                    market_targets,
+                    workers_count,
+                )
+                .await
+                {
+                    Ok(_) => println!("market orders resolution completed"),
+                    Err(e) => println!("market orders resolution failed: {}", e),
+                }
+            });
+        }
+    }
 
     let server_task = start_http_server(context.clone(), port).await;
 
@@ -129,6 +176,9 @@ async fn main() -> Result<()> {
     println!("HTTP server stopped");
 
     stats_task.abort();
+    if let Some(autosave_task) = autosave_task {
+        autosave_task.abort();
+    }
 
     let mut dynamics_db_guard = context.dynamics_db.write().await;
     println!("🏁 Main cleanup - about to store dynamics");
@@ -138,65 +188,176 @@ async fn main() -> Result<()> {
     Ok(())
 }
 
+/// Resolve the assets of a character with `workers_count` saga workers (at least one).
+/// The workers share the client's rate limit, which is the real ceiling of the throughput:
+/// past a few workers more of them only queue up on it.
 pub async fn start_assets_resolution_system(
     context: Arc<AppContext>,
     character_id: CharacterId,
+    workers_count: usize,
 ) -> Result<()> {
-    let workers_count = 3;
+    // Two sagas would race on the same assets db and fetch everything twice
+    let Some(_resolution) = context.try_begin_assets_resolution(character_id) else {
+        return Err(anyhow!(
+            "assets resolution for character {} is already running",
+            character_id
+        ));
+    };
+
+    // Corporation assets need a director who granted the corporation scope
+    let corporation_id = match context.config.corporation_id {
+        Some(corporation_id) => {
+            let characters = context.characters.lock().await;
+            let granted = characters
+                .get(character_id)
+                .is_some_and(|character| character.has_scope(esi::SCOPE_READ_CORPORATION_ASSETS));
+            if !granted {
+                println!(
+                    "⚠️ Character {} didn't grant {}, skipping corporation {} assets; log it in again through /auth/start to grant it",
+                    character_id,
+                    esi::SCOPE_READ_CORPORATION_ASSETS,
+                    corporation_id
+                );
+            }
+            granted.then_some(corporation_id)
+        }
+        None => None,
+    };
 
-    let saga = assets::run_assets_saga(context.clone(), character_id, workers_count).await?;
+    let outcome = assets::run_assets_saga(
+        context.clone(),
+        character_id,
+        corporation_id,
+        workers_count.max(1),
+        Some(ASSETS_SAGA_DEADLINE),
+    )
+    .await?;
+
+    if let Err(e) = context.character_assets_db.store() {
+        eprintln!("❌ Failed to store character assets: {}", e);
+    }
 
-    context.character_assets_db.store();
+    println!("📊 Assets fetch stats:\n{}", outcome.fetch_stats);
+    for (key, error) in &outcome.dead_letters {
+        eprintln!("💀 Gave up on {:?}: {}", key, error);
+    }
+    let changes = &outcome.changes;
+    if !changes.is_empty() {
+        println!(
+            "🔀 Assets changes: {} added, {} removed, {} moved",
+            changes.added.len(),
+            changes.removed.len(),
+            changes.moved.len()
+        );
+    }
 
-    println!("assets resolution completed");
+    println!(
+        "assets resolution completed: {} items resolved in {:.1?} with {} ESI calls, {} given up on",
+        outcome.resolved_count,
+        outcome.elapsed,
+        outcome.esi_calls,
+        outcome.dead_letters.len()
+    );
     Ok(())
 }
 
-pub async fn start_market_orders_resolution_system(context: Arc<AppContext>) -> Result<()> {
-    let saga = Arc::new(RwLock::new(MarketResolutionSaga::new(context.clone())));
+/// Resolve market orders of the given (region, type) targets once, with `workers_count`
+/// saga workers (at least one). As for assets, the rate limit caps the throughput
+/// whatever the worker count.
+pub async fn start_market_orders_resolution_system(
+    context: Arc<AppContext>,
+    targets: Vec<(RegionId, TypeId)>,
+    workers_count: usize,
+) -> Result<()> {
+    market::run_market_saga(context, targets, workers_count.max(1)).await?;
 
-    let mut worker_handles = Vec::new();
-    for _ in 0..3 {
-        let worker = market::Worker::new(
-            market::WorkerType::MarketOrders,
-            saga.clone(),
-            context.clone(),
-        );
+    println!("market orders resolution completed");
+    Ok(())
+}
 
-        let handle = tokio::spawn(async move { worker.start().await });
-        worker_handles.push(handle);
-    }
+#[derive(OpenApi)]
+#[openapi(
+    info(title = "eve", description = "EVE Online assets and market tools"),
+    paths(
+        openapi_handler,
+        auth_start,
+        auth_callback,
+        device_auth_start,
+        device_auth_poll,
+        list_characters_handler,
+        dynamics_report_handler,
+        dynamics_summary_handler,
+        dynamics_lookup_handler,
+        assets_report_handler,
+        assets_csv_handler,
+        profile_dynamics_report_handler,
+        hoboleaks_refresh_handler,
+        market_status_handler,
+        market_spread_handler,
+        cancel_assets_handler,
+        assets_refresh_handler,
+        assets_status_handler,
+        metrics_handler
+    ),
+    components(schemas(ErrorResponse, eve::db::DynamicSummary))
+)]
+struct ApiDoc;
 
-    {
-        let mut saga = saga.write().await;
-        saga.handle_event(market::SagaEvent::SagaStarted).await?;
+/// Error body returned by JSON endpoints
+#[derive(Serialize, ToSchema)]
+struct ErrorResponse {
+    error: String,
+    status: String,
+}
+
+impl ErrorResponse {
+    fn new(error: String) -> Self {
+        Self {
+            error,
+            status: "error".to_string(),
+        }
     }
 
-    for handle in worker_handles {
-        handle.await.context("Failed to join worker task")?;
+    fn into_response(self, status: StatusCode) -> Response<String> {
+        Response::builder()
+            .status(status)
+            .header("content-type", "application/json")
+            .body(serde_json::to_string(&self).unwrap())
+            .unwrap()
     }
+}
 
-    println!("market orders resolution completed");
-    Ok(())
+/// OpenAPI description of the HTTP endpoints
+#[utoipa::path(
+    get,
+    path = "/openapi.json",
+    responses((status = 200, description = "OpenAPI document", content_type = "application/json"))
+)]
+async fn openapi_handler() -> impl IntoResponse {
+    axum::Json(ApiDoc::openapi())
 }
 
-async fn dynamics_report_handler(State(state): State<AppState>) -> impl IntoResponse {
+/// Report of mutated items owned by the authorized characters
+#[utoipa::path(
+    get,
+    path = "/my/dynamics",
+    params(handlers::dynamics::DynamicsFilter),
+    responses(
+        (status = 200, description = "Dynamics report", body = handlers::dynamics::DynamicsReport),
+        (status = 500, description = "Report generation failed", body = ErrorResponse)
+    )
+)]
+async fn dynamics_report_handler(
+    State(state): State<AppState>,
+    Query(filter): Query<handlers::dynamics::DynamicsFilter>,
+) -> impl IntoResponse {
     let context = &state.context;
 
-    let report = match handlers::dynamics::DynamicsReport::new(context).await {
+    let report = match handlers::dynamics::DynamicsReport::new_filtered(context, &filter).await {
         Ok(report) => report,
         Err(e) => {
-            return Response::builder()
-                .status(StatusCode::INTERNAL_SERVER_ERROR)
-                .header("content-type", "application/json")
-                .body(
-                    serde_json::json!({
-                        "error": format!("Failed to generate dynamics report: {}", e),
-                        "status": "error"
-                    })
-                    .to_string(),
-                )
-                .unwrap();
+            return ErrorResponse::new(format!("Failed to generate dynamics report: {}", e))
+                .into_response(StatusCode::INTERNAL_SERVER_ERROR);
         }
     };
 
@@ -206,20 +367,108 @@ async fn dynamics_report_handler(State(state): State<AppState>) -> impl IntoResp
             .header("content-type", "application/json")
             .body(report_json)
             .unwrap(),
-        Err(e) => Response::builder()
-            .status(StatusCode::INTERNAL_SERVER_ERROR)
-            .header("content-type", "application/json")
-            .body(
-                serde_json::json!({
-                    "error": format!("Failed to serialize dynamics report: {}", e),
-                    "status": "error"
-                })
-                .to_string(),
-            )
-            .unwrap(),
+        Err(e) => ErrorResponse::new(format!("Failed to serialize dynamics report: {}", e))
+            .into_response(StatusCode::INTERNAL_SERVER_ERROR),
+    }
+}
+
+/// Rolled attributes and mutator ranges of a single mutated item, owned or not
+#[utoipa::path(
+    get,
+    path = "/dynamics/lookup",
+    params(handlers::dynamics::DynamicLookupParams),
+    responses(
+        (status = 200, description = "Mutated item", body = handlers::dynamics::DynamicLookup),
+        (status = 404, description = "No such mutated item", body = ErrorResponse),
+        (status = 500, description = "Type, mutator or attribute data missing", body = ErrorResponse),
+        (status = 502, description = "ESI request failed", body = ErrorResponse)
+    )
+)]
+async fn dynamics_lookup_handler(
+    State(state): State<AppState>,
+    Query(params): Query<handlers::dynamics::DynamicLookupParams>,
+) -> impl IntoResponse {
+    use handlers::dynamics::DynamicsError;
+
+    match handlers::dynamics::resolve_single(&state.context, params.type_id, params.item_id).await {
+        Ok(lookup) => axum::Json(lookup).into_response(),
+        Err(e) => {
+            let status = match e {
+                DynamicsError::NotFoundDynamicItem { .. } => StatusCode::NOT_FOUND,
+                DynamicsError::EsiError(_) => StatusCode::BAD_GATEWAY,
+                _ => StatusCode::INTERNAL_SERVER_ERROR,
+            };
+            ErrorResponse::new(format!("Failed to look up dynamic item: {}", e))
+                .into_response(status)
+                .into_response()
+        }
+    }
+}
+
+/// Regular assets of the authorized characters by location and market group
+#[utoipa::path(
+    get,
+    path = "/my/assets",
+    responses(
+        (status = 200, description = "Assets report", body = handlers::assets::AssetsReport),
+        (status = 500, description = "Report generation failed", body = ErrorResponse)
+    )
+)]
+async fn assets_report_handler(State(state): State<AppState>) -> impl IntoResponse {
+    match handlers::assets::AssetsReport::new(&state.context).await {
+        Ok(report) => axum::Json(report).into_response(),
+        Err(e) => ErrorResponse::new(format!("Failed to generate assets report: {}", e))
+            .into_response(StatusCode::INTERNAL_SERVER_ERROR)
+            .into_response(),
     }
 }
 
+/// Mutated items of the authorized characters with their types and location
+#[utoipa::path(
+    get,
+    path = "/my/dynamics/summary",
+    responses(
+        (status = 200, description = "Mutated items", body = Vec<eve::db::DynamicSummary>)
+    )
+)]
+async fn dynamics_summary_handler(State(state): State<AppState>) -> impl IntoResponse {
+    axum::Json(state.context.character_assets_db.list_dynamics_summary())
+}
+
+/// All assets of the authorized characters as CSV
+#[utoipa::path(
+    get,
+    path = "/my/assets.csv",
+    responses(
+        (status = 200, description = "Assets, one row per item", body = String, content_type = "text/csv"),
+        (status = 500, description = "Export failed", body = ErrorResponse)
+    )
+)]
+async fn assets_csv_handler(State(state): State<AppState>) -> impl IntoResponse {
+    let mut csv = Vec::new();
+    if let Err(e) = state.context.character_assets_db.export_csv(&mut csv) {
+        return ErrorResponse::new(format!("Failed to export assets: {}", e))
+            .into_response(StatusCode::INTERNAL_SERVER_ERROR)
+            .into_response();
+    }
+
+    Response::builder()
+        .status(StatusCode::OK)
+        .header("content-type", "text/csv; charset=utf-8")
+        .header("content-disposition", "attachment; filename=\"assets.csv\"")
+        .body(Body::from(csv))
+        .unwrap()
+}
+
+/// Flamegraph of the dynamics report generation
+#[utoipa::path(
+    get,
+    path = "/profile/my/dynamics",
+    responses(
+        (status = 200, description = "Flamegraph of the report generation", content_type = "image/svg+xml"),
+        (status = 500, description = "Profiling failed", body = String, content_type = "text/plain")
+    )
+)]
 async fn profile_dynamics_report_handler(State(state): State<AppState>) -> impl IntoResponse {
     println!("Starting profiling of dynamics report...");
 
@@ -282,114 +531,810 @@ async fn profile_dynamics_report_handler(State(state): State<AppState>) -> impl
     }
 }
 
+#[derive(Serialize, ToSchema)]
+struct HoboleaksRefreshResponse {
+    status: String,
+    mutators: usize,
+    /// Types only the new mapping refers to, read from the SDE
+    types_added: usize,
+}
+
+/// Force a hoboleaks refresh and reload the mutaplasmid effects
+#[utoipa::path(
+    post,
+    path = "/admin/hoboleaks/refresh",
+    responses(
+        (status = 200, description = "Mutaplasmid effects reloaded", body = HoboleaksRefreshResponse),
+        (status = 502, description = "Hoboleaks refresh failed", body = ErrorResponse)
+    )
+)]
+async fn hoboleaks_refresh_handler(State(state): State<AppState>) -> impl IntoResponse {
+    match state.context.refresh_mutaplasmid_effects().await {
+        Ok((mutators, types_added)) => axum::Json(HoboleaksRefreshResponse {
+            status: "ok".to_string(),
+            mutators,
+            types_added,
+        })
+        .into_response(),
+        Err(e) => ErrorResponse::new(format!("Failed to refresh hoboleaks data: {}", e))
+            .into_response(StatusCode::BAD_GATEWAY)
+            .into_response(),
+    }
+}
+
+#[derive(Serialize, ToSchema)]
+struct MarketTargetStatus {
+    region_id: i64,
+    type_id: TypeId,
+    last_updated: String,
+}
+
+/// Last refresh time of every market target
+#[utoipa::path(
+    get,
+    path = "/market/status",
+    responses((status = 200, description = "Market targets status", body = Vec<MarketTargetStatus>))
+)]
+async fn market_status_handler(State(state): State<AppState>) -> impl IntoResponse {
+    let market_last_updated = state.context.market_last_updated.read().await;
+    let mut statuses: Vec<MarketTargetStatus> = market_last_updated
+        .iter()
+        .map(|((region_id, type_id), updated_at)| MarketTargetStatus {
+            region_id: *region_id,
+            type_id: *type_id,
+            last_updated: updated_at.to_rfc3339(),
+        })
+        .collect();
+    statuses.sort_by_key(|s| (s.region_id, s.type_id));
+
+    axum::Json(statuses)
+}
+
+/// Best bid and ask of a market target as of the latest market saga run
+#[utoipa::path(
+    get,
+    path = "/market/spread",
+    params(MarketSpreadParams),
+    responses(
+        (status = 200, description = "Spread of the market", body = eve::saga::market::analyze::Spread),
+        (status = 404, description = "Not a market target, or it has no orders", body = ErrorResponse)
+    )
+)]
+async fn market_spread_handler(
+    State(state): State<AppState>,
+    Query(params): Query<MarketSpreadParams>,
+) -> impl IntoResponse {
+    let spread = state
+        .context
+        .market_spreads
+        .read()
+        .await
+        .get(&(params.region, params.type_id))
+        .copied();
+
+    match spread {
+        Some(spread) => axum::Json(spread).into_response(),
+        None => ErrorResponse::new(format!(
+            "No market orders of type {} in region {}",
+            params.type_id, params.region
+        ))
+        .into_response(StatusCode::NOT_FOUND)
+        .into_response(),
+    }
+}
+
+#[derive(Serialize, ToSchema)]
+struct CancelAssetsResponse {
+    status: String,
+    character_id: CharacterId,
+}
+
+/// Cancel the running assets resolution of a character
+#[utoipa::path(
+    post,
+    path = "/assets/{character_id}/cancel",
+    params(("character_id" = u64, Path, description = "Character whose assets saga to cancel")),
+    responses(
+        (status = 202, description = "Cancellation requested", body = CancelAssetsResponse),
+        (status = 404, description = "No running assets resolution", body = ErrorResponse)
+    )
+)]
+async fn cancel_assets_handler(
+    State(state): State<AppState>,
+    Path(character_id): Path<CharacterId>,
+) -> impl IntoResponse {
+    if state.context.cancel_assets_saga(character_id).await {
+        (
+            StatusCode::ACCEPTED,
+            axum::Json(CancelAssetsResponse {
+                status: "cancelling".to_string(),
+                character_id,
+            }),
+        )
+            .into_response()
+    } else {
+        ErrorResponse::new(format!(
+            "No running assets resolution for character {}",
+            character_id
+        ))
+        .into_response(StatusCode::NOT_FOUND)
+        .into_response()
+    }
+}
+
+/// Character to act on, the one authorized in this session if not given
+#[derive(Deserialize, utoipa::IntoParams)]
+struct CharacterParams {
+    character_id: Option<CharacterId>,
+}
+
+/// The character of the query, or of the session, that must be known
+async fn requested_character(
+    context: &AppContext,
+    session: &Session,
+    params: &CharacterParams,
+) -> Result<CharacterId, Response<String>> {
+    let character_id = match params.character_id {
+        Some(character_id) => character_id,
+        None => session
+            .get::<CharacterId>("character_id")
+            .await
+            .ok()
+            .flatten()
+            .ok_or_else(|| {
+                ErrorResponse::new("No character_id given and none authorized".to_string())
+                    .into_response(StatusCode::BAD_REQUEST)
+            })?,
+    };
+
+    if context.characters.lock().await.get(character_id).is_none() {
+        return Err(
+            ErrorResponse::new(format!("Unknown character {}", character_id))
+                .into_response(StatusCode::NOT_FOUND),
+        );
+    }
+    Ok(character_id)
+}
+
+#[derive(Serialize, ToSchema)]
+struct AssetsRefreshResponse {
+    status: String,
+    character_id: CharacterId,
+    job_id: String,
+}
+
+/// Start a new assets resolution of a character in the background
+#[utoipa::path(
+    post,
+    path = "/my/assets/refresh",
+    params(CharacterParams),
+    responses(
+        (status = 202, description = "Resolution started", body = AssetsRefreshResponse),
+        (status = 409, description = "A resolution is already running, its job is returned", body = AssetsRefreshResponse),
+        (status = 400, description = "No character given", body = ErrorResponse),
+        (status = 404, description = "Unknown character", body = ErrorResponse)
+    )
+)]
+async fn assets_refresh_handler(
+    State(state): State<AppState>,
+    session: Session,
+    Query(params): Query<CharacterParams>,
+) -> impl IntoResponse {
+    let character_id = match requested_character(&state.context, &session, &params).await {
+        Ok(character_id) => character_id,
+        Err(response) => return response.into_response(),
+    };
+
+    let (status_code, status, job_id) =
+        match spawn_assets_resolution(state.context.clone(), character_id).await {
+            Ok(job_id) => (StatusCode::ACCEPTED, "started", job_id),
+            Err(job_id) => (StatusCode::CONFLICT, "already_running", job_id),
+        };
+
+    (
+        status_code,
+        axum::Json(AssetsRefreshResponse {
+            status: status.to_string(),
+            character_id,
+            job_id: job_id.to_string(),
+        }),
+    )
+        .into_response()
+}
+
+#[derive(Serialize, ToSchema)]
+struct AssetsStatusResponse {
+    character_id: CharacterId,
+    running: bool,
+    job_id: Option<String>,
+    started_at: Option<String>,
+    finished_at: Option<String>,
+    error: Option<String>,
+    progress: Option<eve::saga::framework::SagaProgress>,
+}
+
+/// Whether an assets resolution of a character is running, and how the last one went
+#[utoipa::path(
+    get,
+    path = "/my/assets/status",
+    params(CharacterParams),
+    responses(
+        (status = 200, description = "Assets resolution status", body = AssetsStatusResponse),
+        (status = 400, description = "No character given", body = ErrorResponse),
+        (status = 404, description = "Unknown character", body = ErrorResponse)
+    )
+)]
+async fn assets_status_handler(
+    State(state): State<AppState>,
+    session: Session,
+    Query(params): Query<CharacterParams>,
+) -> impl IntoResponse {
+    let character_id = match requested_character(&state.context, &session, &params).await {
+        Ok(character_id) => character_id,
+        Err(response) => return response.into_response(),
+    };
+
+    let job = state.context.assets_job(character_id).await;
+    let progress = state.context.assets_saga_progress(character_id).await;
+
+    axum::Json(AssetsStatusResponse {
+        character_id,
+        running: job.as_ref().is_some_and(|job| job.is_running()),
+        job_id: job.as_ref().map(|job| job.job_id.to_string()),
+        started_at: job.as_ref().map(|job| job.started_at.to_rfc3339()),
+        finished_at: job
+            .as_ref()
+            .and_then(|job| job.finished_at)
+            .map(|at| at.to_rfc3339()),
+        error: job.and_then(|job| job.error),
+        progress,
+    })
+    .into_response()
+}
+
+#[derive(Serialize, ToSchema)]
+struct MetricsResponse {
+    #[serde(flatten)]
+    client: ClientMetrics,
+    /// ESI permits no saga holds right now, out of `esi_concurrency`
+    available_esi_permits: usize,
+    /// Cache hits and fetches of the latest completed assets run, `null` before the first
+    last_assets_fetch_stats: Option<FetchStatsSnapshot>,
+}
+
+/// ESI request counts and latencies of the shared http client
+#[utoipa::path(
+    get,
+    path = "/metrics",
+    responses((status = 200, description = "ESI client metrics", body = MetricsResponse))
+)]
+async fn metrics_handler(State(state): State<AppState>) -> impl IntoResponse {
+    axum::Json(MetricsResponse {
+        client: state.context.http_client.metrics(),
+        available_esi_permits: state.context.available_esi_permits(),
+        last_assets_fetch_stats: *state.context.last_assets_fetch_stats.read().await,
+    })
+}
+
 #[derive(Clone)]
 struct AppState {
     context: Arc<AppContext>,
+    device_logins: DeviceLogins,
+}
+
+/// Device logins started on `/auth/device/start`, keyed by login id
+type DeviceLogins = Arc<Mutex<HashMap<uuid::Uuid, DeviceLogin>>>;
+
+/// How long a device login is kept after its code expired, for the result to be polled
+const DEVICE_LOGIN_GRACE: Duration = Duration::from_secs(10 * 60);
+
+struct DeviceLogin {
+    status: DeviceLoginStatus,
+    /// Forgotten after this even if never polled
+    expires_at: Instant,
+}
+
+/// Drop the device logins nobody polled in time
+fn prune_device_logins(logins: &mut HashMap<uuid::Uuid, DeviceLogin>, now: Instant) {
+    logins.retain(|_, login| login.expires_at > now);
+}
+
+/// Status of a device login. A final status is handed out once, the login is forgotten
+/// afterwards.
+fn poll_device_login(
+    logins: &mut HashMap<uuid::Uuid, DeviceLogin>,
+    login_id: &uuid::Uuid,
+) -> Option<DeviceLoginStatus> {
+    match logins.get(login_id)?.status {
+        DeviceLoginStatus::Pending => Some(DeviceLoginStatus::Pending),
+        _ => logins.remove(login_id).map(|login| login.status),
+    }
+}
+
+#[derive(Serialize, ToSchema)]
+struct AuthStartResponse {
+    /// EVE SSO login page to open in a browser
+    auth_url: String,
 }
 
-async fn auth_start(State(state): State<AppState>, session: Session) -> Result<String, String> {
+/// Start the EVE SSO login, answers with the url to open in a browser. Requests the
+/// configured scopes plus the ones given in `scopes`
+#[utoipa::path(
+    get,
+    path = "/auth/start",
+    params(AuthStartParams),
+    responses(
+        (status = 200, description = "SSO login url", body = AuthStartResponse),
+        (status = 500, description = "Session unavailable", body = ErrorResponse)
+    )
+)]
+async fn auth_start(
+    State(state): State<AppState>,
+    session: Session,
+    Query(params): Query<AuthStartParams>,
+) -> impl IntoResponse {
     let (pkce_challenge, pkce_verifier) = PkceCodeChallenge::new_random_sha256();
     let csrf_token = CsrfToken::new_random();
+    let scopes = state
+        .context
+        .config
+        .requested_scopes(params.scopes.as_deref());
 
     let auth_session = AuthSession {
         pkce_verifier: pkce_verifier.secret().to_string(),
         csrf_token: csrf_token.secret().to_string(),
+        scopes: scopes.clone(),
     };
 
     println!("pkce_challenge: {:?}", pkce_challenge);
     println!("pkce_verifier: {:?}", pkce_verifier.secret());
     println!("csrf_token: {:?}", csrf_token.secret());
 
-    session
-        .insert("auth_data", &auth_session)
-        .await
-        .map_err(|e| format!("failed to store auth data: {e}"))?;
+    if let Err(e) = session.insert("auth_data", &auth_session).await {
+        return ErrorResponse::new(format!("failed to store auth data: {e}"))
+            .into_response(StatusCode::INTERNAL_SERVER_ERROR)
+            .into_response();
+    }
 
     let oauth2_client = &state.context.oauth2_client;
 
     let (auth_url, _) = oauth2_client
         .authorize_url(|| csrf_token)
-        .add_scope(Scope::new("esi-assets.read_assets.v1".to_string()))
+        .add_scopes(scopes.into_iter().map(Scope::new))
         .set_pkce_challenge(pkce_challenge)
         .url();
 
     println!("auth_url: {}", auth_url);
-    Ok(format!("go to {auth_url}"))
+    axum::Json(AuthStartResponse {
+        auth_url: auth_url.to_string(),
+    })
+    .into_response()
 }
 
-async fn list_characters_handler(State(state): State<AppState>) -> Result<String, String> {
+/// Authorized character, without its tokens
+#[derive(Serialize, ToSchema)]
+struct CharacterSummary {
+    character_id: CharacterId,
+    character_name: String,
+    /// SSO scopes the character granted
+    scopes: BTreeSet<String>,
+}
+
+/// Characters that went through the SSO login
+#[utoipa::path(
+    get,
+    path = "/characters",
+    responses((status = 200, description = "Authorized characters", body = Vec<CharacterSummary>))
+)]
+async fn list_characters_handler(State(state): State<AppState>) -> impl IntoResponse {
     let guard = state.context.characters.lock().await;
-    let characters = guard.list();
-    Ok(format!("Characters: {:?}", characters))
+    let mut characters: Vec<CharacterSummary> = guard
+        .list()
+        .into_iter()
+        .map(|character| CharacterSummary {
+            character_id: character.character_id,
+            character_name: character.character_name.clone(),
+            scopes: character.scopes.clone(),
+        })
+        .collect();
+    characters.sort_by_key(|character| character.character_id);
+
+    axum::Json(characters)
 }
 
+#[derive(Serialize, ToSchema)]
+struct AuthCallbackResponse {
+    character_id: CharacterId,
+    character_name: String,
+    /// Assets resolution started for the character, or the one already running
+    assets_job_id: String,
+}
+
+/// SSO redirect target: exchanges the code for tokens, remembers the character in the
+/// session and starts resolving its assets
+#[utoipa::path(
+    get,
+    path = "/auth/callback",
+    params(CallbackParams),
+    responses(
+        (status = 200, description = "Character authorized", body = AuthCallbackResponse),
+        (status = 400, description = "No login started in this session or CSRF token mismatch", body = ErrorResponse),
+        (status = 500, description = "Session unavailable", body = ErrorResponse),
+        (status = 502, description = "Token exchange or character lookup failed", body = ErrorResponse)
+    )
+)]
 async fn auth_callback(
     State(state): State<AppState>,
     session: Session,
     Query(params): Query<CallbackParams>,
-) -> Result<String, String> {
-    let auth_data: AuthSession = session
-        .get("auth_data")
-        .await
-        .map_err(|e| e.to_string())?
-        .ok_or_else(|| "No auth data found in session".to_string())?;
+) -> impl IntoResponse {
+    let error = |status: StatusCode, message: String| {
+        ErrorResponse::new(message)
+            .into_response(status)
+            .into_response()
+    };
+
+    let auth_data: AuthSession = match session.get("auth_data").await {
+        Ok(Some(auth_data)) => auth_data,
+        Ok(None) => {
+            return error(
+                StatusCode::BAD_REQUEST,
+                "No auth data found in session, start at /auth/start".to_string(),
+            );
+        }
+        Err(e) => {
+            return error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("failed to read auth data: {e}"),
+            );
+        }
+    };
 
     if auth_data.csrf_token != params.state {
-        return Err("Invalid CSRF token".to_string());
+        return error(StatusCode::BAD_REQUEST, "Invalid CSRF token".to_string());
     }
 
     let pkce_verifier = PkceCodeVerifier::new(auth_data.pkce_verifier);
+    let requested_scopes = auth_data.scopes;
     session.remove::<AuthSession>("auth_data").await.ok();
 
     let oauth2_client = &state.context.oauth2_client;
 
-    let oauth2_token = oauth2_client
+    let oauth2_token = match oauth2_client
         .exchange_code(AuthorizationCode::new(params.code))
         .set_pkce_verifier(pkce_verifier)
         .request_async(&reqwest::Client::new())
         .await
-        .context("token exchange failed")
-        .map_err(|e| e.to_string())?;
+    {
+        Ok(oauth2_token) => oauth2_token,
+        Err(e) => {
+            return error(
+                StatusCode::BAD_GATEWAY,
+                format!("token exchange failed: {e}"),
+            );
+        }
+    };
 
-    let http_client = state.context.http_client.as_ref();
-    let character_info = esi::get_character_info(http_client, &oauth2_token)
-        .await
-        .map_err(|e| e.to_string())?;
+    let (character_id, character_name) =
+        match authorize_character(&state.context, oauth2_token, requested_scopes).await {
+            Ok(character) => character,
+            Err(e) => {
+                return error(
+                    StatusCode::BAD_GATEWAY,
+                    format!("character lookup failed: {e}"),
+                );
+            }
+        };
+
+    if let Err(e) = session.insert("character_id", character_id).await {
+        return error(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("failed to store character id: {e}"),
+        );
+    }
+
+    let assets_job_id = match spawn_assets_resolution(state.context.clone(), character_id).await {
+        Ok(job_id) => job_id,
+        Err(job_id) => {
+            println!(
+                "asset resolution for character {} already running as job {}",
+                character_id, job_id
+            );
+            job_id
+        }
+    };
+
+    axum::Json(AuthCallbackResponse {
+        character_id,
+        character_name,
+        assets_job_id: assets_job_id.to_string(),
+    })
+    .into_response()
+}
+
+/// Look up the character a fresh token belongs to and remember it with the scopes it granted
+async fn authorize_character(
+    context: &AppContext,
+    oauth2_token: BasicTokenResponse,
+    requested_scopes: BTreeSet<String>,
+) -> Result<(CharacterId, String), esi::EsiError> {
+    let character_info = esi::get_character_info(&context.http_client, &oauth2_token).await?;
+    let character_id = character_info.character_id;
+    let character_name = character_info.character_name;
+    // The SSO may grant fewer scopes than asked for, it says so in the token response
+    let scopes = match oauth2_token.scopes() {
+        Some(granted) => granted.iter().map(|scope| scope.to_string()).collect(),
+        None => requested_scopes,
+    };
+
+    let mut characters = context.characters.lock().await;
+    characters.add(CharacterClient::new(
+        character_id,
+        character_name.clone(),
+        oauth2_token,
+        scopes,
+    ));
+    if let Err(e) = characters.store(&context.data_dir) {
+        eprintln!("❌ Failed to store characters: {}", e);
+    }
+
+    Ok((character_id, character_name))
+}
+
+#[derive(Serialize, ToSchema)]
+struct DeviceAuthStartResponse {
+    /// Pass to `/auth/device/poll` to learn how the login went
+    login_id: String,
+    /// Page where the user enters `user_code`, on any device with a browser
+    verification_uri: String,
+    /// Verification page with the code filled in, if the SSO provides one
+    verification_uri_complete: Option<String>,
+    user_code: String,
+    /// The code is valid for this long
+    expires_in_secs: u64,
+}
+
+/// State of a device login
+#[derive(Serialize, ToSchema, Clone)]
+#[serde(tag = "status", rename_all = "snake_case")]
+enum DeviceLoginStatus {
+    /// The user hasn't entered the code yet
+    Pending,
+    Authorized {
+        character_id: CharacterId,
+        character_name: String,
+        /// Assets resolution started for the character, or the one already running
+        assets_job_id: String,
+    },
+    Failed {
+        error: String,
+    },
+}
+
+/// Start a login for machines without a browser (OAuth device authorization grant). The
+/// user enters the returned code on another device while the server polls the SSO
+#[utoipa::path(
+    post,
+    path = "/auth/device/start",
+    params(AuthStartParams),
+    responses(
+        (status = 200, description = "Device code issued", body = DeviceAuthStartResponse),
+        (status = 501, description = "No device authorization endpoint configured", body = ErrorResponse),
+        (status = 502, description = "Device code request failed", body = ErrorResponse)
+    )
+)]
+async fn device_auth_start(
+    State(state): State<AppState>,
+    Query(params): Query<AuthStartParams>,
+) -> impl IntoResponse {
+    let context = state.context.clone();
+    let Some(device_auth_url) = context.config.device_auth_url.clone() else {
+        return ErrorResponse::new(
+            "Device login isn't configured, set EVE_DEVICE_AUTH_URL".to_string(),
+        )
+        .into_response(StatusCode::NOT_IMPLEMENTED)
+        .into_response();
+    };
+    let scopes = context.config.requested_scopes(params.scopes.as_deref());
 
+    let details = match request_device_code(&context, &device_auth_url, &scopes).await {
+        Ok(details) => details,
+        Err(e) => {
+            return ErrorResponse::new(e.to_string())
+                .into_response(StatusCode::BAD_GATEWAY)
+                .into_response();
+        }
+    };
+
+    let login_id = uuid::Uuid::new_v4();
     {
-        state.context.characters.lock().await.add(CharacterClient {
-            character_id: character_info.character_id,
-            character_name: character_info.character_name,
-            oauth_token: oauth2_token,
-        })
+        let now = Instant::now();
+        let mut device_logins = state.device_logins.lock().await;
+        prune_device_logins(&mut device_logins, now);
+        device_logins.insert(
+            login_id,
+            DeviceLogin {
+                status: DeviceLoginStatus::Pending,
+                expires_at: now + details.expires_in() + DEVICE_LOGIN_GRACE,
+            },
+        );
     }
 
+    let response = DeviceAuthStartResponse {
+        login_id: login_id.to_string(),
+        verification_uri: details.verification_uri().to_string(),
+        verification_uri_complete: details
+            .verification_uri_complete()
+            .map(|uri| uri.secret().to_string()),
+        user_code: details.user_code().secret().to_string(),
+        expires_in_secs: details.expires_in().as_secs(),
+    };
+
+    let device_logins = state.device_logins.clone();
     tokio::spawn(async move {
-        println!(
-            "starting asset resolution for character {}",
-            character_info.character_id
-        );
-        match start_assets_resolution_system(state.context.clone(), character_info.character_id)
+        let status = match complete_device_login(context, &details, scopes).await {
+            Ok((character_id, character_name, assets_job_id)) => {
+                println!("🔑 Device login {} authorized {}", login_id, character_name);
+                DeviceLoginStatus::Authorized {
+                    character_id,
+                    character_name,
+                    assets_job_id: assets_job_id.to_string(),
+                }
+            }
+            Err(e) => {
+                println!("❌ Device login {} failed: {}", login_id, e);
+                DeviceLoginStatus::Failed {
+                    error: e.to_string(),
+                }
+            }
+        };
+        if let Some(login) = device_logins.lock().await.get_mut(&login_id) {
+            login.status = status;
+        }
+    });
+
+    axum::Json(response).into_response()
+}
+
+/// Ask the SSO for a device code and print where to enter it, for logins from the console
+async fn request_device_code(
+    context: &AppContext,
+    device_auth_url: &str,
+    scopes: &BTreeSet<String>,
+) -> Result<StandardDeviceAuthorizationResponse> {
+    let device_auth_url = DeviceAuthorizationUrl::new(device_auth_url.to_string())
+        .context("invalid device auth url")?;
+    let oauth2_client = context
+        .oauth2_client
+        .as_ref()
+        .clone()
+        .set_device_authorization_url(device_auth_url);
+
+    let details: StandardDeviceAuthorizationResponse = oauth2_client
+        .exchange_device_code()
+        .add_scopes(scopes.iter().cloned().map(Scope::new))
+        .request_async(&reqwest::Client::new())
+        .await
+        .map_err(|e| anyhow!("device code request failed: {e}"))?;
+
+    println!(
+        "🔑 To log in, open {} and enter the code {}",
+        details.verification_uri(),
+        details.user_code().secret()
+    );
+    Ok(details)
+}
+
+/// Poll the token endpoint until the user entered the code or it expired, then remember
+/// the character and start resolving its assets
+async fn complete_device_login(
+    context: Arc<AppContext>,
+    details: &StandardDeviceAuthorizationResponse,
+    requested_scopes: BTreeSet<String>,
+) -> Result<(CharacterId, String, uuid::Uuid)> {
+    let oauth2_token = context
+        .oauth2_client
+        .exchange_device_access_token(details)
+        .request_async(
+            &reqwest::Client::new(),
+            tokio::time::sleep,
+            Some(details.expires_in()),
+        )
+        .await
+        .map_err(|e| anyhow!("device token request failed: {e}"))?;
+
+    let (character_id, character_name) =
+        authorize_character(&context, oauth2_token, requested_scopes)
             .await
+            .map_err(|e| anyhow!("character lookup failed: {e}"))?;
+
+    let assets_job_id = match spawn_assets_resolution(context, character_id).await {
+        Ok(job_id) | Err(job_id) => job_id,
+    };
+    Ok((character_id, character_name, assets_job_id))
+}
+
+/// How a device login started on `/auth/device/start` is going. Once authorized, the
+/// character is remembered in the session like after `/auth/callback`. The outcome is
+/// returned once, the login is unknown afterwards.
+#[utoipa::path(
+    get,
+    path = "/auth/device/poll",
+    params(DevicePollParams),
+    responses(
+        (status = 200, description = "Device login state", body = DeviceLoginStatus),
+        (status = 404, description = "Unknown, expired or already finished login id", body = ErrorResponse)
+    )
+)]
+async fn device_auth_poll(
+    State(state): State<AppState>,
+    session: Session,
+    Query(params): Query<DevicePollParams>,
+) -> impl IntoResponse {
+    let status = match params.login_id.parse::<uuid::Uuid>() {
+        Ok(login_id) => poll_device_login(&mut *state.device_logins.lock().await, &login_id),
+        Err(_) => None,
+    };
+    let Some(status) = status else {
+        return ErrorResponse::new(format!("Unknown device login {}", params.login_id))
+            .into_response(StatusCode::NOT_FOUND)
+            .into_response();
+    };
+
+    if let DeviceLoginStatus::Authorized { character_id, .. } = &status
+        && let Err(e) = session.insert("character_id", *character_id).await
+    {
+        return ErrorResponse::new(format!("failed to store character id: {e}"))
+            .into_response(StatusCode::INTERNAL_SERVER_ERROR)
+            .into_response();
+    }
+
+    axum::Json(status).into_response()
+}
+
+/// Run the assets resolution of a character in the background, returns its job id.
+/// Fails with the id of the running job instead of starting a second one.
+async fn spawn_assets_resolution(
+    context: Arc<AppContext>,
+    character_id: CharacterId,
+) -> Result<uuid::Uuid, uuid::Uuid> {
+    let job_id = context.start_assets_job(character_id).await?;
+
+    tokio::spawn(async move {
+        println!("starting asset resolution for character {}", character_id);
+        let workers_count = context.config.assets_workers;
+        let error = match start_assets_resolution_system(
+            context.clone(),
+            character_id,
+            workers_count,
+        )
+        .await
         {
-            Ok(_) => println!(
-                "asset resolution for character {} completed",
-                character_info.character_id
-            ),
-            Err(e) => println!(
-                "asset resolution for character {} failed: {}",
-                character_info.character_id, e
-            ),
-        }
+            Ok(_) => {
+                println!("asset resolution for character {} completed", character_id);
+                None
+            }
+            Err(e) => {
+                println!(
+                    "asset resolution for character {} failed: {}",
+                    character_id, e
+                );
+                Some(e.to_string())
+            }
+        };
+        context.finish_assets_job(character_id, job_id, error).await;
     });
 
-    Ok("auth successful".to_string())
+    Ok(job_id)
 }
 
 #[derive(Serialize, Deserialize)]
 struct AuthSession {
     pkce_verifier: String,
     csrf_token: String,
+    /// Scopes asked for, taken as granted if the token response doesn't list them
+    #[serde(default)]
+    scopes: BTreeSet<String>,
 }
 
 async fn start_http_server(context: Arc<AppContext>, port: u16) -> tokio::task::JoinHandle<()> {
@@ -404,11 +1349,26 @@ async fn start_http_server(context: Arc<AppContext>, port: u16) -> tokio::task::
     let app = Router::new()
         .route("/auth/start", get(auth_start))
         .route("/auth/callback", get(auth_callback))
+        .route("/auth/device/start", post(device_auth_start))
+        .route("/auth/device/poll", get(device_auth_poll))
         .route("/characters", get(list_characters_handler))
         .route("/my/dynamics", get(dynamics_report_handler))
+        .route("/my/dynamics/summary", get(dynamics_summary_handler))
+        .route("/dynamics/lookup", get(dynamics_lookup_handler))
+        .route("/my/assets", get(assets_report_handler))
+        .route("/my/assets.csv", get(assets_csv_handler))
         .route("/profile/my/dynamics", get(profile_dynamics_report_handler))
+        .route("/openapi.json", get(openapi_handler))
+        .route("/admin/hoboleaks/refresh", post(hoboleaks_refresh_handler))
+        .route("/market/status", get(market_status_handler))
+        .route("/market/spread", get(market_spread_handler))
+        .route("/assets/{character_id}/cancel", post(cancel_assets_handler))
+        .route("/my/assets/refresh", post(assets_refresh_handler))
+        .route("/my/assets/status", get(assets_status_handler))
+        .route("/metrics", get(metrics_handler))
         .with_state(AppState {
             context: context.clone(),
+            device_logins: Arc::new(Mutex::new(HashMap::new())),
         })
         .layer(session_layer);
 
@@ -430,8 +1390,95 @@ async fn shutdown_signal() {
         .expect("failed to install ctrl-c handler");
 }
 
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Deserialize, utoipa::IntoParams, Debug, Clone)]
+pub struct AuthStartParams {
+    /// Scopes requested on top of the configured ones, separated by commas or spaces
+    scopes: Option<String>,
+}
+
+#[derive(Deserialize, utoipa::IntoParams, Debug, Clone)]
+pub struct DevicePollParams {
+    /// Login id handed out by `/auth/device/start`
+    login_id: String,
+}
+
+#[derive(Deserialize, utoipa::IntoParams, Debug, Clone)]
+pub struct MarketSpreadParams {
+    #[param(value_type = i64)]
+    region: RegionId,
+    #[serde(rename = "type")]
+    #[param(rename = "type")]
+    type_id: TypeId,
+}
+
+#[derive(Deserialize, utoipa::IntoParams, Debug, Clone)]
 pub struct CallbackParams {
+    /// Authorization code issued by the SSO
     code: String,
+    /// CSRF token handed out by `/auth/start`
     state: String,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn login(status: DeviceLoginStatus, expires_at: Instant) -> DeviceLogin {
+        DeviceLogin { status, expires_at }
+    }
+
+    #[test]
+    fn finished_device_login_is_polled_once() {
+        let now = Instant::now();
+        let pending = uuid::Uuid::new_v4();
+        let failed = uuid::Uuid::new_v4();
+        let mut logins = HashMap::from([
+            (
+                pending,
+                login(DeviceLoginStatus::Pending, now + DEVICE_LOGIN_GRACE),
+            ),
+            (
+                failed,
+                login(
+                    DeviceLoginStatus::Failed {
+                        error: "expired_token".to_string(),
+                    },
+                    now + DEVICE_LOGIN_GRACE,
+                ),
+            ),
+        ]);
+
+        assert!(matches!(
+            poll_device_login(&mut logins, &pending),
+            Some(DeviceLoginStatus::Pending)
+        ));
+        assert!(matches!(
+            poll_device_login(&mut logins, &pending),
+            Some(DeviceLoginStatus::Pending)
+        ));
+        assert!(matches!(
+            poll_device_login(&mut logins, &failed),
+            Some(DeviceLoginStatus::Failed { .. })
+        ));
+        assert!(poll_device_login(&mut logins, &failed).is_none());
+    }
+
+    #[test]
+    fn expired_device_logins_are_pruned() {
+        let now = Instant::now();
+        let expired = uuid::Uuid::new_v4();
+        let current = uuid::Uuid::new_v4();
+        let mut logins = HashMap::from([
+            (expired, login(DeviceLoginStatus::Pending, now)),
+            (
+                current,
+                login(DeviceLoginStatus::Pending, now + DEVICE_LOGIN_GRACE),
+            ),
+        ]);
+
+        prune_device_logins(&mut logins, now);
+
+        assert!(!logins.contains_key(&expired));
+        assert!(logins.contains_key(&current));
+    }
+}
@@ -1,52 +1,584 @@
 use http::Error as HttpError;
-use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
-use reqwest::{Client, Error, RequestBuilder, Response};
-use std::sync::Arc;
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
-use tokio::sync::Mutex;
+use reqwest::header::{
+    DATE, ETAG, EXPIRES, HeaderMap, HeaderName, HeaderValue, IF_NONE_MATCH, USER_AGENT,
+};
+use reqwest::{Client, Error, Method, RequestBuilder, Response, StatusCode};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use tokio::time::sleep;
+use tracing::{info, warn};
+use utoipa::ToSchema;
 
 use crate::RatelimitGroup;
 
+// ESI error budget per window
+const ERROR_LIMIT: u32 = 100;
+const DEFAULT_ERROR_THRESHOLD: u32 = 10;
+
+/// Latest `X-ESI-Error-Limit-Remain` / `X-ESI-Error-Limit-Reset` values
+#[derive(Debug)]
+struct ErrorBudget {
+    remain: u32,
+    reset_at: Duration,
+    threshold: u32,
+}
+
+impl ErrorBudget {
+    fn new(threshold: u32) -> Self {
+        ErrorBudget {
+            remain: ERROR_LIMIT,
+            reset_at: Duration::ZERO,
+            threshold,
+        }
+    }
+
+    fn update(&mut self, remain: u32, reset_in: Duration, now: Duration) {
+        self.remain = remain;
+        self.reset_at = now + reset_in;
+    }
+
+    fn reset_in(&self, now: Duration) -> Duration {
+        self.reset_at.saturating_sub(now)
+    }
+
+    /// How long to hold requests back: until the window resets if the budget is nearly spent
+    fn wait_at(&self, now: Duration) -> Option<Duration> {
+        if self.remain >= self.threshold {
+            return None;
+        }
+
+        let reset_in = self.reset_in(now);
+        if reset_in.is_zero() {
+            None
+        } else {
+            Some(reset_in)
+        }
+    }
+}
+
+// Consecutive 5xx/transport failures that open the circuit, and how long it stays open
+const DEFAULT_CIRCUIT_THRESHOLD: u32 = 10;
+const DEFAULT_CIRCUIT_COOLDOWN: Duration = Duration::from_secs(30);
+// How often requests held back by a half-open circuit check whether the probe came back
+const CIRCUIT_PROBE_POLL: Duration = Duration::from_secs(1);
+
+/// Stops sending requests while ESI is down. After `threshold` consecutive server errors
+/// or transport failures the circuit opens and requests wait for `cooldown`; then a
+/// single probe request goes out, closing the circuit if it succeeds and reopening it
+/// if it fails.
+#[derive(Debug)]
+struct CircuitBreaker {
+    threshold: u32,
+    cooldown: Duration,
+    consecutive_failures: u32,
+    open_until: Option<Duration>,
+    /// When the probe of a half-open circuit was let through
+    probe_sent_at: Option<Duration>,
+}
+
+impl CircuitBreaker {
+    fn new(threshold: u32, cooldown: Duration) -> Self {
+        CircuitBreaker {
+            threshold,
+            cooldown,
+            consecutive_failures: 0,
+            open_until: None,
+            probe_sent_at: None,
+        }
+    }
+
+    /// How long to hold a request back, None lets it through. Letting a request through a
+    /// half-open circuit makes it the probe, a probe that never reported back is replaced
+    /// after `cooldown`.
+    fn wait_at(&mut self, now: Duration) -> Option<Duration> {
+        let open_until = self.open_until?;
+        if now < open_until {
+            return Some(open_until - now);
+        }
+
+        match self.probe_sent_at {
+            Some(sent_at) if now < sent_at + self.cooldown => Some(CIRCUIT_PROBE_POLL),
+            _ => {
+                self.probe_sent_at = Some(now);
+                None
+            }
+        }
+    }
+
+    fn record(&mut self, failed: bool, now: Duration) {
+        if !failed {
+            if self.open_until.is_some() {
+                info!("✅ ESI is responding again, closing the circuit");
+            }
+            self.consecutive_failures = 0;
+            self.open_until = None;
+            self.probe_sent_at = None;
+            return;
+        }
+
+        self.consecutive_failures += 1;
+        let probe_failed = self.probe_sent_at.is_some();
+        if self.threshold > 0 && (probe_failed || self.consecutive_failures == self.threshold) {
+            warn!(
+                "⚠️  ESI failed {} times in a row, pausing requests for {:?}",
+                self.consecutive_failures, self.cooldown
+            );
+            self.open_until = Some(now + self.cooldown);
+            self.probe_sent_at = None;
+        }
+    }
+
+    fn state(&self, now: Duration) -> CircuitState {
+        match self.open_until {
+            None => CircuitState::Closed {
+                consecutive_failures: self.consecutive_failures,
+            },
+            Some(open_until) if now < open_until => CircuitState::Open {
+                retry_in_ms: (open_until - now).as_millis() as u64,
+            },
+            Some(_) => CircuitState::HalfOpen,
+        }
+    }
+}
+
+/// State of a client's circuit breaker, see `RatelimitedClient::with_circuit_breaker`
+#[derive(Serialize, ToSchema, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum CircuitState {
+    /// Requests go through
+    Closed { consecutive_failures: u32 },
+    /// ESI kept failing, requests wait until the cooldown is over
+    Open { retry_in_ms: u64 },
+    /// Cooldown over, a single probe request decides whether to close or reopen
+    HalfOpen,
+}
+
+#[derive(Debug)]
+struct Limits {
+    ratelimit_group: RatelimitGroup,
+    error_budget: ErrorBudget,
+    circuit_breaker: CircuitBreaker,
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    // Limits and cache entries are replaced whole, so they stay consistent even if a
+    // holder panicked and a poisoned lock is still usable
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Upper bounds of the request latency buckets in milliseconds, slower requests land in
+/// an extra overflow bucket
+const LATENCY_BUCKETS_MS: [u64; 12] = [
+    10, 25, 50, 100, 250, 500, 1_000, 2_500, 5_000, 10_000, 30_000, 60_000,
+];
+
+/// Counters updated by every `send`, shared by the requests of a client
+#[derive(Debug, Default)]
+struct Metrics {
+    requests: AtomicU64,
+    transport_errors: AtomicU64,
+    /// Responses by status class, indexed by `status / 100`
+    status_classes: [AtomicU64; 6],
+    throttle_wait_nanos: AtomicU64,
+    retry_after_wait_nanos: AtomicU64,
+    latency_buckets: [AtomicU64; LATENCY_BUCKETS_MS.len() + 1],
+}
+
+impl Metrics {
+    fn add_wait(counter: &AtomicU64, wait: Duration) {
+        counter.fetch_add(wait.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    fn record(&self, latency: Duration, status: Option<StatusCode>) {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+        match status {
+            Some(status) => {
+                let class = (status.as_u16() / 100) as usize;
+                self.status_classes[class.min(5)].fetch_add(1, Ordering::Relaxed);
+            }
+            None => {
+                self.transport_errors.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+
+        let latency_ms = latency.as_millis() as u64;
+        let bucket = LATENCY_BUCKETS_MS
+            .iter()
+            .position(|le| latency_ms <= *le)
+            .unwrap_or(LATENCY_BUCKETS_MS.len());
+        self.latency_buckets[bucket].fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn snapshot(&self) -> ClientMetrics {
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        let buckets: Vec<u64> = self.latency_buckets.iter().map(load).collect();
+
+        ClientMetrics {
+            requests: load(&self.requests),
+            transport_errors: load(&self.transport_errors),
+            status_2xx: load(&self.status_classes[2]),
+            status_3xx: load(&self.status_classes[3]),
+            status_4xx: load(&self.status_classes[4]),
+            status_5xx: load(&self.status_classes[5]),
+            throttle_wait_ms: load(&self.throttle_wait_nanos) / 1_000_000,
+            retry_after_wait_ms: load(&self.retry_after_wait_nanos) / 1_000_000,
+            latency_p50_ms: latency_percentile(&buckets, 0.50),
+            latency_p99_ms: latency_percentile(&buckets, 0.99),
+            circuit: CircuitState::Closed {
+                consecutive_failures: 0,
+            },
+            latency_buckets: buckets
+                .iter()
+                .enumerate()
+                .map(|(i, count)| LatencyBucket {
+                    le_ms: LATENCY_BUCKETS_MS.get(i).copied(),
+                    count: *count,
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Upper bound of the bucket holding the `quantile` of the requests, None without
+/// requests or when it falls in the overflow bucket
+fn latency_percentile(buckets: &[u64], quantile: f64) -> Option<u64> {
+    let total: u64 = buckets.iter().sum();
+    if total == 0 {
+        return None;
+    }
+
+    let rank = ((total as f64) * quantile).ceil().max(1.0) as u64;
+    let mut seen = 0;
+    for (i, count) in buckets.iter().enumerate() {
+        seen += count;
+        if seen >= rank {
+            return LATENCY_BUCKETS_MS.get(i).copied();
+        }
+    }
+    None
+}
+
+/// Requests sent by a `RatelimitedClient` since it was built. Latencies cover the HTTP
+/// exchange only, the time held back by the client's own limits is in `throttle_wait_ms`.
+#[derive(Serialize, ToSchema, Debug, Clone)]
+pub struct ClientMetrics {
+    pub requests: u64,
+    /// Requests that got no response (connect errors, timeouts)
+    pub transport_errors: u64,
+    pub status_2xx: u64,
+    pub status_3xx: u64,
+    pub status_4xx: u64,
+    pub status_5xx: u64,
+    /// Time spent waiting for the rate limits and the ESI error budget
+    pub throttle_wait_ms: u64,
+    /// Time spent backing off as asked by ESI's `Retry-After`
+    pub retry_after_wait_ms: u64,
+    /// Estimated from the buckets, `null` without requests or beyond the largest bucket
+    pub latency_p50_ms: Option<u64>,
+    pub latency_p99_ms: Option<u64>,
+    pub latency_buckets: Vec<LatencyBucket>,
+    pub circuit: CircuitState,
+}
+
+#[derive(Serialize, ToSchema, Debug, Clone)]
+pub struct LatencyBucket {
+    /// Upper bound in milliseconds, `null` for the overflow bucket
+    pub le_ms: Option<u64>,
+    pub count: u64,
+}
+
+/// Last successful response of a GET, replayed when ESI answers 304 Not Modified
+#[derive(Debug, Clone)]
+struct CachedResponse {
+    etag: HeaderValue,
+    headers: HeaderMap,
+    body: Vec<u8>,
+}
+
+type EtagCache = Arc<Mutex<HashMap<String, CachedResponse>>>;
+
+fn now() -> Duration {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_else(|_| Duration::from_secs(0))
+}
+
+/// Host of the real ESI, without trailing slash
+pub const DEFAULT_ESI_BASE_URL: &str = "https://esi.evetech.net";
+
+/// User-Agent sent unless configured otherwise, ESI asks for one that identifies the app
+/// and says how to reach its developer
+pub const DEFAULT_USER_AGENT: &str = concat!(
+    "rust-eve-tools/",
+    env!("CARGO_PKG_VERSION"),
+    " (+https://github.com/stay-focused-dev/rust-eve-tools)"
+);
+
+const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Settings of the reqwest `Client` underneath a `RatelimitedClient`
+#[derive(Debug, Clone)]
+pub struct RatelimitedClientBuilder {
+    user_agent: String,
+    connect_timeout: Option<Duration>,
+    timeout: Option<Duration>,
+    pool_idle_timeout: Option<Duration>,
+    pool_max_idle_per_host: Option<usize>,
+}
+
+impl Default for RatelimitedClientBuilder {
+    fn default() -> Self {
+        RatelimitedClientBuilder {
+            user_agent: DEFAULT_USER_AGENT.to_string(),
+            connect_timeout: Some(DEFAULT_CONNECT_TIMEOUT),
+            timeout: Some(DEFAULT_TIMEOUT),
+            pool_idle_timeout: None,
+            pool_max_idle_per_host: None,
+        }
+    }
+}
+
+impl RatelimitedClientBuilder {
+    pub fn user_agent(mut self, user_agent: &str) -> Self {
+        self.user_agent = user_agent.to_string();
+        self
+    }
+
+    /// Time allowed to establish a connection, `None` waits forever
+    pub fn connect_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.connect_timeout = timeout;
+        self
+    }
+
+    /// Time allowed for a whole request including reading the body, `None` waits forever
+    pub fn timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// How long unused connections are kept open, reqwest's default if unset
+    pub fn pool_idle_timeout(mut self, timeout: Duration) -> Self {
+        self.pool_idle_timeout = Some(timeout);
+        self
+    }
+
+    /// Upper bound of unused connections kept open per host, reqwest's default if unset
+    pub fn pool_max_idle_per_host(mut self, max: usize) -> Self {
+        self.pool_max_idle_per_host = Some(max);
+        self
+    }
+
+    /// Build the reqwest client and put it behind `ratelimit_group`
+    pub fn build(self, ratelimit_group: RatelimitGroup) -> Result<RatelimitedClient, Error> {
+        let mut builder = Client::builder().user_agent(&self.user_agent);
+        if let Some(timeout) = self.connect_timeout {
+            builder = builder.connect_timeout(timeout);
+        }
+        if let Some(timeout) = self.timeout {
+            builder = builder.timeout(timeout);
+        }
+        if let Some(timeout) = self.pool_idle_timeout {
+            builder = builder.pool_idle_timeout(timeout);
+        }
+        if let Some(max) = self.pool_max_idle_per_host {
+            builder = builder.pool_max_idle_per_host(max);
+        }
+
+        let mut client = RatelimitedClient::with_client(builder.build()?, ratelimit_group);
+        // reqwest refused to build with an invalid user agent already
+        if let Ok(user_agent) = HeaderValue::from_str(&self.user_agent) {
+            client = client.with_user_agent(user_agent);
+        }
+        Ok(client)
+    }
+}
+
 pub struct RatelimitedClient {
     inner: Client,
-    ratelimit_group: Arc<Mutex<RatelimitGroup>>,
+    base_url: String,
+    compatibility_date: Option<String>,
+    limits: Arc<Mutex<Limits>>,
+    respect_retry_after: bool,
+    etag_cache: Option<EtagCache>,
+    user_agent: HeaderValue,
+    metrics: Arc<Metrics>,
 }
 
 impl RatelimitedClient {
+    /// Configure the underlying reqwest client, defaults to `DEFAULT_USER_AGENT` and
+    /// connect/request timeouts instead of reqwest's none
+    pub fn builder() -> RatelimitedClientBuilder {
+        RatelimitedClientBuilder::default()
+    }
+
     pub fn new(ratelimit_group: RatelimitGroup) -> Self {
-        RatelimitedClient {
-            inner: Client::new(),
-            ratelimit_group: Arc::new(Mutex::new(ratelimit_group)),
-        }
+        Self::with_client(Client::new(), ratelimit_group)
     }
 
     pub fn with_client(client: Client, ratelimit_group: RatelimitGroup) -> Self {
         RatelimitedClient {
             inner: client,
-            ratelimit_group: Arc::new(Mutex::new(ratelimit_group)),
+            base_url: DEFAULT_ESI_BASE_URL.to_string(),
+            compatibility_date: None,
+            limits: Arc::new(Mutex::new(Limits {
+                ratelimit_group,
+                error_budget: ErrorBudget::new(DEFAULT_ERROR_THRESHOLD),
+                circuit_breaker: CircuitBreaker::new(
+                    DEFAULT_CIRCUIT_THRESHOLD,
+                    DEFAULT_CIRCUIT_COOLDOWN,
+                ),
+            })),
+            respect_retry_after: false,
+            etag_cache: None,
+            user_agent: HeaderValue::from_static(DEFAULT_USER_AGENT),
+            metrics: Arc::new(Metrics::default()),
         }
     }
 
-    pub fn get(&self, url: impl AsRef<str>) -> RatelimitedRequestBuilder {
+    /// User-Agent attached to every request that doesn't set one itself
+    pub fn with_user_agent(mut self, user_agent: HeaderValue) -> Self {
+        self.user_agent = user_agent;
+        self
+    }
+
+    /// Send ESI requests to `base_url` instead of the real ESI, e.g. a local mock server
+    pub fn with_base_url(mut self, base_url: &str) -> Self {
+        self.base_url = base_url.trim_end_matches('/').to_string();
+        self
+    }
+
+    /// Scheme and host ESI request urls are built from, without trailing slash
+    pub fn base_url(&self) -> &str {
+        &self.base_url
+    }
+
+    /// Pin ESI routes to a compatibility date (`YYYY-MM-DD`) sent as `X-Compatibility-Date`
+    /// instead of using the `/latest/` routes
+    pub fn with_compatibility_date(mut self, date: &str) -> Self {
+        self.compatibility_date = Some(date.to_string());
+        self
+    }
+
+    pub fn compatibility_date(&self) -> Option<&str> {
+        self.compatibility_date.as_deref()
+    }
+
+    /// Pause all requests until the error window resets once fewer than `threshold`
+    /// errors are left in the ESI error budget
+    pub fn with_error_threshold(self, threshold: u32) -> Self {
+        lock(&self.limits).error_budget.threshold = threshold;
+        self
+    }
+
+    /// Hold all requests back for `cooldown` once `threshold` requests in a row failed
+    /// with a 5xx or got no response, instead of piling more failures onto an ESI
+    /// outage. A `threshold` of 0 disables the circuit breaker.
+    pub fn with_circuit_breaker(self, threshold: u32, cooldown: Duration) -> Self {
+        lock(&self.limits).circuit_breaker = CircuitBreaker::new(threshold, cooldown);
+        self
+    }
+
+    pub fn circuit_state(&self) -> CircuitState {
+        lock(&self.limits).circuit_breaker.state(now())
+    }
+
+    /// Errors left in the current ESI error window and the time until it resets
+    pub fn error_budget(&self) -> (u32, Duration) {
+        let limits = lock(&self.limits);
+        (
+            limits.error_budget.remain,
+            limits.error_budget.reset_in(now()),
+        )
+    }
+
+    /// Request counts, statuses, latencies and time spent throttled so far
+    pub fn metrics(&self) -> ClientMetrics {
+        let mut metrics = self.metrics.snapshot();
+        metrics.circuit = self.circuit_state();
+        metrics
+    }
+
+    /// How long until `send` would let the next request through, zero if it would go now.
+    /// Nothing is recorded, so this doesn't consume any of the rate limit.
+    pub fn time_until_available(&self) -> Duration {
+        let now = now();
+        let limits = lock(&self.limits);
+        let circuit_wait = match limits.circuit_breaker.state(now) {
+            CircuitState::Open { retry_in_ms } => Some(Duration::from_millis(retry_in_ms)),
+            _ => None,
+        };
+        // `send` waits for all of them, so the longest one is when it goes
+        [
+            limits.error_budget.wait_at(now),
+            circuit_wait,
+            limits.ratelimit_group.can_hit_at(now),
+        ]
+        .into_iter()
+        .flatten()
+        .max()
+        .unwrap_or(Duration::ZERO)
+    }
+
+    /// When enabled, a 420/429 response makes `send` sleep for the `Retry-After`
+    /// seconds before handing the response back, so the next request isn't sent
+    /// while ESI still rejects us.
+    pub fn with_retry_after(mut self, enabled: bool) -> Self {
+        self.respect_retry_after = enabled;
+        self
+    }
+
+    /// When enabled, GETs are sent with `If-None-Match` once a response carried an `ETag`,
+    /// and a 304 is answered with the cached body as if ESI had sent it again.
+    /// Bodies are kept in memory per URL for the lifetime of the client.
+    pub fn with_etag_cache(mut self, enabled: bool) -> Self {
+        self.etag_cache = enabled.then(|| Arc::new(Mutex::new(HashMap::new())));
+        self
+    }
+
+    fn request(&self, builder: RequestBuilder) -> RatelimitedRequestBuilder {
         RatelimitedRequestBuilder {
-            builder: self.inner.get(url.as_ref()),
-            ratelimit_group: Arc::clone(&self.ratelimit_group),
+            builder,
+            limits: Arc::clone(&self.limits),
+            respect_retry_after: self.respect_retry_after,
+            etag_cache: self.etag_cache.clone(),
+            user_agent: self.user_agent.clone(),
+            metrics: Arc::clone(&self.metrics),
         }
     }
 
+    pub fn get(&self, url: impl AsRef<str>) -> RatelimitedRequestBuilder {
+        self.request(self.inner.get(url.as_ref()))
+    }
+
     pub fn post(&self, url: impl AsRef<str>) -> RatelimitedRequestBuilder {
-        RatelimitedRequestBuilder {
-            builder: self.inner.post(url.as_ref()),
-            ratelimit_group: Arc::clone(&self.ratelimit_group),
-        }
+        self.request(self.inner.post(url.as_ref()))
+    }
+
+    pub fn put(&self, url: impl AsRef<str>) -> RatelimitedRequestBuilder {
+        self.request(self.inner.put(url.as_ref()))
+    }
+
+    pub fn delete(&self, url: impl AsRef<str>) -> RatelimitedRequestBuilder {
+        self.request(self.inner.delete(url.as_ref()))
+    }
+
+    pub fn patch(&self, url: impl AsRef<str>) -> RatelimitedRequestBuilder {
+        self.request(self.inner.patch(url.as_ref()))
     }
 }
 
 pub struct RatelimitedRequestBuilder {
     builder: RequestBuilder,
 
-    ratelimit_group: Arc<Mutex<RatelimitGroup>>,
+    limits: Arc<Mutex<Limits>>,
+    respect_retry_after: bool,
+    etag_cache: Option<EtagCache>,
+    user_agent: HeaderValue,
+    metrics: Arc<Metrics>,
 }
 
 impl RatelimitedRequestBuilder {
@@ -59,42 +591,334 @@ impl RatelimitedRequestBuilder {
     {
         RatelimitedRequestBuilder {
             builder: self.builder.header(key, value),
-            ratelimit_group: self.ratelimit_group,
+            ..self
         }
     }
 
     pub fn headers(self, headers: HeaderMap) -> Self {
         RatelimitedRequestBuilder {
             builder: self.builder.headers(headers),
-            ratelimit_group: self.ratelimit_group,
+            ..self
         }
     }
 
     pub fn json<T: serde::Serialize + ?Sized>(self, json: &T) -> Self {
         RatelimitedRequestBuilder {
             builder: self.builder.json(json),
-            ratelimit_group: self.ratelimit_group,
+            ..self
         }
     }
 
     pub async fn send(self) -> Result<Response, Error> {
-        let ratelimit_group = self.ratelimit_group;
-
         loop {
-            let now = SystemTime::now()
-                .duration_since(UNIX_EPOCH)
-                .unwrap_or_else(|_| Duration::from_secs(0));
+            let wait_time = {
+                let now = now();
+                let mut limits = lock(&self.limits);
+                // The circuit comes after the non-recording rate limit check, a request
+                // let through as the probe must not then wait on the rate limit
+                limits
+                    .error_budget
+                    .wait_at(now)
+                    .or_else(|| limits.ratelimit_group.can_hit_at(now))
+                    .or_else(|| limits.circuit_breaker.wait_at(now))
+                    .or_else(|| limits.ratelimit_group.hit_at(now))
+            };
+
+            match wait_time {
+                Some(wait_time) => {
+                    Metrics::add_wait(&self.metrics.throttle_wait_nanos, wait_time);
+                    sleep(wait_time).await
+                }
+                None => break,
+            }
+        }
 
-            let mut ratelimit_group_guard = ratelimit_group.lock().await;
-            if let Some(wait_time) = ratelimit_group_guard.hit_at(now) {
-                drop(ratelimit_group_guard);
+        let (client, request) = self.builder.build_split();
+        let mut request = request?;
+        request
+            .headers_mut()
+            .entry(USER_AGENT)
+            .or_insert(self.user_agent);
 
-                sleep(wait_time).await;
-                continue;
+        // Only GETs are conditional, the cache is keyed by the full URL
+        let etag_cache = self
+            .etag_cache
+            .filter(|_| request.method() == Method::GET)
+            .map(|cache| (cache, request.url().to_string()));
+
+        if let Some((cache, url)) = &etag_cache
+            && let Some(cached) = lock(cache).get(url)
+        {
+            request
+                .headers_mut()
+                .insert(IF_NONE_MATCH, cached.etag.clone());
+        }
+
+        let started_at = Instant::now();
+        let response = client.execute(request).await;
+        let status = response.as_ref().ok().map(|response| response.status());
+        self.metrics.record(started_at.elapsed(), status);
+        lock(&self.limits)
+            .circuit_breaker
+            .record(status.is_none_or(|status| status.is_server_error()), now());
+        let response = response?;
+
+        if let Some((remain, reset_in)) = error_limit_headers(&response) {
+            let mut limits = lock(&self.limits);
+            limits.error_budget.update(remain, reset_in, now());
+            if remain < limits.error_budget.threshold {
+                warn!(
+                    "⚠️  ESI error budget low: {} left, resets in {:?}",
+                    remain, reset_in
+                );
             }
+        }
 
-            break;
+        if self.respect_retry_after
+            && let Some(retry_after) = retry_after(&response)
+        {
+            warn!(
+                "⚠️  ESI responded {}, backing off for {:?}",
+                response.status(),
+                retry_after
+            );
+            Metrics::add_wait(&self.metrics.retry_after_wait_nanos, retry_after);
+            sleep(retry_after).await;
         }
-        self.builder.send().await
+
+        match etag_cache {
+            Some((cache, url)) => replay_or_store(&cache, url, response).await,
+            None => Ok(response),
+        }
+    }
+}
+
+/// Answer a 304 with the cached response of `url`, remember 200s carrying an `ETag`
+async fn replay_or_store(
+    cache: &Mutex<HashMap<String, CachedResponse>>,
+    url: String,
+    response: Response,
+) -> Result<Response, Error> {
+    match response.status() {
+        StatusCode::NOT_MODIFIED => {
+            let Some(cached) = lock(cache).get(&url).cloned() else {
+                return Ok(response);
+            };
+
+            // Freshness headers of the 304 supersede the cached ones
+            let mut headers = cached.headers;
+            for name in [DATE, ETAG, EXPIRES] {
+                if let Some(value) = response.headers().get(&name) {
+                    headers.insert(name, value.clone());
+                }
+            }
+
+            Ok(build_response(headers, cached.body))
+        }
+        StatusCode::OK => {
+            let Some(etag) = response.headers().get(ETAG).cloned() else {
+                return Ok(response);
+            };
+
+            let headers = response.headers().clone();
+            let body = response.bytes().await?.to_vec();
+            lock(cache).insert(
+                url,
+                CachedResponse {
+                    etag,
+                    headers: headers.clone(),
+                    body: body.clone(),
+                },
+            );
+
+            Ok(build_response(headers, body))
+        }
+        _ => Ok(response),
+    }
+}
+
+fn build_response(headers: HeaderMap, body: Vec<u8>) -> Response {
+    let mut response = http::Response::new(body);
+    *response.headers_mut() = headers;
+    Response::from(response)
+}
+
+/// `X-ESI-Error-Limit-Remain` and `X-ESI-Error-Limit-Reset` of a response, if present
+fn error_limit_headers(response: &Response) -> Option<(u32, Duration)> {
+    let header = |name: &str| {
+        response
+            .headers()
+            .get(name)
+            .and_then(|h| h.to_str().ok())
+            .and_then(|v| v.trim().parse::<u64>().ok())
+    };
+
+    let remain = header("x-esi-error-limit-remain")?;
+    let reset = header("x-esi-error-limit-reset")?;
+
+    Some((remain as u32, Duration::from_secs(reset)))
+}
+
+/// Back-off requested by ESI on a 420 (error limited) or 429 (rate limited) response
+fn retry_after(response: &Response) -> Option<Duration> {
+    if !matches!(response.status().as_u16(), 420 | 429) {
+        return None;
+    }
+
+    response
+        .headers()
+        .get("retry-after")
+        .and_then(|h| h.to_str().ok())
+        .and_then(|v| v.trim().parse::<u64>().ok())
+        .map(Duration::from_secs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Ratelimit;
+    use axum::Router;
+    use axum::routing::any;
+
+    /// Serve `router` on a free local port, returns its base url
+    async fn serve(router: Router) -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, router).await.unwrap() });
+        format!("http://{addr}")
+    }
+
+    /// One request a minute, so the second one of a test would wait
+    fn one_per_minute() -> RatelimitedClient {
+        RatelimitedClient::new(RatelimitGroup::new(vec![Ratelimit::new(
+            Duration::from_secs(60),
+            1,
+        )]))
+    }
+
+    #[tokio::test]
+    async fn put_delete_and_patch_count_against_the_rate_limit() {
+        let url = serve(Router::new().route("/", any(|| async { "ok" }))).await;
+
+        for method in [Method::PUT, Method::DELETE, Method::PATCH] {
+            let client = one_per_minute();
+            let request = match method {
+                Method::PUT => client.put(&url),
+                Method::DELETE => client.delete(&url),
+                _ => client.patch(&url),
+            };
+            let response = request.send().await.unwrap();
+
+            assert_eq!(response.status(), StatusCode::OK);
+            assert!(
+                client.time_until_available() > Duration::ZERO,
+                "{} didn't use up the rate limit",
+                method
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn time_until_available_is_the_longest_wait() {
+        // Error budget nearly spent for 2 more seconds, rate limit used up for a minute
+        let url = serve(Router::new().route(
+            "/",
+            any(|| async {
+                (
+                    [
+                        ("X-ESI-Error-Limit-Remain", "1"),
+                        ("X-ESI-Error-Limit-Reset", "2"),
+                    ],
+                    "ok",
+                )
+            }),
+        ))
+        .await;
+        let client = one_per_minute().with_error_threshold(5);
+
+        client.get(&url).send().await.unwrap();
+
+        let (remain, reset_in) = client.error_budget();
+        assert_eq!(remain, 1);
+        assert!(reset_in <= Duration::from_secs(2));
+        assert!(client.time_until_available() > Duration::from_secs(30));
+    }
+
+    #[tokio::test]
+    async fn not_modified_replays_the_cached_body() {
+        // Bodies sent and 304s answered by the mock ESI
+        let counts = Arc::new((AtomicU64::new(0), AtomicU64::new(0)));
+        let handler_counts = Arc::clone(&counts);
+        let router = Router::new().route(
+            "/orders",
+            any(move |headers: HeaderMap| {
+                let counts = Arc::clone(&handler_counts);
+                async move {
+                    if headers
+                        .get(IF_NONE_MATCH)
+                        .is_some_and(|etag| etag == "\"v1\"")
+                    {
+                        counts.1.fetch_add(1, Ordering::Relaxed);
+                        return (StatusCode::NOT_MODIFIED, [(ETAG, "\"v1\"")], "");
+                    }
+                    counts.0.fetch_add(1, Ordering::Relaxed);
+                    (StatusCode::OK, [(ETAG, "\"v1\"")], "[1,2,3]")
+                }
+            }),
+        );
+        let url = format!("{}/orders", serve(router).await);
+        let client = RatelimitedClient::new(RatelimitGroup::new(vec![])).with_etag_cache(true);
+
+        let first = client.get(&url).send().await.unwrap().text().await.unwrap();
+        let second = client.get(&url).send().await.unwrap();
+
+        assert_eq!(second.status(), StatusCode::OK);
+        assert_eq!(second.text().await.unwrap(), first);
+        assert_eq!(counts.0.load(Ordering::Relaxed), 1, "body sent again");
+        assert_eq!(counts.1.load(Ordering::Relaxed), 1);
+    }
+
+    /// Serves the User-Agent a request was sent with
+    fn echo_user_agent() -> Router {
+        Router::new().route(
+            "/",
+            any(|headers: HeaderMap| async move {
+                headers
+                    .get(USER_AGENT)
+                    .and_then(|value| value.to_str().ok())
+                    .unwrap_or_default()
+                    .to_string()
+            }),
+        )
+    }
+
+    #[tokio::test]
+    async fn requests_carry_the_default_user_agent() {
+        let url = serve(echo_user_agent()).await;
+
+        let built = RatelimitedClient::builder()
+            .build(RatelimitGroup::new(vec![]))
+            .unwrap();
+        let plain = RatelimitedClient::new(RatelimitGroup::new(vec![]));
+
+        for client in [built, plain] {
+            let user_agent = client.get(&url).send().await.unwrap().text().await.unwrap();
+            assert_eq!(user_agent, DEFAULT_USER_AGENT);
+        }
+    }
+
+    #[tokio::test]
+    async fn request_user_agent_wins_over_the_default() {
+        let url = serve(echo_user_agent()).await;
+        let client = RatelimitedClient::new(RatelimitGroup::new(vec![]));
+
+        let response = client
+            .get(&url)
+            .header(USER_AGENT, "eve-tools-test")
+            .send()
+            .await
+            .unwrap();
+
+        assert_eq!(response.text().await.unwrap(), "eve-tools-test");
     }
 }
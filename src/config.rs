@@ -0,0 +1,305 @@
+// config.rs - Runtime settings of the server, read from a JSON file and EVE_* env vars
+use anyhow::Context;
+use serde::Deserialize;
+use std::collections::BTreeSet;
+use std::path::Path;
+use std::time::Duration;
+
+use crate::client::DEFAULT_ESI_BASE_URL;
+use crate::eve::esi::{SCOPE_READ_ASSETS, SCOPE_READ_CORPORATION_ASSETS};
+use crate::eve::sde::SdeOptions;
+use crate::saga::market::default_targets;
+use crate::{CorporationId, Ratelimit, RatelimitGroup, RegionId, TypeId};
+
+/// Config file read when `EVE_CONFIG` isn't set
+pub const DEFAULT_CONFIG_FILE: &str = "config.json";
+
+/// Server settings. Every field is optional in the file, env vars take precedence:
+/// `EVE_SDE_PATH`, `EVE_DATA_DIR`, `EVE_CLIENT_ID`, `EVE_PORT`, `EVE_CALLBACK_BASE_URL`,
+/// `EVE_ESI_BASE_URL`, `EVE_ESI_COMPATIBILITY_DATE`, `EVE_CORPORATION_ID`, `EVE_USER_AGENT`,
+/// `EVE_SCOPES` (comma separated), `EVE_DEVICE_AUTH_URL`, `EVE_ASSETS_WORKERS`,
+/// `EVE_MARKET_WORKERS`, `EVE_MARKET_REFRESH_INTERVAL_SECS` (`0` resolves once),
+/// `EVE_MARKET_REFRESH_JITTER_SECS`, `EVE_MARKET_TARGETS` (`region:type` pairs separated
+/// by commas).
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct Config {
+    /// SQLite dump of the static data export
+    pub sde_path: String,
+    pub sde: SdeConfig,
+    /// Directory of the cbor databases and characters.cbor
+    pub data_dir: String,
+    /// Client id of the EVE SSO application
+    pub client_id: String,
+    pub port: u16,
+    /// Public address of the server, the SSO callback is `{callback_base_url}/auth/callback`.
+    /// Defaults to `http://localhost:{port}`
+    pub callback_base_url: Option<String>,
+    /// ESI host, overridden to point at a mock server in tests
+    pub esi_base_url: String,
+    /// ESI compatibility date (`YYYY-MM-DD`), the `/latest/` routes are used if unset
+    pub esi_compatibility_date: Option<String>,
+    /// User-Agent of the ESI requests, should name the app and a contact.
+    /// Defaults to `client::DEFAULT_USER_AGENT`
+    pub user_agent: Option<String>,
+    /// Upper bound of simultaneous ESI requests shared by all sagas
+    pub esi_concurrency: usize,
+    /// Workers of an assets saga run. More workers only help while requests are waiting on
+    /// the network, the rate limit and `esi_concurrency` cap the throughput regardless
+    pub assets_workers: usize,
+    /// Workers of a market saga run, capped the same way as `assets_workers`
+    pub market_workers: usize,
+    /// How often the market saga re-runs, `null` resolves the market orders once at startup
+    pub market_refresh_interval_secs: Option<u64>,
+    /// Random delay added to each market refresh, up to this many seconds
+    pub market_refresh_jitter_secs: u64,
+    /// (region, type) pairs whose market orders are resolved
+    pub market_targets: Vec<(RegionId, TypeId)>,
+    /// Take the abyssal item types from the hoboleaks mutaplasmid mapping instead of
+    /// matching SDE type names, falls back to the names if hoboleaks is unreachable
+    pub abyssal_types_from_hoboleaks: bool,
+    /// How long the hoboleaks data stored in the data dir is used before refetching it
+    pub hoboleaks_cache_ttl_secs: u64,
+    /// Retries of temporary hoboleaks failures, with exponential backoff
+    pub hoboleaks_retries: u32,
+    /// How often changed databases are written to the data dir, `null` disables autosave
+    pub autosave_interval_secs: Option<u64>,
+    pub ratelimit: RatelimitConfig,
+    /// Corporation whose assets are resolved too, needs a director's token
+    pub corporation_id: Option<CorporationId>,
+    /// SSO scopes requested at login, see `requested_scopes`
+    pub scopes: Vec<String>,
+    /// Device authorization endpoint of the SSO, enables the headless `/auth/device/*` login
+    pub device_auth_url: Option<String>,
+}
+
+/// Connection pool of the SDE database, see `SdeOptions`
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct SdeConfig {
+    pub max_connections: u32,
+    pub busy_timeout_ms: u64,
+    pub read_only: bool,
+}
+
+/// ESI request budget, enforced client side
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct RatelimitConfig {
+    pub per_second: usize,
+    pub per_minute: usize,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            sde_path: "sqlite-latest.sqlite".to_string(),
+            sde: SdeConfig::default(),
+            data_dir: "data".to_string(),
+            client_id: "49f3698f399f4870afaf1f632592abe0".to_string(),
+            port: 8080,
+            callback_base_url: None,
+            esi_base_url: DEFAULT_ESI_BASE_URL.to_string(),
+            esi_compatibility_date: None,
+            user_agent: None,
+            esi_concurrency: 4,
+            assets_workers: 3,
+            market_workers: 3,
+            market_refresh_interval_secs: Some(15 * 60),
+            market_refresh_jitter_secs: 30,
+            market_targets: default_targets(),
+            abyssal_types_from_hoboleaks: false,
+            hoboleaks_cache_ttl_secs: 24 * 60 * 60,
+            hoboleaks_retries: 3,
+            autosave_interval_secs: Some(5 * 60),
+            ratelimit: RatelimitConfig::default(),
+            corporation_id: None,
+            scopes: vec![SCOPE_READ_ASSETS.to_string()],
+            device_auth_url: None,
+        }
+    }
+}
+
+impl Default for SdeConfig {
+    fn default() -> Self {
+        let options = SdeOptions::default();
+        Self {
+            max_connections: options.max_connections,
+            busy_timeout_ms: options.busy_timeout.as_millis() as u64,
+            read_only: options.read_only,
+        }
+    }
+}
+
+impl Default for RatelimitConfig {
+    fn default() -> Self {
+        Self {
+            per_second: 2,
+            per_minute: 120,
+        }
+    }
+}
+
+impl Config {
+    /// Reads the file named by `EVE_CONFIG` (`config.json` if unset, defaults if that
+    /// doesn't exist either) and applies the env var overrides
+    pub fn load() -> anyhow::Result<Self> {
+        let path = std::env::var("EVE_CONFIG").ok();
+        let mut config = match &path {
+            Some(path) => Self::from_file(path)?,
+            None if Path::new(DEFAULT_CONFIG_FILE).exists() => {
+                Self::from_file(DEFAULT_CONFIG_FILE)?
+            }
+            None => Self::default(),
+        };
+        config.apply_env()?;
+        Ok(config)
+    }
+
+    pub fn from_file(path: &str) -> anyhow::Result<Self> {
+        let file = std::fs::File::open(path).with_context(|| format!("can't open {}", path))?;
+        serde_json::from_reader(std::io::BufReader::new(file))
+            .with_context(|| format!("invalid config {}", path))
+    }
+
+    fn apply_env(&mut self) -> anyhow::Result<()> {
+        let var = |name: &str| std::env::var(name).ok();
+
+        if let Some(sde_path) = var("EVE_SDE_PATH") {
+            self.sde_path = sde_path;
+        }
+        if let Some(data_dir) = var("EVE_DATA_DIR") {
+            self.data_dir = data_dir;
+        }
+        if let Some(client_id) = var("EVE_CLIENT_ID") {
+            self.client_id = client_id;
+        }
+        if let Some(port) = var("EVE_PORT") {
+            self.port = port.parse().context("invalid EVE_PORT")?;
+        }
+        if let Some(callback_base_url) = var("EVE_CALLBACK_BASE_URL") {
+            self.callback_base_url = Some(callback_base_url);
+        }
+        if let Some(esi_base_url) = var("EVE_ESI_BASE_URL") {
+            self.esi_base_url = esi_base_url;
+        }
+        if let Some(date) = var("EVE_ESI_COMPATIBILITY_DATE") {
+            self.esi_compatibility_date = Some(date);
+        }
+        if let Some(user_agent) = var("EVE_USER_AGENT") {
+            self.user_agent = Some(user_agent);
+        }
+        if let Some(workers) = var("EVE_ASSETS_WORKERS") {
+            self.assets_workers = workers.parse().context("invalid EVE_ASSETS_WORKERS")?;
+        }
+        if let Some(workers) = var("EVE_MARKET_WORKERS") {
+            self.market_workers = workers.parse().context("invalid EVE_MARKET_WORKERS")?;
+        }
+        if let Some(interval) = var("EVE_MARKET_REFRESH_INTERVAL_SECS") {
+            let interval: u64 = interval
+                .parse()
+                .context("invalid EVE_MARKET_REFRESH_INTERVAL_SECS")?;
+            self.market_refresh_interval_secs = (interval > 0).then_some(interval);
+        }
+        if let Some(jitter) = var("EVE_MARKET_REFRESH_JITTER_SECS") {
+            self.market_refresh_jitter_secs = jitter
+                .parse()
+                .context("invalid EVE_MARKET_REFRESH_JITTER_SECS")?;
+        }
+        if let Some(targets) = var("EVE_MARKET_TARGETS") {
+            self.market_targets = parse_market_targets(&targets)?;
+        }
+        if let Some(scopes) = var("EVE_SCOPES") {
+            self.scopes = split_scopes(&scopes);
+        }
+        if let Some(device_auth_url) = var("EVE_DEVICE_AUTH_URL") {
+            self.device_auth_url = Some(device_auth_url);
+        }
+        if let Some(corporation_id) = var("EVE_CORPORATION_ID") {
+            self.corporation_id = Some(
+                corporation_id
+                    .parse()
+                    .context("invalid EVE_CORPORATION_ID")?,
+            );
+        }
+        Ok(())
+    }
+
+    pub fn redirect_url(&self) -> String {
+        let base = match &self.callback_base_url {
+            Some(base) => base.trim_end_matches('/').to_string(),
+            None => format!("http://localhost:{}", self.port),
+        };
+        format!("{}/auth/callback", base)
+    }
+
+    /// Configured scopes, the corporation assets scope if a corporation is configured, and
+    /// `extra` scopes separated by commas or spaces
+    pub fn requested_scopes(&self, extra: Option<&str>) -> BTreeSet<String> {
+        let mut scopes: BTreeSet<String> = self.scopes.iter().cloned().collect();
+        if self.corporation_id.is_some() {
+            scopes.insert(SCOPE_READ_CORPORATION_ASSETS.to_string());
+        }
+        scopes.extend(extra.map(split_scopes).unwrap_or_default());
+        scopes
+    }
+
+    pub fn hoboleaks_cache_ttl(&self) -> Duration {
+        Duration::from_secs(self.hoboleaks_cache_ttl_secs)
+    }
+
+    pub fn market_refresh_interval(&self) -> Option<Duration> {
+        self.market_refresh_interval_secs.map(Duration::from_secs)
+    }
+
+    pub fn market_refresh_jitter(&self) -> Duration {
+        Duration::from_secs(self.market_refresh_jitter_secs)
+    }
+
+    pub fn autosave_interval(&self) -> Option<Duration> {
+        self.autosave_interval_secs.map(Duration::from_secs)
+    }
+
+    pub fn sde_options(&self) -> SdeOptions {
+        SdeOptions {
+            max_connections: self.sde.max_connections,
+            busy_timeout: Duration::from_millis(self.sde.busy_timeout_ms),
+            read_only: self.sde.read_only,
+        }
+    }
+
+    pub fn ratelimit_group(&self) -> RatelimitGroup {
+        RatelimitGroup::new(vec![
+            Ratelimit::new_sliding(Duration::from_secs(1), self.ratelimit.per_second),
+            Ratelimit::new(Duration::from_secs(60), self.ratelimit.per_minute),
+        ])
+    }
+}
+
+fn parse_market_targets(targets: &str) -> anyhow::Result<Vec<(RegionId, TypeId)>> {
+    targets
+        .split(',')
+        .filter(|target| !target.trim().is_empty())
+        .map(|target| {
+            let (region_id, type_id) = target
+                .trim()
+                .split_once(':')
+                .with_context(|| format!("invalid EVE_MARKET_TARGETS entry {}", target))?;
+            let region_id = region_id.parse().with_context(|| {
+                format!("invalid region in EVE_MARKET_TARGETS entry {}", target)
+            })?;
+            let type_id: i32 = type_id
+                .parse()
+                .with_context(|| format!("invalid type in EVE_MARKET_TARGETS entry {}", target))?;
+            Ok((region_id, type_id.into()))
+        })
+        .collect()
+}
+
+fn split_scopes(scopes: &str) -> Vec<String> {
+    scopes
+        .split([',', ' '])
+        .filter(|scope| !scope.is_empty())
+        .map(str::to_string)
+        .collect()
+}
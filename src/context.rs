@@ -1,11 +1,25 @@
+use chrono::{DateTime, Utc};
+use oauth2::TokenResponse;
 use oauth2::basic::BasicTokenResponse;
+use serde::{Deserialize, Serialize};
 use sqlx::sqlite::SqlitePool;
-use std::collections::HashMap;
+use std::collections::{BTreeSet, HashMap, HashSet};
 use std::sync::Arc;
-use tokio::sync::{Mutex, RwLock};
+use tokio::sync::{Mutex, RwLock, Semaphore, SemaphorePermit, watch};
+use tokio_util::sync::CancellationToken;
+use uuid::Uuid;
 
+use crate::db::GetData;
 use crate::eve::hoboleaks::{self, MutaplasmidData};
-use crate::{AllAssetsDb, CharacterAssetsDb, CharacterId, DynamicsDb, RatelimitedClient};
+use crate::eve::{esi, sde};
+use crate::handlers::dynamics::virtual_attributes::{self, VirtualAttributeFormula};
+use crate::saga::framework::SagaProgress;
+use crate::saga::market::analyze::Spread;
+use crate::saga::stats::FetchStatsSnapshot;
+use crate::{
+    AllAssetsDb, CharacterAssetsDb, CharacterId, Config, DynamicsDb, ItemType, MarketGroupId,
+    MarketHistoryDay, MarketHistoryDb, MarketOrdersDb, RatelimitedClient, RegionId, TypeId,
+};
 
 // OAuth2 client type - adjust based on your actual oauth2 setup
 type ClientWithAuthAndTokenUrl = oauth2::basic::BasicClient<
@@ -17,14 +31,50 @@ type ClientWithAuthAndTokenUrl = oauth2::basic::BasicClient<
 >;
 
 pub struct AppContext {
+    pub config: Config,
     pub sde_pool: SqlitePool,
     pub http_client: Arc<RatelimitedClient>,
     pub oauth2_client: Arc<ClientWithAuthAndTokenUrl>,
     pub dynamics_db: RwLock<DynamicsDb>,
     pub assets_db: RwLock<AllAssetsDb>,
+    pub market_orders_db: RwLock<MarketOrdersDb>,
+    pub market_history_db: RwLock<MarketHistoryDb>,
     pub character_assets_db: CharacterAssetsDb,
     pub data_dir: String,
     pub characters: Mutex<CharacterManager>,
+    // Held while a character's token is refreshed, see `refresh_character_token`
+    pub token_refreshes: parking_lot::Mutex<HashMap<CharacterId, Arc<Mutex<()>>>>,
+
+    // Derived attributes shown in the dynamics report
+    pub virtual_formulas: Vec<VirtualAttributeFormula>,
+
+    // Bounds the number of in-flight ESI requests across all sagas
+    pub esi_semaphore: Arc<Semaphore>,
+    pub esi_concurrency: usize,
+
+    // Paged ESI responses (assets, market orders) kept until they expire
+    pub esi_expires: esi::ExpiresCache,
+
+    // SDE types already read, the SDE doesn't change while the server runs
+    pub sde_types: Mutex<HashMap<TypeId, ItemType>>,
+
+    // Names of stations/systems resolved through /universe/names/
+    pub location_names: RwLock<HashMap<i64, String>>,
+
+    // When market orders of a (region, type) target were last refreshed
+    pub market_last_updated: RwLock<HashMap<(RegionId, TypeId), DateTime<Utc>>>,
+    // Spread of every market target, recomputed when the market saga completes
+    pub market_spreads: RwLock<HashMap<(RegionId, TypeId), Spread>>,
+
+    // Progress of the latest assets saga run per character
+    pub assets_progress: RwLock<HashMap<CharacterId, watch::Receiver<SagaProgress>>>,
+    pub assets_cancellation: RwLock<HashMap<CharacterId, CancellationToken>>,
+    // Latest requested assets resolution per character
+    pub assets_jobs: RwLock<HashMap<CharacterId, AssetsJob>>,
+    // Characters whose assets saga is running, see `try_begin_assets_resolution`
+    pub assets_in_progress: parking_lot::Mutex<HashSet<CharacterId>>,
+    // Fetch stats of the latest assets saga run that completed, of any character
+    pub last_assets_fetch_stats: RwLock<Option<FetchStatsSnapshot>>,
 
     // Hoboleaks cache
     pub hoboleaks_data: Arc<tokio::sync::RwLock<Option<MutaplasmidData>>>,
@@ -35,37 +85,427 @@ impl AppContext {
     pub async fn with_client(
         http_client: Arc<RatelimitedClient>,
         oauth2_client: Arc<ClientWithAuthAndTokenUrl>,
-        sde_path: &str,
-        data_dir: &str,
+        config: Config,
     ) -> anyhow::Result<Self> {
-        let sde_pool = crate::eve::sde::create_conn_pool(sde_path).await?;
+        let data_dir = config.data_dir.as_str();
+        let esi_concurrency = config.esi_concurrency;
+        let sde_pool =
+            crate::eve::sde::create_conn_pool(&config.sde_path, &config.sde_options()).await?;
         let abyssal_items = crate::eve::sde::get_abyssal_modules(&sde_pool).await?;
-        let abyssal_items = abyssal_items.iter().copied().map(Into::into).collect();
+        let mut abyssal_items: Vec<TypeId> =
+            abyssal_items.iter().copied().map(Into::into).collect();
+
+        let mut hoboleaks_data = hoboleaks::load_cache(data_dir, config.hoboleaks_cache_ttl());
+        if config.abyssal_types_from_hoboleaks {
+            if hoboleaks_data.is_none() {
+                let retries = config.hoboleaks_retries;
+                match hoboleaks::get_mutaplasmids_with_retry(&http_client, retries).await {
+                    Ok(data) => {
+                        if let Err(e) = hoboleaks::store_cache(data_dir, &data) {
+                            eprintln!("❌ Failed to store hoboleaks data: {}", e);
+                        }
+                        hoboleaks_data = Some(data);
+                    }
+                    Err(e) => eprintln!(
+                        "⚠️  Unable to fetch hoboleaks, abyssal types are matched by name: {}",
+                        e
+                    ),
+                }
+            }
+            if let Some(data) = &hoboleaks_data {
+                abyssal_items = reconcile_abyssal_items(&abyssal_items, data);
+            }
+        }
+        let hoboleaks_last_fetch = hoboleaks_data.as_ref().map(|_| std::time::Instant::now());
 
         let dynamics_db = RwLock::new(DynamicsDb::from_dir(data_dir)?);
         let assets_db = RwLock::new(AllAssetsDb::from_dir(data_dir)?);
+        let market_orders_db = RwLock::new(MarketOrdersDb::from_dir(data_dir)?);
+        let market_history_db = RwLock::new(MarketHistoryDb::from_dir(data_dir)?);
         let data_dir = data_dir.to_string();
-        let characters = Mutex::new(CharacterManager::new());
+        let characters = CharacterManager::from_dir(&data_dir)?;
+        if let Some(corporation_id) = config.corporation_id {
+            // Characters stored before scopes were recorded only count as granting the
+            // assets scope, whatever they granted back then
+            for character in characters.list() {
+                if !character.has_scope(esi::SCOPE_READ_CORPORATION_ASSETS) {
+                    println!(
+                        "⚠️  Character {} ({}) didn't grant {}, log it in again through /auth/start to include corporation {} assets",
+                        character.character_name,
+                        character.character_id,
+                        esi::SCOPE_READ_CORPORATION_ASSETS,
+                        corporation_id
+                    );
+                }
+            }
+        }
+        let characters = Mutex::new(characters);
         let character_assets_db = CharacterAssetsDb::from_dir(&data_dir.clone(), abyssal_items)?;
+        let virtual_formulas = virtual_attributes::load_virtual_formulas(&data_dir)?;
 
         Ok(Self {
+            config,
             sde_pool,
             http_client,
             oauth2_client,
             dynamics_db,
             assets_db,
+            market_orders_db,
+            market_history_db,
             data_dir,
             characters,
+            token_refreshes: parking_lot::Mutex::new(HashMap::new()),
             character_assets_db,
-            hoboleaks_data: Arc::new(RwLock::new(None)),
-            hoboleaks_last_fetch: Arc::new(RwLock::new(None)),
+            virtual_formulas,
+            esi_semaphore: Arc::new(Semaphore::new(esi_concurrency)),
+            esi_concurrency,
+            esi_expires: esi::ExpiresCache::default(),
+            sde_types: Mutex::new(HashMap::new()),
+            location_names: RwLock::new(HashMap::new()),
+            market_last_updated: RwLock::new(HashMap::new()),
+            market_spreads: RwLock::new(HashMap::new()),
+            assets_progress: RwLock::new(HashMap::new()),
+            assets_cancellation: RwLock::new(HashMap::new()),
+            assets_jobs: RwLock::new(HashMap::new()),
+            assets_in_progress: parking_lot::Mutex::new(HashSet::new()),
+            last_assets_fetch_stats: RwLock::new(None),
+            hoboleaks_data: Arc::new(RwLock::new(hoboleaks_data)),
+            hoboleaks_last_fetch: Arc::new(RwLock::new(hoboleaks_last_fetch)),
         })
     }
 
-    /// Get hoboleaks data with caching (cache for 1 hour)
-    pub async fn get_hoboleaks_data(
+    /// Wait for a slot to perform an ESI request. The permit is released on drop.
+    pub async fn acquire_esi_permit(&self) -> SemaphorePermit<'_> {
+        self.esi_semaphore
+            .acquire()
+            .await
+            .expect("esi semaphore is never closed")
+    }
+
+    pub fn available_esi_permits(&self) -> usize {
+        self.esi_semaphore.available_permits()
+    }
+
+    /// SDE type of the given id, None if it isn't in the SDE. Read types are cached so
+    /// repeated lookups don't hit SQLite.
+    pub async fn get_sde_type(&self, type_id: TypeId) -> Result<Option<ItemType>, sqlx::Error> {
+        let mut item_types = self.get_sde_types(&[type_id]).await?;
+        Ok(item_types.remove(&type_id))
+    }
+
+    /// Like `get_sde_type` for many ids, the uncached ones are read in a single query.
+    /// Ids that aren't in the SDE are absent from the result.
+    pub async fn get_sde_types(
         &self,
-    ) -> Result<Option<MutaplasmidData>, hoboleaks::HoboleaksError> {
+        type_ids: &[TypeId],
+    ) -> Result<HashMap<TypeId, ItemType>, sqlx::Error> {
+        let mut found = HashMap::new();
+        let mut missing = vec![];
+        {
+            let sde_types = self.sde_types.lock().await;
+            for type_id in type_ids {
+                match sde_types.get(type_id) {
+                    Some(item_type) => {
+                        found.insert(*type_id, item_type.clone());
+                    }
+                    None => missing.push((*type_id).into()),
+                }
+            }
+        }
+
+        if missing.is_empty() {
+            return Ok(found);
+        }
+
+        let item_types = crate::eve::sde::get_types_by_ids(&self.sde_pool, &missing).await?;
+
+        let mut sde_types = self.sde_types.lock().await;
+        for item_type in item_types {
+            sde_types.insert(item_type.type_id, item_type.clone());
+            found.insert(item_type.type_id, item_type);
+        }
+        Ok(found)
+    }
+
+    /// Full names like "Ship Equipment / Hull & Armor / Armor Repairers" of the market
+    /// groups of every known type. Names are cached in the assets db, only groups of types
+    /// added since the last call are read from the SDE.
+    pub async fn market_group_paths(&self) -> Result<HashMap<MarketGroupId, String>, sqlx::Error> {
+        let missing = self.character_assets_db.market_groups_without_path();
+        if !missing.is_empty() {
+            let mut paths =
+                crate::eve::sde::resolve_market_group_hierarchy(&self.sde_pool, &missing).await?;
+            // Groups unknown to the SDE come back empty, they're retried next time
+            paths.retain(|_, path| !path.is_empty());
+            self.character_assets_db.add_market_group_paths(paths);
+        }
+        Ok(self.character_assets_db.get_market_group_paths())
+    }
+
+    /// Resolve names of the given location ids, caching them for subsequent calls.
+    /// Only NPC stations and solar systems are resolvable, other ids (structures,
+    /// unknown items) are skipped and absent from the result.
+    pub async fn resolve_location_names(
+        &self,
+        location_ids: &[i64],
+    ) -> Result<HashMap<i64, String>, esi::EsiError> {
+        let mut resolved = HashMap::new();
+        let mut missing = vec![];
+        {
+            let location_names = self.location_names.read().await;
+            for id in location_ids {
+                match location_names.get(id) {
+                    Some(name) => {
+                        resolved.insert(*id, name.clone());
+                    }
+                    None if is_universe_location(*id) => missing.push(*id),
+                    None => {}
+                }
+            }
+        }
+
+        if missing.is_empty() {
+            return Ok(resolved);
+        }
+
+        missing.sort_unstable();
+        missing.dedup();
+
+        let names = {
+            let _permit = self.acquire_esi_permit().await;
+            esi::get_universe_names(&self.http_client, &missing).await?
+        };
+
+        let mut location_names = self.location_names.write().await;
+        for name in names {
+            location_names.insert(name.id, name.name.clone());
+            resolved.insert(name.id, name.name);
+        }
+
+        Ok(resolved)
+    }
+
+    /// Periodically write the character assets and dynamics dbs if they changed, so a
+    /// crash doesn't lose a whole saga run. Abort the task on shutdown.
+    pub fn spawn_autosave(
+        self: Arc<Self>,
+        interval: std::time::Duration,
+    ) -> tokio::task::JoinHandle<()> {
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            // The first tick completes immediately, nothing changed yet
+            ticker.tick().await;
+
+            loop {
+                ticker.tick().await;
+
+                if self.character_assets_db.needs_store() {
+                    let context = self.clone();
+                    let stored =
+                        tokio::task::spawn_blocking(move || context.character_assets_db.store())
+                            .await;
+                    match stored {
+                        Ok(Ok(bytes)) => {
+                            println!("💾 Autosaved character assets ({} bytes)", bytes)
+                        }
+                        Ok(Err(e)) => eprintln!("❌ Failed to autosave character assets: {}", e),
+                        Err(e) => eprintln!("❌ Character assets autosave panicked: {}", e),
+                    }
+                }
+
+                if self.dynamics_db.read().await.needs_store() {
+                    // Only the encoding holds the lock, the file is written off the runtime
+                    let snapshot = self.dynamics_db.write().await.snapshot();
+                    let stored = match snapshot {
+                        Ok(Some(snapshot)) => tokio::task::spawn_blocking(move || snapshot.write())
+                            .await
+                            .map_err(|e| format!("autosave panicked: {}", e))
+                            .and_then(|written| written.map_err(|e| e.to_string()))
+                            .map(Some),
+                        Ok(None) => Ok(None),
+                        Err(e) => Err(e.to_string()),
+                    };
+                    match stored {
+                        Ok(Some(taken_at)) => self.dynamics_db.write().await.mark_stored(taken_at),
+                        Ok(None) => {}
+                        Err(e) => eprintln!("❌ Failed to autosave dynamics: {}", e),
+                    }
+                }
+            }
+        })
+    }
+
+    /// Latest progress of the assets saga of a character, None if it never ran
+    pub async fn assets_saga_progress(&self, character_id: CharacterId) -> Option<SagaProgress> {
+        let assets_progress = self.assets_progress.read().await;
+        assets_progress
+            .get(&character_id)
+            .map(|receiver| *receiver.borrow())
+    }
+
+    /// Cancel the running assets saga of a character, false if there is none
+    pub async fn cancel_assets_saga(&self, character_id: CharacterId) -> bool {
+        let assets_cancellation = self.assets_cancellation.read().await;
+        match assets_cancellation.get(&character_id) {
+            Some(token) if !token.is_cancelled() => {
+                token.cancel();
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Record a new assets resolution of a character, replacing the previous one.
+    /// Fails with the id of the running job if there is one.
+    pub async fn start_assets_job(&self, character_id: CharacterId) -> Result<Uuid, Uuid> {
+        let mut assets_jobs = self.assets_jobs.write().await;
+        if let Some(job) = assets_jobs.get(&character_id)
+            && job.is_running()
+        {
+            return Err(job.job_id);
+        }
+
+        let job = AssetsJob {
+            job_id: Uuid::new_v4(),
+            started_at: Utc::now(),
+            finished_at: None,
+            error: None,
+        };
+        let job_id = job.job_id;
+        assets_jobs.insert(character_id, job);
+        Ok(job_id)
+    }
+
+    /// Claim the assets of a character for a saga run, None if a run is already going on.
+    /// The claim is released when the guard is dropped.
+    pub fn try_begin_assets_resolution(
+        &self,
+        character_id: CharacterId,
+    ) -> Option<AssetsResolutionGuard<'_>> {
+        AssetsResolutionGuard::claim(&self.assets_in_progress, character_id)
+    }
+
+    /// Mark an assets resolution as done, ignored if a newer one was started since
+    pub async fn finish_assets_job(
+        &self,
+        character_id: CharacterId,
+        job_id: Uuid,
+        error: Option<String>,
+    ) {
+        let mut assets_jobs = self.assets_jobs.write().await;
+        if let Some(job) = assets_jobs.get_mut(&character_id)
+            && job.job_id == job_id
+        {
+            job.finished_at = Some(Utc::now());
+            job.error = error;
+        }
+    }
+
+    /// Latest assets resolution of a character, None if none was started
+    pub async fn assets_job(&self, character_id: CharacterId) -> Option<AssetsJob> {
+        self.assets_jobs.read().await.get(&character_id).cloned()
+    }
+
+    pub async fn mark_market_targets_updated(&self, targets: &[(RegionId, TypeId)]) {
+        let now = Utc::now();
+        let mut market_last_updated = self.market_last_updated.write().await;
+        for target in targets {
+            market_last_updated.insert(*target, now);
+        }
+    }
+
+    /// Fetch the daily market history of a type in a region and merge it into the market
+    /// history db. Within a day the cached ESI response is reused unless `force` is set.
+    pub async fn refresh_market_history(
+        &self,
+        region_id: RegionId,
+        type_id: TypeId,
+        force: bool,
+    ) -> Result<Vec<MarketHistoryDay>, esi::EsiError> {
+        let days = {
+            let _permit = self.acquire_esi_permit().await;
+            esi::get_market_history(
+                &self.http_client,
+                &self.esi_expires,
+                region_id,
+                type_id,
+                force,
+            )
+            .await?
+        };
+
+        let mut market_history_db = self.market_history_db.write().await;
+        market_history_db.replace(region_id, type_id, days);
+        if let Err(e) = market_history_db.store() {
+            eprintln!("❌ Failed to store market history: {}", e);
+        }
+        Ok(market_history_db
+            .get(region_id, type_id)
+            .map(|entry| entry.days.clone())
+            .unwrap_or_default())
+    }
+
+    /// Exchange the stored refresh token of a character for a fresh access token.
+    /// `seen` is the token the caller found expiring or rejected. Refreshes of a character
+    /// run one at a time, a caller that waited for another one gets the token it stored
+    /// instead of sending the same refresh token again, which fails once the SSO rotated it.
+    /// The characters lock isn't held while talking to the SSO.
+    pub async fn refresh_character_token(
+        &self,
+        character_id: CharacterId,
+        seen: &BasicTokenResponse,
+    ) -> anyhow::Result<BasicTokenResponse> {
+        let refresh_lock = self
+            .token_refreshes
+            .lock()
+            .entry(character_id)
+            .or_default()
+            .clone();
+        let _refreshing = refresh_lock.lock().await;
+
+        let refresh_token = {
+            let characters = self.characters.lock().await;
+            let character = characters
+                .get(character_id)
+                .ok_or_else(|| anyhow::anyhow!("unknown character with id: {character_id}"))?;
+            if character.oauth_token.access_token().secret() != seen.access_token().secret() {
+                return Ok(character.oauth_token.clone());
+            }
+            character
+                .oauth_token
+                .refresh_token()
+                .cloned()
+                .ok_or_else(|| anyhow::anyhow!("no refresh token for character {character_id}"))?
+        };
+
+        let mut token = self
+            .oauth2_client
+            .exchange_refresh_token(&refresh_token)
+            .request_async(&reqwest::Client::new())
+            .await
+            .map_err(|e| anyhow::anyhow!("token refresh failed: {e}"))?;
+
+        // The SSO may rotate the refresh token, keep the old one if it didn't send a new one
+        if token.refresh_token().is_none() {
+            token.set_refresh_token(Some(refresh_token));
+        }
+
+        {
+            let mut characters = self.characters.lock().await;
+            characters.set_token(character_id, token.clone());
+            // A rotated refresh token invalidates the stored one, so persist right away
+            if let Err(e) = characters.store(&self.data_dir) {
+                eprintln!("❌ Failed to store characters: {}", e);
+            }
+        }
+
+        println!("🔑 Refreshed access token of character {}", character_id);
+        Ok(token)
+    }
+
+    /// Hoboleaks data from the 1 hour in-memory cache or, failing that, from the copy in
+    /// the data dir if it's younger than the configured ttl. None if both are stale.
+    pub async fn cached_hoboleaks_data(&self) -> Option<MutaplasmidData> {
         const CACHE_DURATION: std::time::Duration = std::time::Duration::from_secs(3600); // 1 hour
 
         // Check if we have recent cached data
@@ -79,30 +519,27 @@ impl AppContext {
                             "✅ Using cached hoboleaks data (age: {:?})",
                             last_time.elapsed()
                         );
-                        return Ok(Some(data.clone()));
+                        return Some(data.clone());
                     }
                 }
             }
         }
 
-        // Fetch fresh data
-        println!("🔄 Fetching fresh hoboleaks data...");
-        match hoboleaks::get_mutaplasmids(&self.http_client).await {
-            Ok(data) => {
-                // Update cache
-                {
-                    let mut cached_data = self.hoboleaks_data.write().await;
-                    *cached_data = Some(data.clone());
-                }
-                {
-                    let mut last_fetch = self.hoboleaks_last_fetch.write().await;
-                    *last_fetch = Some(std::time::Instant::now());
-                }
+        let data = hoboleaks::load_cache(&self.data_dir, self.config.hoboleaks_cache_ttl())?;
+        self.set_hoboleaks_data(data.clone()).await;
+        Some(data)
+    }
 
-                println!("✅ Successfully fetched and cached hoboleaks data");
+    /// Get hoboleaks data with caching, see `cached_hoboleaks_data`
+    pub async fn get_hoboleaks_data(
+        &self,
+    ) -> Result<Option<MutaplasmidData>, hoboleaks::HoboleaksError> {
+        if let Some(data) = self.cached_hoboleaks_data().await {
+            return Ok(Some(data));
+        }
 
-                Ok(Some(data))
-            }
+        match self.fetch_hoboleaks_data().await {
+            Ok(data) => Ok(Some(data)),
             Err(e) => {
                 println!("❌ Failed to fetch hoboleaks data: {}", e);
 
@@ -117,25 +554,244 @@ impl AppContext {
             }
         }
     }
+
+    /// Fetch hoboleaks data bypassing the cache age check and store it in the cache
+    pub async fn fetch_hoboleaks_data(&self) -> Result<MutaplasmidData, hoboleaks::HoboleaksError> {
+        println!("🔄 Fetching fresh hoboleaks data...");
+        let data = hoboleaks::get_mutaplasmids_with_retry(
+            &self.http_client,
+            self.config.hoboleaks_retries,
+        )
+        .await?;
+
+        self.set_hoboleaks_data(data.clone()).await;
+        if let Err(e) = hoboleaks::store_cache(&self.data_dir, &data) {
+            eprintln!("❌ Failed to store hoboleaks data: {}", e);
+        }
+
+        println!("✅ Successfully fetched and cached hoboleaks data");
+
+        Ok(data)
+    }
+
+    async fn set_hoboleaks_data(&self, data: MutaplasmidData) {
+        {
+            let mut cached_data = self.hoboleaks_data.write().await;
+            *cached_data = Some(data);
+        }
+        {
+            let mut last_fetch = self.hoboleaks_last_fetch.write().await;
+            *last_fetch = Some(std::time::Instant::now());
+        }
+    }
+
+    /// Force a hoboleaks refresh and replace the mutaplasmid effects of the character db.
+    /// Returns the number of mutators loaded and of types added for them.
+    pub async fn refresh_mutaplasmid_effects(&self) -> anyhow::Result<(usize, usize)> {
+        let data = self.fetch_hoboleaks_data().await?;
+        let types_added = self.replace_mutaplasmid_effects(&data).await?;
+        Ok((data.len(), types_added))
+    }
+
+    /// Replace the mutaplasmid effects of the character db and store the types only the
+    /// new mapping refers to, the reports fail on types that are missing. Returns the
+    /// number of types added.
+    pub async fn replace_mutaplasmid_effects(
+        &self,
+        data: &MutaplasmidData,
+    ) -> anyhow::Result<usize> {
+        let new_items = self.character_assets_db.replace_mutaplasmid_effects(data);
+        println!("🔁 Replaced mutaplasmid effects of {} mutators", data.len());
+
+        let referenced: Vec<TypeId> = new_items
+            .iter()
+            .filter_map(|item| match item {
+                GetData::Type(type_id) => Some(*type_id),
+                _ => None,
+            })
+            .collect();
+        let missing: Vec<TypeId> = self.character_assets_db.with_types(|types| {
+            referenced
+                .into_iter()
+                .filter(|type_id| !types.contains_key(type_id))
+                .collect()
+        });
+        if missing.is_empty() {
+            return Ok(0);
+        }
+
+        let item_types = self.get_sde_types(&missing).await?;
+        let mut market_group_ids = BTreeSet::new();
+        for item_type in item_types.values() {
+            let new_items = self.character_assets_db.add_type(item_type.clone());
+            market_group_ids.extend(new_items.iter().filter_map(|item| match item {
+                GetData::MarketGroup(market_group_id) => Some(*market_group_id),
+                _ => None,
+            }));
+        }
+
+        let market_group_ids: Vec<MarketGroupId> = market_group_ids.into_iter().collect();
+        for market_group in sde::get_market_groups_by_ids(&self.sde_pool, &market_group_ids).await?
+        {
+            self.character_assets_db.add_market_group(market_group);
+        }
+
+        let unresolved: Vec<&TypeId> = missing
+            .iter()
+            .filter(|type_id| !item_types.contains_key(type_id))
+            .collect();
+        if !unresolved.is_empty() {
+            eprintln!(
+                "⚠️  {} types of the mutaplasmid effects aren't in the SDE, the next assets run fetches them: {:?}",
+                unresolved.len(),
+                unresolved
+            );
+        }
+        println!(
+            "🧬 Added {} types referenced by the mutaplasmid effects",
+            item_types.len()
+        );
+        Ok(item_types.len())
+    }
+}
+
+/// Abyssal types according to the mutaplasmid mapping, reporting how they differ from
+/// the types found by name in the SDE
+fn reconcile_abyssal_items(by_name: &[TypeId], data: &MutaplasmidData) -> Vec<TypeId> {
+    let by_mapping = hoboleaks::resulting_types(data);
+    let by_name: BTreeSet<TypeId> = by_name.iter().copied().collect();
+
+    let only_by_name = by_name.difference(&by_mapping).count();
+    let only_by_mapping = by_mapping.difference(&by_name).count();
+    println!(
+        "🧬 {} abyssal types from hoboleaks, {} name matches aren't mutated items, {} weren't matched by name",
+        by_mapping.len(),
+        only_by_name,
+        only_by_mapping
+    );
+
+    by_mapping.into_iter().collect()
+}
+
+/// Solar systems and NPC stations, the locations /universe/names/ can resolve
+fn is_universe_location(id: i64) -> bool {
+    (30_000_000..40_000_000).contains(&id) || (60_000_000..70_000_000).contains(&id)
 }
 
+/// Held while the assets saga of a character runs
+pub struct AssetsResolutionGuard<'a> {
+    in_progress: &'a parking_lot::Mutex<HashSet<CharacterId>>,
+    character_id: CharacterId,
+}
+
+impl<'a> AssetsResolutionGuard<'a> {
+    /// Add the character to `in_progress`, None if it's already there
+    fn claim(
+        in_progress: &'a parking_lot::Mutex<HashSet<CharacterId>>,
+        character_id: CharacterId,
+    ) -> Option<Self> {
+        if !in_progress.lock().insert(character_id) {
+            return None;
+        }
+        Some(AssetsResolutionGuard {
+            in_progress,
+            character_id,
+        })
+    }
+}
+
+impl Drop for AssetsResolutionGuard<'_> {
+    fn drop(&mut self) {
+        self.in_progress.lock().remove(&self.character_id);
+    }
+}
+
+/// An assets resolution run of a character
 #[derive(Clone, Debug)]
+pub struct AssetsJob {
+    pub job_id: Uuid,
+    pub started_at: DateTime<Utc>,
+    /// None while the resolution is running
+    pub finished_at: Option<DateTime<Utc>>,
+    pub error: Option<String>,
+}
+
+impl AssetsJob {
+    pub fn is_running(&self) -> bool {
+        self.finished_at.is_none()
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct CharacterClient {
     pub character_id: u64,
     pub character_name: String,
     pub oauth_token: BasicTokenResponse,
+    /// SSO scopes the character granted
+    #[serde(default = "legacy_scopes")]
+    pub scopes: BTreeSet<String>,
+    /// When the access token stops working, computed from its `expires_in` when it was
+    /// obtained. None for characters stored before it was recorded
+    #[serde(default)]
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+/// Expiry of a token obtained just now, None if the SSO didn't say how long it lives
+fn token_expires_at(oauth_token: &BasicTokenResponse) -> Option<DateTime<Utc>> {
+    let expires_in = chrono::Duration::from_std(oauth_token.expires_in()?).ok()?;
+    Some(Utc::now() + expires_in)
+}
+
+/// Characters stored before scopes were recorded only ever granted the assets scope
+fn legacy_scopes() -> BTreeSet<String> {
+    BTreeSet::from([esi::SCOPE_READ_ASSETS.to_string()])
 }
 
 impl CharacterClient {
-    pub fn new(character_id: u64, character_name: String, oauth_token: BasicTokenResponse) -> Self {
+    pub fn new(
+        character_id: u64,
+        character_name: String,
+        oauth_token: BasicTokenResponse,
+        scopes: BTreeSet<String>,
+    ) -> Self {
         Self {
             character_id,
             character_name,
+            expires_at: token_expires_at(&oauth_token),
             oauth_token,
+            scopes,
+        }
+    }
+
+    /// Whether the access token already expired. A token of unknown age counts as expired
+    pub fn is_expired(&self) -> bool {
+        self.expires_within(std::time::Duration::ZERO)
+    }
+
+    /// Whether the access token expires in less than `margin`, so it can be refreshed
+    /// before ESI starts answering 401
+    pub fn expires_within(&self, margin: std::time::Duration) -> bool {
+        match self.expires_at {
+            Some(expires_at) => {
+                let margin = chrono::Duration::from_std(margin).unwrap_or(chrono::Duration::MAX);
+                expires_at <= Utc::now() + margin
+            }
+            None => true,
         }
     }
+
+    /// Whether the character granted `scope`, ESI answers 403 to calls needing one it didn't
+    pub fn has_scope(&self, scope: &str) -> bool {
+        self.scopes.contains(scope)
+    }
 }
 
+/// Authenticated characters, persisted to `characters.cbor` in the data dir.
+///
+/// WARNING: the file holds the oauth tokens, refresh tokens included, in plaintext.
+/// Anyone able to read it can act as the characters until the tokens are revoked,
+/// so keep the data dir private. The file is created readable by the owner only.
+#[derive(Default)]
 pub struct CharacterManager {
     characters: HashMap<CharacterId, CharacterClient>,
 }
@@ -147,6 +803,69 @@ impl CharacterManager {
         }
     }
 
+    pub fn from_dir(dir: &str) -> Result<Self, std::io::Error> {
+        let file_path = Self::last_file(dir);
+        if !std::path::Path::new(&file_path).exists() {
+            return Ok(Self::new());
+        }
+
+        let cbor_data = std::fs::read(&file_path)?;
+        let characters = serde_cbor::from_slice::<HashMap<CharacterId, CharacterClient>>(
+            &cbor_data,
+        )
+        .map_err(|e| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("failed to deserialize the characters file: {e}"),
+            )
+        })?;
+
+        println!(
+            "⚠️  Loaded {} characters with plaintext tokens from {}",
+            characters.len(),
+            file_path
+        );
+        Ok(Self { characters })
+    }
+
+    pub fn store(&self, dir: &str) -> Result<(), std::io::Error> {
+        let file_path = Self::last_file(dir);
+        let temp_path = format!("{file_path}.tmp");
+        let encoded = serde_cbor::ser::to_vec(&self.characters).map_err(std::io::Error::other)?;
+
+        // The tokens are never readable by others, not even before the permissions are set
+        let mut options = std::fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        let mut file = options.open(&temp_path)?;
+        // A temp file left over by an older version keeps its mode, `mode` only applies on
+        // creation
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            file.set_permissions(std::fs::Permissions::from_mode(0o600))?;
+        }
+        std::io::Write::write_all(&mut file, &encoded)?;
+        file.sync_all()?;
+        drop(file);
+        std::fs::rename(temp_path, &file_path)?;
+
+        println!(
+            "⚠️  Stored {} characters with plaintext tokens to {}",
+            self.characters.len(),
+            file_path
+        );
+        Ok(())
+    }
+
+    fn last_file(dir: &str) -> String {
+        format!("{}/characters.cbor", dir)
+    }
+
     pub fn add(&mut self, character: CharacterClient) {
         self.characters.insert(character.character_id, character);
     }
@@ -155,6 +874,22 @@ impl CharacterManager {
         self.characters.get(&character_id)
     }
 
+    /// Replace the oauth token of a known character, returns false for unknown ones
+    pub fn set_token(
+        &mut self,
+        character_id: CharacterId,
+        oauth_token: BasicTokenResponse,
+    ) -> bool {
+        match self.characters.get_mut(&character_id) {
+            Some(character) => {
+                character.expires_at = token_expires_at(&oauth_token);
+                character.oauth_token = oauth_token;
+                true
+            }
+            None => false,
+        }
+    }
+
     pub fn list(&self) -> Vec<&CharacterClient> {
         self.characters.values().collect()
     }
@@ -167,3 +902,24 @@ pub struct OauthConfig {
     pub token_url: oauth2::TokenUrl,
     pub redirect_url: oauth2::RedirectUrl,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn overlapping_assets_resolutions_are_refused() {
+        let in_progress = parking_lot::Mutex::new(HashSet::new());
+        let character_id: CharacterId = 2112625428;
+
+        let first = AssetsResolutionGuard::claim(&in_progress, character_id);
+        assert!(first.is_some());
+        // Triggered again while the first run goes on
+        assert!(AssetsResolutionGuard::claim(&in_progress, character_id).is_none());
+        // Other characters aren't held up
+        assert!(AssetsResolutionGuard::claim(&in_progress, 95465499).is_some());
+
+        drop(first);
+        assert!(AssetsResolutionGuard::claim(&in_progress, character_id).is_some());
+    }
+}
@@ -1,22 +1,35 @@
 #![allow(dead_code)]
+use crate::eve::hoboleaks::MutaplasmidData;
+use crate::mydb::versioned;
 use crate::{
-    AssetItem, DogmaAttribute, DogmaAttributeId, DynamicItem, ItemId, ItemType, MarketGroup,
-    MarketGroupId, Station, StationId, TypeId,
+    AssetItem, CharacterId, CorporationId, DogmaAttribute, DogmaAttributeId, DynamicItem, ItemId,
+    ItemType, LocationKind, MarketGroup, MarketGroupId, Station, StationId, Structure, StructureId,
+    TypeId,
 };
 
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use serde_cbor;
+use std::borrow::Cow;
 use std::collections::{BTreeMap, BTreeSet, HashMap};
-use std::sync::RwLock;
+use std::io::Write;
+use parking_lot::{Mutex, RwLock};
 use std::time::{Instant, Duration};
 use std::sync::Arc;
+use tracing::{debug, info, warn};
+use utoipa::ToSchema;
+
+/// Version of the new_assets.cbor layout, see `versioned`
+const CHARACTER_ASSETS_FORMAT_VERSION: u32 = 1;
 
 
 #[derive(Default)]
 pub struct ChainStats {
+    pub cache_hits: usize,
     pub direct_station: usize,
     pub lookups: usize,
+    /// Assets whose container chain was cut off at the depth limit
+    pub depth_capped: usize,
     pub max_depth: u32,
     pub total_depth: u32,
     pub total_calls: u32,
@@ -34,8 +47,10 @@ impl ChainStats {
     pub fn print_summary(&self) {
         println!("=== Chain Stats Summary ===");
         println!("Total calls: {}", self.total_calls);
+        println!("Cache hits: {}", self.cache_hits);
         println!("Direct stations: {}", self.direct_station);
         println!("Total lookups: {}", self.lookups);
+        println!("Depth capped: {}", self.depth_capped);
         println!("Max depth: {}", self.max_depth);
         println!("Average depth: {:.2}", self.avg_depth());
     }
@@ -43,13 +58,11 @@ impl ChainStats {
 
 #[derive(Default)]
 pub struct ChainTimings {
-    pub cache_hit: Duration,
     pub cache_lookup: Duration,
     pub asset_lookup: Duration,
     pub name_lookup: Duration,
     pub station_lookup: Duration,
     pub string_ops: Duration,
-    pub arc_creation: Duration,
     pub total: Duration,
 }
 
@@ -58,20 +71,219 @@ impl ChainTimings {
         println!("=== Chain Timings Breakdown ===");
         let total_us = self.total.as_micros() as f64;
         println!("Total:          {:?} (100.0%)", self.total);
-        println!("  Cache hits:   {:?} ({:.1}%)", self.cache_hit, self.cache_hit.as_micros() as f64 / total_us * 100.0);
         println!("  Cache lookup: {:?} ({:.1}%)", self.cache_lookup, self.cache_lookup.as_micros() as f64 / total_us * 100.0);
         println!("  Asset lookup: {:?} ({:.1}%)", self.asset_lookup, self.asset_lookup.as_micros() as f64 / total_us * 100.0);
         println!("  Name lookup:  {:?} ({:.1}%)", self.name_lookup, self.name_lookup.as_micros() as f64 / total_us * 100.0);
         println!("  Station lookup:{:?} ({:.1}%)", self.station_lookup, self.station_lookup.as_micros() as f64 / total_us * 100.0);
         println!("  String ops:   {:?} ({:.1}%)", self.string_ops, self.string_ops.as_micros() as f64 / total_us * 100.0);
-        println!("  Arc creation: {:?} ({:.1}%)", self.arc_creation, self.arc_creation.as_micros() as f64 / total_us * 100.0);
     }    
 }
 
+/// Default number of nested containers `build_location_chain` walks up before giving up.
+pub const MAX_LOCATION_DEPTH: u32 = 10;
+
+/// Marks the start of a container path truncated at the depth limit.
+pub const TRUNCATED_CHAIN_MARKER: &str = "...";
+
+/// Station followed by the container path, just the station for items sitting in it directly
+pub(crate) fn location_label(station_name: String, location_name: String) -> String {
+    if location_name == "Direct" {
+        station_name
+    } else {
+        format!("{} -> {}", station_name, location_name)
+    }
+}
+
+/// Numbered ESI location flags, e.g. "MedSlot3"
+const NUMBERED_LOCATION_FLAGS: [(&str, &str); 8] = [
+    ("HiSlot", "High Slot"),
+    ("MedSlot", "Medium Slot"),
+    ("LoSlot", "Low Slot"),
+    ("RigSlot", "Rig Slot"),
+    ("SubSystemSlot", "Subsystem Slot"),
+    ("ServiceSlot", "Service Slot"),
+    ("FighterTube", "Fighter Tube"),
+    ("CorpSAG", "Corporation Hangar"),
+];
+
+/// Display label of an ESI `location_flag`, "MedSlot3" => "Medium Slot 3". Flags without
+/// a label of their own are split before capitals and digits, "SpecializedGasHold" =>
+/// "Specialized Gas Hold"
+pub fn pretty_location_flag(flag: &str) -> String {
+    for (prefix, label) in NUMBERED_LOCATION_FLAGS {
+        let number = flag
+            .strip_prefix(prefix)
+            .filter(|number| !number.is_empty() && number.chars().all(|c| c.is_ascii_digit()));
+        if let Some(number) = number {
+            return format!("{} {}", label, number);
+        }
+    }
+
+    let label = match flag {
+        "Cargo" => Some("Cargo Hold"),
+        "DroneBay" => Some("Drone Bay"),
+        "FighterBay" => Some("Fighter Bay"),
+        "FleetHangar" => Some("Fleet Hangar"),
+        "ShipHangar" => Some("Ship Maintenance Bay"),
+        "SpecializedFuelBay" | "StructureFuel" => Some("Fuel Bay"),
+        "SpecializedOreHold" => Some("Ore Hold"),
+        "SubSystemBay" => Some("Subsystem Bay"),
+        "HangarAll" => Some("Hangar"),
+        _ => None,
+    };
+    if let Some(label) = label {
+        return label.to_string();
+    }
+
+    let mut label = String::with_capacity(flag.len() + 4);
+    let mut prev: Option<char> = None;
+    for c in flag.chars() {
+        if let Some(prev) = prev {
+            let word_start = c.is_ascii_uppercase()
+                && (prev.is_ascii_lowercase() || prev.is_ascii_digit());
+            let number_start = c.is_ascii_digit() && prev.is_ascii_alphabetic();
+            if word_start || number_start {
+                label.push(' ');
+            }
+        }
+        label.push(c);
+        prev = Some(c);
+    }
+    label
+}
+
+/// Quotes a CSV field if it contains a separator, quote or line break (RFC 4180).
+/// Text starting like a formula gets a `'` in front so spreadsheets show it as is,
+/// container names are set by players.
+fn csv_field(value: &str) -> Cow<'_, str> {
+    let value: Cow<'_, str> = if value.starts_with(['=', '+', '-', '@']) {
+        Cow::Owned(format!("'{value}"))
+    } else {
+        Cow::Borrowed(value)
+    };
+    if value.contains([',', '"', '\n', '\r']) {
+        Cow::Owned(format!("\"{}\"", value.replace('"', "\"\"")))
+    } else {
+        value
+    }
+}
+
+/// Human readable name of an asset: the custom name from ESI if there is one,
+/// then the name of its type, and `Container_{item_id}` as the last resort.
+pub fn asset_display_name(
+    asset: &AssetItem,
+    assets_names: &BTreeMap<ItemId, String>,
+    types: &BTreeMap<TypeId, ItemType>,
+) -> String {
+    if let Some(name) = assets_names.get(&asset.item_id) {
+        return name.clone();
+    }
+
+    if let Some(item_type) = types.get(&asset.type_id) {
+        return item_type.name.clone();
+    }
+
+    format!("Container_{}", asset.item_id)
+}
+
+/// Stations and structures an asset location chain can end in
+pub struct LocationMaps<'a> {
+    pub stations: &'a BTreeMap<StationId, Station>,
+    pub structures: &'a BTreeMap<StructureId, Structure>,
+}
+
+impl LocationMaps<'_> {
+    /// Name of a station or structure location, None if it isn't resolved (yet)
+    fn name_of(&self, location_id: i64, location_type: &str) -> Option<String> {
+        match LocationKind::of(location_type, location_id) {
+            LocationKind::Station => self
+                .stations
+                .get(&(location_id as StationId))
+                .map(|station| station.name.clone()),
+            LocationKind::Structure => self
+                .structures
+                .get(&location_id)
+                .map(|structure| structure.name.clone()),
+            _ => None,
+        }
+    }
+}
+
+/// Owned mutated item without its attributes, names are None while not resolved yet
+#[derive(Serialize, ToSchema, Debug, Clone)]
+pub struct DynamicSummary {
+    pub item_id: ItemId,
+    pub resulting_type_id: Option<TypeId>,
+    pub resulting_type_name: Option<String>,
+    pub source_type_id: TypeId,
+    pub source_type_name: Option<String>,
+    pub mutator_type_id: TypeId,
+    pub mutator_name: Option<String>,
+    pub location: String,
+}
+
+/// Item that is still owned but sits somewhere else than before
+#[derive(Serialize, Debug, Clone)]
+pub struct MovedAsset {
+    pub item_id: ItemId,
+    pub from_location_id: i64,
+    pub to_location_id: i64,
+}
+
+/// Changes between two snapshots of a character's assets
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct AssetsDiff {
+    pub added: Vec<ItemId>,
+    pub removed: Vec<ItemId>,
+    pub moved: Vec<MovedAsset>,
+}
+
+impl AssetsDiff {
+    pub fn between(
+        old: &BTreeMap<ItemId, AssetItem>,
+        new: &BTreeMap<ItemId, AssetItem>,
+    ) -> AssetsDiff {
+        let mut diff = AssetsDiff::default();
+        for (item_id, asset) in new {
+            match old.get(item_id) {
+                None => diff.added.push(*item_id),
+                Some(previous) if previous.location_id != asset.location_id => {
+                    diff.moved.push(MovedAsset {
+                        item_id: *item_id,
+                        from_location_id: previous.location_id,
+                        to_location_id: asset.location_id,
+                    })
+                }
+                Some(_) => {}
+            }
+        }
+        diff.removed = old
+            .keys()
+            .filter(|item_id| !new.contains_key(item_id))
+            .copied()
+            .collect();
+        diff
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.moved.is_empty()
+    }
+}
+
+/// Character or corporation an asset was listed for
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum AssetOwner {
+    Character(CharacterId),
+    Corporation(CorporationId),
+}
+
 pub struct CharacterAssets {
     pub assets: RwLock<BTreeMap<ItemId, AssetItem>>,
     pub assets_names: RwLock<BTreeMap<ItemId, String>>,
+    /// Owner of each asset, so a run only replaces the assets of the owners it listed
+    pub asset_owners: RwLock<BTreeMap<ItemId, AssetOwner>>,
     pub stations: RwLock<BTreeMap<StationId, Station>>,
+    pub structures: RwLock<BTreeMap<StructureId, Structure>>,
     pub dynamics: RwLock<BTreeMap<ItemId, DynamicItem>>,
     pub dogma_attributes: RwLock<BTreeMap<DogmaAttributeId, DogmaAttribute>>,
     pub dogma_attributes_name_to_id: RwLock<BTreeMap<String, DogmaAttributeId>>,
@@ -79,6 +291,9 @@ pub struct CharacterAssets {
     pub market_groups: RwLock<BTreeMap<MarketGroupId, MarketGroup>>,
     pub abyssal_items: RwLock<BTreeSet<TypeId>>,
     pub mutaplasmid_effects: RwLock<MutaplasmidEffects>,
+    /// type_id => market group, built on first use of `market_group_of_type` and dropped
+    /// whenever a type or market group is added. Derived, so never stored
+    type_market_groups: RwLock<Option<HashMap<TypeId, MarketGroupId>>>,
 }
 
 #[derive(PartialEq, Hash, Eq, Clone, Ord, PartialOrd, Debug)]
@@ -86,6 +301,7 @@ pub enum GetData {
     Dynamic(TypeId, ItemId),
     MarketGroup(MarketGroupId),
     Station(StationId),
+    Structure(StructureId),
     Type(TypeId),
     DogmaAttribute(DogmaAttributeId),
 }
@@ -108,21 +324,80 @@ pub struct AttributeRange {
     pub min: f64,
 }
 
+impl MutaplasmidEffects {
+    fn add(
+        &mut self,
+        mutator_type_id: TypeId,
+        attributes: Vec<(DogmaAttributeId, f64, f64)>,
+        input_output: Vec<(TypeId, Vec<TypeId>)>,
+    ) -> BTreeSet<GetData> {
+        // println!("DEBUG: mutator_type_id {}", mutator_type_id);
+        let mut new_items = BTreeSet::new();
+        for (resulting_type_id, source_type_ids) in input_output {
+            for source_type_id in source_type_ids {
+                self.source_to_mutator_to_resulting
+                    .entry(source_type_id)
+                    .or_default()
+                    .entry(mutator_type_id)
+                    .or_insert(resulting_type_id);
+
+                self.resulting_to_applicable
+                    .entry(resulting_type_id)
+                    .or_default()
+                    .insert(source_type_id);
+
+                self.resulting_to_mutator_to_source
+                    .entry(resulting_type_id)
+                    .or_default()
+                    .entry(mutator_type_id)
+                    .or_default()
+                    .insert(source_type_id);
+
+                // println!("    DEBUG: type {}", source_type_id);
+                new_items.insert(GetData::Type(source_type_id));
+            }
+        }
+
+        for (attribute_id, min, max) in attributes {
+            let (min, max) = if min > max {
+                warn!(
+                    "⚠️  Mutator {} has inverted range for attribute {} ({} > {}), swapping",
+                    mutator_type_id, attribute_id, min, max
+                );
+                (max, min)
+            } else {
+                (min, max)
+            };
+
+            self.attributes
+                .entry(mutator_type_id)
+                .or_default()
+                .entry(attribute_id)
+                .or_insert(AttributeRange { min, max });
+        }
+
+        new_items
+    }
+}
+
 impl Clone for CharacterAssets {
     fn clone(&self) -> Self {
         CharacterAssets {
-            assets: RwLock::new(self.assets.read().unwrap().clone()),
-            assets_names: RwLock::new(self.assets_names.read().unwrap().clone()),
-            stations: RwLock::new(self.stations.read().unwrap().clone()),
-            dynamics: RwLock::new(self.dynamics.read().unwrap().clone()),
-            types: RwLock::new(self.types.read().unwrap().clone()),
-            dogma_attributes: RwLock::new(self.dogma_attributes.read().unwrap().clone()),
+            assets: RwLock::new(self.assets.read().clone()),
+            assets_names: RwLock::new(self.assets_names.read().clone()),
+            asset_owners: RwLock::new(self.asset_owners.read().clone()),
+            stations: RwLock::new(self.stations.read().clone()),
+            structures: RwLock::new(self.structures.read().clone()),
+            dynamics: RwLock::new(self.dynamics.read().clone()),
+            types: RwLock::new(self.types.read().clone()),
+            dogma_attributes: RwLock::new(self.dogma_attributes.read().clone()),
             dogma_attributes_name_to_id: RwLock::new(
-                self.dogma_attributes_name_to_id.read().unwrap().clone(),
+                self.dogma_attributes_name_to_id.read().clone(),
             ),
-            market_groups: RwLock::new(self.market_groups.read().unwrap().clone()),
-            abyssal_items: RwLock::new(self.abyssal_items.read().unwrap().clone()),
-            mutaplasmid_effects: RwLock::new(self.mutaplasmid_effects.read().unwrap().clone()),
+            market_groups: RwLock::new(self.market_groups.read().clone()),
+            abyssal_items: RwLock::new(self.abyssal_items.read().clone()),
+            mutaplasmid_effects: RwLock::new(self.mutaplasmid_effects.read().clone()),
+            type_market_groups: RwLock::new(None),
         }
     }
 }
@@ -131,7 +406,13 @@ impl Clone for CharacterAssets {
 struct SerializableCharacterAssets {
     assets: BTreeMap<ItemId, AssetItem>,
     assets_names: BTreeMap<ItemId, String>,
+    // Absent from files stored before owners were recorded
+    #[serde(default)]
+    asset_owners: BTreeMap<ItemId, AssetOwner>,
     stations: BTreeMap<StationId, Station>,
+    // Absent from files stored before structures were resolved
+    #[serde(default)]
+    structures: BTreeMap<StructureId, Structure>,
     dynamics: BTreeMap<ItemId, DynamicItem>,
     dogma_attributes: BTreeMap<DogmaAttributeId, DogmaAttribute>,
     dogma_attributes_name_to_id: BTreeMap<String, DogmaAttributeId>,
@@ -146,39 +427,34 @@ impl Serialize for CharacterAssets {
     where
         S: Serializer,
     {
-        let assets = self.assets.read().map_err(serde::ser::Error::custom)?;
-        let assets_names = self
-            .assets_names
-            .read()
-            .map_err(serde::ser::Error::custom)?;
-        let stations = self.stations.read().map_err(serde::ser::Error::custom)?;
-        let dynamics = self.dynamics.read().map_err(serde::ser::Error::custom)?;
-        let dogma_attributes = self
-            .dogma_attributes
-            .read()
-            .map_err(serde::ser::Error::custom)?;
-        let dogma_attributes_name_to_id = self
-            .dogma_attributes_name_to_id
-            .read()
-            .map_err(serde::ser::Error::custom)?;
-        let types = self.types.read().map_err(serde::ser::Error::custom)?;
-        let market_groups = self
-            .market_groups
-            .read()
-            .map_err(serde::ser::Error::custom)?;
-        let abyssal_items = self
-            .abyssal_items
-            .read()
-            .map_err(serde::ser::Error::custom)?;
-        let mutaplasmid_effects = self
-            .mutaplasmid_effects
-            .read()
-            .map_err(serde::ser::Error::custom)?;
-
-        let serializable = SerializableCharacterAssets {
+        self.snapshot().serialize(serializer)
+    }
+}
+
+impl CharacterAssets {
+    /// Copy of all maps taken while holding every read lock at once, so no writer can
+    /// slip in between two maps. The locks are taken in field order, the same order
+    /// writers nest them in (e.g. dynamics before dogma attributes and types).
+    fn snapshot(&self) -> SerializableCharacterAssets {
+        let assets = self.assets.read();
+        let assets_names = self.assets_names.read();
+        let asset_owners = self.asset_owners.read();
+        let stations = self.stations.read();
+        let structures = self.structures.read();
+        let dynamics = self.dynamics.read();
+        let dogma_attributes = self.dogma_attributes.read();
+        let dogma_attributes_name_to_id = self.dogma_attributes_name_to_id.read();
+        let types = self.types.read();
+        let market_groups = self.market_groups.read();
+        let abyssal_items = self.abyssal_items.read();
+        let mutaplasmid_effects = self.mutaplasmid_effects.read();
+
+        SerializableCharacterAssets {
             assets: assets.clone(),
             assets_names: assets_names.clone(),
+            asset_owners: asset_owners.clone(),
             stations: stations.clone(),
+            structures: structures.clone(),
             dynamics: dynamics.clone(),
             dogma_attributes: dogma_attributes.clone(),
             dogma_attributes_name_to_id: dogma_attributes_name_to_id.clone(),
@@ -186,9 +462,7 @@ impl Serialize for CharacterAssets {
             market_groups: market_groups.clone(),
             abyssal_items: abyssal_items.clone(),
             mutaplasmid_effects: mutaplasmid_effects.clone(),
-        };
-
-        serializable.serialize(serializer)
+        }
     }
 }
 
@@ -197,11 +471,18 @@ impl<'de> Deserialize<'de> for CharacterAssets {
     where
         D: Deserializer<'de>,
     {
-        let serializable = SerializableCharacterAssets::deserialize(deserializer)?;
-        Ok(CharacterAssets {
+        SerializableCharacterAssets::deserialize(deserializer).map(CharacterAssets::from)
+    }
+}
+
+impl From<SerializableCharacterAssets> for CharacterAssets {
+    fn from(serializable: SerializableCharacterAssets) -> Self {
+        CharacterAssets {
             assets: RwLock::new(serializable.assets),
             assets_names: RwLock::new(serializable.assets_names),
+            asset_owners: RwLock::new(serializable.asset_owners),
             stations: RwLock::new(serializable.stations),
+            structures: RwLock::new(serializable.structures),
             dynamics: RwLock::new(serializable.dynamics),
             dogma_attributes: RwLock::new(serializable.dogma_attributes),
             dogma_attributes_name_to_id: RwLock::new(serializable.dogma_attributes_name_to_id),
@@ -209,7 +490,8 @@ impl<'de> Deserialize<'de> for CharacterAssets {
             market_groups: RwLock::new(serializable.market_groups),
             abyssal_items: RwLock::new(serializable.abyssal_items),
             mutaplasmid_effects: RwLock::new(serializable.mutaplasmid_effects),
-        })
+            type_market_groups: RwLock::new(None),
+        }
     }
 }
 
@@ -218,7 +500,9 @@ impl CharacterAssets {
         CharacterAssets {
             assets: RwLock::new(BTreeMap::new()),
             assets_names: RwLock::new(BTreeMap::new()),
+            asset_owners: RwLock::new(BTreeMap::new()),
             stations: RwLock::new(BTreeMap::new()),
+            structures: RwLock::new(BTreeMap::new()),
             dynamics: RwLock::new(BTreeMap::new()),
             dogma_attributes: RwLock::new(BTreeMap::new()),
             dogma_attributes_name_to_id: RwLock::new(BTreeMap::new()),
@@ -226,6 +510,7 @@ impl CharacterAssets {
             market_groups: RwLock::new(BTreeMap::new()),
             abyssal_items: RwLock::new(BTreeSet::from_iter(abyssal_items)),
             mutaplasmid_effects: RwLock::new(MutaplasmidEffects::default()),
+            type_market_groups: RwLock::new(None),
         }
     }
 
@@ -234,52 +519,30 @@ impl CharacterAssets {
         mutator_type_id: TypeId,
         attributes: Vec<(DogmaAttributeId, f64, f64)>,
         input_output: Vec<(TypeId, Vec<TypeId>)>, // [(resulting_type, [applicable_types]), ...]
-    ) -> Result<Vec<GetData>, String> {
-        let mut mutaplasmid_effects = self
-            .mutaplasmid_effects
-            .write()
-            .map_err(|e| format!("Failed to acquire write lock: {}", e))?;
+    ) -> Vec<GetData> {
+        let mut mutaplasmid_effects = self.mutaplasmid_effects.write();
 
-        // println!("DEBUG: mutator_type_id {}", mutator_type_id);
-        let mut new_items = BTreeSet::new();
-        for (resulting_type_id, source_type_ids) in input_output {
-            for source_type_id in source_type_ids {
-                mutaplasmid_effects
-                    .source_to_mutator_to_resulting
-                    .entry(source_type_id)
-                    .or_default()
-                    .entry(mutator_type_id)
-                    .or_insert(resulting_type_id);
-
-                mutaplasmid_effects
-                    .resulting_to_applicable
-                    .entry(resulting_type_id)
-                    .or_default()
-                    .insert(source_type_id);
+        let new_items = mutaplasmid_effects.add(mutator_type_id, attributes, input_output);
 
-                mutaplasmid_effects
-                    .resulting_to_mutator_to_source
-                    .entry(resulting_type_id)
-                    .or_default()
-                    .entry(mutator_type_id)
-                    .or_default()
-                    .insert(source_type_id);
+        new_items.into_iter().collect()
+    }
 
-                // println!("    DEBUG: type {}", source_type_id);
-                new_items.insert(GetData::Type(source_type_id));
-            }
+    /// Drop all known mutaplasmid effects and load them again from fresh hoboleaks data
+    pub fn replace_mutaplasmid_effects(&self, data: &MutaplasmidData) -> Vec<GetData> {
+        let mut effects = MutaplasmidEffects::default();
+        let mut new_items = BTreeSet::new();
+        for (mutator_type_id, mutator_data) in data {
+            new_items.extend(effects.add(
+                *mutator_type_id,
+                mutator_data.attribute_ranges(),
+                mutator_data.input_output(),
+            ));
         }
 
-        for (attribute_id, min, max) in attributes {
-            mutaplasmid_effects
-                .attributes
-                .entry(mutator_type_id)
-                .or_default()
-                .entry(attribute_id)
-                .or_insert(AttributeRange { min, max });
-        }
+        let mut mutaplasmid_effects = self.mutaplasmid_effects.write();
+        *mutaplasmid_effects = effects;
 
-        Ok(new_items.into_iter().collect())
+        new_items.into_iter().collect()
     }
 
     pub fn get_mutators_by_resulting_type_id(
@@ -287,15 +550,11 @@ impl CharacterAssets {
         resulting_type_id: &TypeId,
     ) -> Result<BTreeMap<(TypeId, String), BTreeMap<DogmaAttributeId, AttributeRange>>, String>
     {
-        let mutaplasmid_effects = self
-            .mutaplasmid_effects
-            .read()
-            .map_err(|e| format!("Failed to acquire read lock: {}", e))?;
+        let mutaplasmid_effects = self.mutaplasmid_effects.read();
 
-        let types = self
-            .types
-            .read()
-            .map_err(|e| format!("Failed to acquire read lock: {}", e))?;
+        // Recursive: the dynamics report calls this from several threads while holding
+        // the types lock through `with_all_data`, a queued writer must not block them
+        let types = self.types.read_recursive();
 
         let mut res = BTreeMap::new();
 
@@ -304,9 +563,14 @@ impl CharacterAssets {
             .get(resulting_type_id)
         {
             for (mutator_type_id, _) in mutator_to_source {
-                let mutator_type = types.get(mutator_type_id).unwrap();
-
-                let attributes = mutaplasmid_effects.attributes.get(mutator_type_id).unwrap();
+                let mutator_type = types
+                    .get(mutator_type_id)
+                    .ok_or_else(|| format!("mutator type {} not found", mutator_type_id))?;
+
+                let attributes = mutaplasmid_effects
+                    .attributes
+                    .get(mutator_type_id)
+                    .ok_or_else(|| format!("attributes of mutator {} not found", mutator_type_id))?;
                 res.entry((*mutator_type_id, mutator_type.name.clone()))
                     .or_insert_with(|| attributes.clone());
             }
@@ -319,15 +583,11 @@ impl CharacterAssets {
         &self,
         resulting_type_id: &TypeId,
     ) -> Result<BTreeMap<DogmaAttributeId, AttributeRange>, String> {
-        let mutaplasmid_effects = self
-            .mutaplasmid_effects
-            .read()
-            .map_err(|e| format!("Failed to acquire read lock: {}", e))?;
+        let mutaplasmid_effects = self.mutaplasmid_effects.read();
 
-        let types = self
-            .types
-            .read()
-            .map_err(|e| format!("Failed to acquire read lock: {}", e))?;
+        // Recursive: the dynamics report calls this from several threads while holding
+        // the types lock through `with_all_data`, a queued writer must not block them
+        let types = self.types.read_recursive();
 
         let mut min_max_attributes: BTreeMap<DogmaAttributeId, AttributeRange> = BTreeMap::new();
 
@@ -336,10 +596,15 @@ impl CharacterAssets {
             .get(resulting_type_id)
             .unwrap_or(&BTreeMap::new())
         {
-            let mutator_attributes = mutaplasmid_effects.attributes.get(mutator_type_id).unwrap();
+            let mutator_attributes = mutaplasmid_effects
+                .attributes
+                .get(mutator_type_id)
+                .ok_or_else(|| format!("attributes of mutator {} not found", mutator_type_id))?;
 
             for source_type_id in source_type_ids {
-                let source_type = types.get(source_type_id).unwrap();
+                let source_type = types
+                    .get(source_type_id)
+                    .ok_or_else(|| format!("source type {} not found", source_type_id))?;
 
                 for attribute in &source_type.dogma_attributes {
                     if let Some(attr_range) = mutator_attributes.get(&attribute.attribute_id) {
@@ -380,10 +645,7 @@ impl CharacterAssets {
         &self,
         mutator_type_id: &TypeId,
     ) -> Result<BTreeMap<DogmaAttributeId, AttributeRange>, String> {
-        let mutaplasmid_effects = self
-            .mutaplasmid_effects
-            .read()
-            .map_err(|e| format!("Failed to acquire read lock: {}", e))?;
+        let mutaplasmid_effects = self.mutaplasmid_effects.read();
 
         let r = mutaplasmid_effects
             .attributes
@@ -398,10 +660,7 @@ impl CharacterAssets {
         source_type_id: TypeId,
         mutator_type_id: TypeId,
     ) -> Result<TypeId, String> {
-        let mutaplasmid_effects = self
-            .mutaplasmid_effects
-            .read()
-            .map_err(|e| format!("Failed to acquire read lock: {}", e))?;
+        let mutaplasmid_effects = self.mutaplasmid_effects.read();
 
         let resulting_type = mutaplasmid_effects
             .source_to_mutator_to_resulting
@@ -417,10 +676,7 @@ impl CharacterAssets {
         &self,
         mutator_type_id: &TypeId,
     ) -> Result<BTreeSet<DogmaAttributeId>, String> {
-        let mutaplasmid_effects = self
-            .mutaplasmid_effects
-            .read()
-            .map_err(|e| format!("Failed to acquire read lock: {}", e))?;
+        let mutaplasmid_effects = self.mutaplasmid_effects.read();
 
         let attributes = mutaplasmid_effects
             .attributes
@@ -434,10 +690,7 @@ impl CharacterAssets {
         &self,
         resulting_type_id: &TypeId,
     ) -> Result<BTreeSet<TypeId>, String> {
-        let mutaplasmid_effects = self
-            .mutaplasmid_effects
-            .read()
-            .map_err(|e| format!("Failed to acquire read lock: {}", e))?;
+        let mutaplasmid_effects = self.mutaplasmid_effects.read();
 
         let applicable_types = mutaplasmid_effects
             .resulting_to_applicable
@@ -447,107 +700,205 @@ impl CharacterAssets {
         Ok(applicable_types.clone())
     }
 
-    pub fn add_asset(&self, asset: AssetItem) -> Result<Vec<GetData>, String> {
+    pub fn add_asset(&self, asset: AssetItem) -> Vec<GetData> {
         {
-            let mut assets = self
-                .assets
-                .write()
-                .map_err(|e| format!("Failed to acquire write lock: {}", e))?;
+            let mut assets = self.assets.write();
             assets.insert(asset.item_id, asset.clone());
         }
 
         let mut new_items = vec![];
 
-        if self.is_on_station(&asset) {
-            let station_id = asset.location_id as StationId;
-            let stations = self
-                .stations
-                .read()
-                .map_err(|e| format!("Failed to acquire read lock: {}", e))?;
-            if !stations.contains_key(&station_id) {
-                new_items.push(GetData::Station(station_id));
+        match asset.location_kind() {
+            LocationKind::Station => {
+                let station_id = asset.location_id as StationId;
+                let stations = self.stations.read();
+                if !stations.contains_key(&station_id) {
+                    new_items.push(GetData::Station(station_id));
+                }
             }
+            LocationKind::Structure => {
+                let structures = self.structures.read();
+                if !structures.contains_key(&asset.location_id) {
+                    new_items.push(GetData::Structure(asset.location_id));
+                }
+            }
+            // Containers and ships are assets of the same page, space has nothing to resolve
+            LocationKind::Item | LocationKind::Solarsystem | LocationKind::Other => {}
         }
 
-        if self.is_abyssal(&asset)? {
-            let dynamics = self
-                .dynamics
-                .read()
-                .map_err(|e| format!("Failed to acquire read lock: {}", e))?;
+        if self.is_dynamic(&asset) {
+            let dynamics = self.dynamics.read();
             if !dynamics.contains_key(&asset.item_id) {
                 new_items.push(GetData::Dynamic(asset.type_id, asset.item_id));
             }
         }
 
         {
-            let types = self
-                .types
-                .read()
-                .map_err(|e| format!("Failed to acquire read lock: {}", e))?;
+            let types = self.types.read();
             if !types.contains_key(&asset.type_id) {
                 new_items.push(GetData::Type(asset.type_id));
             }
         }
 
-        Ok(new_items)
+        new_items
     }
 
-    pub fn add_asset_name(&self, asset_id: ItemId, name: String) -> Result<Vec<GetData>, String> {
-        let mut assets_names = self
-            .assets_names
-            .write()
-            .map_err(|e| format!("Failed to acquire write lock: {}", e))?;
-        assets_names.insert(asset_id, name);
-        Ok(vec![])
+    /// `add_asset` for a whole page, every lock is taken once instead of once per item.
+    /// A set, so a type or location shared by many items of the page is queued once
+    pub fn add_assets(&self, items: Vec<AssetItem>) -> BTreeSet<GetData> {
+        let new_items = self.missing_for_assets(&items);
+
+        {
+            let mut assets = self.assets.write();
+            assets.extend(items.into_iter().map(|asset| (asset.item_id, asset)));
+        }
+
+        new_items
+    }
+
+    /// Stations, structures, dynamics and types the items refer to that aren't known yet
+    pub fn missing_for_assets(&self, items: &[AssetItem]) -> BTreeSet<GetData> {
+        let mut new_items = BTreeSet::new();
+
+        {
+            let stations = self.stations.read();
+            new_items.extend(
+                items
+                    .iter()
+                    .filter(|asset| asset.is_station())
+                    .map(|asset| asset.location_id as StationId)
+                    .filter(|station_id| !stations.contains_key(station_id))
+                    .map(GetData::Station),
+            );
+        }
+
+        {
+            let structures = self.structures.read();
+            new_items.extend(
+                items
+                    .iter()
+                    .filter(|asset| asset.is_structure())
+                    .filter(|asset| !structures.contains_key(&asset.location_id))
+                    .map(|asset| GetData::Structure(asset.location_id)),
+            );
+        }
+
+        // Same as `is_dynamic`, without a lock per item
+        let dynamic_items: Vec<&AssetItem> = {
+            let abyssal_items = self.abyssal_items.read();
+            items
+                .iter()
+                .filter(|asset| asset.is_singleton && abyssal_items.contains(&asset.type_id))
+                .collect()
+        };
+        {
+            let dynamics = self.dynamics.read();
+            new_items.extend(
+                dynamic_items
+                    .into_iter()
+                    .filter(|asset| !dynamics.contains_key(&asset.item_id))
+                    .map(|asset| GetData::Dynamic(asset.type_id, asset.item_id)),
+            );
+        }
+
+        {
+            let types = self.types.read();
+            new_items.extend(
+                items
+                    .iter()
+                    .filter(|asset| !types.contains_key(&asset.type_id))
+                    .map(|asset| GetData::Type(asset.type_id)),
+            );
+        }
+
+        new_items
     }
 
-    pub fn add_station(
+    /// Replace the assets of `owner` with `items`, the assets of other owners stay. Assets
+    /// stored before owners were recorded have none and are dropped as well, each owner's
+    /// next run lists them again. Returns the previous assets of `owner`
+    pub fn replace_owner_assets(
         &self,
-        station_id: StationId,
-        station: Station,
-    ) -> Result<Vec<GetData>, String> {
+        owner: AssetOwner,
+        items: Vec<AssetItem>,
+    ) -> BTreeMap<ItemId, AssetItem> {
+        let mut assets = self.assets.write();
+        let mut assets_names = self.assets_names.write();
+        let mut asset_owners = self.asset_owners.write();
+
+        let listed: BTreeSet<ItemId> = items.iter().map(|asset| asset.item_id).collect();
+        let replaced: Vec<ItemId> = assets
+            .keys()
+            .filter(|item_id| asset_owners.get(item_id).is_none_or(|o| *o == owner))
+            .copied()
+            .collect();
+
+        let mut previous = BTreeMap::new();
+        for item_id in replaced {
+            let Some(asset) = assets.remove(&item_id) else {
+                continue;
+            };
+            // Unowned items only count as previous if they are still listed, otherwise
+            // another owner's items would show up as removed
+            if asset_owners.remove(&item_id).is_some() || listed.contains(&item_id) {
+                previous.insert(item_id, asset);
+            }
+            // Names of a run in flight arrive before its items, only drop those of items
+            // that are gone
+            if !listed.contains(&item_id) {
+                assets_names.remove(&item_id);
+            }
+        }
+
+        for asset in items {
+            asset_owners.insert(asset.item_id, owner);
+            assets.insert(asset.item_id, asset);
+        }
+
+        previous
+    }
+
+    pub fn add_asset_name(&self, asset_id: ItemId, name: String) -> Vec<GetData> {
+        let mut assets_names = self.assets_names.write();
+        assets_names.insert(asset_id, name);
+        vec![]
+    }
+
+    pub fn add_station(&self, station_id: StationId, station: Station) -> Vec<GetData> {
         {
-            let mut stations = self
-                .stations
-                .write()
-                .map_err(|e| format!("Failed to acquire write lock: {}", e))?;
+            let mut stations = self.stations.write();
             stations.insert(station_id, station);
         }
 
-        Ok(vec![])
+        vec![]
     }
 
-    pub fn add_dogma_attribute(
-        &self,
-        dogma_attribute: DogmaAttribute,
-    ) -> Result<Vec<GetData>, String> {
+    pub fn add_structure(&self, structure_id: StructureId, structure: Structure) -> Vec<GetData> {
+        let mut structures = self.structures.write();
+        structures.insert(structure_id, structure);
+
+        vec![]
+    }
+
+    pub fn add_dogma_attribute(&self, dogma_attribute: DogmaAttribute) -> Vec<GetData> {
         let attribute_id = dogma_attribute.attribute_id;
         let attribute_name = dogma_attribute.name.clone();
 
         {
-            let mut dogma_attributes = self
-                .dogma_attributes
-                .write()
-                .map_err(|e| format!("Failed to acquire write lock: {}", e))?;
+            let mut dogma_attributes = self.dogma_attributes.write();
             dogma_attributes.insert(attribute_id, dogma_attribute);
 
-            let mut dogma_attributes_name_to_id = self
-                .dogma_attributes_name_to_id
-                .write()
-                .map_err(|e| format!("Failed to acquire write lock: {}", e))?;
+            let mut dogma_attributes_name_to_id = self.dogma_attributes_name_to_id.write();
 
             let name = attribute_name.unwrap_or_else(|| format!("attribute_{}", attribute_id));
             dogma_attributes_name_to_id.insert(name, attribute_id);
         }
 
-        Ok(vec![])
+        vec![]
     }
 
     pub fn get_attribute_id_by_name(&self, name: String) -> Result<DogmaAttributeId, String> {
-        let dogma_attributes_name_to_id = self
-            .dogma_attributes_name_to_id
-            .read()
-            .map_err(|e| format!("Failed to acquire read lock: {}", e))?;
+        let dogma_attributes_name_to_id = self.dogma_attributes_name_to_id.read();
 
         dogma_attributes_name_to_id
             .get(&name)
@@ -555,61 +906,83 @@ impl CharacterAssets {
             .ok_or_else(|| format!("Attribute '{}' not found", name))
     }
 
-    pub fn add_type(&self, item_type: ItemType) -> Result<Vec<GetData>, String> {
+    pub fn add_type(&self, item_type: ItemType) -> BTreeSet<GetData> {
         let type_id = item_type.type_id;
         let maybe_market_group_id = item_type.market_group_id;
 
         {
-            let mut types = self
-                .types
-                .write()
-                .map_err(|e| format!("Failed to acquire write lock: {}", e))?;
+            let mut types = self.types.write();
             types.insert(type_id, item_type);
         }
+        *self.type_market_groups.write() = None;
 
-        let mut new_items = vec![];
+        let mut new_items = BTreeSet::new();
 
         if let Some(market_group_id) = maybe_market_group_id {
-            let market_groups = self
-                .market_groups
-                .read()
-                .map_err(|e| format!("Failed to acquire read lock: {}", e))?;
+            let market_groups = self.market_groups.read();
 
             if !market_groups.contains_key(&market_group_id) {
-                new_items.push(GetData::MarketGroup(market_group_id));
+                new_items.insert(GetData::MarketGroup(market_group_id));
             }
         }
 
-        Ok(new_items)
+        new_items
     }
 
-    pub fn add_market_group(&self, market_group: MarketGroup) -> Result<Vec<GetData>, String> {
+    pub fn add_market_group(&self, market_group: MarketGroup) -> BTreeSet<GetData> {
         let market_group_id = market_group.market_group_id;
 
         {
-            let mut market_groups = self
-                .market_groups
-                .write()
-                .map_err(|e| format!("Failed to acquire write lock: {}", e))?;
+            let mut market_groups = self.market_groups.write();
             market_groups.insert(market_group_id, market_group.clone());
         }
+        *self.type_market_groups.write() = None;
 
-        let mut new_items = vec![];
+        let mut new_items = BTreeSet::new();
 
         {
-            let types = self
-                .types
-                .read()
-                .map_err(|e| format!("Failed to acquire read lock: {}", e))?;
+            let types = self.types.read();
 
             for type_id in market_group.types {
                 if !types.contains_key(&type_id) {
-                    new_items.push(GetData::Type(type_id));
+                    new_items.insert(GetData::Type(type_id));
                 }
             }
         }
 
-        Ok(new_items)
+        new_items
+    }
+
+    /// Market group containing a type, from the type itself or else from the type lists
+    /// of the known market groups
+    pub fn market_group_of_type(&self, type_id: TypeId) -> Option<MarketGroupId> {
+        if let Some(index) = &*self.type_market_groups.read() {
+            return index.get(&type_id).copied();
+        }
+
+        // The index lock is held while building so an add_type/add_market_group running
+        // meanwhile drops the result afterwards instead of being overwritten by it
+        let mut index = self.type_market_groups.write();
+        index
+            .get_or_insert_with(|| self.build_type_market_groups())
+            .get(&type_id)
+            .copied()
+    }
+
+    fn build_type_market_groups(&self) -> HashMap<TypeId, MarketGroupId> {
+        let mut index = HashMap::new();
+        for market_group in self.market_groups.read().values() {
+            for type_id in &market_group.types {
+                index.insert(*type_id, market_group.market_group_id);
+            }
+        }
+        // The type's own market group wins over a stale type list
+        for item_type in self.types.read().values() {
+            if let Some(market_group_id) = item_type.market_group_id {
+                index.insert(item_type.type_id, market_group_id);
+            }
+        }
+        index
     }
 
     pub fn add_dynamic(
@@ -617,107 +990,80 @@ impl CharacterAssets {
         type_id: TypeId,
         item_id: ItemId,
         dynamic: DynamicItem,
-    ) -> Result<Vec<GetData>, String> {
-        let new_items = self.add_dynamic_internal(type_id, item_id, dynamic.clone())?;
-        Ok(new_items)
+    ) -> BTreeSet<GetData> {
+        self.add_dynamic_internal(type_id, item_id, dynamic)
     }
 
-    fn is_on_station(&self, asset: &AssetItem) -> bool {
-        asset.location_type == "station"
+    pub fn is_abyssal(&self, asset: &AssetItem) -> bool {
+        let abyssal_items = self.abyssal_items.read();
+        abyssal_items.contains(&asset.type_id)
     }
 
-    pub fn is_abyssal(&self, asset: &AssetItem) -> Result<bool, String> {
-        let abyssal_items = self
-            .abyssal_items
-            .read()
-            .map_err(|e| format!("Failed to acquire read lock: {}", e))?;
-        Ok(abyssal_items.contains(&asset.type_id))
+    /// Only assembled (singleton) abyssal items carry per-item dynamic attributes,
+    /// packaged stacks of an abyssal type have nothing to resolve.
+    pub fn is_dynamic(&self, asset: &AssetItem) -> bool {
+        asset.is_singleton && self.is_abyssal(asset)
     }
 
+    /// Dependencies are checked before the item is stored so no two locks are ever held at
+    /// once, each of them is taken a single time per item
     fn add_dynamic_internal(
         &self,
         _type_id: TypeId,
         item_id: ItemId,
         dynamic: DynamicItem,
-    ) -> Result<Vec<GetData>, String> {
-        let source_type_id = dynamic.source_type_id;
-
-        let mut dynamics = self
-            .dynamics
-            .write()
-            .map_err(|e| format!("Failed to acquire write lock: {}", e))?;
-        dynamics.insert(item_id, dynamic.clone());
-
-        let mut new_items = vec![];
+    ) -> BTreeSet<GetData> {
+        let mut new_items = BTreeSet::new();
 
         {
-            let dogma_attributes = self
-                .dogma_attributes
-                .read()
-                .map_err(|e| format!("Failed to acquire read lock: {}", e))?;
-
-            for attr in &dynamic.dogma_attributes {
-                if !dogma_attributes.contains_key(&attr.attribute_id) {
-                    new_items.push(GetData::DogmaAttribute(attr.attribute_id));
-                }
-            }
+            let dogma_attributes = self.dogma_attributes.read();
+            new_items.extend(
+                dynamic
+                    .dogma_attributes
+                    .iter()
+                    .map(|attr| attr.attribute_id)
+                    .filter(|attribute_id| !dogma_attributes.contains_key(attribute_id))
+                    .map(GetData::DogmaAttribute),
+            );
         }
 
-        // Add source type dependency
+        // Source and mutator types
         {
-            let types = self
-                .types
-                .read()
-                .map_err(|e| format!("Failed to acquire read lock: {}", e))?;
+            let types = self.types.read();
+            new_items.extend(
+                [dynamic.source_type_id, dynamic.mutator_type_id]
+                    .into_iter()
+                    .filter(|type_id| !types.contains_key(type_id))
+                    .map(GetData::Type),
+            );
+        }
 
-            if !types.contains_key(&source_type_id) {
-                new_items.push(GetData::Type(source_type_id));
-            }
+        self.dynamics.write().insert(item_id, dynamic);
 
-            if !types.contains_key(&dynamic.mutator_type_id) {
-                new_items.push(GetData::Type(dynamic.mutator_type_id));
-            }
-        }
+        new_items
+    }
 
-        Ok(new_items)
-    }
-
-    pub fn all_items_resolved(&self) -> Result<bool, String> {
-        let assets = self
-            .assets
-            .read()
-            .map_err(|e| format!("Failed to acquire read lock: {}", e))?;
-
-        let stations = self
-            .stations
-            .read()
-            .map_err(|e| format!("Failed to acquire read lock: {}", e))?;
-        let dynamics = self
-            .dynamics
-            .read()
-            .map_err(|e| format!("Failed to acquire read lock: {}", e))?;
-        let types = self
-            .types
-            .read()
-            .map_err(|e| format!("Failed to acquire read lock: {}", e))?;
-        let market_groups = self
-            .market_groups
-            .read()
-            .map_err(|e| format!("Failed to acquire read lock: {}", e))?;
+    pub fn all_items_resolved(&self) -> bool {
+        let assets = self.assets.read();
+
+        let stations = self.stations.read();
+        let dynamics = self.dynamics.read();
+        let types = self.types.read();
+        let market_groups = self.market_groups.read();
 
         for asset in assets.values() {
-            if self.is_on_station(asset) {
+            if asset.is_station() {
                 let station_id = asset.location_id as StationId;
                 if !stations.contains_key(&station_id) {
-                    println!("station not found for {asset:?}");
-                    return Ok(false);
+                    debug!("station not found for {asset:?}");
+                    return false;
                 }
             }
 
             let mut type_id = asset.type_id;
 
-            let is_abyssal = self.is_abyssal(asset)?;
-            if is_abyssal {
+            let is_dynamic = self.is_dynamic(asset);
+            if is_dynamic {
                 let dynamic = dynamics.get(&asset.item_id);
 
                 match dynamic {
@@ -726,7 +1072,7 @@ impl CharacterAssets {
                     }
                     None => {
                         // println!("dynamic not found for {asset:?}");
-                        return Ok(false);
+                        return false;
                     }
                 }
             }
@@ -737,32 +1083,29 @@ impl CharacterAssets {
                     if let Some(market_group_id) = item_type.market_group_id {
                         if !market_groups.contains_key(&market_group_id) {
                             // println!("market group not found for item type {item_type:?}");
-                            return Ok(false);
+                            return false;
                         }
                     }
                 }
                 None => {
                     // println!("type not found for {asset:?}");
-                    return Ok(false);
+                    return false;
                 }
             }
         }
 
-        let dogma_attributes = self
-            .dogma_attributes
-            .read()
-            .map_err(|e| format!("Failed to acquire read lock: {}", e))?;
+        let dogma_attributes = self.dogma_attributes.read();
         for dynamic in dynamics.values() {
             for attr in dynamic.dogma_attributes.iter() {
                 if !dogma_attributes.contains_key(&attr.attribute_id) {
-                    println!("dogma attribute not found for {attr:?}");
-                    return Ok(false);
+                    debug!("dogma attribute not found for {attr:?}");
+                    return false;
                 }
             }
         }
 
-        println!("all assets are valid");
-        Ok(true)
+        debug!("all assets are valid");
+        true
     }
 }
 
@@ -771,11 +1114,15 @@ pub struct CharacterAssetsDb {
     dir: String,
     last_stored_at: RwLock<DateTime<Utc>>,
     last_updated_at: RwLock<DateTime<Utc>>,
+    /// "Parent / Child" names of market groups, derived from the SDE so never stored
+    market_group_paths: RwLock<HashMap<MarketGroupId, String>>,
+    /// Held for a whole `store`, the autosave and the saga would race on the temp file
+    store_lock: Mutex<()>,
 }
 
 #[derive(Serialize, Deserialize)]
 struct SerializableCharacterAssetsDb {
-    db: CharacterAssets,
+    db: SerializableCharacterAssets,
     dir: String,
     last_stored_at: DateTime<Utc>,
     last_updated_at: DateTime<Utc>,
@@ -786,22 +1133,7 @@ impl Serialize for CharacterAssetsDb {
     where
         S: Serializer,
     {
-        let last_stored_at = self
-            .last_stored_at
-            .read()
-            .map_err(serde::ser::Error::custom)?;
-        let last_updated_at = self
-            .last_updated_at
-            .read()
-            .map_err(serde::ser::Error::custom)?;
-
-        let serializable = SerializableCharacterAssetsDb {
-            db: self.db.clone(),
-            dir: self.dir.clone(),
-            last_stored_at: *last_stored_at,
-            last_updated_at: *last_updated_at,
-        };
-        serializable.serialize(serializer)
+        self.snapshot().serialize(serializer)
     }
 }
 
@@ -813,10 +1145,12 @@ impl<'de> Deserialize<'de> for CharacterAssetsDb {
         let serializable = SerializableCharacterAssetsDb::deserialize(deserializer)?;
 
         Ok(CharacterAssetsDb {
-            db: serializable.db,
+            db: serializable.db.into(),
             dir: serializable.dir,
             last_stored_at: RwLock::new(serializable.last_stored_at),
             last_updated_at: RwLock::new(serializable.last_updated_at),
+            market_group_paths: RwLock::new(HashMap::new()),
+            store_lock: Mutex::new(()),
         })
     }
 }
@@ -826,304 +1160,422 @@ impl CharacterAssetsDb {
         dir: &str,
         abyssal_items: Vec<TypeId>,
     ) -> Result<CharacterAssetsDb, std::io::Error> {
+        let file_path = Self::last_file(dir);
+        if std::path::Path::new(&file_path).exists() {
+            match Self::load(&file_path) {
+                Ok(serializable) => {
+                    let mut db = CharacterAssets::from(serializable.db);
+                    // The abyssal types are computed fresh at startup, types added since
+                    // the file was stored would be missing otherwise
+                    db.abyssal_items.get_mut().extend(abyssal_items);
+                    info!(
+                        "character_assets_db: loaded {} assets from {}",
+                        db.assets.get_mut().len(),
+                        file_path
+                    );
+                    // The stored dir is where the file was first written, the data dir
+                    // may have moved since
+                    return Ok(CharacterAssetsDb {
+                        db,
+                        dir: dir.to_string(),
+                        last_stored_at: RwLock::new(serializable.last_stored_at),
+                        last_updated_at: RwLock::new(serializable.last_updated_at),
+                        market_group_paths: RwLock::new(HashMap::new()),
+                        store_lock: Mutex::new(()),
+                    });
+                }
+                // Everything in it can be fetched again, a broken file isn't worth
+                // refusing to start over
+                Err(e) => warn!(
+                    "character_assets_db: can't load {}, starting empty: {}",
+                    file_path, e
+                ),
+            }
+        }
+
         let now = Utc::now();
         Ok(CharacterAssetsDb {
             db: CharacterAssets::new(abyssal_items),
             dir: dir.to_string(),
             last_stored_at: RwLock::new(now),
             last_updated_at: RwLock::new(now),
+            market_group_paths: RwLock::new(HashMap::new()),
+            store_lock: Mutex::new(()),
         })
     }
 
-    pub fn with_all_data<R, F>(&self, f: F) -> Result<R, String>
+    pub fn with_all_data<R, F>(&self, f: F) -> R
     where
         F: FnOnce(
             &BTreeMap<ItemId, AssetItem>,
             &BTreeMap<ItemId, String>,
-            &BTreeMap<StationId, Station>,
+            LocationMaps<'_>,
             &BTreeMap<ItemId, DynamicItem>,
             &BTreeMap<TypeId, ItemType>,
             &BTreeMap<DogmaAttributeId, DogmaAttribute>,
         ) -> R,
     {
-        let assets = self
-            .db
-            .assets
-            .read()
-            .map_err(|e| format!("Failed to acquire read lock: {}", e))?;
-        let assets_names = self
-            .db
-            .assets_names
-            .read()
-            .map_err(|e| format!("Failed to acquire read lock: {}", e))?;
-        let stations = self
-            .db
-            .stations
-            .read()
-            .map_err(|e| format!("Failed to acquire read lock: {}", e))?;
-        let dynamics = self
-            .db
-            .dynamics
-            .read()
-            .map_err(|e| format!("Failed to acquire read lock: {}", e))?;
-        let types = self
-            .db
-            .types
-            .read()
-            .map_err(|e| format!("Failed to acquire read lock: {}", e))?;
-        let dogma_attributes = self
-            .db
-            .dogma_attributes
-            .read()
-            .map_err(|e| format!("Failed to acquire read lock: {}", e))?;
-        Ok(f(&*assets, &*assets_names, &*stations, &*dynamics, &*types, &*dogma_attributes))
+        let assets = self.db.assets.read();
+        let assets_names = self.db.assets_names.read();
+        let stations = self.db.stations.read();
+        let structures = self.db.structures.read();
+        let dynamics = self.db.dynamics.read();
+        let types = self.db.types.read();
+        let dogma_attributes = self.db.dogma_attributes.read();
+        let locations = LocationMaps {
+            stations: &stations,
+            structures: &structures,
+        };
+        f(&*assets, &*assets_names, locations, &*dynamics, &*types, &*dogma_attributes)
     }
 
 
 
 
+/// Outermost location of an asset which is not an asset itself (station, system, structure),
+/// with its location type.
+pub fn root_location(&self, asset: &AssetItem, assets: &BTreeMap<ItemId, AssetItem>) -> (i64, String) {
+    let mut current = asset;
+    for _ in 0..MAX_LOCATION_DEPTH {
+        match assets.get(&ItemId::from(current.location_id)) {
+            Some(parent_asset) => current = parent_asset,
+            None => break,
+        }
+    }
+
+    (current.location_id, current.location_type.clone())
+}
+
+/// Station name, location type and container path (`"Direct"` when the asset sits in the
+/// station itself) of an asset. Results are memoized per location id in `cache`.
+///
+/// At most `max_depth` containers are walked; a path cut off there starts with
+/// `TRUNCATED_CHAIN_MARKER`.
+#[allow(clippy::too_many_arguments)]
 pub fn build_location_chain(
     &self,
     asset: &AssetItem,
     assets: &BTreeMap<ItemId, AssetItem>,
     assets_names: &BTreeMap<ItemId, String>,
-    stations: &BTreeMap<StationId, Station>,
+    types: &BTreeMap<TypeId, ItemType>,
+    locations: &LocationMaps<'_>,
+    max_depth: u32,
     cache: &mut HashMap<i64, (String, String, String)>,
 ) -> (String, String, String) {
-    // let cache_start = Instant::now();
-    if let Some(cached) = cache.get(&asset.location_id) {
-        //timings.cache_hit += cache_start.elapsed();
-        // timings.total += total_start.elapsed();
-        return cached.clone();
-    }
-    // timings.cache_lookup += cache_start.elapsed();
-
-    let mut location_chain = vec![];
-    let mut current_location_id = asset.location_id;
-    let mut current_location_type = asset.location_type.clone();
-    let mut station_name = "Unknown".to_string();
+    walk_location_chain(
+        asset,
+        assets,
+        assets_names,
+        types,
+        locations,
+        max_depth,
+        cache,
+        &mut (),
+    )
+}
 
-    if current_location_type == "station" {
-        let station_start = Instant::now();
-        if let Some(station) = stations.get(&(current_location_id as StationId)) {
-            station_name = station.name.clone();
-        }
-        // timings.station_lookup += station_start.elapsed();
+/// Same as `build_location_chain`, additionally collecting depth stats and per phase timings.
+#[allow(clippy::too_many_arguments)]
+pub fn build_location_chain_profiled(
+    &self,
+    asset: &AssetItem,
+    assets: &BTreeMap<ItemId, AssetItem>,
+    assets_names: &BTreeMap<ItemId, String>,
+    types: &BTreeMap<TypeId, ItemType>,
+    locations: &LocationMaps<'_>,
+    max_depth: u32,
+    cache: &mut HashMap<i64, (String, String, String)>,
+    stats: &mut ChainStats,
+    timings: &mut ChainTimings,
+) -> (String, String, String) {
+    let start = Instant::now();
+    let mut profiler = ChainProfiler { stats, timings };
+    let result = walk_location_chain(
+        asset,
+        assets,
+        assets_names,
+        types,
+        locations,
+        max_depth,
+        cache,
+        &mut profiler,
+    );
+    profiler.timings.total += start.elapsed();
+    result
+}
 
-        let result = (station_name, current_location_type, "Direct".to_string());
 
-        cache.insert(asset.location_id, result.clone());
-        // timings.total += total_start.elapsed();
-        return result;
+    // Getter methods for accessing inner data structures
+    pub fn get_all_assets(&self) -> BTreeMap<ItemId, AssetItem> {
+        let assets = self.db.assets.read();
+        assets.clone()
     }
 
-    let mut depth = 0;
-    const MAX_DEPTH: u32 = 10;
-    
-    while depth < MAX_DEPTH {
-        let asset_start = Instant::now();
-        let parent_asset = assets.get(&(ItemId::from(current_location_id)));
-        // timings.asset_lookup += asset_start.elapsed();
-        
-        if let Some(parent_asset) = parent_asset {
-            let name_start = Instant::now();
-            let name = assets_names
-                .get(&parent_asset.item_id)
-                .cloned()
-                .unwrap_or_else(|| format!("Container_{}", parent_asset.item_id));
-            // timings.name_lookup += name_start.elapsed();
-
-            location_chain.push(name);
-            current_location_id = parent_asset.location_id;
-            current_location_type = parent_asset.location_type.clone();
-
-            if current_location_type == "station" {
-                let station_start = Instant::now();
-                if let Some(station) = stations.get(&(current_location_id as StationId)) {
-                    station_name = station.name.clone();
-                }
-                // timings.station_lookup += station_start.elapsed();
-                break;
-            }
-        } else {
-            if current_location_type == "station" {
-                let station_start = Instant::now();
-                if let Some(station) = stations.get(&(current_location_id as StationId)) {
-                    station_name = station.name.clone();
-                }
-                // timings.station_lookup += station_start.elapsed();
-            }
-            break;
-        }
-
-        depth += 1;
+    pub fn with_assets<R, F>(&self, f: F) -> R
+    where
+        F: FnOnce(&BTreeMap<ItemId, AssetItem>) -> R
+    {
+        let assets = self.db.assets.read();
+        f(&*assets)
     }
 
-    let string_start = Instant::now();
-    location_chain.reverse();
-    let location_name = if location_chain.is_empty() {
-        "Direct".to_string()
-    } else {
-        location_chain.join(" -> ")
-    };
-    // timings.string_ops += string_start.elapsed();
-
-    let result = (station_name, current_location_type, location_name);
+    /// Assets located directly at `location_id` (station, structure, solar system or container)
+    pub fn assets_at_location(&self, location_id: i64) -> Vec<AssetItem> {
+        let assets = self.db.assets.read();
+        assets
+            .values()
+            .filter(|asset| asset.location_id == location_id)
+            .cloned()
+            .collect()
+    }
 
-    cache.insert(asset.location_id, result.clone());
-    // timings.total += total_start.elapsed();
+    /// Assets placed directly inside the container or ship `item_id`, without descending
+    /// into nested containers
+    pub fn assets_in_container(&self, item_id: ItemId) -> Vec<AssetItem> {
+        self.assets_at_location(i64::from(item_id))
+    }
 
-    result
-}
+    /// What changed going from the stored assets to `new`
+    pub fn diff_assets(&self, new: &BTreeMap<ItemId, AssetItem>) -> AssetsDiff {
+        let assets = self.db.assets.read();
+        AssetsDiff::between(&assets, new)
+    }
 
+    /// Total quantity per type across all assets
+    pub fn quantities_by_type(&self) -> BTreeMap<TypeId, i64> {
+        self.sum_quantities(|_| true)
+    }
 
-    // Getter methods for accessing inner data structures
-    pub fn get_all_assets(&self) -> Result<BTreeMap<ItemId, AssetItem>, String> {
-        let assets = self
-            .db
-            .assets
-            .read()
-            .map_err(|e| format!("Failed to acquire read lock: {}", e))?;
-        Ok(assets.clone())
+    /// Total quantity per type across assets with the given `location_flag` (e.g. "Hangar"),
+    /// leaving out fitted modules, cargo and the like
+    pub fn quantities_by_type_with_flag(&self, location_flag: &str) -> BTreeMap<TypeId, i64> {
+        self.sum_quantities(|asset| asset.location_flag == location_flag)
     }
 
-    pub fn with_assets<R, F>(&self, f: F) -> Result<R, String>
+    fn sum_quantities<F>(&self, filter: F) -> BTreeMap<TypeId, i64>
     where
-        F: FnOnce(&BTreeMap<ItemId, AssetItem>) -> R
+        F: Fn(&AssetItem) -> bool,
     {
-        let assets = self
-            .db
-            .assets
-            .read()
-            .map_err(|e| format!("Failed to acquire read lock: {}", e))?;
-        Ok(f(&*assets))
+        let assets = self.db.assets.read();
+        let mut quantities = BTreeMap::new();
+        for asset in assets.values().filter(|asset| filter(asset)) {
+            *quantities.entry(asset.type_id).or_insert(0) += asset.item_count();
+        }
+        quantities
     }
 
-    pub fn get_all_types(&self) -> Result<BTreeMap<TypeId, ItemType>, String> {
-        let types = self
-            .db
-            .types
-            .read()
-            .map_err(|e| format!("Failed to acquire read lock: {}", e))?;
-        Ok(types.clone())
+    /// Owned mutated items with their types and location, without the attribute analysis
+    /// of the dynamics report
+    pub fn list_dynamics_summary(&self) -> Vec<DynamicSummary> {
+        self.with_all_data(|assets, assets_names, locations, dynamics, types, _| {
+            let type_name = |type_id: &TypeId| types.get(type_id).map(|t| t.name.clone());
+            let mut cache = HashMap::new();
+            let mut summaries = Vec::with_capacity(dynamics.len());
+            for (item_id, dynamic) in dynamics {
+                // Dynamic items are kept across runs, skip the ones no longer owned
+                let Some(asset) = assets.get(item_id) else {
+                    continue;
+                };
+                let resulting_type_id = self
+                    .db
+                    .get_resulting_type_by_source_mutator(
+                        dynamic.source_type_id,
+                        dynamic.mutator_type_id,
+                    )
+                    .ok();
+                let (station_name, _, location_name) = self.build_location_chain(
+                    asset,
+                    assets,
+                    assets_names,
+                    types,
+                    &locations,
+                    MAX_LOCATION_DEPTH,
+                    &mut cache,
+                );
+                summaries.push(DynamicSummary {
+                    item_id: *item_id,
+                    resulting_type_id,
+                    resulting_type_name: resulting_type_id.as_ref().and_then(type_name),
+                    source_type_id: dynamic.source_type_id,
+                    source_type_name: type_name(&dynamic.source_type_id),
+                    mutator_type_id: dynamic.mutator_type_id,
+                    mutator_name: type_name(&dynamic.mutator_type_id),
+                    location: location_label(station_name, location_name),
+                });
+            }
+            summaries
+        })
+    }
+
+    /// Writes every asset as a CSV row: item_id, type_id, type_name, name (see
+    /// `asset_display_name`), quantity, location_id, location (station followed by the
+    /// container path) and location_flag, as shown by `pretty_location_flag`
+    pub fn export_csv<W: Write>(&self, mut w: W) -> Result<(), String> {
+        self.with_all_data(|assets, assets_names, locations, _, types, _| {
+            let mut cache = HashMap::new();
+            writeln!(
+                w,
+                "item_id,type_id,type_name,name,quantity,location_id,location,location_flag"
+            )?;
+            for asset in assets.values() {
+                let type_name = types
+                    .get(&asset.type_id)
+                    .map(|t| t.name.as_str())
+                    .unwrap_or_default();
+                let (station_name, _, location_name) = self.build_location_chain(
+                    asset,
+                    assets,
+                    assets_names,
+                    types,
+                    &locations,
+                    MAX_LOCATION_DEPTH,
+                    &mut cache,
+                );
+                let location = location_label(station_name, location_name);
+                writeln!(
+                    w,
+                    "{},{},{},{},{},{},{},{}",
+                    asset.item_id,
+                    asset.type_id,
+                    csv_field(type_name),
+                    csv_field(&asset_display_name(asset, assets_names, types)),
+                    asset.quantity,
+                    asset.location_id,
+                    csv_field(&location),
+                    csv_field(&pretty_location_flag(&asset.location_flag))
+                )?;
+            }
+            w.flush()
+        })
+        .map_err(|e| format!("Failed to write CSV: {}", e))
     }
 
-    pub fn with_types<R, F>(&self, f: F) -> Result<R, String>
+    pub fn get_all_types(&self) -> BTreeMap<TypeId, ItemType> {
+        let types = self.db.types.read();
+        types.clone()
+    }
+
+    pub fn with_types<R, F>(&self, f: F) -> R
     where
         F: FnOnce(&BTreeMap<TypeId, ItemType>) -> R,
     {
-        let types = self
-            .db
-            .types
-            .read()
-            .map_err(|e| format!("Failed to acquire read lock: {}", e))?;
-        Ok(f(&*types))
+        let types = self.db.types.read();
+        f(&*types)
     }
 
-    pub fn get_all_market_groups(&self) -> Result<BTreeMap<MarketGroupId, MarketGroup>, String> {
-        let market_groups = self
-            .db
-            .market_groups
-            .read()
-            .map_err(|e| format!("Failed to acquire read lock: {}", e))?;
-        Ok(market_groups.clone())
+    pub fn get_all_market_groups(&self) -> BTreeMap<MarketGroupId, MarketGroup> {
+        let market_groups = self.db.market_groups.read();
+        market_groups.clone()
     }
 
-    pub fn get_all_stations(&self) -> Result<BTreeMap<StationId, Station>, String> {
-        let stations = self
-            .db
-            .stations
-            .read()
-            .map_err(|e| format!("Failed to acquire read lock: {}", e))?;
-        Ok(stations.clone())
+    pub fn market_group_of_type(&self, type_id: TypeId) -> Option<MarketGroupId> {
+        self.db.market_group_of_type(type_id)
     }
 
-    pub fn get_all_dynamics(&self) -> Result<BTreeMap<ItemId, DynamicItem>, String> {
-        let dynamics = self
-            .db
-            .dynamics
-            .read()
-            .map_err(|e| format!("Failed to acquire read lock: {}", e))?;
-        Ok(dynamics.clone())
+    /// Market group ids of the known types that have no hierarchical name cached yet
+    pub fn market_groups_without_path(&self) -> Vec<MarketGroupId> {
+        let types = self.db.types.read();
+        let paths = self.market_group_paths.read();
+        let missing: BTreeSet<MarketGroupId> = types
+            .values()
+            .filter_map(|item_type| item_type.market_group_id)
+            .filter(|id| !paths.contains_key(id))
+            .collect();
+        missing.into_iter().collect()
+    }
+
+    pub fn add_market_group_paths(&self, paths: HashMap<MarketGroupId, String>) {
+        self.market_group_paths.write().extend(paths);
     }
 
-    pub fn with_dynamics<R, F>(&self, f: F) -> Result<R, String>
+    /// Hierarchical names cached so far, see `AppContext::market_group_paths`
+    pub fn get_market_group_paths(&self) -> HashMap<MarketGroupId, String> {
+        self.market_group_paths.read().clone()
+    }
+
+    pub fn get_all_stations(&self) -> BTreeMap<StationId, Station> {
+        let stations = self.db.stations.read();
+        stations.clone()
+    }
+
+    pub fn get_all_dynamics(&self) -> BTreeMap<ItemId, DynamicItem> {
+        let dynamics = self.db.dynamics.read();
+        dynamics.clone()
+    }
+
+    pub fn with_dynamics<R, F>(&self, f: F) -> R
     where
         F: FnOnce(&BTreeMap<ItemId, DynamicItem>) -> R,
     {
-        let dynamics = self
-            .db
-            .dynamics
-            .read()
-            .map_err(|e| format!("Failed to acquire read lock: {}", e))?;
-        Ok(f(&*dynamics))
+        let dynamics = self.db.dynamics.read();
+        f(&*dynamics)
     }
 
-    pub fn get_all_dogma_attributes(
-        &self,
-    ) -> Result<BTreeMap<DogmaAttributeId, DogmaAttribute>, String> {
-        let dogma_attributes = self
-            .db
-            .dogma_attributes
-            .read()
-            .map_err(|e| format!("Failed to acquire read lock: {}", e))?;
-        Ok(dogma_attributes.clone())
+    pub fn get_all_dogma_attributes(&self) -> BTreeMap<DogmaAttributeId, DogmaAttribute> {
+        let dogma_attributes = self.db.dogma_attributes.read();
+        dogma_attributes.clone()
     }
 
-    pub fn get_all_asset_names(&self) -> Result<BTreeMap<ItemId, String>, String> {
-        let asset_names = self
-            .db
-            .assets_names
-            .read()
-            .map_err(|e| format!("Failed to acquire read lock: {}", e))?;
-        Ok(asset_names.clone())
-    }
-
-    pub fn add_asset(&self, item: AssetItem) -> Result<Vec<GetData>, String> {
-        let new_items = self.db.add_asset(item)?;
-        let mut t = self
-            .last_updated_at
-            .write()
-            .map_err(|_| "Failed to write last_updated_at")?;
+    pub fn get_all_asset_names(&self) -> BTreeMap<ItemId, String> {
+        let asset_names = self.db.assets_names.read();
+        asset_names.clone()
+    }
+
+    pub fn add_asset(&self, item: AssetItem) -> Vec<GetData> {
+        let new_items = self.db.add_asset(item);
+        let mut t = self.last_updated_at.write();
         *t = Utc::now();
-        Ok(new_items)
+        new_items
     }
 
-    pub fn add_asset_name(&self, item_id: ItemId, name: String) -> Result<(), String> {
-        self.db.add_asset_name(item_id, name)?;
-        let mut t = self
-            .last_updated_at
-            .write()
-            .map_err(|_| "Failed to write last_updated_at")?;
+    pub fn add_assets(&self, items: Vec<AssetItem>) -> BTreeSet<GetData> {
+        let new_items = self.db.add_assets(items);
+        let mut t = self.last_updated_at.write();
         *t = Utc::now();
-        Ok(())
+        new_items
     }
 
-    pub fn add_station(
+    pub fn missing_for_assets(&self, items: &[AssetItem]) -> BTreeSet<GetData> {
+        self.db.missing_for_assets(items)
+    }
+
+    /// See `CharacterAssets::replace_owner_assets`
+    pub fn replace_owner_assets(
         &self,
-        station_id: StationId,
-        station: Station,
-    ) -> Result<Vec<GetData>, String> {
-        let new_items = self.db.add_station(station_id, station)?;
-        let mut t = self
-            .last_updated_at
-            .write()
-            .map_err(|_| "Failed to write last_updated_at")?;
+        owner: AssetOwner,
+        items: Vec<AssetItem>,
+    ) -> BTreeMap<ItemId, AssetItem> {
+        let previous = self.db.replace_owner_assets(owner, items);
+        let mut t = self.last_updated_at.write();
         *t = Utc::now();
-        Ok(new_items)
+        previous
     }
 
-    pub fn add_dogma_attribute(
-        &self,
-        dogma_attribute: DogmaAttribute,
-    ) -> Result<Vec<GetData>, String> {
-        let new_items = self.db.add_dogma_attribute(dogma_attribute)?;
-        let mut t = self
-            .last_updated_at
-            .write()
-            .map_err(|_| "Failed to write last_updated_at")?;
+    pub fn add_asset_name(&self, item_id: ItemId, name: String) {
+        self.db.add_asset_name(item_id, name);
+        let mut t = self.last_updated_at.write();
+        *t = Utc::now();
+    }
+
+    pub fn add_station(&self, station_id: StationId, station: Station) -> Vec<GetData> {
+        let new_items = self.db.add_station(station_id, station);
+        let mut t = self.last_updated_at.write();
         *t = Utc::now();
-        Ok(new_items)
+        new_items
+    }
+
+    pub fn add_structure(&self, structure_id: StructureId, structure: Structure) -> Vec<GetData> {
+        let new_items = self.db.add_structure(structure_id, structure);
+        let mut t = self.last_updated_at.write();
+        *t = Utc::now();
+        new_items
+    }
+
+    pub fn add_dogma_attribute(&self, dogma_attribute: DogmaAttribute) -> Vec<GetData> {
+        let new_items = self.db.add_dogma_attribute(dogma_attribute);
+        let mut t = self.last_updated_at.write();
+        *t = Utc::now();
+        new_items
     }
 
     pub fn get_attribute_id_by_name(
@@ -1133,14 +1585,11 @@ pub fn build_location_chain(
         self.db.get_attribute_id_by_name(attribute_name)
     }
 
-    pub fn add_market_group(&self, market_group: MarketGroup) -> Result<Vec<GetData>, String> {
-        let new_items = self.db.add_market_group(market_group)?;
-        let mut t = self
-            .last_updated_at
-            .write()
-            .map_err(|_| "Failed to write last_updated_at")?;
+    pub fn add_market_group(&self, market_group: MarketGroup) -> BTreeSet<GetData> {
+        let new_items = self.db.add_market_group(market_group);
+        let mut t = self.last_updated_at.write();
         *t = Utc::now();
-        Ok(new_items)
+        new_items
     }
 
     pub fn add_dynamic(
@@ -1148,24 +1597,18 @@ pub fn build_location_chain(
         type_id: TypeId,
         item_id: ItemId,
         dynamic: DynamicItem,
-    ) -> Result<Vec<GetData>, String> {
-        let new_items = self.db.add_dynamic(type_id, item_id, dynamic)?;
-        let mut t = self
-            .last_updated_at
-            .write()
-            .map_err(|_| "Failed to write last_updated_at")?;
+    ) -> BTreeSet<GetData> {
+        let new_items = self.db.add_dynamic(type_id, item_id, dynamic);
+        let mut t = self.last_updated_at.write();
         *t = Utc::now();
-        Ok(new_items)
+        new_items
     }
 
-    pub fn add_type(&self, item_type: ItemType) -> Result<Vec<GetData>, String> {
-        let new_items = self.db.add_type(item_type)?;
-        let mut t = self
-            .last_updated_at
-            .write()
-            .map_err(|_| "Failed to write last_updated_at")?;
+    pub fn add_type(&self, item_type: ItemType) -> BTreeSet<GetData> {
+        let new_items = self.db.add_type(item_type);
+        let mut t = self.last_updated_at.write();
         *t = Utc::now();
-        Ok(new_items)
+        new_items
     }
 
     pub fn add_mutaplasmid_effects(
@@ -1173,16 +1616,20 @@ pub fn build_location_chain(
         mutator_type_id: TypeId,
         attributes: Vec<(DogmaAttributeId, f64, f64)>,
         input_output: Vec<(TypeId, Vec<TypeId>)>, // [(resulting_type, [applicable_types]), ...]
-    ) -> Result<Vec<GetData>, String> {
-        let new_items =
-            self.db
-                .add_mutaplasmid_effects(mutator_type_id, attributes, input_output)?;
-        let mut t = self
-            .last_updated_at
-            .write()
-            .map_err(|_| "Failed to write last_updated_at")?;
+    ) -> Vec<GetData> {
+        let new_items = self
+            .db
+            .add_mutaplasmid_effects(mutator_type_id, attributes, input_output);
+        let mut t = self.last_updated_at.write();
         *t = Utc::now();
-        Ok(new_items)
+        new_items
+    }
+
+    pub fn replace_mutaplasmid_effects(&self, data: &MutaplasmidData) -> Vec<GetData> {
+        let new_items = self.db.replace_mutaplasmid_effects(data);
+        let mut t = self.last_updated_at.write();
+        *t = Utc::now();
+        new_items
     }
 
     pub fn get_mutator_ids_by_resulting_type_id(
@@ -1232,61 +1679,512 @@ pub fn build_location_chain(
             .get_applicable_types_by_resulting_type(resulting_type_id)
     }
 
-    pub fn is_abyssal(&self, asset: &AssetItem) -> Result<bool, String> {
+    pub fn is_abyssal(&self, asset: &AssetItem) -> bool {
         self.db.is_abyssal(asset)
     }
 
-    pub fn all_items_resolved(&self) -> Result<bool, String> {
+    pub fn is_dynamic(&self, asset: &AssetItem) -> bool {
+        self.db.is_dynamic(asset)
+    }
+
+    pub fn all_items_resolved(&self) -> bool {
         self.db.all_items_resolved()
     }
 
-    pub fn store(&self) -> Result<(), String> {
-        let should_store = {
-            let last_stored_at = self
-                .last_stored_at
-                .read()
-                .map_err(|_| "Failed to read last_stored_at")?;
-            let last_updated_at = self
-                .last_updated_at
-                .read()
-                .map_err(|_| "Failed to read last_updated_at")?;
-            println!("character_assets_db: {last_stored_at} / {last_updated_at}");
+    /// Whether there are changes `store` would write
+    pub fn needs_store(&self) -> bool {
+        *self.last_stored_at.read() < *self.last_updated_at.read()
+    }
+
+    /// Consistent copy of the db, see `CharacterAssets::snapshot`
+    fn snapshot(&self) -> SerializableCharacterAssetsDb {
+        let db = self.db.snapshot();
+        SerializableCharacterAssetsDb {
+            db,
+            dir: self.dir.clone(),
+            last_stored_at: *self.last_stored_at.read(),
+            last_updated_at: *self.last_updated_at.read(),
+        }
+    }
+
+    /// Write the db to `new_assets.cbor` if it changed since the last store, returns the
+    /// number of bytes written (0 if unchanged).
+    ///
+    /// The file holds a snapshot taken at a single point in time. Updates racing with the
+    /// store either made it into the snapshot or are newer than it, so they keep the db
+    /// dirty and are written by the next store.
+    pub fn store(&self) -> Result<usize, String> {
+        // Taken before the check, a store waiting for another one then finds nothing left
+        let _store = self.store_lock.lock();
+        if !self.needs_store() {
+            debug!("character_assets_db: Using old file");
+            return Ok(0);
+        }
+
+        // Taken before the snapshot: an update landing while it's copied bumps
+        // last_updated_at past this
+        let snapshot_at = Utc::now();
+        let mut snapshot = self.snapshot();
+        snapshot.last_stored_at = snapshot_at;
+
+        let file_path = Self::last_file(&self.dir);
+        let temp_path = format!("{file_path}.tmp");
+        let encoded = versioned::encode(CHARACTER_ASSETS_FORMAT_VERSION, &snapshot)
+            .map_err(|e| format!("Failed to serialize data: {}", e))?;
+
+        std::fs::write(&temp_path, &encoded)
+            .map_err(|e| format!("Failed to write to file: {}", e))?;
+        std::fs::rename(temp_path, &file_path)
+            .map_err(|e| format!("Failed to rename file: {}", e))?;
+
+        // Only a completed write counts as stored, a failed one is retried next time
+        *self.last_stored_at.write() = snapshot_at;
+        info!(
+            "character_assets_db: stored {} bytes to {}",
+            encoded.len(),
+            file_path
+        );
+
+        Ok(encoded.len())
+    }
+
+    fn load(file_path: &str) -> Result<SerializableCharacterAssetsDb, std::io::Error> {
+        let cbor_data = std::fs::read(file_path)?;
+        versioned::decode(&cbor_data, CHARACTER_ASSETS_FORMAT_VERSION, |legacy| {
+            serde_cbor::from_slice(legacy)
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+        })
+    }
+
+    fn last_file(dir: &str) -> String {
+        format!("{}/new_assets.cbor", dir)
+    }
+}
+
+/// Instrumentation hooks of `walk_location_chain`. The `()` implementation does nothing
+/// and is compiled away, so the plain `build_location_chain` pays no timing overhead.
+trait ChainProbe {
+    fn time<R>(
+        &mut self,
+        _phase: fn(&mut ChainTimings) -> &mut Duration,
+        f: impl FnOnce() -> R,
+    ) -> R {
+        f()
+    }
+
+    fn cache_hit(&mut self) {}
+
+    fn resolved(&mut self, _depth: u32, _direct: bool) {}
+
+    fn depth_capped(&mut self) {}
+}
+
+impl ChainProbe for () {}
+
+struct ChainProfiler<'a> {
+    stats: &'a mut ChainStats,
+    timings: &'a mut ChainTimings,
+}
+
+impl ChainProbe for ChainProfiler<'_> {
+    fn time<R>(
+        &mut self,
+        phase: fn(&mut ChainTimings) -> &mut Duration,
+        f: impl FnOnce() -> R,
+    ) -> R {
+        let start = Instant::now();
+        let result = f();
+        *phase(self.timings) += start.elapsed();
+        result
+    }
+
+    fn cache_hit(&mut self) {
+        self.stats.total_calls += 1;
+        self.stats.cache_hits += 1;
+    }
+
+    fn resolved(&mut self, depth: u32, direct: bool) {
+        self.stats.total_calls += 1;
+        if direct {
+            self.stats.direct_station += 1;
+        }
+        self.stats.lookups += depth as usize;
+        self.stats.total_depth += depth;
+        self.stats.max_depth = self.stats.max_depth.max(depth);
+    }
+
+    fn depth_capped(&mut self) {
+        self.stats.depth_capped += 1;
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn walk_location_chain<P: ChainProbe>(
+    asset: &AssetItem,
+    assets: &BTreeMap<ItemId, AssetItem>,
+    assets_names: &BTreeMap<ItemId, String>,
+    types: &BTreeMap<TypeId, ItemType>,
+    locations: &LocationMaps<'_>,
+    max_depth: u32,
+    cache: &mut HashMap<i64, (String, String, String)>,
+    probe: &mut P,
+) -> (String, String, String) {
+    let cached = probe.time(
+        |t| &mut t.cache_lookup,
+        || cache.get(&asset.location_id).cloned(),
+    );
+    if let Some(cached) = cached {
+        probe.cache_hit();
+        if cached.2.starts_with(TRUNCATED_CHAIN_MARKER) {
+            probe.depth_capped();
+        }
+        return cached;
+    }
+
+    let mut location_chain = vec![];
+    let mut current_location_id = asset.location_id;
+    let mut current_location_type = asset.location_type.clone();
+    let mut station_name = "Unknown".to_string();
+
+    let direct = probe.time(
+        |t| &mut t.station_lookup,
+        || locations.name_of(current_location_id, &current_location_type),
+    );
+    if let Some(name) = direct {
+        let result = (name, current_location_type, "Direct".to_string());
+        cache.insert(asset.location_id, result.clone());
+        probe.resolved(0, true);
+        return result;
+    }
+
+    let mut depth = 0;
+    let mut station_found = false;
+
+    while depth < max_depth {
+        let parent_asset = probe.time(
+            |t| &mut t.asset_lookup,
+            || assets.get(&ItemId::from(current_location_id)),
+        );
+        let Some(parent_asset) = parent_asset else {
+            break;
+        };
+
+        let name = probe.time(
+            |t| &mut t.name_lookup,
+            || asset_display_name(parent_asset, assets_names, types),
+        );
+        location_chain.push(name);
+        current_location_id = parent_asset.location_id;
+        current_location_type = parent_asset.location_type.clone();
+        depth += 1;
+
+        let found = probe.time(
+            |t| &mut t.station_lookup,
+            || locations.name_of(current_location_id, &current_location_type),
+        );
+        if let Some(name) = found {
+            station_name = name;
+            station_found = true;
+            break;
+        }
+    }
+
+    // Ran out of depth while the chain still continues through another asset
+    let capped = !station_found
+        && depth == max_depth
+        && assets.contains_key(&ItemId::from(current_location_id));
+    if capped {
+        location_chain.push(TRUNCATED_CHAIN_MARKER.to_string());
+        probe.depth_capped();
+    }
+
+    let location_name = probe.time(
+        |t| &mut t.string_ops,
+        || {
+            location_chain.reverse();
+            if location_chain.is_empty() {
+                "Direct".to_string()
+            } else {
+                location_chain.join(" -> ")
+            }
+        },
+    );
+
+    let result = (station_name, current_location_type, location_name);
+    cache.insert(asset.location_id, result.clone());
+    probe.resolved(depth, false);
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn asset(item_id: i64, type_id: i32, location_id: i64) -> AssetItem {
+        AssetItem {
+            item_id: item_id.into(),
+            type_id: type_id.into(),
+            location_id,
+            location_type: "station".to_string(),
+            quantity: 1,
+            location_flag: "Hangar".to_string(),
+            is_singleton: false,
+            is_blueprint_copy: None,
+        }
+    }
+
+    #[test]
+    fn page_sharing_types_and_stations_queues_each_once() {
+        let db = CharacterAssets::new(vec![]);
+        // 1000 items of 5 types spread over 2 stations, as a full ESI page of a hangar
+        let page: Vec<AssetItem> = (0..1000)
+            .map(|i| asset(1_000_000 + i, 34 + (i % 5) as i32, 60003760 + (i % 2)))
+            .collect();
+
+        let new_items = db.missing_for_assets(&page);
+
+        // 2000 lookups as a list (a type and a station per item), 5 types and 2 stations
+        assert_eq!(new_items.len(), 7);
+        assert!(new_items.contains(&GetData::Type(34.into())));
+        assert!(new_items.contains(&GetData::Station(60003761)));
+    }
+
+    #[test]
+    fn market_group_listing_a_type_twice_queues_it_once() {
+        let db = CharacterAssets::new(vec![]);
+        let market_group = MarketGroup {
+            description: String::new(),
+            market_group_id: 1,
+            name: "Minerals".to_string(),
+            parent_group_id: None,
+            types: vec![34.into(), 35.into(), 34.into()],
+        };
+
+        let new_items = db.add_market_group(market_group);
+
+        assert_eq!(
+            new_items,
+            BTreeSet::from([GetData::Type(34.into()), GetData::Type(35.into())])
+        );
+    }
+
+    fn item_type(type_id: i32, name: &str) -> ItemType {
+        ItemType {
+            capacity: None,
+            description: String::new(),
+            dogma_attributes: vec![],
+            dogma_effects: vec![],
+            graphic_id: None,
+            group_id: 0,
+            icon_id: None,
+            market_group_id: None,
+            mass: None,
+            name: name.to_string(),
+            packaged_volume: None,
+            portion_size: None,
+            published: true,
+            radius: None,
+            type_id: type_id.into(),
+            volume: None,
+        }
+    }
+
+    #[test]
+    fn csv_field_defuses_formulas_and_quotes() {
+        assert_eq!(csv_field("Tritanium"), "Tritanium");
+        assert_eq!(csv_field("=HYPERLINK(\"x\")"), "\"'=HYPERLINK(\"\"x\"\")\"");
+        assert_eq!(csv_field("+1"), "'+1");
+        assert_eq!(csv_field("-loot"), "'-loot");
+        assert_eq!(csv_field("@SUM(A1)"), "'@SUM(A1)");
+        assert_eq!(csv_field("Jita, 4-4"), "\"Jita, 4-4\"");
+    }
+
+    #[test]
+    fn display_name_falls_back_to_the_type_then_the_container_id() {
+        let booster = asset(1, 10858, 60003760);
+        let orca = asset(2, 28606, 60003760);
+        let unknown = asset(3, 99999, 60003760);
+        let assets_names = BTreeMap::from([(orca.item_id, "My Orca".to_string())]);
+        let types = BTreeMap::from([
+            (booster.type_id, item_type(10858, "Medium Shield Booster")),
+            (orca.type_id, item_type(28606, "Orca")),
+        ]);
+
+        // The custom name wins over the type name
+        assert_eq!(asset_display_name(&orca, &assets_names, &types), "My Orca");
+        assert_eq!(
+            asset_display_name(&booster, &assets_names, &types),
+            "Medium Shield Booster"
+        );
+        assert_eq!(
+            asset_display_name(&unknown, &assets_names, &types),
+            "Container_3"
+        );
+    }
+
+    #[test]
+    fn only_assembled_abyssal_items_are_dynamic() {
+        let db = CharacterAssets::new(vec![47745.into()]);
+        let packaged = asset(1, 47745, 60003760);
+        let assembled = AssetItem {
+            is_singleton: true,
+            ..asset(2, 47745, 60003760)
+        };
+        let plain = AssetItem {
+            is_singleton: true,
+            ..asset(3, 5945, 60003760)
+        };
+
+        assert!(!db.is_dynamic(&packaged));
+        assert!(db.is_dynamic(&assembled));
+        assert!(!db.is_dynamic(&plain));
+
+        let dynamic = GetData::Dynamic(47745.into(), 2.into());
+        assert!(db.add_asset(assembled.clone()).contains(&dynamic));
+        assert!(
+            !db.add_asset(packaged.clone())
+                .iter()
+                .any(|item| matches!(item, GetData::Dynamic(..)))
+        );
+
+        let new_items = db.add_assets(vec![packaged, assembled, plain]);
+        let dynamics: Vec<&GetData> = new_items
+            .iter()
+            .filter(|item| matches!(item, GetData::Dynamic(..)))
+            .collect();
+        assert_eq!(dynamics, vec![&dynamic]);
+    }
 
-            *last_stored_at < *last_updated_at
+    #[test]
+    fn singletons_count_as_one_item() {
+        // Nothing is stored, the dir doesn't need to exist
+        let dir = std::env::temp_dir().join(format!("assets-{}", uuid::Uuid::new_v4()));
+        let db = CharacterAssetsDb::from_dir(dir.to_str().unwrap(), vec![]).unwrap();
+        let stack = AssetItem {
+            quantity: 500,
+            ..asset(1, 34, 60003760)
+        };
+        // Assembled containers report a quantity of -1
+        let container = AssetItem {
+            quantity: -1,
+            is_singleton: true,
+            location_flag: "Cargo".to_string(),
+            ..asset(2, 3297, 60003760)
         };
+        let packaged = AssetItem {
+            quantity: 3,
+            ..asset(3, 3297, 60003760)
+        };
+        db.add_assets(vec![stack, container, packaged]);
+
+        let quantities = db.quantities_by_type();
+        assert_eq!(quantities[&34.into()], 500);
+        assert_eq!(quantities[&3297.into()], 4);
+        assert_eq!(db.quantities_by_type_with_flag("Cargo")[&3297.into()], 1);
+    }
 
-        if should_store {
-            {
-                let mut last_stored_at = self
-                    .last_stored_at
-                    .write()
-                    .map_err(|_| "Failed to write last_stored_at")?;
-                *last_stored_at = Utc::now();
+    #[test]
+    fn concurrent_stores_leave_a_readable_file() {
+        let dir = std::env::temp_dir().join(format!("assets-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let dir = dir.to_str().unwrap().to_string();
+        let db = Arc::new(CharacterAssetsDb::from_dir(&dir, vec![]).unwrap());
+
+        for round in 0..20 {
+            db.add_assets(vec![asset(round, 34, 60003760)]);
+            let stores: Vec<_> = (0..2)
+                .map(|_| {
+                    let db = db.clone();
+                    std::thread::spawn(move || db.store())
+                })
+                .collect();
+            for store in stores {
+                store.join().unwrap().unwrap();
             }
+            assert!(!db.needs_store());
 
-            let file_path = Self::last_file(&self.dir);
-            println!("character_assets_db: file_path: {file_path}");
-            let temp_path = format!("{file_path}.tmp");
-            println!("character_assets_db: temp_path: {temp_path}");
-            let encoded = serde_cbor::ser::to_vec(&self)
-                .map_err(|e| format!("Failed to serialize data: {}", e));
-
-            println!("character_assets_db: encoded");
-            std::fs::write(&temp_path, encoded?)
-                .map_err(|e| format!("Failed to write to file: {}", e))?;
-            println!("character_assets_db: temp_path written");
-            std::fs::rename(temp_path, file_path)
-                .map_err(|e| format!("Failed to rename file: {}", e))?;
-            println!("character_assets_db: file renamed");
-        } else {
-            println!("character_assets_db: Using old file")
+            let reloaded = CharacterAssetsDb::from_dir(&dir, vec![]).unwrap();
+            assert_eq!(reloaded.quantities_by_type()[&34.into()], round + 1);
         }
-        println!("character_assets_db: Done");
+    }
 
-        Ok(())
+    #[test]
+    fn swapped_mutator_range_is_normalized() {
+        let mut effects = MutaplasmidEffects::default();
+        let mutator: TypeId = 47408.into();
+        effects.add(
+            mutator,
+            vec![(20, 1.1, 0.9), (54, 0.8, 1.2)],
+            vec![(47745.into(), vec![5945.into()])],
+        );
+
+        let attributes = &effects.attributes[&mutator];
+        assert_eq!((attributes[&20].min, attributes[&20].max), (0.9, 1.1));
+        assert_eq!((attributes[&54].min, attributes[&54].max), (0.8, 1.2));
     }
 
-    fn last_file(dir: &str) -> String {
-        format!("{}/new_assets.cbor", dir)
+    #[test]
+    fn location_flags_get_their_labels() {
+        assert_eq!(pretty_location_flag("MedSlot3"), "Medium Slot 3");
+        assert_eq!(pretty_location_flag("HiSlot0"), "High Slot 0");
+        assert_eq!(pretty_location_flag("DroneBay"), "Drone Bay");
+        assert_eq!(pretty_location_flag("Cargo"), "Cargo Hold");
+    }
+
+    #[test]
+    fn unknown_location_flags_are_split_before_capitals_and_digits() {
+        assert_eq!(
+            pretty_location_flag("SpecializedGasHold"),
+            "Specialized Gas Hold"
+        );
+        assert_eq!(pretty_location_flag("QuafeBay2"), "Quafe Bay 2");
+        // Not a numbered flag without the number, nor with anything else after the prefix
+        assert_eq!(pretty_location_flag("HiSlot"), "Hi Slot");
+        assert_eq!(pretty_location_flag("HiSlotX"), "Hi Slot X");
+        assert_eq!(pretty_location_flag("AutoFit"), "Auto Fit");
+    }
+
+    fn dynamic(mutator_type_id: i32, source_type_id: i32) -> DynamicItem {
+        DynamicItem {
+            created_by: 0,
+            dogma_attributes: (0..20)
+                .map(|attribute_id| crate::DogmaAttributeConcise {
+                    attribute_id,
+                    value: 1.0,
+                })
+                .collect(),
+            dogma_effects: vec![],
+            mutator_type_id: mutator_type_id.into(),
+            source_type_id: source_type_id.into(),
+        }
+    }
+
+    /// Timing of a 5000 dynamics load, as a large assets run stores them, from 4 workers
+    /// at once. Run with `cargo test --release -- --ignored --nocapture add_dynamics`
+    #[test]
+    #[ignore]
+    fn add_dynamics_timing() {
+        let db = CharacterAssets::new(vec![]);
+        let started = std::time::Instant::now();
+        std::thread::scope(|scope| {
+            for worker in 0..4i64 {
+                let db = &db;
+                scope.spawn(move || {
+                    for i in 0..1250 {
+                        let item_id = ItemId::from(worker * 1250 + i);
+                        db.add_dynamic(47408.into(), item_id, dynamic(47408, 2048));
+                    }
+                });
+            }
+        });
+        let elapsed = started.elapsed();
+
+        assert_eq!(db.dynamics.read().len(), 5000);
+        println!(
+            "5000 dynamics stored in {:?}, {:?} per item",
+            elapsed,
+            elapsed / 5000
+        );
     }
 }
@@ -1,14 +1,28 @@
 #![allow(async_fn_in_trait)]
 
+use chrono::{DateTime, Utc};
+use futures::StreamExt;
 use oauth2::TokenResponse;
 use oauth2::basic::BasicTokenResponse;
+use std::collections::HashMap;
+use std::sync::Mutex;
 use thiserror::Error;
+use tracing::debug;
 
 use super::types::{
-    AssetItem, AssetName, CharacterResponse, DogmaAttribute, DogmaAttributeId, DynamicItem,
-    ItemType, MarketGroup, MarketGroupId, MarketOrder, RegionId, Station, StationId, TypeId,
+    AssetItem, AssetName, CharacterResponse, CorporationId, DogmaAttribute, DogmaAttributeId,
+    DynamicItem, ItemType, MarketGroup, MarketGroupId, MarketHistoryDay, MarketOrder, RegionId,
+    Station, StationId, Structure, StructureId, TypeId, UniverseName, WalletTransaction,
 };
 use crate::RatelimitedClient;
+use crate::client::RatelimitedRequestBuilder;
+
+/// SSO scope of `get_assets_chunk` and `get_assets_names`
+pub const SCOPE_READ_ASSETS: &str = "esi-assets.read_assets.v1";
+/// SSO scope of `get_corp_assets_chunk` and `get_corp_assets_names`, granted to directors
+pub const SCOPE_READ_CORPORATION_ASSETS: &str = "esi-assets.read_corporation_assets.v1";
+/// SSO scope of `get_wallet_balance` and `get_wallet_transactions`
+pub const SCOPE_READ_CHARACTER_WALLET: &str = "esi-wallet.read_character_wallet.v1";
 
 #[derive(Error, Debug)]
 pub enum EsiError {
@@ -18,14 +32,26 @@ pub enum EsiError {
     #[error("API error: {status} - {message}")]
     ApiError { status: u16, message: String },
 
+    /// 401, the token is missing, expired or revoked
     #[error("Auth error: {0}")]
     AuthError(String),
 
+    /// 403, the token is fine but doesn't give access, a new token won't change that
+    #[error("Forbidden: {0}")]
+    Forbidden(String),
+
     #[error("Parsing error: {0}")]
     ParseError(String),
 
     #[error("EVE server error: {0}")]
     ServerError(String),
+
+    /// The page is past the last one, the page count can shrink between requests
+    #[error("Page {page} out of range, total pages: {total_pages:?}")]
+    PageOutOfRange {
+        page: usize,
+        total_pages: Option<usize>,
+    },
 }
 
 impl EsiError {
@@ -37,13 +63,20 @@ impl EsiError {
         }
 
         match status.as_u16() {
-            401 | 403 => {
+            401 => {
                 let error_text = response
                     .text()
                     .await
                     .unwrap_or_else(|_| "Authentication failed".to_string());
                 Err(Self::AuthError(error_text))
             }
+            403 => {
+                let error_text = response
+                    .text()
+                    .await
+                    .unwrap_or_else(|_| "Forbidden".to_string());
+                Err(Self::Forbidden(error_text))
+            }
             500..=599 => {
                 let error_text = response
                     .text()
@@ -67,6 +100,49 @@ impl EsiError {
     }
 }
 
+/// Bodies of paged ESI responses keyed by URL, served until their `Expires` header passes
+#[derive(Default)]
+pub struct ExpiresCache {
+    entries: Mutex<HashMap<String, CachedPage>>,
+}
+
+/// One page of a paged endpoint and the `X-Pages` count
+#[derive(Debug)]
+pub struct Page<T> {
+    pub items: T,
+    pub total_pages: usize,
+    /// Answered from the `ExpiresCache`, no request was sent
+    pub cached: bool,
+}
+
+#[derive(Clone)]
+struct CachedPage {
+    expires: DateTime<Utc>,
+    body: Vec<u8>,
+    total_pages: usize,
+}
+
+impl ExpiresCache {
+    fn fresh(&self, url: &str) -> Option<CachedPage> {
+        let entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        entries
+            .get(url)
+            .filter(|page| page.expires > Utc::now())
+            .cloned()
+    }
+
+    fn store(&self, url: &str, page: CachedPage) {
+        let mut entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        entries.insert(url.to_string(), page);
+    }
+
+    /// When the cached response of `url` goes stale, if there is one
+    pub fn expires_at(&self, url: &str) -> Option<DateTime<Utc>> {
+        let entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        entries.get(url).map(|page| page.expires)
+    }
+}
+
 pub trait ResponseExt {
     async fn parse_esi_json<T: serde::de::DeserializeOwned>(self) -> Result<T, EsiError>;
 }
@@ -83,14 +159,39 @@ impl ResponseExt for reqwest::Response {
     }
 }
 
+/// Url of an ESI route like `/universe/types/587/`. Routes are prefixed with `/latest`
+/// unless the client pins a compatibility date, which `esi_get`/`esi_post` then send.
+pub fn esi_url(http_client: &RatelimitedClient, route: &str) -> String {
+    match http_client.compatibility_date() {
+        Some(_) => format!("{}{}", http_client.base_url(), route),
+        None => format!("{}/latest{}", http_client.base_url(), route),
+    }
+}
+
+fn esi_get(http_client: &RatelimitedClient, url: &str) -> RatelimitedRequestBuilder {
+    with_compatibility_date(http_client, http_client.get(url))
+}
+
+fn esi_post(http_client: &RatelimitedClient, url: &str) -> RatelimitedRequestBuilder {
+    with_compatibility_date(http_client, http_client.post(url))
+}
+
+fn with_compatibility_date(
+    http_client: &RatelimitedClient,
+    request: RatelimitedRequestBuilder,
+) -> RatelimitedRequestBuilder {
+    match http_client.compatibility_date() {
+        Some(date) => request.header("X-Compatibility-Date", date),
+        None => request,
+    }
+}
+
 pub async fn get_character_info(
     http_client: &RatelimitedClient,
     token_response: &BasicTokenResponse,
 ) -> Result<CharacterResponse, EsiError> {
-    println!("============1");
-
     let response = http_client
-        .get("https://esi.evetech.net/verify/")
+        .get(format!("{}/verify/", http_client.base_url()))
         .header(
             "Authorization",
             format!("Bearer {}", token_response.access_token().secret()),
@@ -105,72 +206,199 @@ pub async fn get_character_info(
         .map_err(EsiError::from)
 }
 
+/// Max item ids accepted by a single assets names request, more are answered with a 400
+pub const ASSETS_NAMES_CHUNK: usize = 1000;
+
+/// Names of a character's containers and ships, in chunks of `ASSETS_NAMES_CHUNK`
 pub async fn get_assets_names(
     http_client: &RatelimitedClient,
     token_response: &BasicTokenResponse,
     character_id: u64,
-    item_ids: &Vec<i64>,
+    item_ids: &[i64],
+) -> Result<Vec<AssetName>, EsiError> {
+    let url = esi_url(
+        http_client,
+        &format!("/characters/{character_id}/assets/names/"),
+    );
+    post_assets_names(http_client, token_response, &url, item_ids).await
+}
+
+/// Names of a corporation's containers and ships, requested with a director's token
+pub async fn get_corp_assets_names(
+    http_client: &RatelimitedClient,
+    token_response: &BasicTokenResponse,
+    corporation_id: CorporationId,
+    item_ids: &[i64],
+) -> Result<Vec<AssetName>, EsiError> {
+    let url = esi_url(
+        http_client,
+        &format!("/corporations/{corporation_id}/assets/names/"),
+    );
+    post_assets_names(http_client, token_response, &url, item_ids).await
+}
+
+async fn post_assets_names(
+    http_client: &RatelimitedClient,
+    token_response: &BasicTokenResponse,
+    url: &str,
+    item_ids: &[i64],
 ) -> Result<Vec<AssetName>, EsiError> {
-    println!("============2");
     let access_token = token_response.access_token().secret();
 
-    let url = format!("https://esi.evetech.net/latest/characters/{character_id}/assets/names/");
-    println!("get url: {url}, items count: {}", item_ids.len());
+    let mut names = Vec::with_capacity(item_ids.len());
+    for chunk in item_ids.chunks(ASSETS_NAMES_CHUNK) {
+        debug!(%url, items = chunk.len(), "ESI POST");
 
-    let response = http_client
-        .post(url)
-        .header("Authorization", format!("Bearer {access_token}"))
-        .json(&item_ids)
-        .send()
-        .await?;
+        let response = esi_post(http_client, url)
+            .header("Authorization", format!("Bearer {access_token}"))
+            .json(chunk)
+            .send()
+            .await?;
+
+        debug!(status = %response.status(), headers = ?response.headers(), "ESI response");
+
+        let chunk_names = EsiError::from_response(response)
+            .await?
+            .parse_esi_json::<Vec<AssetName>>()
+            .await?;
+        names.extend(chunk_names);
+    }
+
+    Ok(names)
+}
+
+/// One page of corporation assets, like `get_assets_chunk`.
+/// Needs the `esi-assets.read_corporation_assets.v1` scope of a director.
+pub async fn get_corp_assets_chunk(
+    http_client: &RatelimitedClient,
+    expires_cache: &ExpiresCache,
+    token_response: &BasicTokenResponse,
+    corporation_id: CorporationId,
+    page: usize,
+    force: bool,
+) -> Result<Page<Vec<AssetItem>>, EsiError> {
+    let access_token = token_response.access_token().secret();
 
-    println!(
-        "response: {:?}, response code: {:?}",
-        response.status(),
-        response.headers()
+    let url = esi_url(
+        http_client,
+        &format!("/corporations/{corporation_id}/assets/?page={page}"),
     );
+    debug!(%url, "ESI GET");
 
-    EsiError::from_response(response)
-        .await?
-        .parse_esi_json::<Vec<AssetName>>()
-        .await
+    let request =
+        esi_get(http_client, &url).header("Authorization", format!("Bearer {access_token}"));
+
+    in_page_range(
+        get_paged(request, &url, Some(expires_cache), force).await,
+        page,
+    )
 }
 
+/// One page of character assets. A cached page is returned while its `Expires` header
+/// hasn't passed, unless `force` is set. A page past the last one is
+/// `EsiError::PageOutOfRange`.
 pub async fn get_assets_chunk(
     http_client: &RatelimitedClient,
+    expires_cache: &ExpiresCache,
     token_response: &BasicTokenResponse,
     character_id: u64,
     page: usize,
-) -> Result<(Vec<AssetItem>, usize), EsiError> {
-    println!("============3");
+    force: bool,
+) -> Result<Page<Vec<AssetItem>>, EsiError> {
     let access_token = token_response.access_token().secret();
 
-    let url =
-        format!("https://esi.evetech.net/latest/characters/{character_id}/assets/?page={page}");
-    println!("get url: {url}");
+    let url = esi_url(
+        http_client,
+        &format!("/characters/{character_id}/assets/?page={page}"),
+    );
+    debug!(%url, "ESI GET");
 
-    let response = http_client
-        .get(url)
+    let request =
+        esi_get(http_client, &url).header("Authorization", format!("Bearer {access_token}"));
+
+    in_page_range(
+        get_paged(request, &url, Some(expires_cache), force).await,
+        page,
+    )
+}
+
+/// Isk in the character's wallet
+pub async fn get_wallet_balance(
+    http_client: &RatelimitedClient,
+    token_response: &BasicTokenResponse,
+    character_id: u64,
+) -> Result<f64, EsiError> {
+    let access_token = token_response.access_token().secret();
+
+    let url = esi_url(http_client, &format!("/characters/{character_id}/wallet/"));
+    debug!(%url, "ESI GET");
+
+    let response = esi_get(http_client, &url)
         .header("Authorization", format!("Bearer {access_token}"))
         .send()
         .await?;
 
-    println!(
-        "response: {:?}, response code: {:?}",
-        response.status(),
-        response.headers()
-    );
+    EsiError::from_response(response)
+        .await?
+        .parse_esi_json::<f64>()
+        .await
+}
 
-    let pages_str = response
-        .headers()
-        .get("x-pages")
-        .and_then(|h| h.to_str().ok())
-        .unwrap_or("1");
+/// Market transactions of the last 30 days, newest first. The endpoint isn't paged by
+/// number: a call returns up to 2500 transactions, pass the smallest `transaction_id`
+/// seen as `from_id` to get the ones before it. An empty result means there are no more.
+pub async fn get_wallet_transactions(
+    http_client: &RatelimitedClient,
+    token_response: &BasicTokenResponse,
+    character_id: u64,
+    from_id: Option<i64>,
+) -> Result<Vec<WalletTransaction>, EsiError> {
+    let access_token = token_response.access_token().secret();
+
+    let route = match from_id {
+        Some(from_id) => {
+            format!("/characters/{character_id}/wallet/transactions/?from_id={from_id}")
+        }
+        None => format!("/characters/{character_id}/wallet/transactions/"),
+    };
+    let url = esi_url(http_client, &route);
+    debug!(%url, "ESI GET");
+
+    let response = esi_get(http_client, &url)
+        .header("Authorization", format!("Bearer {access_token}"))
+        .send()
+        .await?;
 
-    let total_pages = pages_str.parse::<usize>().unwrap_or(1);
-    let assets = response.parse_esi_json::<Vec<AssetItem>>().await?;
+    EsiError::from_response(response)
+        .await?
+        .parse_esi_json::<Vec<WalletTransaction>>()
+        .await
+}
 
-    Ok((assets, total_pages))
+/// Every transaction `get_wallet_transactions` can reach, walking back with `from_id`
+pub async fn get_all_wallet_transactions(
+    http_client: &RatelimitedClient,
+    token_response: &BasicTokenResponse,
+    character_id: u64,
+) -> Result<Vec<WalletTransaction>, EsiError> {
+    let mut transactions: Vec<WalletTransaction> = Vec::new();
+    let mut from_id = None;
+    loop {
+        let batch =
+            get_wallet_transactions(http_client, token_response, character_id, from_id).await?;
+        // `from_id` is inclusive, the boundary transaction comes back again
+        let oldest = batch.iter().map(|t| t.transaction_id).min();
+        let before = transactions.len();
+        transactions.extend(
+            batch
+                .into_iter()
+                .filter(|t| from_id.is_none_or(|from_id| t.transaction_id < from_id)),
+        );
+        if transactions.len() == before {
+            return Ok(transactions);
+        }
+        from_id = oldest;
+    }
 }
 
 pub async fn get_dynamic_item_attributes(
@@ -178,62 +406,107 @@ pub async fn get_dynamic_item_attributes(
     item_id: i64,
     type_id: i32,
 ) -> Result<DynamicItem, EsiError> {
-    println!("============4");
-
-    let url = format!("https://esi.evetech.net/latest/dogma/dynamic/items/{type_id}/{item_id}/");
-    println!("calling url {url}");
+    let url = esi_url(
+        http_client,
+        &format!("/dogma/dynamic/items/{type_id}/{item_id}/"),
+    );
+    debug!(%url, "ESI GET");
 
-    let response = http_client
-        .get(&url)
+    let response = esi_get(http_client, &url)
         //.header("Authorization", format!("Bearer {access_token}"))
         .send()
         .await?;
 
-    println!(
-        "response: {:?}, response code: {:?}",
-        response.status(),
-        response.headers()
-    );
+    debug!(status = %response.status(), headers = ?response.headers(), "ESI response");
 
-    response.parse_esi_json::<DynamicItem>().await
+    // Unknown items are a 404, not a body that fails to parse
+    EsiError::from_response(response)
+        .await?
+        .parse_esi_json::<DynamicItem>()
+        .await
 }
 
 pub async fn get_station(
     http_client: &RatelimitedClient,
     station_id: StationId,
 ) -> Result<Station, EsiError> {
-    println!("============5");
+    let url = esi_url(http_client, &format!("/universe/stations/{station_id}/"));
+    debug!(%url, "ESI GET");
 
-    let url = format!("https://esi.evetech.net/latest/universe/stations/{station_id}/");
-    println!("calling url {url}");
+    let response = esi_get(http_client, &url).send().await?;
 
-    let response = http_client.get(&url).send().await?;
+    debug!(status = %response.status(), headers = ?response.headers(), "ESI response");
 
-    println!(
-        "response: {:?}, response code: {:?}",
-        response.status(),
-        response.headers()
+    response.parse_esi_json().await
+}
+
+/// Player-owned structure, needs a token of a character with docking access.
+/// Fails with `Forbidden` for structures the character can't see.
+pub async fn get_structure(
+    http_client: &RatelimitedClient,
+    token_response: &BasicTokenResponse,
+    structure_id: StructureId,
+) -> Result<Structure, EsiError> {
+    let access_token = token_response.access_token().secret();
+
+    let url = esi_url(
+        http_client,
+        &format!("/universe/structures/{structure_id}/"),
     );
+    debug!(%url, "ESI GET");
 
-    response.parse_esi_json().await
+    let response = esi_get(http_client, &url)
+        .header("Authorization", format!("Bearer {access_token}"))
+        .send()
+        .await?;
+
+    debug!(status = %response.status(), headers = ?response.headers(), "ESI response");
+
+    EsiError::from_response(response)
+        .await?
+        .parse_esi_json::<Structure>()
+        .await
+}
+
+/// Max ids accepted by a single /universe/names/ request
+pub const UNIVERSE_NAMES_CHUNK: usize = 1000;
+
+/// Resolve names of stations, systems and other universe entities, in chunks of
+/// `UNIVERSE_NAMES_CHUNK`. The endpoint rejects the whole request if any id is invalid.
+pub async fn get_universe_names(
+    http_client: &RatelimitedClient,
+    ids: &[i64],
+) -> Result<Vec<UniverseName>, EsiError> {
+    let url = esi_url(http_client, "/universe/names/");
+
+    let mut names = Vec::with_capacity(ids.len());
+    for chunk in ids.chunks(UNIVERSE_NAMES_CHUNK) {
+        debug!(%url, ids = chunk.len(), "ESI POST");
+
+        let response = esi_post(http_client, &url).json(chunk).send().await?;
+
+        debug!(status = %response.status(), headers = ?response.headers(), "ESI response");
+
+        let chunk_names = EsiError::from_response(response)
+            .await?
+            .parse_esi_json::<Vec<UniverseName>>()
+            .await?;
+        names.extend(chunk_names);
+    }
+
+    Ok(names)
 }
 
 pub async fn get_dogma_attribute(
     http_client: &RatelimitedClient,
     attribute_id: DogmaAttributeId,
 ) -> Result<DogmaAttribute, EsiError> {
-    println!("============6");
+    let url = esi_url(http_client, &format!("/dogma/attributes/{attribute_id}/"));
+    debug!(%url, "ESI GET");
 
-    let url = format!("https://esi.evetech.net/latest/dogma/attributes/{attribute_id}/");
-    println!("calling url {url}");
+    let response = esi_get(http_client, &url).send().await?;
 
-    let response = http_client.get(&url).send().await?;
-
-    println!(
-        "response: {:?}, response code: {:?}",
-        response.status(),
-        response.headers()
-    );
+    debug!(status = %response.status(), headers = ?response.headers(), "ESI response");
 
     response.parse_esi_json::<DogmaAttribute>().await
 }
@@ -242,17 +515,12 @@ pub async fn get_type(
     http_client: &RatelimitedClient,
     type_id: i32,
 ) -> Result<ItemType, EsiError> {
-    println!("============7");
-    let url = format!("https://esi.evetech.net/latest/universe/types/{type_id}/");
-    println!("calling url {url}");
+    let url = esi_url(http_client, &format!("/universe/types/{type_id}/"));
+    debug!(%url, "ESI GET");
 
-    let response = http_client.get(&url).send().await?;
+    let response = esi_get(http_client, &url).send().await?;
 
-    println!(
-        "response: {:?}, response code: {:?}",
-        response.status(),
-        response.headers()
-    );
+    debug!(status = %response.status(), headers = ?response.headers(), "ESI response");
 
     response.parse_esi_json().await
 }
@@ -261,68 +529,347 @@ pub async fn get_market_group(
     http_client: &RatelimitedClient,
     market_group_id: MarketGroupId,
 ) -> Result<MarketGroup, EsiError> {
-    println!("============8");
+    let url = esi_url(http_client, &format!("/markets/groups/{market_group_id}/"));
+    debug!(%url, "ESI GET");
 
-    let url = format!("https://esi.evetech.net/latest/markets/groups/{market_group_id}/");
-    println!("calling url {url}");
+    let response = esi_get(http_client, &url).send().await?;
 
-    let response = http_client.get(&url).send().await?;
-
-    println!(
-        "response: {:?}, response code: {:?}",
-        response.status(),
-        response.headers()
-    );
+    debug!(status = %response.status(), headers = ?response.headers(), "ESI response");
 
     response.parse_esi_json::<MarketGroup>().await
 }
 
 pub async fn get_sell_orders(
     http_client: &RatelimitedClient,
+    expires_cache: &ExpiresCache,
     region_id: RegionId,
     type_id: TypeId,
     page: usize,
+    force: bool,
 ) -> Result<(Vec<MarketOrder>, usize), EsiError> {
-    get_orders(http_client, "sell", region_id, type_id, page).await
+    get_orders(
+        http_client,
+        expires_cache,
+        "sell",
+        region_id,
+        type_id,
+        page,
+        force,
+    )
+    .await
 }
 
 pub async fn get_buy_orders(
     http_client: &RatelimitedClient,
+    expires_cache: &ExpiresCache,
     region_id: RegionId,
     type_id: TypeId,
     page: usize,
+    force: bool,
 ) -> Result<(Vec<MarketOrder>, usize), EsiError> {
-    get_orders(http_client, "buy", region_id, type_id, page).await
+    get_orders(
+        http_client,
+        expires_cache,
+        "buy",
+        region_id,
+        type_id,
+        page,
+        force,
+    )
+    .await
 }
 
 async fn get_orders(
     http_client: &RatelimitedClient,
+    expires_cache: &ExpiresCache,
     order_type: &str,
     region_id: RegionId,
     type_id: TypeId,
     page: usize,
+    force: bool,
 ) -> Result<(Vec<MarketOrder>, usize), EsiError> {
-    let url = format!(
-        "https://esi.evetech.net/latest/markets/{region_id}/orders?order_type={order_type}&type_id={type_id}&page={page}"
+    let url = esi_url(
+        http_client,
+        &format!(
+            "/markets/{region_id}/orders?order_type={order_type}&type_id={type_id}&page={page}"
+        ),
     );
-    println!("calling url {url}");
+    debug!(%url, "ESI GET");
 
-    let response = http_client.get(&url).send().await?;
+    let page = get_paged(esi_get(http_client, &url), &url, Some(expires_cache), force).await?;
+    Ok((page.items, page.total_pages))
+}
 
-    println!(
-        "response: {:?}, response_code: {:?}",
-        response.status(),
-        response.headers()
+/// Daily aggregates of a type in a region for about the last year, oldest first. ESI
+/// recomputes them once a day, the response is kept in `expires_cache` until then.
+pub async fn get_market_history(
+    http_client: &RatelimitedClient,
+    expires_cache: &ExpiresCache,
+    region_id: RegionId,
+    type_id: TypeId,
+    force: bool,
+) -> Result<Vec<MarketHistoryDay>, EsiError> {
+    let url = esi_url(
+        http_client,
+        &format!("/markets/{region_id}/history/?type_id={type_id}"),
     );
+    debug!(%url, "ESI GET");
+
+    let page = get_paged(esi_get(http_client, &url), &url, Some(expires_cache), force).await?;
+    Ok(page.items)
+}
+
+/// Pages of `fetch_all_pages` requested at the same time
+const FETCH_ALL_PAGES_CONCURRENCY: usize = 4;
+
+/// Every item of a public paged endpoint, `url_builder` giving the url of a page
+/// (see `esi_url`). Page 1 is fetched first for `X-Pages`, the remaining pages then
+/// concurrently; each request still waits for the client's rate limit.
+pub async fn fetch_all_pages<T, F>(
+    http_client: &RatelimitedClient,
+    url_builder: F,
+) -> Result<Vec<T>, EsiError>
+where
+    T: serde::de::DeserializeOwned,
+    F: Fn(usize) -> String,
+{
+    let fetch_page = |page: usize| {
+        let url = url_builder(page);
+        async move { get_paged::<Vec<T>>(esi_get(http_client, &url), &url, None, true).await }
+    };
+
+    let first = fetch_page(1).await?;
+    let mut items = first.items;
+    let mut pages = futures::stream::iter((2..=first.total_pages).map(fetch_page))
+        .buffered(FETCH_ALL_PAGES_CONCURRENCY);
+    while let Some(page) = pages.next().await {
+        items.extend(page?.items);
+    }
+
+    Ok(items)
+}
+
+/// Send a request for a page of a paged endpoint, or answer it from `expires_cache`
+/// while the previous response of `url` is fresh.
+async fn get_paged<T: serde::de::DeserializeOwned>(
+    request: RatelimitedRequestBuilder,
+    url: &str,
+    expires_cache: Option<&ExpiresCache>,
+    force: bool,
+) -> Result<Page<T>, EsiError> {
+    if !force && let Some(cached) = expires_cache.and_then(|cache| cache.fresh(url)) {
+        debug!(%url, expires = %cached.expires, "using cached response");
+        return Ok(Page {
+            items: parse_body(&cached.body)?,
+            total_pages: cached.total_pages,
+            cached: true,
+        });
+    }
+
+    let response = request.send().await?;
+
+    debug!(status = %response.status(), headers = ?response.headers(), "ESI response");
+
+    let response = EsiError::from_response(response).await?;
 
-    let pages_str = response
+    let total_pages = response
         .headers()
         .get("x-pages")
         .and_then(|h| h.to_str().ok())
-        .unwrap_or("1");
+        .and_then(|v| v.parse::<usize>().ok())
+        .unwrap_or(1);
 
-    let total_pages = pages_str.parse::<usize>().unwrap_or(1);
-    let orders = response.parse_esi_json::<Vec<MarketOrder>>().await?;
+    let expires = response
+        .headers()
+        .get("expires")
+        .and_then(|h| h.to_str().ok())
+        .and_then(|v| DateTime::parse_from_rfc2822(v).ok())
+        .map(|v| v.with_timezone(&Utc));
+
+    let body = response.bytes().await?.to_vec();
+    let data = parse_body(&body)?;
+
+    if let (Some(expires), Some(expires_cache)) = (expires, expires_cache) {
+        expires_cache.store(
+            url,
+            CachedPage {
+                expires,
+                body,
+                total_pages,
+            },
+        );
+    }
+
+    Ok(Page {
+        items: data,
+        total_pages,
+        cached: false,
+    })
+}
+
+/// Turn a page ESI doesn't have into `PageOutOfRange`: a 404 for a later page, or a page
+/// past the `X-Pages` count of its own response
+fn in_page_range<T>(result: Result<Page<T>, EsiError>, page: usize) -> Result<Page<T>, EsiError> {
+    match result {
+        Ok(Page { total_pages, .. }) if page > total_pages => Err(EsiError::PageOutOfRange {
+            page,
+            total_pages: Some(total_pages),
+        }),
+        Err(EsiError::ApiError { status: 404, .. }) if page > 1 => Err(EsiError::PageOutOfRange {
+            page,
+            total_pages: None,
+        }),
+        result => result,
+    }
+}
+
+fn parse_body<T: serde::de::DeserializeOwned>(body: &[u8]) -> Result<T, EsiError> {
+    serde_json::from_slice(body)
+        .map_err(|e| EsiError::ParseError(format!("failed to parse JSON: {}", e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::RatelimitGroup;
+    use axum::Router;
+    use axum::extract::Query;
+    use axum::http::StatusCode;
+    use axum::routing::get;
+    use oauth2::basic::BasicTokenType;
+    use oauth2::{AccessToken, EmptyExtraTokenFields};
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Serve `router` as ESI on a free local port, returns a client sending requests to it
+    async fn mock_esi(router: Router) -> RatelimitedClient {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, router).await.unwrap() });
+        RatelimitedClient::new(RatelimitGroup::new(vec![])).with_base_url(&format!("http://{addr}"))
+    }
+
+    fn token() -> BasicTokenResponse {
+        BasicTokenResponse::new(
+            AccessToken::new("token".to_string()),
+            BasicTokenType::Bearer,
+            EmptyExtraTokenFields {},
+        )
+    }
+
+    #[tokio::test]
+    async fn assets_page_past_a_shrunk_page_count_is_out_of_range() {
+        // Three pages when page 1 is fetched, two by the time page 3 is
+        let calls = Arc::new(AtomicUsize::new(0));
+        let router = Router::new().route(
+            "/latest/characters/1/assets/",
+            get(move || {
+                let total_pages = match calls.fetch_add(1, Ordering::Relaxed) {
+                    0 => "3",
+                    _ => "2",
+                };
+                async move { ([("x-pages", total_pages)], "[]") }
+            }),
+        );
+        let client = mock_esi(router).await;
+        let expires_cache = ExpiresCache::default();
+
+        let page_1 = get_assets_chunk(&client, &expires_cache, &token(), 1, 1, false)
+            .await
+            .unwrap();
+        assert_eq!(page_1.total_pages, 3);
+
+        let page_3 = get_assets_chunk(&client, &expires_cache, &token(), 1, 3, false).await;
+        assert!(matches!(
+            page_3,
+            Err(EsiError::PageOutOfRange {
+                page: 3,
+                total_pages: Some(2)
+            })
+        ));
+    }
 
-    Ok((orders, total_pages))
+    #[tokio::test]
+    async fn fresh_assets_page_is_answered_from_the_cache() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let requests = Arc::clone(&calls);
+        let router = Router::new().route(
+            "/latest/characters/1/assets/",
+            get(move || {
+                requests.fetch_add(1, Ordering::Relaxed);
+                let expires = (Utc::now() + chrono::Duration::hours(1)).to_rfc2822();
+                async move { ([("x-pages", "1".to_string()), ("expires", expires)], "[]") }
+            }),
+        );
+        let client = mock_esi(router).await;
+        let expires_cache = ExpiresCache::default();
+
+        let sent = get_assets_chunk(&client, &expires_cache, &token(), 1, 1, false)
+            .await
+            .unwrap();
+        let cached = get_assets_chunk(&client, &expires_cache, &token(), 1, 1, false)
+            .await
+            .unwrap();
+
+        assert!(!sent.cached);
+        assert!(cached.cached);
+        assert_eq!(cached.total_pages, 1);
+        assert_eq!(calls.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn assets_page_answered_with_404_is_out_of_range() {
+        let router = Router::new().route(
+            "/latest/characters/1/assets/",
+            get(|Query(query): Query<HashMap<String, String>>| async move {
+                match query.get("page").map(String::as_str) {
+                    Some("1") => (StatusCode::OK, [("x-pages", "1")], "[]"),
+                    _ => (
+                        StatusCode::NOT_FOUND,
+                        [("x-pages", "1")],
+                        "{\"error\":\"Requested page does not exist!\"}",
+                    ),
+                }
+            }),
+        );
+        let client = mock_esi(router).await;
+
+        let page_2 =
+            get_assets_chunk(&client, &ExpiresCache::default(), &token(), 1, 2, false).await;
+        assert!(matches!(
+            page_2,
+            Err(EsiError::PageOutOfRange {
+                page: 2,
+                total_pages: None
+            })
+        ));
+    }
+
+    #[tokio::test]
+    async fn assets_names_are_requested_in_chunks() {
+        // Item ids of every request the mock ESI got
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let seen = Arc::clone(&requests);
+        let router = Router::new().route(
+            "/latest/characters/1/assets/names/",
+            axum::routing::post(move |axum::Json(item_ids): axum::Json<Vec<i64>>| {
+                seen.lock().unwrap().push(item_ids.clone());
+                let names: Vec<serde_json::Value> = item_ids
+                    .iter()
+                    .map(|item_id| serde_json::json!({ "item_id": item_id, "name": "Box" }))
+                    .collect();
+                async move { axum::Json(names) }
+            }),
+        );
+        let client = mock_esi(router).await;
+        let item_ids: Vec<i64> = (1..=2500).collect();
+
+        let names = get_assets_names(&client, &token(), 1, &item_ids)
+            .await
+            .unwrap();
+
+        let sizes: Vec<usize> = requests.lock().unwrap().iter().map(Vec::len).collect();
+        assert_eq!(sizes, vec![1000, 1000, 500]);
+        assert_eq!(names.len(), 2500);
+    }
 }
@@ -1,8 +1,11 @@
 #![allow(async_fn_in_trait)]
 
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{BTreeSet, HashMap};
+use std::time::Duration;
 use thiserror::Error;
+use tracing::{debug, warn};
 
 use super::types::{DogmaAttributeId, TypeId};
 use crate::RatelimitedClient;
@@ -105,6 +108,32 @@ pub struct MutaplasmidsEffects {
     pub attribute_i_ds: HashMap<DogmaAttributeId, AttributeRange>,
 }
 
+impl MutaplasmidsEffects {
+    /// `(attribute_id, min, max)` for every attribute the mutator changes
+    pub fn attribute_ranges(&self) -> Vec<(DogmaAttributeId, f64, f64)> {
+        self.attribute_i_ds
+            .iter()
+            .map(|(attribute_id, range)| (*attribute_id, range.min, range.max))
+            .collect()
+    }
+
+    /// `(resulting_type, [applicable_types])` pairs
+    pub fn input_output(&self) -> Vec<(TypeId, Vec<TypeId>)> {
+        self.input_output_mapping
+            .iter()
+            .map(|i| (i.resulting_type, i.applicable_types.clone()))
+            .collect()
+    }
+}
+
+/// Every type a mutaplasmid can produce, i.e. all abyssal (mutated) item types
+pub fn resulting_types(data: &MutaplasmidData) -> BTreeSet<TypeId> {
+    data.values()
+        .flat_map(|effects| &effects.input_output_mapping)
+        .map(|mapping| mapping.resulting_type)
+        .collect()
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct InputOutputMapping {
@@ -118,23 +147,92 @@ pub struct AttributeRange {
     pub min: f64,
 }
 
+impl AttributeRange {
+    /// Swap the bounds if they come in the wrong order. Returns true if they were swapped.
+    pub fn normalize(&mut self) -> bool {
+        if self.min > self.max {
+            std::mem::swap(&mut self.min, &mut self.max);
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// On-disk copy of the mutaplasmid data in the data dir
+pub const CACHE_FILE: &str = "hoboleaks.cbor";
+
+#[derive(Serialize, Deserialize)]
+struct CachedMutaplasmids {
+    fetched_at: DateTime<Utc>,
+    data: MutaplasmidData,
+}
+
+/// Data written by `store_cache`, None if there is none, it can't be read or it's older than `ttl`
+pub fn load_cache(dir: &str, ttl: Duration) -> Option<MutaplasmidData> {
+    let file_path = format!("{}/{}", dir, CACHE_FILE);
+    let cbor_data = std::fs::read(&file_path).ok()?;
+    let cached = match serde_cbor::from_slice::<CachedMutaplasmids>(&cbor_data) {
+        Ok(cached) => cached,
+        Err(e) => {
+            eprintln!("⚠️  Ignoring unreadable {}: {}", file_path, e);
+            return None;
+        }
+    };
+
+    let age = (Utc::now() - cached.fetched_at)
+        .to_std()
+        .unwrap_or_default();
+    if age > ttl {
+        println!("⌛ Hoboleaks cache is stale (age: {:?})", age);
+        return None;
+    }
+
+    println!(
+        "✅ Loaded hoboleaks data from {} (age: {:?})",
+        file_path, age
+    );
+    Some(cached.data)
+}
+
+pub fn store_cache(dir: &str, data: &MutaplasmidData) -> Result<(), std::io::Error> {
+    let file_path = format!("{}/{}", dir, CACHE_FILE);
+    let temp_path = format!("{file_path}.tmp");
+    let cached = CachedMutaplasmids {
+        fetched_at: Utc::now(),
+        data: data.clone(),
+    };
+    let encoded = serde_cbor::ser::to_vec(&cached).map_err(std::io::Error::other)?;
+
+    std::fs::write(&temp_path, encoded)?;
+    std::fs::rename(temp_path, file_path)
+}
+
 pub async fn get_mutaplasmids(
     http_client: &RatelimitedClient,
 ) -> Result<MutaplasmidData, HoboleaksError> {
-    println!("============5");
-
     let url = format!("https://sde.hoboleaks.space/tq/dynamicitemattributes.json");
-    println!("calling url {url}");
+    debug!(%url, "Hoboleaks GET");
 
     let response = http_client.get(&url).send().await?;
+    debug!(status = %response.status(), headers = ?response.headers(), "Hoboleaks response");
 
-    println!(
-        "response: {:?}, response code: {:?}",
-        response.status(),
-        response.headers()
-    );
+    // Surface error statuses before parsing, so a 503 counts as temporary
+    let response = HoboleaksError::from_response(response).await?;
+    let mut data: MutaplasmidData = response.parse_esi_json().await?;
 
-    response.parse_esi_json().await
+    for (mutator_type_id, effects) in data.iter_mut() {
+        for (attribute_id, range) in effects.attribute_i_ds.iter_mut() {
+            if range.normalize() {
+                eprintln!(
+                    "⚠️  Swapped inverted range of attribute {} for mutator {}",
+                    attribute_id, mutator_type_id
+                );
+            }
+        }
+    }
+
+    Ok(data)
 }
 
 // Enhanced get_mutaplasmids with retry logic
@@ -148,7 +246,7 @@ pub async fn get_mutaplasmids_with_retry(
         match get_mutaplasmids(http_client).await {
             Ok(data) => return Ok(data),
             Err(e) => {
-                println!("Hoboleaks attempt {} failed: {}", attempt + 1, e);
+                warn!("Hoboleaks attempt {} failed: {}", attempt + 1, e);
 
                 if !e.is_temporary() || attempt == max_retries {
                     return Err(e);
@@ -156,7 +254,7 @@ pub async fn get_mutaplasmids_with_retry(
 
                 // Exponential backoff for temporary errors
                 let delay = std::time::Duration::from_millis(1000 * (2_u64.pow(attempt)));
-                println!("Retrying in {:?}...", delay);
+                warn!("Retrying in {:?}...", delay);
                 tokio::time::sleep(delay).await;
 
                 last_error = Some(e);
@@ -166,3 +264,18 @@ pub async fn get_mutaplasmids_with_retry(
 
     Err(last_error.unwrap())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn swapped_range_is_normalized() {
+        let mut range = AttributeRange { max: 0.9, min: 1.1 };
+        assert!(range.normalize());
+        assert_eq!((range.min, range.max), (0.9, 1.1));
+
+        assert!(!range.normalize());
+        assert_eq!((range.min, range.max), (0.9, 1.1));
+    }
+}
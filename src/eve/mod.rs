@@ -4,7 +4,9 @@ pub mod sde;
 pub mod types;
 
 pub use types::{
-    AssetItem, AssetName, CharacterId, CharacterResponse, DogmaAttribute, DogmaAttributeConcise,
-    DogmaAttributeId, DynamicId, DynamicItem, ItemId, ItemType, MarketGroup, MarketGroupId,
-    MarketOrder, RegionId, Station, StationId, TypeId,
+    AssetItem, AssetName, BlueprintInfo, BlueprintMaterial, CharacterId, CharacterResponse,
+    CorporationId, DogmaAttribute, DogmaAttributeConcise, DogmaAttributeId, DynamicId, DynamicItem,
+    ItemId, ItemType, LocationKind, MarketGroup, MarketGroupId, MarketHistoryDay, MarketOrder,
+    Region, RegionId, SolarSystem, SolarSystemId, Station, StationId, Structure, StructureId,
+    TypeId, UniverseName, WalletTransaction,
 };
@@ -1,13 +1,42 @@
 use super::types::{
-    DogmaAttribute, DogmaAttributeConcise, ItemType, MarketGroup, MarketGroupId, TypeId,
+    BlueprintInfo, BlueprintMaterial, DogmaAttribute, DogmaAttributeConcise, ItemType,
+    MAX_BLUEPRINT_MATERIAL_EFFICIENCY, MAX_BLUEPRINT_TIME_EFFICIENCY, MarketGroup, MarketGroupId,
+    Region, RegionId, SolarSystem, SolarSystemId, TypeId,
 };
-use sqlx::{Result, Row, sqlite::SqlitePool, sqlite::SqlitePoolOptions};
+use sqlx::sqlite::{SqliteConnectOptions, SqlitePool, SqlitePoolOptions};
+use sqlx::{Result, Row};
 use std::collections::HashMap;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// How the SDE database is opened
+#[derive(Debug, Clone)]
+pub struct SdeOptions {
+    pub max_connections: u32,
+    /// How long a query waits on a locked database before failing
+    pub busy_timeout: Duration,
+    /// The SDE is never written to, read-only lets several processes share the file
+    pub read_only: bool,
+}
+
+impl Default for SdeOptions {
+    fn default() -> Self {
+        Self {
+            max_connections: 10,
+            busy_timeout: Duration::from_secs(5),
+            read_only: true,
+        }
+    }
+}
+
+pub async fn create_conn_pool(fp: &str, options: &SdeOptions) -> Result<SqlitePool> {
+    let connect_options = SqliteConnectOptions::from_str(fp)?
+        .read_only(options.read_only)
+        .busy_timeout(options.busy_timeout);
 
-pub async fn create_conn_pool(fp: &str) -> Result<SqlitePool> {
     let pool = SqlitePoolOptions::new()
-        .max_connections(10)
-        .connect(fp)
+        .max_connections(options.max_connections)
+        .connect_with(connect_options)
         .await?;
     Ok(pool)
 }
@@ -30,6 +59,44 @@ pub async fn get_abyssal_modules(pool: &SqlitePool) -> Result<Vec<i32>> {
     Ok(modules)
 }
 
+/// Published types whose name contains `query`, case-insensitive, shortest names first
+pub async fn search_types_by_name(
+    pool: &SqlitePool,
+    query: &str,
+    limit: usize,
+) -> Result<Vec<(TypeId, String)>> {
+    // `%` and `_` typed by the user are matched literally
+    let escaped = query
+        .replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_");
+    let pattern = format!("%{}%", escaped);
+
+    let query = "
+        SELECT
+            typeID,
+            typeName
+        FROM
+            invTypes
+        WHERE
+                typeName LIKE ? ESCAPE '\\'
+            AND published = 1
+        ORDER BY LENGTH(typeName), typeName
+        LIMIT ?";
+    let rows = sqlx::query(query)
+        .bind(pattern)
+        .bind(limit as i64)
+        .fetch_all(pool)
+        .await?;
+
+    let mut types = vec![];
+    for row in rows {
+        let type_id: i32 = row.get(0);
+        types.push((type_id.into(), row.get(1)));
+    }
+    Ok(types)
+}
+
 pub async fn get_types_by_ids(
     pool: &SqlitePool,
     type_ids: &[i32],
@@ -124,6 +191,102 @@ pub async fn get_types_by_ids(
     Ok(result)
 }
 
+/// `activityID` of manufacturing in the `industry*` tables
+const MANUFACTURING_ACTIVITY_ID: i32 = 1;
+
+/// Manufacturing data of the given blueprint types, ids that aren't blueprints are skipped
+pub async fn get_blueprints_by_ids(
+    pool: &SqlitePool,
+    type_ids: &[i32],
+) -> Result<Vec<BlueprintInfo>> {
+    if type_ids.is_empty() {
+        return Ok(vec![]);
+    }
+
+    let placeholders = type_ids.iter().map(|_| "?").collect::<Vec<_>>().join(",");
+
+    let query = format!(
+        "SELECT
+            b.typeID,
+            b.maxProductionLimit,
+            a.time,
+            p.productTypeID,
+            p.quantity as productQuantity
+        FROM industryBlueprints b
+        LEFT JOIN industryActivity a
+            ON a.typeID = b.typeID AND a.activityID = ?
+        LEFT JOIN industryActivityProducts p
+            ON p.typeID = b.typeID AND p.activityID = ?
+        WHERE b.typeID IN ({})",
+        placeholders
+    );
+
+    let mut query_builder = sqlx::query(&query)
+        .bind(MANUFACTURING_ACTIVITY_ID)
+        .bind(MANUFACTURING_ACTIVITY_ID);
+    for type_id in type_ids {
+        query_builder = query_builder.bind(type_id);
+    }
+
+    let rows = query_builder.fetch_all(pool).await?;
+    let mut blueprints_map: HashMap<TypeId, BlueprintInfo> = HashMap::new();
+
+    for row in rows {
+        let type_id: i32 = row.get("typeID");
+        blueprints_map
+            .entry(type_id.into())
+            .or_insert_with(|| BlueprintInfo {
+                type_id: type_id.into(),
+                max_production_limit: row.get("maxProductionLimit"),
+                max_material_efficiency: MAX_BLUEPRINT_MATERIAL_EFFICIENCY,
+                max_time_efficiency: MAX_BLUEPRINT_TIME_EFFICIENCY,
+                manufacturing_time: row.get("time"),
+                product_type_id: row.get::<Option<i32>, _>("productTypeID").map(TypeId::from),
+                product_quantity: row.get("productQuantity"),
+                materials: Vec::new(),
+            });
+    }
+
+    let query = format!(
+        "SELECT
+            typeID,
+            materialTypeID,
+            quantity
+        FROM industryActivityMaterials
+        WHERE activityID = ? AND typeID IN ({})
+        ORDER BY typeID, materialTypeID",
+        placeholders
+    );
+
+    let mut query_builder = sqlx::query(&query).bind(MANUFACTURING_ACTIVITY_ID);
+    for type_id in type_ids {
+        query_builder = query_builder.bind(type_id);
+    }
+
+    let rows = query_builder.fetch_all(pool).await?;
+
+    for row in rows {
+        let type_id: i32 = row.get("typeID");
+        if let Some(blueprint) = blueprints_map.get_mut(&type_id.into()) {
+            let material_type_id: i32 = row.get("materialTypeID");
+            blueprint.materials.push(BlueprintMaterial {
+                type_id: material_type_id.into(),
+                quantity: row.get("quantity"),
+            });
+        }
+    }
+
+    // Keep the order of type_ids like get_types_by_ids
+    let mut result = Vec::new();
+    for &type_id in type_ids {
+        if let Some(blueprint) = blueprints_map.remove(&type_id.into()) {
+            result.push(blueprint);
+        }
+    }
+
+    Ok(result)
+}
+
 pub async fn get_dogma_attributes_by_ids(
     pool: &SqlitePool,
     attribute_ids: &[i32],
@@ -247,6 +410,79 @@ pub async fn get_market_groups_by_ids(
     Ok(market_groups)
 }
 
+pub async fn get_systems_by_ids(
+    pool: &SqlitePool,
+    system_ids: &[SolarSystemId],
+) -> Result<Vec<SolarSystem>> {
+    if system_ids.is_empty() {
+        return Ok(vec![]);
+    }
+
+    let placeholders = system_ids.iter().map(|_| "?").collect::<Vec<_>>().join(",");
+    let query = format!(
+        "SELECT
+            solarSystemID,
+            solarSystemName,
+            regionID,
+            security
+        FROM mapSolarSystems
+        WHERE solarSystemID IN ({})",
+        placeholders
+    );
+
+    let mut query_builder = sqlx::query(&query);
+    for system_id in system_ids {
+        query_builder = query_builder.bind(system_id);
+    }
+
+    let rows = query_builder.fetch_all(pool).await?;
+    let mut systems = Vec::new();
+
+    for row in rows {
+        systems.push(SolarSystem {
+            system_id: row.get("solarSystemID"),
+            name: row.get("solarSystemName"),
+            region_id: row.get("regionID"),
+            security: row.get("security"),
+        });
+    }
+
+    Ok(systems)
+}
+
+pub async fn get_regions_by_ids(pool: &SqlitePool, region_ids: &[RegionId]) -> Result<Vec<Region>> {
+    if region_ids.is_empty() {
+        return Ok(vec![]);
+    }
+
+    let placeholders = region_ids.iter().map(|_| "?").collect::<Vec<_>>().join(",");
+    let query = format!(
+        "SELECT
+            regionID,
+            regionName
+        FROM mapRegions
+        WHERE regionID IN ({})",
+        placeholders
+    );
+
+    let mut query_builder = sqlx::query(&query);
+    for region_id in region_ids {
+        query_builder = query_builder.bind(region_id);
+    }
+
+    let rows = query_builder.fetch_all(pool).await?;
+    let mut regions = Vec::new();
+
+    for row in rows {
+        regions.push(Region {
+            region_id: row.get("regionID"),
+            name: row.get("regionName"),
+        });
+    }
+
+    Ok(regions)
+}
+
 /// Resolve market group hierarchy to build full names like "Small Energy Nosferatu"
 pub async fn resolve_market_group_hierarchy(
     pool: &SqlitePool,
@@ -1,9 +1,14 @@
+use chrono::{DateTime, NaiveDate, Utc};
 use serde::{Deserialize, Serialize};
 use std::fmt;
+use utoipa::ToSchema;
 
 pub type CharacterId = u64;
+pub type CorporationId = u64;
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize, ToSchema,
+)]
 #[serde(transparent)]
 pub struct ItemId(i64);
 
@@ -26,7 +31,9 @@ impl From<ItemId> for i64 {
     }
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize, ToSchema,
+)]
 #[serde(transparent)]
 pub struct TypeId(i32);
 impl fmt::Display for TypeId {
@@ -112,6 +119,57 @@ pub struct AssetItem {
     pub is_blueprint_copy: Option<bool>,
 }
 
+/// What an asset's `location_id` points at
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LocationKind {
+    Station,
+    /// Player-owned structure, the id is a structure id
+    Structure,
+    /// Ship, container or other asset, the id is an item id
+    Item,
+    Solarsystem,
+    Other,
+}
+
+impl LocationKind {
+    /// Classify an ESI `location_type`, structures are reported as "other" with an id
+    /// above `STRUCTURE_ID_THRESHOLD`
+    pub fn of(location_type: &str, location_id: i64) -> Self {
+        match location_type {
+            "station" => LocationKind::Station,
+            "item" => LocationKind::Item,
+            "solar_system" => LocationKind::Solarsystem,
+            "other" if location_id >= STRUCTURE_ID_THRESHOLD => LocationKind::Structure,
+            _ => LocationKind::Other,
+        }
+    }
+}
+
+impl AssetItem {
+    pub fn location_kind(&self) -> LocationKind {
+        LocationKind::of(&self.location_type, self.location_id)
+    }
+
+    pub fn is_station(&self) -> bool {
+        self.location_kind() == LocationKind::Station
+    }
+
+    /// Whether the asset sits directly in a player-owned structure
+    pub fn is_structure(&self) -> bool {
+        self.location_kind() == LocationKind::Structure
+    }
+
+    /// Number of items the asset stands for. Singletons (assembled ships, containers,
+    /// BPCs) are always a single item, whatever is reported in `quantity`
+    pub fn item_count(&self) -> i64 {
+        if self.is_singleton {
+            1
+        } else {
+            self.quantity as i64
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct AssetName {
     pub item_id: ItemId,
@@ -143,6 +201,81 @@ pub struct Station {
 
 pub type StationId = i32;
 
+/// Player-owned structure (citadel, engineering complex, ...) as returned by
+/// /universe/structures/{id}/, only visible to characters with docking access
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Structure {
+    pub name: String,
+    pub owner_id: i32,
+    pub position: Option<Position>,
+    pub solar_system_id: i32,
+    pub type_id: Option<i32>,
+}
+
+pub type StructureId = i64;
+
+/// Structure ids are item ids, far above the NPC station and solar system ranges
+pub const STRUCTURE_ID_THRESHOLD: i64 = 1_000_000_000_000;
+
+pub type SolarSystemId = i32;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SolarSystem {
+    pub system_id: SolarSystemId,
+    pub name: String,
+    pub region_id: RegionId,
+    pub security: f64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Region {
+    pub region_id: RegionId,
+    pub name: String,
+}
+
+/// Aggregates of one day of trading, as returned by /markets/{region_id}/history/
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MarketHistoryDay {
+    pub date: NaiveDate,
+    pub average: f64,
+    pub highest: f64,
+    pub lowest: f64,
+    pub order_count: i64,
+    pub volume: i64,
+}
+
+/// Market transaction of a character as returned by /characters/{id}/wallet/transactions/
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct WalletTransaction {
+    /// Character or corporation on the other side of the trade
+    pub client_id: i32,
+    pub date: DateTime<Utc>,
+    pub is_buy: bool,
+    /// False for trades made on behalf of the corporation
+    pub is_personal: bool,
+    pub journal_ref_id: i64,
+    pub location_id: i64,
+    pub quantity: i32,
+    pub transaction_id: i64,
+    pub type_id: TypeId,
+    pub unit_price: f64,
+}
+
+impl WalletTransaction {
+    /// Isk paid (negative) or received for the whole transaction, taxes and fees aside
+    pub fn isk_flow(&self) -> f64 {
+        let total = self.unit_price * self.quantity as f64;
+        if self.is_buy { -total } else { total }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct UniverseName {
+    pub category: String,
+    pub id: i64,
+    pub name: String,
+}
+
 pub type DogmaAttributeId = i32;
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -170,6 +303,33 @@ pub struct MarketGroup {
     pub types: Vec<TypeId>,
 }
 
+/// Highest material efficiency a blueprint can be researched to
+pub const MAX_BLUEPRINT_MATERIAL_EFFICIENCY: u8 = 10;
+/// Highest time efficiency a blueprint can be researched to
+pub const MAX_BLUEPRINT_TIME_EFFICIENCY: u8 = 20;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BlueprintMaterial {
+    pub type_id: TypeId,
+    pub quantity: i64,
+}
+
+/// Manufacturing data of a blueprint from the SDE `industry*` tables
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BlueprintInfo {
+    pub type_id: TypeId,
+    /// Max runs of a copy made from this blueprint
+    pub max_production_limit: i32,
+    pub max_material_efficiency: u8,
+    pub max_time_efficiency: u8,
+    /// Seconds per manufacturing run, `None` for blueprints that can't be manufactured
+    pub manufacturing_time: Option<i32>,
+    pub product_type_id: Option<TypeId>,
+    pub product_quantity: Option<i64>,
+    /// Materials of a single run at ME 0
+    pub materials: Vec<BlueprintMaterial>,
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct MarketOrder {
     duration: i64,
@@ -185,3 +345,59 @@ pub struct MarketOrder {
     volume_remain: i64,
     volume_total: i64,
 }
+
+impl MarketOrder {
+    pub fn duration(&self) -> i64 {
+        self.duration
+    }
+
+    pub fn is_buy_order(&self) -> bool {
+        self.is_buy_order
+    }
+
+    /// Raw RFC 3339 issue time as sent by ESI
+    pub fn issued(&self) -> &str {
+        &self.issued
+    }
+
+    /// Issue time, e.g. to sort orders by age with `sort_by_key(|o| o.issued_at().ok())`
+    pub fn issued_at(&self) -> Result<DateTime<Utc>, chrono::ParseError> {
+        DateTime::parse_from_rfc3339(&self.issued).map(|issued| issued.with_timezone(&Utc))
+    }
+
+    pub fn location_id(&self) -> i64 {
+        self.location_id
+    }
+
+    pub fn min_volume(&self) -> i64 {
+        self.min_volume
+    }
+
+    pub fn order_id(&self) -> i64 {
+        self.order_id
+    }
+
+    pub fn price(&self) -> f64 {
+        self.price
+    }
+
+    pub fn range(&self) -> &str {
+        &self.range
+    }
+
+    pub fn system_id(&self) -> i64 {
+        self.system_id
+    }
+
+    pub fn type_id(&self) -> i64 {
+        self.type_id
+    }
+
+    pub fn volume_remain(&self) -> i64 {
+        self.volume_remain
+    }
+
+    pub fn volume_total(&self) -> i64 {
+        self.volume_total
+    }
+}
@@ -0,0 +1,167 @@
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::time::Instant;
+use utoipa::ToSchema;
+
+use crate::AppContext;
+use crate::db::{MAX_LOCATION_DEPTH, location_label, pretty_location_flag};
+use crate::{MarketGroupId, TypeId};
+
+const NO_MARKET_GROUP: &str = "No market group";
+
+/// Market group => type => (quantity, stacks, location flags) of one location
+type ByMarketGroup =
+    BTreeMap<Option<MarketGroupId>, BTreeMap<TypeId, (i64, usize, BTreeSet<String>)>>;
+
+/// Regular (non-mutated) assets of the characters grouped by location, then market group
+#[derive(Serialize, ToSchema)]
+pub struct AssetsReport {
+    locations: Vec<LocationAssets>,
+    /// Items over all locations, assembled ships and containers count as one
+    total_quantity: i64,
+    generated_at: String,
+}
+
+/// Assets at one station or structure, followed by the container path if they sit in one
+#[derive(Serialize, ToSchema)]
+pub struct LocationAssets {
+    pub location: String,
+    pub total_quantity: i64,
+    pub market_groups: Vec<MarketGroupAssets>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct MarketGroupAssets {
+    /// None for types that can't be traded on the market
+    pub market_group_id: Option<MarketGroupId>,
+    pub market_group_name: String,
+    /// Full market category, e.g. "Ship Equipment / Hull & Armor / Armor Repairers"
+    pub market_group_path: Option<String>,
+    pub total_quantity: i64,
+    pub types: Vec<TypeAssets>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct TypeAssets {
+    pub type_id: TypeId,
+    /// None while the type isn't resolved yet
+    pub type_name: Option<String>,
+    pub quantity: i64,
+    /// Number of separate stacks (or singletons) the quantity is spread over
+    pub stacks: usize,
+    /// Where the stacks sit within the location, e.g. "Hangar" or "Drone Bay"
+    pub location_flags: Vec<String>,
+}
+
+impl AssetsReport {
+    pub async fn new(context: &AppContext) -> Result<Self, String> {
+        let start_time = Instant::now();
+        let character_assets_db = &context.character_assets_db;
+
+        // Read up front, `with_all_data` doesn't hand out the market groups
+        let market_groups = character_assets_db.get_all_market_groups();
+        let market_group_paths = context
+            .market_group_paths()
+            .await
+            .map_err(|e| format!("Failed to resolve market group paths: {}", e))?;
+
+        let locations = character_assets_db.with_all_data(
+            |assets, assets_names, locations, dynamics, types, _| {
+                let mut cache = HashMap::new();
+                let mut grouped: BTreeMap<String, ByMarketGroup> = BTreeMap::new();
+
+                for asset in assets.values() {
+                    // Mutated items have their own report
+                    if dynamics.contains_key(&asset.item_id) {
+                        continue;
+                    }
+
+                    let (station_name, _, location_name) = character_assets_db
+                        .build_location_chain(
+                            asset,
+                            assets,
+                            assets_names,
+                            types,
+                            &locations,
+                            MAX_LOCATION_DEPTH,
+                            &mut cache,
+                        );
+                    let market_group_id = types
+                        .get(&asset.type_id)
+                        .and_then(|item_type| item_type.market_group_id);
+                    let quantity = asset.item_count();
+
+                    let entry = grouped
+                        .entry(location_label(station_name, location_name))
+                        .or_default()
+                        .entry(market_group_id)
+                        .or_default()
+                        .entry(asset.type_id)
+                        .or_default();
+                    entry.0 += quantity;
+                    entry.1 += 1;
+                    if !entry.2.contains(&asset.location_flag) {
+                        entry.2.insert(asset.location_flag.clone());
+                    }
+                }
+
+                grouped
+                    .into_iter()
+                    .map(|(location, by_market_group)| {
+                        let mut groups: Vec<MarketGroupAssets> = by_market_group
+                            .into_iter()
+                            .map(|(market_group_id, by_type)| {
+                                let mut type_assets: Vec<TypeAssets> = by_type
+                                    .into_iter()
+                                    .map(|(type_id, (quantity, stacks, flags))| TypeAssets {
+                                        type_id,
+                                        type_name: types.get(&type_id).map(|t| t.name.clone()),
+                                        quantity,
+                                        stacks,
+                                        // Flags sharing a label ("Hangar", "HangarAll")
+                                        // are listed once
+                                        location_flags: flags
+                                            .iter()
+                                            .map(|flag| pretty_location_flag(flag))
+                                            .collect::<BTreeSet<_>>()
+                                            .into_iter()
+                                            .collect(),
+                                    })
+                                    .collect();
+                                type_assets.sort_by(|a, b| a.type_name.cmp(&b.type_name));
+
+                                let market_group_name = market_group_id
+                                    .and_then(|id| market_groups.get(&id))
+                                    .map(|group| group.name.clone())
+                                    .unwrap_or_else(|| NO_MARKET_GROUP.to_string());
+                                MarketGroupAssets {
+                                    market_group_id,
+                                    market_group_name,
+                                    market_group_path: market_group_id
+                                        .and_then(|id| market_group_paths.get(&id).cloned()),
+                                    total_quantity: type_assets.iter().map(|t| t.quantity).sum(),
+                                    types: type_assets,
+                                }
+                            })
+                            .collect();
+                        groups.sort_by(|a, b| a.market_group_name.cmp(&b.market_group_name));
+
+                        LocationAssets {
+                            location,
+                            total_quantity: groups.iter().map(|g| g.total_quantity).sum(),
+                            market_groups: groups,
+                        }
+                    })
+                    .collect::<Vec<_>>()
+            },
+        );
+
+        println!("assets report generated in {:?}", start_time.elapsed());
+
+        Ok(AssetsReport {
+            total_quantity: locations.iter().map(|l| l.total_quantity).sum(),
+            locations,
+            generated_at: chrono::Utc::now().to_rfc3339(),
+        })
+    }
+}
@@ -1,33 +1,66 @@
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::time::Instant;
 use thiserror::Error;
+use utoipa::{IntoParams, ToSchema};
 
-use crate::AppContext;
+use crate::db::{ChainStats, ChainTimings, MAX_LOCATION_DEPTH};
+use crate::{AppContext, DynamicsDb, esi, sde};
 use crate::{DogmaAttributeId, ItemId, TypeId};
 
+pub mod score;
 pub mod virtual_attributes;
-use virtual_attributes::{
-    append_attribute_values, append_min_max_attribute_values, append_varying_attributes,
-    initialize_virtual_attributes,
-};
+use virtual_attributes::VirtualAttributes;
 
-#[derive(Serialize)]
+/// Mutated (abyssal) items owned by the characters, grouped by resulting item group
+#[derive(Serialize, ToSchema)]
 pub struct DynamicsReport {
     data: BTreeMap<String, ResultingGroup>,
+    /// Number of resulting groups before `resulting_type`/`offset`/`limit` were applied
+    total_groups: usize,
     generated_at: String,
 }
 
-#[derive(Serialize)]
+/// Subset of the resulting groups to put in a report. Groups are ordered by name,
+/// `offset` and `limit` apply after the `resulting_type` filter. `min_score` applies
+/// last, a page can hold fewer groups than `limit` once it dropped some.
+#[derive(Deserialize, IntoParams, Default, Debug, Clone)]
+pub struct DynamicsFilter {
+    /// Only the group of this resulting type, e.g. "Abyssal Damage Control"
+    pub resulting_type: Option<String>,
+    pub offset: Option<usize>,
+    pub limit: Option<usize>,
+    /// Only items scoring at least this (0 to 1), unscored items are dropped too
+    pub min_score: Option<f64>,
+}
+
+impl DynamicsFilter {
+    fn select<'a, T>(&self, groups: impl Iterator<Item = (&'a String, T)>) -> Vec<T> {
+        groups
+            .filter(|(name, _)| self.resulting_type.as_ref().is_none_or(|r| r == *name))
+            .skip(self.offset.unwrap_or(0))
+            .take(self.limit.unwrap_or(usize::MAX))
+            .map(|(_, group)| group)
+            .collect()
+    }
+
+    fn keeps_score(&self, score: Option<f64>) -> bool {
+        self.min_score
+            .is_none_or(|min_score| score.is_some_and(|score| score >= min_score))
+    }
+}
+
+#[derive(Serialize, ToSchema)]
 pub struct ResultingGroup {
     pub source_mutator_groups: Vec<SourceMutatorGroup>,
     pub base_types: Vec<BaseItemType>,
+    pub excluded_base_types: Vec<ExcludedBaseType>,
     pub mutators: Vec<MutatorConcise>,
     pub varying_attributes: Vec<VaryingAttribute>,
     pub min_max_attributes: Vec<AttributeRange>,
 }
 
-#[derive(Serialize)]
+#[derive(Serialize, ToSchema)]
 pub struct SourceMutatorGroup {
     pub source_type_id: TypeId,
     pub mutator_type_id: TypeId,
@@ -35,49 +68,239 @@ pub struct SourceMutatorGroup {
     pub dynamics: Vec<DynamicItemData>,
 }
 
-#[derive(Serialize)]
+#[derive(Serialize, ToSchema)]
 pub struct MutatorConcise {
     pub id: TypeId,
     pub name: String,
     pub attributes: Vec<AttributeRange>,
 }
 
-#[derive(Serialize)]
+#[derive(Serialize, ToSchema)]
 pub struct BaseItemType {
     pub id: TypeId,
     pub name: String,
+    /// Full market category, e.g. "Ship Equipment / Hull & Armor / Armor Repairers"
+    pub market_group_path: Option<String>,
     pub attributes: Vec<AttributeValue>,
 }
 
-#[derive(Serialize, Clone)]
+/// Applicable base type left out of the group because its attributes are incomplete
+#[derive(Serialize, ToSchema)]
+pub struct ExcludedBaseType {
+    pub id: TypeId,
+    pub name: String,
+    #[schema(value_type = Vec<i32>)]
+    pub missing_attributes: Vec<DogmaAttributeId>,
+}
+
+#[derive(Serialize, Clone, ToSchema)]
 pub struct DynamicItemData {
     item_id: ItemId,
     station_name: String,
     location_type: String,
     location_name: String,
+    /// Full market category of the item's type
+    market_group_path: Option<String>,
     attributes: Vec<AttributeValue>,
+    /// How good the rolls are, 0 (worst) to 1 (best), see `score::item_score`
+    score: Option<f64>,
 }
 
-#[derive(Serialize, Clone)]
+#[derive(Serialize, Clone, ToSchema)]
 pub struct VaryingAttribute {
+    #[schema(value_type = i32)]
     id: DogmaAttributeId,
     name: String,
     high_is_good: Option<bool>,
 }
 
-#[derive(Serialize, Clone, Debug)]
+#[derive(Serialize, Clone, Debug, ToSchema)]
 pub struct AttributeValue {
+    #[schema(value_type = i32)]
     id: DogmaAttributeId,
     value: f64,
 }
 
-#[derive(Serialize, Clone, Debug)]
+#[derive(Serialize, Clone, Debug, ToSchema)]
 pub struct AttributeRange {
+    #[schema(value_type = i32)]
     id: DogmaAttributeId,
     min: f64,
     max: f64,
 }
 
+/// A single mutated item looked up by id, see `resolve_single`
+#[derive(Serialize, ToSchema)]
+pub struct DynamicLookup {
+    pub item_id: ItemId,
+    pub type_id: TypeId,
+    pub type_name: String,
+    pub source_type_id: TypeId,
+    pub source_type_name: String,
+    pub mutator_type_id: TypeId,
+    pub mutator_type_name: String,
+    /// Attributes the mutator rolls, with the range it can roll them in
+    pub attributes: Vec<RolledAttribute>,
+    /// How good the rolls are, 0 (worst) to 1 (best), like `DynamicItemData::score`
+    pub score: Option<f64>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct RolledAttribute {
+    #[schema(value_type = i32)]
+    pub id: DogmaAttributeId,
+    pub name: String,
+    pub high_is_good: Option<bool>,
+    /// Value of the mutated item, None if ESI didn't send it
+    pub value: Option<f64>,
+    /// Value of the source type before mutation
+    pub base_value: Option<f64>,
+    /// Range the mutator can roll from the base value
+    pub min: Option<f64>,
+    pub max: Option<f64>,
+}
+
+/// Item and type of a mutated item to look up
+#[derive(Deserialize, IntoParams, Debug, Clone)]
+pub struct DynamicLookupParams {
+    #[param(value_type = i32)]
+    pub type_id: TypeId,
+    #[param(value_type = i64)]
+    pub item_id: ItemId,
+}
+
+/// Rolled attributes of any mutated item and the ranges of its mutator, without running
+/// a saga. The item is read from the dynamics db or fetched from ESI and cached there,
+/// the types and attribute names come from the SDE.
+pub async fn resolve_single(
+    context: &AppContext,
+    type_id: TypeId,
+    item_id: ItemId,
+) -> Result<DynamicLookup, DynamicsError> {
+    let (dynamic, _) =
+        DynamicsDb::get_or_insert_with(&context.dynamics_db, (type_id, item_id), || async {
+            let _permit = context.acquire_esi_permit().await;
+            esi::get_dynamic_item_attributes(&context.http_client, item_id.into(), type_id.into())
+                .await
+        })
+        .await
+        .map_err(|e| match e {
+            esi::EsiError::ApiError { status: 404, .. } => {
+                DynamicsError::NotFoundDynamicItem { type_id, item_id }
+            }
+            e => DynamicsError::EsiError(e.to_string()),
+        })?;
+
+    let source_type_id = dynamic.source_type_id;
+    let mutator_type_id = dynamic.mutator_type_id;
+    let types = context
+        .get_sde_types(&[type_id, source_type_id, mutator_type_id])
+        .await
+        .map_err(|e| DynamicsError::DatabaseError(e.to_string()))?;
+    let type_name = |type_id: TypeId| {
+        types
+            .get(&type_id)
+            .map(|item_type| item_type.name.clone())
+            .ok_or(DynamicsError::MissingType { type_id })
+    };
+    let source_type = types
+        .get(&source_type_id)
+        .ok_or(DynamicsError::MissingType {
+            type_id: source_type_id,
+        })?;
+
+    // Mutaplasmid ranges are known once the hoboleaks data is loaded, load it for a
+    // mutator the character db doesn't know yet
+    let ranges = match context
+        .character_assets_db
+        .get_attributes_by_mutator_type_id(&mutator_type_id)
+    {
+        Ok(ranges) => ranges,
+        Err(_) => {
+            let data = context
+                .get_hoboleaks_data()
+                .await
+                .map_err(|e| DynamicsError::DatabaseError(e.to_string()))?
+                .ok_or_else(|| {
+                    DynamicsError::DatabaseError("No hoboleaks data available".to_string())
+                })?;
+            context
+                .replace_mutaplasmid_effects(&data)
+                .await
+                .map_err(|e| DynamicsError::DatabaseError(e.to_string()))?;
+            context
+                .character_assets_db
+                .get_attributes_by_mutator_type_id(&mutator_type_id)
+                .map_err(DynamicsError::DatabaseError)?
+        }
+    };
+
+    let attribute_ids: Vec<i32> = ranges.keys().copied().collect();
+    let dogma_attributes: HashMap<DogmaAttributeId, _> =
+        sde::get_dogma_attributes_by_ids(&context.sde_pool, &attribute_ids)
+            .await
+            .map_err(|e| DynamicsError::DatabaseError(e.to_string()))?
+            .into_iter()
+            .map(|attribute| (attribute.attribute_id, attribute))
+            .collect();
+
+    let value_of = |attributes: &[crate::DogmaAttributeConcise], id: DogmaAttributeId| {
+        attributes
+            .iter()
+            .find(|a| a.attribute_id == id)
+            .map(|a| a.value)
+    };
+    let attributes: Vec<RolledAttribute> = ranges
+        .iter()
+        .map(|(id, range)| {
+            let base_value = value_of(&source_type.dogma_attributes, *id);
+            let (min, max) = match base_value {
+                Some(base) => {
+                    let (v1, v2) = (base * range.min, base * range.max);
+                    (Some(v1.min(v2)), Some(v1.max(v2)))
+                }
+                None => (None, None),
+            };
+            let dogma_attribute = dogma_attributes.get(id);
+            RolledAttribute {
+                id: *id,
+                name: dogma_attribute
+                    .and_then(|a| a.name.clone())
+                    .unwrap_or_else(|| format!("attribute_{}", id)),
+                high_is_good: dogma_attribute.and_then(|a| a.high_is_good),
+                value: value_of(&dynamic.dogma_attributes, *id),
+                base_value,
+                min,
+                max,
+            }
+        })
+        .collect();
+
+    Ok(DynamicLookup {
+        item_id,
+        type_id,
+        type_name: type_name(type_id)?,
+        source_type_id,
+        source_type_name: source_type.name.clone(),
+        mutator_type_id,
+        mutator_type_name: type_name(mutator_type_id)?,
+        score: rolled_score(&attributes),
+        attributes,
+    })
+}
+
+/// Mean `score::score_attribute` of the rolls with a known value, range and direction
+fn rolled_score(attributes: &[RolledAttribute]) -> Option<f64> {
+    score::mean(attributes.iter().filter_map(|a| {
+        Some(score::score_attribute(
+            a.value?,
+            a.min?,
+            a.max?,
+            a.high_is_good?,
+        ))
+    }))
+}
+
 #[derive(Error, Debug, Serialize)]
 pub enum DynamicsError {
     #[error("Duplicate attributes {attributes:?} in item group {item_group}")]
@@ -108,12 +331,25 @@ pub enum DynamicsError {
         b_minus_a: Vec<DogmaAttributeId>,
         place: String,
     },
+    #[error("Type {type_id} not found")]
+    MissingType { type_id: TypeId },
+    #[error("Dogma attribute {attribute_id} not found")]
+    MissingDogmaAttribute { attribute_id: DogmaAttributeId },
+    #[error("Unknown attribute name {0}")]
+    UnknownAttributeName(String),
     #[error("Database error: {0}")]
     DatabaseError(String),
+    #[error("Dynamic item {item_id} of type {type_id} not found")]
+    NotFoundDynamicItem { type_id: TypeId, item_id: ItemId },
+    #[error("ESI error: {0}")]
+    EsiError(String),
 }
 
-fn duplicates<T: Ord + std::hash::Hash>(v: Vec<T>) -> Vec<T> {
-    let mut h = HashMap::new();
+const UNKNOWN_STATION: &str = "Unknown";
+
+/// Values occurring more than once, in ascending order so integrity errors are stable
+fn duplicates<T: Ord>(v: Vec<T>) -> Vec<T> {
+    let mut h = BTreeMap::new();
     for e in v {
         h.entry(e).and_modify(|e| *e += 1).or_insert(1);
     }
@@ -122,6 +358,19 @@ fn duplicates<T: Ord + std::hash::Hash>(v: Vec<T>) -> Vec<T> {
         .collect()
 }
 
+/// Varying attributes absent from a base type. Types resolved from ESI may come without
+/// (some of) their dogma attributes, they can't be compared against the group.
+fn missing_varying_attributes(
+    attributes: &[AttributeValue],
+    varying_attribute_ids: &BTreeSet<DogmaAttributeId>,
+) -> Vec<DogmaAttributeId> {
+    let attribute_ids: BTreeSet<DogmaAttributeId> = attributes.iter().map(|a| a.id).collect();
+    varying_attribute_ids
+        .difference(&attribute_ids)
+        .copied()
+        .collect()
+}
+
 impl DynamicsReport {
     fn check_integrity(&self) -> Result<(), DynamicsError> {
         for (item_group_name, item_group) in &self.data {
@@ -140,7 +389,11 @@ impl DynamicsReport {
                 let found_source_type = item_group
                     .base_types
                     .iter()
-                    .any(|t| t.id == source_mutator_group.source_type_id);
+                    .any(|t| t.id == source_mutator_group.source_type_id)
+                    || item_group
+                        .excluded_base_types
+                        .iter()
+                        .any(|t| t.id == source_mutator_group.source_type_id);
                 if !found_source_type {
                     return Err(DynamicsError::NotFoundSourceType {
                         item_group: item_group_name.to_string(),
@@ -296,27 +549,76 @@ impl DynamicsReport {
     }
 
     pub async fn new(context: &AppContext) -> Result<Self, DynamicsError> {
+        Self::new_filtered(context, &DynamicsFilter::default()).await
+    }
+
+    /// Report of only the resulting groups selected by `filter`, the others aren't computed
+    pub async fn new_filtered(
+        context: &AppContext,
+        filter: &DynamicsFilter,
+    ) -> Result<Self, DynamicsError> {
         let start_time = Instant::now();
 
         let character_assets_db = &context.character_assets_db;
+        let market_group_paths = context
+            .market_group_paths()
+            .await
+            .map_err(|e| DynamicsError::DatabaseError(e.to_string()))?;
 
-        character_assets_db
+        let report = character_assets_db
             .with_all_data(
-                |assets, assets_names, stations, dynamics, types, dogma_attributes| {
+                |assets, assets_names, locations, dynamics, types, dogma_attributes| {
                     println!(
                         "get all from character_assets_db: {:?}",
                         start_time.elapsed()
                     );
 
-                    let name_to_id_resolver = |attribute_name: &str| -> DogmaAttributeId {
-                        let res = character_assets_db
-                            .get_attribute_id_by_name(attribute_name.to_string());
-                        match res {
-                            Ok(id) => id,
-                            Err(err) => panic!("Failed to resolve attribute name: {}", err),
-                        }
+                    let market_group_path = |type_id: &TypeId| {
+                        types
+                            .get(type_id)
+                            .and_then(|item_type| item_type.market_group_id)
+                            .and_then(|id| market_group_paths.get(&id).cloned())
                     };
-                    initialize_virtual_attributes(&name_to_id_resolver);
+
+                    let name_to_id_resolver =
+                        |attribute_name: &str| -> Result<DogmaAttributeId, DynamicsError> {
+                            character_assets_db
+                                .get_attribute_id_by_name(attribute_name.to_string())
+                                .map_err(|_| {
+                                    DynamicsError::UnknownAttributeName(attribute_name.to_string())
+                                })
+                        };
+                    let virtual_attributes =
+                        VirtualAttributes::resolve(&context.virtual_formulas, &name_to_id_resolver)?;
+
+                    // Resulting group name => (source, mutator) pairs of the owned items
+                    let mut pairs_by_group: BTreeMap<String, BTreeSet<(TypeId, TypeId)>> =
+                        BTreeMap::new();
+                    for (item_id, dynamic) in dynamics {
+                        if !assets.contains_key(item_id) {
+                            continue;
+                        }
+                        let pair = (dynamic.source_type_id, dynamic.mutator_type_id);
+                        let resulting_type_id = character_assets_db
+                            .get_resulting_type_by_source_mutator(pair.0, pair.1)
+                            .map_err(DynamicsError::DatabaseError)?;
+                        let resulting_type = types.get(&resulting_type_id).ok_or(
+                            DynamicsError::MissingType {
+                                type_id: resulting_type_id,
+                            },
+                        )?;
+                        pairs_by_group
+                            .entry(resulting_type.name.clone())
+                            .or_default()
+                            .insert(pair);
+                    }
+                    let total_groups = pairs_by_group.len();
+                    let selected_pairs: BTreeSet<(TypeId, TypeId)> = filter
+                        .select(pairs_by_group.iter())
+                        .into_iter()
+                        .flatten()
+                        .copied()
+                        .collect();
 
                     let mut dynamics_by_source_mutator: BTreeMap<
                         (TypeId, TypeId),
@@ -334,8 +636,17 @@ impl DynamicsReport {
                     let mut processed_items = 0;
 
                     let mut location_cache = HashMap::new();
+                    let mut chain_stats = ChainStats::default();
+                    let mut chain_timings = ChainTimings::default();
+                    // root location id => items whose station could not be resolved locally
+                    let mut unresolved_locations: BTreeMap<i64, Vec<ItemId>> = BTreeMap::new();
 
                     for (item_id, dynamic) in dynamics {
+                        let pair = (dynamic.source_type_id, dynamic.mutator_type_id);
+                        if !selected_pairs.contains(&pair) {
+                            continue;
+                        }
+
                         // 1. Asset lookup timing
                         let start = Instant::now();
                         // let asset = assets.get(item_id).unwrap();
@@ -345,17 +656,33 @@ impl DynamicsReport {
                         let start = Instant::now();
                         // let (station_name, location_type, location_name) =
                         //     character_assets_db.build_location_chain(asset);
-                        let asset = assets.get(item_id).unwrap();
+                        // Dynamic items are cached across runs, the asset may be gone by now
+                        let Some(asset) = assets.get(item_id) else {
+                            continue;
+                        };
                         let (station_name, location_type, location_name) = character_assets_db
-                            .build_location_chain(
+                            .build_location_chain_profiled(
                                 asset,
                                 assets,
                                 assets_names,
-                                stations,
+                                types,
+                                &locations,
+                                MAX_LOCATION_DEPTH,
                                 &mut location_cache,
+                                &mut chain_stats,
+                                &mut chain_timings,
                             );
                         location_chain_time += start.elapsed();
 
+                        if station_name == UNKNOWN_STATION {
+                            let (root_location_id, _) =
+                                character_assets_db.root_location(asset, assets);
+                            unresolved_locations
+                                .entry(root_location_id)
+                                .or_default()
+                                .push(*item_id);
+                        }
+
                         // 3. Attributes mapping timing
                         let start = Instant::now();
                         let mut attributes = Vec::with_capacity(dynamic.dogma_attributes.len());
@@ -378,7 +705,9 @@ impl DynamicsReport {
                             station_name,
                             location_type,
                             location_name,
+                            market_group_path: market_group_path(&asset.type_id),
                             attributes,
+                            score: None,
                         };
                         struct_creation_time += start.elapsed();
 
@@ -434,6 +763,13 @@ impl DynamicsReport {
                         btree_insert_time.as_secs_f64() / total_time * 100.0
                     );
                     println!("=============================");
+                    chain_stats.print_summary();
+                    if chain_stats.depth_capped > 0 {
+                        eprintln!(
+                            "⚠️ {} items nest deeper than {} containers, their paths are truncated",
+                            chain_stats.depth_capped, MAX_LOCATION_DEPTH
+                        );
+                    }
                     println!("analyzed all dynamics: {:?}", start_time.elapsed());
 
                     let mut resulting_to_source_mutator: BTreeMap<TypeId, Vec<(TypeId, TypeId)>> =
@@ -450,11 +786,18 @@ impl DynamicsReport {
                     }
                     println!("analyzed all resulting types: {:?}", start_time.elapsed());
 
-                    let mut report = BTreeMap::new();
-
-                    for (resulting_type_id, source_mutators) in &resulting_to_source_mutator {
-                        let resulting_type_name =
-                            types.get(resulting_type_id).unwrap().name.clone();
+                    // Resulting types are independent of each other, build their groups on
+                    // scoped threads borrowing the maps locked above
+                    let build_group = |resulting_type_id: &TypeId,
+                                       source_mutators: &Vec<(TypeId, TypeId)>|
+                     -> Result<(String, ResultingGroup), DynamicsError> {
+                        let resulting_type_name = types
+                            .get(resulting_type_id)
+                            .ok_or(DynamicsError::MissingType {
+                                type_id: *resulting_type_id,
+                            })?
+                            .name
+                            .clone();
 
                         let mut possible_attributes: Vec<BTreeSet<DogmaAttributeId>> = vec![];
 
@@ -466,16 +809,16 @@ impl DynamicsReport {
                         }
 
                         let (all_same, intersected_attributes) = {
-                            let first = possible_attributes.first().unwrap();
-                            let all_same =
-                                possible_attributes.iter().skip(1).all(|set| set == first);
-                            let intersected_attributes = possible_attributes.iter().skip(1).fold(
-                                first.clone(),
-                                |mut acc, set| {
-                                    acc.retain(|x| set.contains(x));
-                                    acc
-                                },
-                            );
+                            let (first, rest) = possible_attributes
+                                .split_first()
+                                .map_or((BTreeSet::new(), &[][..]), |(first, rest)| {
+                                    (first.clone(), rest)
+                                });
+                            let all_same = rest.iter().all(|set| *set == first);
+                            let intersected_attributes = rest.iter().fold(first, |mut acc, set| {
+                                acc.retain(|x| set.contains(x));
+                                acc
+                            });
                             (all_same, intersected_attributes)
                         };
 
@@ -489,7 +832,11 @@ impl DynamicsReport {
                         let mut varying_attributes = vec![];
                         let mut varying_attribute_ids = BTreeSet::new();
                         for attr_id in intersected_attributes {
-                            let attribute = dogma_attributes.get(&attr_id).unwrap();
+                            let attribute = dogma_attributes.get(&attr_id).ok_or(
+                                DynamicsError::MissingDogmaAttribute {
+                                    attribute_id: attr_id,
+                                },
+                            )?;
                             varying_attributes.push(VaryingAttribute {
                                 id: attribute.attribute_id,
                                 name: attribute.name.clone().unwrap_or_else(|| {
@@ -499,7 +846,7 @@ impl DynamicsReport {
                             });
                             varying_attribute_ids.insert(attribute.attribute_id);
                         }
-                        append_varying_attributes(&mut varying_attributes);
+                        virtual_attributes.append_varying_attributes(&mut varying_attributes);
                         // add possible virtual attributes ids
                         varying_attribute_ids = varying_attributes.iter().map(|a| a.id).collect();
 
@@ -509,36 +856,52 @@ impl DynamicsReport {
                             start_time.elapsed()
                         );
 
-                        let base_types: Vec<BaseItemType> = character_assets_db
+                        let mut base_types: Vec<BaseItemType> = vec![];
+                        let mut excluded_base_types: Vec<ExcludedBaseType> = vec![];
+                        for type_id in character_assets_db
                             .get_applicable_types_by_resulting_type(resulting_type_id)
                             .map_err(DynamicsError::DatabaseError)?
                             .iter()
-                            .filter_map(|type_id| match types.get(type_id) {
-                                Some(item_type) => {
-                                    let mut attributes: Vec<_> = item_type
-                                        .dogma_attributes
-                                        .iter()
-                                        .filter(|a| varying_attribute_ids.contains(&a.attribute_id))
-                                        .map(|a| AttributeValue {
-                                            id: a.attribute_id,
-                                            value: a.value,
-                                        })
-                                        .collect();
+                        {
+                            let Some(item_type) = types.get(type_id) else {
+                                eprintln!("Type not found: {}", type_id);
+                                continue;
+                            };
 
-                                    append_attribute_values(&mut attributes);
+                            let mut attributes: Vec<_> = item_type
+                                .dogma_attributes
+                                .iter()
+                                .filter(|a| varying_attribute_ids.contains(&a.attribute_id))
+                                .map(|a| AttributeValue {
+                                    id: a.attribute_id,
+                                    value: a.value,
+                                })
+                                .collect();
 
-                                    Some(BaseItemType {
-                                        id: *type_id,
-                                        name: item_type.name.clone(),
-                                        attributes,
-                                    })
-                                }
-                                None => {
-                                    eprintln!("Type not found: {}", type_id);
-                                    None
-                                }
-                            })
-                            .collect();
+                            virtual_attributes.append_attribute_values(&mut attributes);
+
+                            let missing_attributes =
+                                missing_varying_attributes(&attributes, &varying_attribute_ids);
+                            if !missing_attributes.is_empty() {
+                                eprintln!(
+                                    "{}: excluding base type {} ({}), missing varying attributes {:?}",
+                                    resulting_type_name, item_type.name, type_id, missing_attributes
+                                );
+                                excluded_base_types.push(ExcludedBaseType {
+                                    id: *type_id,
+                                    name: item_type.name.clone(),
+                                    missing_attributes,
+                                });
+                                continue;
+                            }
+
+                            base_types.push(BaseItemType {
+                                id: *type_id,
+                                name: item_type.name.clone(),
+                                market_group_path: market_group_path(type_id),
+                                attributes,
+                            });
+                        }
 
                         let raw_mutators = character_assets_db
                             .get_mutator_ids_by_resulting_type_id(resulting_type_id)
@@ -554,7 +917,7 @@ impl DynamicsReport {
                                     max: range.max,
                                 })
                                 .collect();
-                            append_min_max_attribute_values(&mut attributes);
+                            virtual_attributes.append_min_max_attribute_values(&mut attributes);
 
                             let mutator = MutatorConcise {
                                 id: mutator_type_id,
@@ -577,11 +940,12 @@ impl DynamicsReport {
                             })
                             .collect();
 
-                        append_min_max_attribute_values(&mut min_max_attributes);
+                        virtual_attributes.append_min_max_attribute_values(&mut min_max_attributes);
 
                         let mut resulting_group = ResultingGroup {
                             source_mutator_groups: vec![],
                             base_types,
+                            excluded_base_types,
                             mutators,
                             varying_attributes,
                             min_max_attributes,
@@ -590,17 +954,20 @@ impl DynamicsReport {
                         for (source_type_id, mutator_type_id) in source_mutators {
                             let mut dynamics = dynamics_by_source_mutator
                                 .get(&(*source_type_id, *mutator_type_id))
-                                .unwrap()
-                                .to_vec();
+                                .cloned()
+                                .unwrap_or_default();
 
                             for dynamic in &mut dynamics {
                                 dynamic
                                     .attributes
                                     .retain(|attr| varying_attribute_ids.contains(&attr.id));
-                                append_attribute_values(&mut dynamic.attributes);
+                                virtual_attributes.append_attribute_values(&mut dynamic.attributes);
                             }
 
-                            let source_type = types.get(source_type_id).unwrap();
+                            let source_type =
+                                types.get(source_type_id).ok_or(DynamicsError::MissingType {
+                                    type_id: *source_type_id,
+                                })?;
 
                             let attributes = character_assets_db
                                 .get_attributes_by_mutator_type_id(mutator_type_id)
@@ -626,7 +993,21 @@ impl DynamicsReport {
                                 })
                                 .collect();
 
-                            append_min_max_attribute_values(&mut attributes);
+                            virtual_attributes.append_min_max_attribute_values(&mut attributes);
+
+                            for dynamic in &mut dynamics {
+                                dynamic.score = score::item_score(
+                                    &dynamic.attributes,
+                                    &attributes,
+                                    &resulting_group.varying_attributes,
+                                );
+                            }
+                            // Only the items are filtered, the attribute sets of the group
+                            // stay complete for `check_integrity`
+                            dynamics.retain(|dynamic| filter.keeps_score(dynamic.score));
+                            if dynamics.is_empty() && filter.min_score.is_some() {
+                                continue;
+                            }
 
                             let source_mutator_group = SourceMutatorGroup {
                                 source_type_id: *source_type_id,
@@ -639,11 +1020,54 @@ impl DynamicsReport {
                                 .push(source_mutator_group);
                         }
 
+                        Ok((resulting_type_name, resulting_group))
+                    };
+
+                    let groups: Vec<_> = resulting_to_source_mutator.iter().collect();
+                    let threads = std::thread::available_parallelism()
+                        .map_or(1, |n| n.get())
+                        .min(groups.len())
+                        .max(1);
+                    let chunk_size = groups.len().div_ceil(threads).max(1);
+                    let results: Vec<_> = std::thread::scope(|scope| {
+                        let handles: Vec<_> = groups
+                            .chunks(chunk_size)
+                            .map(|chunk| {
+                                scope.spawn(|| {
+                                    chunk
+                                        .iter()
+                                        .map(|(resulting_type_id, source_mutators)| {
+                                            build_group(resulting_type_id, source_mutators)
+                                        })
+                                        .collect::<Vec<_>>()
+                                })
+                            })
+                            .collect();
+                        handles
+                            .into_iter()
+                            .flat_map(|handle| {
+                                handle
+                                    .join()
+                                    .unwrap_or_else(|e| std::panic::resume_unwind(e))
+                            })
+                            .collect()
+                    });
+
+                    let mut report = BTreeMap::new();
+                    for result in results {
+                        let (resulting_type_name, resulting_group) = result?;
+                        if filter.min_score.is_some()
+                            && resulting_group.source_mutator_groups.is_empty()
+                        {
+                            continue;
+                        }
                         report.insert(resulting_type_name, resulting_group);
                     }
+                    println!("built all resulting groups: {:?}", start_time.elapsed());
 
                     let ret = DynamicsReport {
                         data: report,
+                        total_groups,
                         generated_at: chrono::Utc::now().to_rfc3339(),
                     };
                     if let Err(err) = Self::check_integrity(&ret) {
@@ -653,9 +1077,118 @@ impl DynamicsReport {
                     }
                     println!("created report: {:?}", start_time.elapsed());
 
-                    Ok(ret)
+                    Ok((ret, unresolved_locations))
                 },
-            )
-            .map_err(DynamicsError::DatabaseError)?
+            )?;
+
+        let (mut report, unresolved_locations) = report;
+        if !unresolved_locations.is_empty() {
+            let location_ids: Vec<i64> = unresolved_locations.keys().copied().collect();
+            match context.resolve_location_names(&location_ids).await {
+                Ok(names) => report.substitute_station_names(&unresolved_locations, &names),
+                Err(e) => eprintln!("Failed to resolve location names: {}", e),
+            }
+        }
+
+        Ok(report)
+    }
+
+    /// Second pass of the report: fill in station names resolved through ESI
+    fn substitute_station_names(
+        &mut self,
+        unresolved_locations: &BTreeMap<i64, Vec<ItemId>>,
+        names: &HashMap<i64, String>,
+    ) {
+        let mut item_names: HashMap<ItemId, &String> = HashMap::new();
+        for (location_id, item_ids) in unresolved_locations {
+            if let Some(name) = names.get(location_id) {
+                for item_id in item_ids {
+                    item_names.insert(*item_id, name);
+                }
+            }
+        }
+
+        for group in self.data.values_mut() {
+            for source_mutator_group in &mut group.source_mutator_groups {
+                for dynamic in &mut source_mutator_group.dynamics {
+                    if let Some(name) = item_names.get(&dynamic.item_id) {
+                        dynamic.station_name = (*name).clone();
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn min_score_drops_low_and_unscored_items() {
+        let filter = DynamicsFilter {
+            min_score: Some(0.5),
+            ..DynamicsFilter::default()
+        };
+        assert!(filter.keeps_score(Some(0.5)));
+        assert!(!filter.keeps_score(Some(0.49)));
+        assert!(!filter.keeps_score(None));
+
+        let unfiltered = DynamicsFilter::default();
+        assert!(unfiltered.keeps_score(None));
+        assert!(unfiltered.keeps_score(Some(0.0)));
+    }
+
+    #[test]
+    fn duplicates_are_reported_sorted() {
+        assert_eq!(duplicates(vec![20, 5, 20, 9, 5, 20, 1]), vec![5, 20]);
+        assert_eq!(duplicates(vec![5, 20, 1, 20, 5]), vec![5, 20]);
+        assert!(duplicates(vec![3, 2, 1]).is_empty());
+    }
+
+    #[test]
+    fn base_type_missing_a_varying_attribute_is_reported() {
+        let varying_attribute_ids = BTreeSet::from([20, 54, 1795]);
+        let complete = [
+            AttributeValue { id: 20, value: 1.0 },
+            AttributeValue {
+                id: 54,
+                value: 24000.0,
+            },
+            AttributeValue {
+                id: 1795,
+                value: 0.0,
+            },
+        ];
+        // as a type resolved from ESI that came without one of its attributes
+        let incomplete = [
+            AttributeValue { id: 20, value: 1.0 },
+            AttributeValue {
+                id: 54,
+                value: 24000.0,
+            },
+        ];
+
+        assert!(missing_varying_attributes(&complete, &varying_attribute_ids).is_empty());
+        assert_eq!(
+            missing_varying_attributes(&incomplete, &varying_attribute_ids),
+            vec![1795]
+        );
+        assert_eq!(
+            missing_varying_attributes(&[], &varying_attribute_ids),
+            vec![20, 54, 1795]
+        );
+    }
+
+    #[test]
+    fn duplicate_attributes_error_is_stable() {
+        let error = DynamicsError::DuplicateAttributes {
+            item_group: "Heavy Warp Disruptor".to_string(),
+            attributes: duplicates(vec![1795, 20, 1795, 54, 20]),
+        };
+        assert_eq!(
+            error.to_string(),
+            "Duplicate attributes [20, 1795] in item group Heavy Warp Disruptor"
+        );
     }
 }
@@ -0,0 +1,70 @@
+use std::collections::HashMap;
+
+use super::{AttributeRange, AttributeValue, VaryingAttribute};
+use crate::DogmaAttributeId;
+
+/// Where `value` lies in the `min..=max` a mutator can roll, 0 for the worst roll and
+/// 1 for the best. Values outside the range are clamped, a range without spread is a
+/// perfect roll.
+pub fn score_attribute(value: f64, min: f64, max: f64, high_is_good: bool) -> f64 {
+    if max <= min {
+        return 1.0;
+    }
+
+    let position = ((value - min) / (max - min)).clamp(0.0, 1.0);
+    if high_is_good {
+        position
+    } else {
+        1.0 - position
+    }
+}
+
+/// Mean `score_attribute` over the varying attributes of an item. Attributes without a
+/// range or without a known good direction are left out, None if none is left.
+pub fn item_score(
+    attributes: &[AttributeValue],
+    ranges: &[AttributeRange],
+    varying_attributes: &[VaryingAttribute],
+) -> Option<f64> {
+    let ranges: HashMap<DogmaAttributeId, &AttributeRange> =
+        ranges.iter().map(|range| (range.id, range)).collect();
+    let values: HashMap<DogmaAttributeId, f64> =
+        attributes.iter().map(|a| (a.id, a.value)).collect();
+
+    mean(varying_attributes.iter().filter_map(|attribute| {
+        let high_is_good = attribute.high_is_good?;
+        let range = ranges.get(&attribute.id)?;
+        let value = values.get(&attribute.id)?;
+        Some(score_attribute(*value, range.min, range.max, high_is_good))
+    }))
+}
+
+/// Mean of the scores, None if there are none
+pub fn mean(scores: impl IntoIterator<Item = f64>) -> Option<f64> {
+    let (sum, count) = scores
+        .into_iter()
+        .fold((0.0, 0usize), |(sum, count), score| {
+            (sum + score, count + 1)
+        });
+    (count > 0).then(|| sum / count as f64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mean_of_no_scores_is_none() {
+        assert_eq!(mean([]), None);
+        assert_eq!(mean([0.25]), Some(0.25));
+        assert_eq!(mean([0.0, 0.5, 1.0]), Some(0.5));
+    }
+
+    #[test]
+    fn low_is_good_attributes_score_the_other_way() {
+        assert_eq!(score_attribute(0.9, 0.8, 1.2, true), 0.25);
+        assert_eq!(score_attribute(0.9, 0.8, 1.2, false), 0.75);
+        assert_eq!(score_attribute(2.0, 0.8, 1.2, true), 1.0);
+        assert_eq!(score_attribute(1.0, 1.0, 1.0, false), 1.0);
+    }
+}
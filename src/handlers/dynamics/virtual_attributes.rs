@@ -1,78 +1,267 @@
-use std::sync::OnceLock;
+use serde::Deserialize;
+use std::collections::HashSet;
+use std::path::Path;
 
 use super::{AttributeRange, AttributeValue, VaryingAttribute};
 use crate::DogmaAttributeId;
 
-pub const VIRTUAL_ARMOR_REPAIR_EFFICIENCY_ID: DogmaAttributeId = -1;
-pub const VIRTUAL_ARMOR_REPAIR_SPEED_ID: DogmaAttributeId = -2;
-pub const VIRTUAL_SHIELD_REPAIR_EFFICIENCY_ID: DogmaAttributeId = -3;
-pub const VIRTUAL_SHIELD_REPAIR_SPEED_ID: DogmaAttributeId = -4;
-pub const VIRTUAL_DPS_MODIFIER_ID: DogmaAttributeId = -5;
-pub const VIRTUAL_MISSILE_DPS_MODIFIER_ID: DogmaAttributeId = -6;
-pub const VIRTUAL_NEUTRALIZATION_EFFICIENCY_ID: DogmaAttributeId = -7;
+/// File in the data dir overriding the built-in formulas
+pub const VIRTUAL_ATTRIBUTES_FILE: &str = "virtual_attributes.json";
+
+/// Derived attribute, given either as `expression` or as the product of the `numerator`
+/// attributes divided by the product of the `denominator` attributes.
+/// Virtual ids are assigned by position: -1 for the first formula, -2 for the second...
+#[derive(Deserialize, Debug, Clone)]
+pub struct VirtualAttributeFormula {
+    pub name: String,
+    pub high_is_good: Option<bool>,
+    #[serde(default)]
+    pub numerator: Vec<String>,
+    #[serde(default)]
+    pub denominator: Vec<String>,
+    #[serde(default)]
+    pub expression: Option<Expression>,
+}
 
-struct VirtualAttributeFormula {
-    virtual_id: DogmaAttributeId,
-    name: &'static str,
-    high_is_good: Option<bool>,
-    numerator_attr_names: &'static [&'static str],
-    denominator_attr_names: &'static [&'static str],
+impl VirtualAttributeFormula {
+    /// `expression` if set, otherwise the numerator / denominator quotient
+    pub fn expression(&self) -> Expression {
+        if let Some(expression) = &self.expression {
+            return expression.clone();
+        }
+
+        let product = |names: &[String]| {
+            Expression::Product(names.iter().cloned().map(Expression::Attribute).collect())
+        };
+        Expression::Quotient(
+            Box::new(product(&self.numerator)),
+            Box::new(product(&self.denominator)),
+        )
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if self.name.trim().is_empty() {
+            return Err("formula without a name".to_string());
+        }
+        match &self.expression {
+            Some(_) if !self.numerator.is_empty() || !self.denominator.is_empty() => Err(format!(
+                "{}: both an expression and a numerator / denominator",
+                self.name
+            )),
+            Some(expression) => expression
+                .validate()
+                .map_err(|e| format!("{}: {}", self.name, e)),
+            None if self.numerator.is_empty() => Err(format!(
+                "{}: neither an expression nor a numerator",
+                self.name
+            )),
+            None => self
+                .numerator
+                .iter()
+                .chain(&self.denominator)
+                .try_for_each(|name| validate_attribute_name(name))
+                .map_err(|e| format!("{}: {}", self.name, e)),
+        }
+    }
+}
+
+fn validate_attribute_name(name: &str) -> Result<(), String> {
+    if name.trim().is_empty() {
+        return Err("empty attribute name".to_string());
+    }
+    Ok(())
+}
+
+/// Arithmetic over attributes referenced by display name, e.g.
+/// `{"sum": [{"attribute": "Shield Capacity"}, {"attribute": "Armor Hitpoints"}]}`
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "snake_case")]
+pub enum Expression {
+    Attribute(String),
+    Constant(f64),
+    Sum(Vec<Expression>),
+    Product(Vec<Expression>),
+    Quotient(Box<Expression>, Box<Expression>),
+}
+
+impl Expression {
+    fn validate(&self) -> Result<(), String> {
+        match self {
+            Expression::Attribute(name) => validate_attribute_name(name),
+            Expression::Constant(value) if !value.is_finite() => {
+                Err(format!("constant {} isn't a finite number", value))
+            }
+            Expression::Constant(_) => Ok(()),
+            Expression::Sum(terms) | Expression::Product(terms) if terms.is_empty() => {
+                Err("sum or product without terms".to_string())
+            }
+            Expression::Sum(terms) | Expression::Product(terms) => {
+                terms.iter().try_for_each(Expression::validate)
+            }
+            Expression::Quotient(numerator, denominator) => {
+                numerator.validate()?;
+                denominator.validate()
+            }
+        }
+    }
+
+    fn resolve<E>(
+        &self,
+        name_to_id_resolver: &dyn Fn(&str) -> Result<DogmaAttributeId, E>,
+    ) -> Result<ResolvedExpression, E> {
+        let resolve_all = |terms: &[Expression]| {
+            terms
+                .iter()
+                .map(|term| term.resolve(name_to_id_resolver))
+                .collect::<Result<Vec<_>, E>>()
+        };
+
+        Ok(match self {
+            Expression::Attribute(name) => {
+                ResolvedExpression::Attribute(name_to_id_resolver(name)?)
+            }
+            Expression::Constant(value) => ResolvedExpression::Constant(*value),
+            Expression::Sum(terms) => ResolvedExpression::Sum(resolve_all(terms)?),
+            Expression::Product(terms) => ResolvedExpression::Product(resolve_all(terms)?),
+            Expression::Quotient(numerator, denominator) => ResolvedExpression::Quotient(
+                Box::new(numerator.resolve(name_to_id_resolver)?),
+                Box::new(denominator.resolve(name_to_id_resolver)?),
+            ),
+        })
+    }
+}
+
+#[derive(Debug)]
+enum ResolvedExpression {
+    Attribute(DogmaAttributeId),
+    Constant(f64),
+    Sum(Vec<ResolvedExpression>),
+    Product(Vec<ResolvedExpression>),
+    Quotient(Box<ResolvedExpression>, Box<ResolvedExpression>),
+}
+
+impl ResolvedExpression {
+    /// Value of the expression, None if an attribute is missing or a divisor is zero
+    fn eval(&self, value_of: &dyn Fn(DogmaAttributeId) -> Option<f64>) -> Option<f64> {
+        match self {
+            ResolvedExpression::Attribute(id) => value_of(*id),
+            ResolvedExpression::Constant(value) => Some(*value),
+            ResolvedExpression::Sum(terms) => terms.iter().map(|t| t.eval(value_of)).sum(),
+            ResolvedExpression::Product(terms) => terms.iter().map(|t| t.eval(value_of)).product(),
+            ResolvedExpression::Quotient(numerator, denominator) => {
+                let denominator = denominator.eval(value_of)?;
+                if denominator == 0.0 {
+                    return None;
+                }
+                Some(numerator.eval(value_of)? / denominator)
+            }
+        }
+    }
+
+    /// Interval of the expression given the (min, max) interval of every attribute,
+    /// None if an attribute is missing or a divisor interval touches zero
+    fn eval_range(
+        &self,
+        range_of: &dyn Fn(DogmaAttributeId) -> Option<(f64, f64)>,
+    ) -> Option<(f64, f64)> {
+        match self {
+            ResolvedExpression::Attribute(id) => range_of(*id),
+            ResolvedExpression::Constant(value) => Some((*value, *value)),
+            ResolvedExpression::Sum(terms) => terms.iter().try_fold((0.0, 0.0), |acc, term| {
+                let (min, max) = term.eval_range(range_of)?;
+                Some((acc.0 + min, acc.1 + max))
+            }),
+            ResolvedExpression::Product(terms) => terms.iter().try_fold((1.0, 1.0), |acc, term| {
+                Some(interval_product(acc, term.eval_range(range_of)?))
+            }),
+            ResolvedExpression::Quotient(numerator, denominator) => {
+                let (min, max) = denominator.eval_range(range_of)?;
+                if min <= 0.0 && max >= 0.0 {
+                    return None;
+                }
+                Some(interval_product(
+                    numerator.eval_range(range_of)?,
+                    (1.0 / max, 1.0 / min),
+                ))
+            }
+        }
+    }
+
+    fn all_attributes(&self, present: &dyn Fn(DogmaAttributeId) -> bool) -> bool {
+        match self {
+            ResolvedExpression::Attribute(id) => present(*id),
+            ResolvedExpression::Constant(_) => true,
+            ResolvedExpression::Sum(terms) | ResolvedExpression::Product(terms) => {
+                terms.iter().all(|term| term.all_attributes(present))
+            }
+            ResolvedExpression::Quotient(numerator, denominator) => {
+                numerator.all_attributes(present) && denominator.all_attributes(present)
+            }
+        }
+    }
+}
+
+fn interval_product(a: (f64, f64), b: (f64, f64)) -> (f64, f64) {
+    let candidates = [a.0 * b.0, a.0 * b.1, a.1 * b.0, a.1 * b.1];
+    let min = candidates.iter().copied().fold(f64::INFINITY, f64::min);
+    let max = candidates.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+    (min, max)
 }
 
 #[derive(Debug)]
 struct ResolvedVirtualAttributeFormula {
     virtual_id: DogmaAttributeId,
+    name: String,
+    high_is_good: Option<bool>,
+    expression: ResolvedExpression,
+}
+
+struct BuiltinFormula {
     name: &'static str,
     high_is_good: Option<bool>,
-    numerator_attr_ids: Vec<DogmaAttributeId>,
-    denominator_attr_ids: Vec<DogmaAttributeId>,
+    numerator_attr_names: &'static [&'static str],
+    denominator_attr_names: &'static [&'static str],
 }
 
-const VIRTUAL_FORMULAS: &[VirtualAttributeFormula] = &[
-    VirtualAttributeFormula {
-        virtual_id: VIRTUAL_ARMOR_REPAIR_EFFICIENCY_ID,
+// Virtual ids follow this order, -1 for "Armor Repair Efficiency" and so on
+const BUILTIN_FORMULAS: &[BuiltinFormula] = &[
+    BuiltinFormula {
         name: "Armor Repair Efficiency",
         high_is_good: Some(true),
         numerator_attr_names: &["Armor Hitpoints Repaired"],
         denominator_attr_names: &["Activation Cost"],
     },
-    VirtualAttributeFormula {
-        virtual_id: VIRTUAL_ARMOR_REPAIR_SPEED_ID,
+    BuiltinFormula {
         name: "Armor Repair Speed",
         high_is_good: Some(true),
         numerator_attr_names: &["Armor Hitpoints Repaired"],
         denominator_attr_names: &["Activation time / duration"],
     },
-    VirtualAttributeFormula {
-        virtual_id: VIRTUAL_SHIELD_REPAIR_EFFICIENCY_ID,
+    BuiltinFormula {
         name: "Shield Repair Efficiency",
         high_is_good: Some(true),
         numerator_attr_names: &["Shield Bonus"],
         denominator_attr_names: &["Activation Cost"],
     },
-    VirtualAttributeFormula {
-        virtual_id: VIRTUAL_SHIELD_REPAIR_SPEED_ID,
+    BuiltinFormula {
         name: "Shield Repair Speed",
         high_is_good: Some(true),
         numerator_attr_names: &["Shield Bonus"],
         denominator_attr_names: &["Activation time / duration"],
     },
-    VirtualAttributeFormula {
-        virtual_id: VIRTUAL_DPS_MODIFIER_ID,
+    BuiltinFormula {
         name: "DPS Modifier",
         high_is_good: Some(true),
         numerator_attr_names: &["Damage Modifier"],
         denominator_attr_names: &["rate of fire bonus"],
     },
-    VirtualAttributeFormula {
-        virtual_id: VIRTUAL_MISSILE_DPS_MODIFIER_ID,
+    BuiltinFormula {
         name: "Missile DPS Modifier",
         high_is_good: Some(true),
         numerator_attr_names: &["Missile Damage Bonus"],
         denominator_attr_names: &["rate of fire bonus"],
     },
-    VirtualAttributeFormula {
-        virtual_id: VIRTUAL_NEUTRALIZATION_EFFICIENCY_ID,
+    BuiltinFormula {
         name: "Neutralization Efficiency",
         high_is_good: Some(true),
         numerator_attr_names: &["Neutralization Amount"],
@@ -80,146 +269,109 @@ const VIRTUAL_FORMULAS: &[VirtualAttributeFormula] = &[
     },
 ];
 
-static RESOLVED_FORMULAS: OnceLock<Vec<ResolvedVirtualAttributeFormula>> = OnceLock::new();
-
-pub fn initialize_virtual_attributes(name_to_id_resolver: &dyn Fn(&str) -> DogmaAttributeId) {
-    let resolved_formulas: Vec<ResolvedVirtualAttributeFormula> = VIRTUAL_FORMULAS
+pub fn default_virtual_formulas() -> Vec<VirtualAttributeFormula> {
+    let owned = |names: &[&str]| names.iter().map(|name| name.to_string()).collect();
+    BUILTIN_FORMULAS
         .iter()
-        .map(|formula| {
-            let numerator_attr_ids: Vec<DogmaAttributeId> = formula
-                .numerator_attr_names
-                .iter()
-                .map(|name| name_to_id_resolver(name))
-                .collect();
-
-            let denominator_attr_ids: Vec<DogmaAttributeId> = formula
-                .denominator_attr_names
-                .iter()
-                .map(|name| name_to_id_resolver(name))
-                .collect();
-
-            ResolvedVirtualAttributeFormula {
-                virtual_id: formula.virtual_id,
-                name: formula.name,
-                high_is_good: formula.high_is_good,
-                numerator_attr_ids,
-                denominator_attr_ids,
-            }
+        .map(|formula| VirtualAttributeFormula {
+            name: formula.name.to_string(),
+            high_is_good: formula.high_is_good,
+            numerator: owned(formula.numerator_attr_names),
+            denominator: owned(formula.denominator_attr_names),
+            expression: None,
         })
-        .collect();
-
-    let _ = RESOLVED_FORMULAS.set(resolved_formulas);
-}
-
-fn get_resolved_formulas() -> &'static Vec<ResolvedVirtualAttributeFormula> {
-    RESOLVED_FORMULAS
-        .get()
-        .expect("virtual attributes not initialized")
+        .collect()
 }
 
-pub fn append_attribute_values(attributes: &mut Vec<AttributeValue>) {
-    let resolved_formulas = get_resolved_formulas();
+/// Formulas from `VIRTUAL_ATTRIBUTES_FILE` in `dir`, the built-in ones if there is no such file
+pub fn load_virtual_formulas(dir: &str) -> Result<Vec<VirtualAttributeFormula>, std::io::Error> {
+    let path = Path::new(dir).join(VIRTUAL_ATTRIBUTES_FILE);
+    if !path.exists() {
+        return Ok(default_virtual_formulas());
+    }
 
-    for formula in resolved_formulas {
-        let mut numerator_product = 1.0;
-        let mut missing_numerators = 0;
+    let invalid_data = |e: String| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("{}: {}", path.display(), e),
+        )
+    };
+    let file = std::fs::File::open(&path)?;
+    let formulas: Vec<VirtualAttributeFormula> =
+        serde_json::from_reader(std::io::BufReader::new(file))
+            .map_err(|e| invalid_data(e.to_string()))?;
+    validate_formulas(&formulas).map_err(invalid_data)?;
+    Ok(formulas)
+}
 
-        for numerator_id in &formula.numerator_attr_ids {
-            let mut found = false;
-            for attr in attributes.iter() {
-                if attr.id == *numerator_id {
-                    numerator_product *= attr.value;
-                    found = true;
-                    break;
-                }
-            }
-            if !found {
-                missing_numerators += 1;
-            }
+/// Every formula is computable and has a name of its own. Attribute names are only
+/// checked against the SDE when the report resolves them.
+fn validate_formulas(formulas: &[VirtualAttributeFormula]) -> Result<(), String> {
+    let mut names = HashSet::new();
+    for formula in formulas {
+        formula.validate()?;
+        if !names.insert(formula.name.as_str()) {
+            return Err(format!("{}: defined more than once", formula.name));
         }
+    }
+    Ok(())
+}
 
-        let mut denominator_product = 1.0;
-        let mut missing_denominators = 0;
-
-        for denominator_id in &formula.denominator_attr_ids {
-            let mut found = false;
-            for attr in attributes.iter() {
-                if attr.id == *denominator_id {
-                    denominator_product *= attr.value;
-                    found = true;
-                    break;
-                }
-            }
-            if !found {
-                missing_denominators += 1;
-            }
-        }
+/// Virtual attribute formulas with attribute names resolved to ids of the loaded SDE
+#[derive(Debug)]
+pub struct VirtualAttributes {
+    formulas: Vec<ResolvedVirtualAttributeFormula>,
+}
 
-        let can_calculate =
-            missing_numerators == 0 && missing_denominators == 0 && denominator_product != 0.0;
+impl VirtualAttributes {
+    pub fn resolve<E>(
+        formulas: &[VirtualAttributeFormula],
+        name_to_id_resolver: &dyn Fn(&str) -> Result<DogmaAttributeId, E>,
+    ) -> Result<Self, E> {
+        let formulas = formulas
+            .iter()
+            .zip(1..)
+            .map(|(formula, position)| {
+                Ok(ResolvedVirtualAttributeFormula {
+                    virtual_id: -position,
+                    name: formula.name.clone(),
+                    high_is_good: formula.high_is_good,
+                    expression: formula.expression().resolve(name_to_id_resolver)?,
+                })
+            })
+            .collect::<Result<Vec<_>, E>>()?;
 
-        if can_calculate {
-            attributes.push(AttributeValue {
-                id: formula.virtual_id,
-                value: numerator_product / denominator_product,
-            });
-        }
+        Ok(Self { formulas })
     }
-}
 
-pub fn append_min_max_attribute_values(attributes: &mut Vec<AttributeRange>) {
-    let resolved_formulas = get_resolved_formulas();
-
-    for formula in resolved_formulas {
-        let mut min_numerator_product = 1.0;
-        let mut max_numerator_product = 1.0;
-        let mut missing_numerators = 0;
-
-        for numerator_id in &formula.numerator_attr_ids {
-            let mut found_attribute = false;
-            for attr in attributes.iter() {
-                if attr.id == *numerator_id {
-                    min_numerator_product *= attr.min;
-                    max_numerator_product *= attr.max;
-                    found_attribute = true;
-                    break;
-                }
-            }
-
-            if !found_attribute {
-                missing_numerators += 1;
-            }
-        }
+    pub fn append_attribute_values(&self, attributes: &mut Vec<AttributeValue>) {
+        for formula in &self.formulas {
+            let value = formula.expression.eval(&|id| {
+                attributes
+                    .iter()
+                    .find(|attr| attr.id == id)
+                    .map(|attr| attr.value)
+            });
 
-        let mut min_denominator_product = 1.0;
-        let mut max_denominator_product = 1.0;
-        let mut missing_denominators = 0;
-
-        for denominator_id in &formula.denominator_attr_ids {
-            let mut found_attribute = false;
-            for attr in attributes.iter() {
-                if attr.id == *denominator_id {
-                    min_denominator_product *= attr.min;
-                    max_denominator_product *= attr.max;
-                    found_attribute = true;
-                    break;
-                }
-            }
-            if !found_attribute {
-                missing_denominators += 1;
+            if let Some(value) = value {
+                attributes.push(AttributeValue {
+                    id: formula.virtual_id,
+                    value,
+                });
             }
         }
+    }
 
-        let can_calculate = missing_numerators == 0 && missing_denominators == 0;
-
-        if can_calculate {
-            if min_denominator_product != 0.0 && max_denominator_product != 0.0 {
-                let v1 = min_numerator_product / max_denominator_product;
-                let v2 = max_numerator_product / min_denominator_product;
-
-                let min = v1.min(v2);
-                let max = v1.max(v2);
+    pub fn append_min_max_attribute_values(&self, attributes: &mut Vec<AttributeRange>) {
+        for formula in &self.formulas {
+            let range = formula.expression.eval_range(&|id| {
+                attributes
+                    .iter()
+                    .find(|attr| attr.id == id)
+                    .map(|attr| (attr.min, attr.max))
+            });
 
+            if let Some((min, max)) = range {
                 attributes.push(AttributeRange {
                     id: formula.virtual_id,
                     min,
@@ -228,50 +380,181 @@ pub fn append_min_max_attribute_values(attributes: &mut Vec<AttributeRange>) {
             }
         }
     }
+
+    pub fn append_varying_attributes(&self, attributes: &mut Vec<VaryingAttribute>) {
+        for formula in &self.formulas {
+            let can_calculate = formula
+                .expression
+                .all_attributes(&|id| attributes.iter().any(|attr| attr.id == id));
+
+            if can_calculate {
+                attributes.push(VaryingAttribute {
+                    id: formula.virtual_id,
+                    name: formula.name.clone(),
+                    high_is_good: formula.high_is_good,
+                });
+            }
+        }
+    }
 }
 
-pub fn append_varying_attributes(attributes: &mut Vec<VaryingAttribute>) {
-    let resolved_formulas = get_resolved_formulas();
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    for formula in resolved_formulas {
-        let mut missing_numerators = 0;
+    fn parse(json: &str) -> Vec<VirtualAttributeFormula> {
+        serde_json::from_str(json).unwrap()
+    }
 
-        for numerator_id in &formula.numerator_attr_ids {
-            let mut found = false;
-            for attr in attributes.iter() {
-                if attr.id == *numerator_id {
-                    found = true;
-                    break;
-                }
-            }
-            if !found {
-                missing_numerators += 1;
-            }
+    #[test]
+    fn builtin_formulas_are_valid() {
+        assert_eq!(validate_formulas(&default_virtual_formulas()), Ok(()));
+    }
+
+    #[test]
+    fn formulas_from_the_file_are_validated() {
+        let valid = parse(
+            r#"[
+                {"name": "Cap per second", "numerator": ["Activation Cost"],
+                 "denominator": ["Activation time / duration"]},
+                {"name": "Raw HP", "expression": {"sum": [
+                    {"attribute": "Shield Capacity"}, {"attribute": "Armor Hitpoints"}]}}
+            ]"#,
+        );
+        assert_eq!(validate_formulas(&valid), Ok(()));
+
+        let invalid = [
+            (r#"[{"name": "Nothing"}]"#, "Nothing: neither"),
+            (
+                r#"[{"name": "", "numerator": ["Shield Bonus"]}]"#,
+                "formula without a name",
+            ),
+            (
+                r#"[{"name": "Both", "numerator": ["Shield Bonus"],
+                     "expression": {"attribute": "Shield Bonus"}}]"#,
+                "Both: both",
+            ),
+            (
+                r#"[{"name": "Empty", "expression": {"sum": []}}]"#,
+                "Empty: sum",
+            ),
+            (
+                r#"[{"name": "Blank", "numerator": [" "]}]"#,
+                "Blank: empty attribute",
+            ),
+            (
+                r#"[{"name": "Twice", "numerator": ["Shield Bonus"]},
+                    {"name": "Twice", "numerator": ["Armor Hitpoints Repaired"]}]"#,
+                "Twice: defined more than once",
+            ),
+        ];
+        for (json, error) in invalid {
+            let result = validate_formulas(&parse(json));
+            assert!(
+                result.as_ref().is_err_and(|e| e.starts_with(error)),
+                "{json}: {result:?}"
+            );
         }
+    }
 
-        let mut missing_denominators = 0;
+    #[test]
+    fn invalid_file_fails_to_load() {
+        let dir = std::env::temp_dir().join(format!("virtual-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(
+            dir.join(VIRTUAL_ATTRIBUTES_FILE),
+            r#"[{"name": "Nothing"}]"#,
+        )
+        .unwrap();
+
+        let error = load_virtual_formulas(dir.to_str().unwrap()).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+    }
 
-        for denominator_id in &formula.denominator_attr_ids {
-            let mut found = false;
-            for attr in attributes.iter() {
-                if attr.id == *denominator_id {
-                    found = true;
-                    break;
-                }
-            }
-            if !found {
-                missing_denominators += 1;
+    /// Attributes named "A", "B" and "C" get the ids 1, 2 and 3
+    fn resolve(formula: &str) -> ResolvedExpression {
+        let formula: VirtualAttributeFormula = serde_json::from_str(formula).unwrap();
+        let name_to_id = |name: &str| -> Result<DogmaAttributeId, String> {
+            match name {
+                "A" => Ok(1),
+                "B" => Ok(2),
+                "C" => Ok(3),
+                _ => Err(format!("unknown attribute {name}")),
             }
-        }
+        };
+        formula.expression().resolve(&name_to_id).unwrap()
+    }
 
-        let can_calculate = missing_numerators == 0 && missing_denominators == 0;
+    fn lookup<T: Copy>(values: &[T]) -> impl Fn(DogmaAttributeId) -> Option<T> + '_ {
+        |id| values.get(id as usize - 1).copied()
+    }
 
-        if can_calculate {
-            attributes.push(VaryingAttribute {
-                id: formula.virtual_id,
-                name: formula.name.to_string(),
-                high_is_good: formula.high_is_good,
-            });
-        }
+    #[test]
+    fn sum_adds_the_bounds() {
+        let sum = resolve(
+            r#"{"name": "Sum", "expression": {"sum": [
+                {"attribute": "A"}, {"attribute": "B"}, {"constant": 5}]}}"#,
+        );
+
+        assert_eq!(sum.eval(&lookup(&[1.5, 10.0])), Some(16.5));
+        assert_eq!(
+            sum.eval_range(&lookup(&[(1.0, 2.0), (10.0, 20.0)])),
+            Some((16.0, 27.0))
+        );
+        assert_eq!(sum.eval_range(&lookup(&[(1.0, 2.0)])), None);
+    }
+
+    #[test]
+    fn product_of_signed_ranges_takes_the_extreme_corners() {
+        let product = resolve(
+            r#"{"name": "Product", "expression": {"product": [
+                {"attribute": "A"}, {"attribute": "B"}]}}"#,
+        );
+
+        assert_eq!(product.eval(&lookup(&[-2.0, 4.0])), Some(-8.0));
+        // -2 * -5 = 10, -2 * 4 = -8, 3 * -5 = -15, 3 * 4 = 12
+        assert_eq!(
+            product.eval_range(&lookup(&[(-2.0, 3.0), (-5.0, 4.0)])),
+            Some((-15.0, 12.0))
+        );
+        assert_eq!(
+            product.eval_range(&lookup(&[(-3.0, -2.0), (-5.0, -4.0)])),
+            Some((8.0, 15.0))
+        );
+    }
+
+    #[test]
+    fn quotient_by_a_range_containing_zero_has_no_range() {
+        let quotient = resolve(
+            r#"{"name": "Quotient", "expression": {"quotient": [
+                {"attribute": "A"}, {"attribute": "B"}]}}"#,
+        );
+
+        assert_eq!(quotient.eval(&lookup(&[3.0, 0.0])), None);
+        assert_eq!(
+            quotient.eval_range(&lookup(&[(1.0, 2.0), (-1.0, 1.0)])),
+            None
+        );
+        assert_eq!(
+            quotient.eval_range(&lookup(&[(1.0, 2.0), (0.0, 1.0)])),
+            None
+        );
+        assert_eq!(
+            quotient.eval_range(&lookup(&[(1.0, 2.0), (-4.0, -2.0)])),
+            Some((-1.0, -0.25))
+        );
+    }
+
+    #[test]
+    fn numerator_denominator_formula_divides_the_products() {
+        let legacy =
+            resolve(r#"{"name": "Legacy", "numerator": ["A", "B"], "denominator": ["C"]}"#);
+
+        assert_eq!(legacy.eval(&lookup(&[3.0, 2.0, 1.5])), Some(4.0));
+        // (2..4 * 1..3) / 0.5..2 = 2..12 * 0.5..2
+        assert_eq!(
+            legacy.eval_range(&lookup(&[(2.0, 4.0), (1.0, 3.0), (0.5, 2.0)])),
+            Some((1.0, 24.0))
+        );
     }
 }
@@ -1,4 +1,5 @@
 mod client;
+pub mod config;
 pub mod db;
 pub mod eve;
 mod mydb;
@@ -11,17 +12,22 @@ pub mod context;
 pub mod handlers;
 pub mod saga;
 
-pub use client::RatelimitedClient;
+pub use client::{
+    CircuitState, ClientMetrics, LatencyBucket, RatelimitedClient, RatelimitedClientBuilder,
+};
+pub use config::Config;
 pub use db::CharacterAssetsDb;
 pub use eve::esi;
 pub use eve::hoboleaks;
 pub use eve::sde;
 pub use eve::{
-    AssetItem, AssetName, CharacterId, CharacterResponse, DogmaAttribute, DogmaAttributeConcise,
-    DogmaAttributeId, DynamicId, DynamicItem, ItemId, ItemType, MarketGroup, MarketGroupId,
-    MarketOrder, RegionId, Station, StationId, TypeId,
+    AssetItem, AssetName, BlueprintInfo, BlueprintMaterial, CharacterId, CharacterResponse,
+    CorporationId, DogmaAttribute, DogmaAttributeConcise, DogmaAttributeId, DynamicId, DynamicItem,
+    ItemId, ItemType, LocationKind, MarketGroup, MarketGroupId, MarketHistoryDay, MarketOrder,
+    Region, RegionId, SolarSystem, SolarSystemId, Station, StationId, Structure, StructureId,
+    TypeId, UniverseName, WalletTransaction,
 };
-pub use mydb::{AllAssetsDb, AssetsDb, DynamicsDb};
+pub use mydb::{AllAssetsDb, AssetsDb, DynamicsDb, MarketHistoryDb, MarketOrdersDb};
 pub use ratelimit::{Ratelimit, RatelimitGroup};
 
-pub use context::{AppContext, CharacterClient, CharacterManager, OauthConfig};
+pub use context::{AppContext, AssetsJob, CharacterClient, CharacterManager, OauthConfig};
@@ -1,10 +1,16 @@
 use crate::eve::{AssetItem, CharacterId, ItemId};
+use crate::mydb::versioned;
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
-use serde_cbor;
 use std::collections::BTreeMap;
 use std::io;
 use std::path::Path;
+use tracing::{debug, info};
+
+/// Version of the assets.cbor layout, see `versioned`. The file is only ever written,
+/// `AssetsDb::from_dir` starts empty and the assets are fetched again, so unversioned
+/// files need no migration path.
+const FORMAT_VERSION: u32 = 1;
 
 #[derive(Serialize, Deserialize)]
 pub struct AllAssetsDb {
@@ -66,7 +72,7 @@ impl AllAssetsDb {
 
     pub fn store(&mut self) -> Result<(), std::io::Error> {
         for (character_id, db) in self.db.iter_mut() {
-            println!("Storing assets for character {}", character_id);
+            info!("Storing assets for character {}", character_id);
             db.store()?;
         }
         Ok(())
@@ -102,12 +108,11 @@ impl AssetsDb {
             self.last_stored_at = Utc::now();
             let file_path = Self::last_file(&self.dir);
             let temp_path = format!("{file_path}.tmp");
-            let encoded = serde_cbor::ser::to_vec(&self)
-                .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+            let encoded = versioned::encode(FORMAT_VERSION, &*self)?;
             std::fs::write(&temp_path, encoded)?;
             std::fs::rename(temp_path, file_path)?;
         } else {
-            println!("Using old file")
+            debug!("Using old file")
         }
 
         Ok(())
@@ -1,9 +1,17 @@
+use crate::mydb::versioned;
 use crate::{DynamicId, DynamicItem};
 use chrono::{DateTime, Utc};
+use parking_lot::Mutex;
 use serde::{Deserialize, Serialize};
 use serde_cbor;
 use std::collections::BTreeMap;
 use std::path::Path;
+use std::sync::Arc;
+use tokio::sync::RwLock;
+use tracing::{debug, error, info, warn};
+
+/// Version of the dynamics.cbor layout, see `versioned`
+const FORMAT_VERSION: u32 = 1;
 
 #[derive(Serialize, Deserialize)]
 pub struct DynamicsDb {
@@ -11,6 +19,40 @@ pub struct DynamicsDb {
     dir: String,
     pub last_stored_at: DateTime<Utc>,
     pub last_updated_at: DateTime<Utc>,
+    /// When the newest written snapshot was taken, shared with the snapshots
+    #[serde(skip)]
+    written_at: Arc<Mutex<DateTime<Utc>>>,
+}
+
+/// Encoded dynamics db taken by `DynamicsDb::snapshot`, waiting to be written
+pub struct DynamicsSnapshot {
+    file_path: String,
+    encoded: Vec<u8>,
+    count: usize,
+    taken_at: DateTime<Utc>,
+    written_at: Arc<Mutex<DateTime<Utc>>>,
+}
+
+impl DynamicsSnapshot {
+    /// Write the snapshot to the data dir, blocking. Returns when it was taken, to be
+    /// passed to `DynamicsDb::mark_stored`.
+    ///
+    /// Writes are serialized, and a snapshot older than the file already written is
+    /// dropped: the autosave may still be writing when the shutdown stores.
+    pub fn write(self) -> Result<DateTime<Utc>, std::io::Error> {
+        let mut written_at = self.written_at.lock();
+        if *written_at >= self.taken_at {
+            debug!("⏭️ Dropping a dynamics snapshot older than the stored file");
+            return Ok(self.taken_at);
+        }
+
+        let temp_path = format!("{}.tmp", self.file_path);
+        std::fs::write(&temp_path, self.encoded)?;
+        std::fs::rename(temp_path, &self.file_path)?;
+        *written_at = self.taken_at;
+        info!("✅ Dynamics stored successfully with {} items", self.count);
+        Ok(self.taken_at)
+    }
 }
 
 impl DynamicsDb {
@@ -19,35 +61,14 @@ impl DynamicsDb {
         let path = Path::new(&file_path);
         if path.exists() {
             let cbor_data = std::fs::read(&path)?;
-
-            match serde_cbor::from_slice::<DynamicsDb>(&cbor_data) {
-                Ok(db) => {
-                    println!("sucessfully deserialized DynamicItemDb");
-                    return Ok(db);
-                }
-                Err(e) => {
-                    eprintln!("Error deserializing DynamicItemDb: {}", e);
-
-                    match serde_cbor::from_slice::<BTreeMap<DynamicId, DynamicItem>>(&cbor_data) {
-                        Ok(db_map) => {
-                            eprintln!("sucessfully deserialized just the BTreeMap portion");
-                            return Ok(DynamicsDb {
-                                db: db_map,
-                                dir: dir.to_string(),
-                                last_updated_at: Utc::now(),
-                                last_stored_at: Utc::now(),
-                            });
-                        }
-                        Err(e2) => {
-                            eprintln!("error deserializing BTreeMap: {}", e2);
-                            return Err(std::io::Error::new(
-                                std::io::ErrorKind::InvalidData,
-                                format!("failed to deserialize the database file: {e}"),
-                            ));
-                        }
-                    }
-                }
-            }
+            let mut db = versioned::decode(&cbor_data, FORMAT_VERSION, |legacy| {
+                Self::migrate_legacy(legacy, dir)
+            })?;
+            info!("sucessfully deserialized DynamicItemDb");
+            // The stored dir is where the file was first written, the data dir may
+            // have moved since
+            db.dir = dir.to_string();
+            return Ok(db);
         }
 
         let now = Utc::now();
@@ -56,46 +77,101 @@ impl DynamicsDb {
             dir: dir.to_string().clone(),
             last_stored_at: now,
             last_updated_at: now,
+            written_at: Default::default(),
         })
     }
 
+    /// Files from before the versioned envelope hold the bare `DynamicsDb`, or even
+    /// older ones just its BTreeMap
+    fn migrate_legacy(cbor_data: &[u8], dir: &str) -> Result<DynamicsDb, std::io::Error> {
+        match serde_cbor::from_slice::<DynamicsDb>(cbor_data) {
+            Ok(db) => Ok(db),
+            Err(e) => {
+                warn!("Error deserializing DynamicItemDb: {}", e);
+
+                match serde_cbor::from_slice::<BTreeMap<DynamicId, DynamicItem>>(cbor_data) {
+                    Ok(db_map) => {
+                        warn!("sucessfully deserialized just the BTreeMap portion");
+                        Ok(DynamicsDb {
+                            db: db_map,
+                            dir: dir.to_string(),
+                            last_updated_at: Utc::now(),
+                            last_stored_at: Utc::now(),
+                            written_at: Default::default(),
+                        })
+                    }
+                    Err(e2) => {
+                        error!("error deserializing BTreeMap: {}", e2);
+                        Err(std::io::Error::new(
+                            std::io::ErrorKind::InvalidData,
+                            format!("failed to deserialize the database file: {e}"),
+                        ))
+                    }
+                }
+            }
+        }
+    }
+
     pub fn add(&mut self, id: DynamicId, item: DynamicItem) {
         self.db.insert(id, item);
         let old_updated = self.last_updated_at;
         self.last_updated_at = Utc::now();
-        println!(
+        debug!(
             "➕ Added dynamic {:?}, updated timestamp from {} to {}",
             id, old_updated, self.last_updated_at
         );
     }
 
+    /// Whether there are changes `store` would write
+    pub fn needs_store(&self) -> bool {
+        self.last_stored_at < self.last_updated_at
+    }
+
     pub fn store(&mut self) -> Result<(), std::io::Error> {
-        println!(
+        if let Some(snapshot) = self.snapshot()? {
+            let taken_at = snapshot.write()?;
+            self.mark_stored(taken_at);
+        }
+        Ok(())
+    }
+
+    /// Record that a snapshot taken at `taken_at` was written. Only a completed write
+    /// counts as stored, a failed one is retried next time
+    pub fn mark_stored(&mut self, taken_at: DateTime<Utc>) {
+        self.last_stored_at = self.last_stored_at.max(taken_at);
+    }
+
+    /// Encode the db if it changed since it was last stored, so the file can be written
+    /// without holding the lock. None if there is nothing to store, the db stays
+    /// unstored until `mark_stored` is called with the time the snapshot returns
+    pub fn snapshot(&mut self) -> Result<Option<DynamicsSnapshot>, std::io::Error> {
+        debug!(
             "🔍 Store called - last_stored: {}, last_updated: {}, need_store: {}",
             self.last_stored_at,
             self.last_updated_at,
             self.last_stored_at < self.last_updated_at
         );
 
-        if self.last_stored_at < self.last_updated_at {
-            self.last_stored_at = Utc::now();
-            let file_path = Self::last_file(&self.dir);
-            let temp_path = format!("{file_path}.tmp");
-            let encoded = serde_cbor::ser::to_vec(&self)
-                .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
-            std::fs::write(&temp_path, encoded)?;
-            std::fs::rename(temp_path, file_path)?;
-            println!(
-                "✅ Dynamics stored successfully with {} items",
-                self.db.len()
-            );
-        } else {
-            println!(
+        if !self.needs_store() {
+            debug!(
                 "⏭️ Using old file - no changes to store (count: {})",
                 self.db.len()
             );
+            return Ok(None);
         }
-        Ok(())
+
+        // The file is what will be stored, so it says it was stored at the snapshot
+        let taken_at = Utc::now();
+        let last_stored_at = std::mem::replace(&mut self.last_stored_at, taken_at);
+        let encoded = versioned::encode(FORMAT_VERSION, &*self);
+        self.last_stored_at = last_stored_at;
+        Ok(Some(DynamicsSnapshot {
+            file_path: Self::last_file(&self.dir),
+            encoded: encoded?,
+            count: self.db.len(),
+            taken_at,
+            written_at: self.written_at.clone(),
+        }))
     }
 
     pub fn contain(&self, id: DynamicId) -> bool {
@@ -106,6 +182,37 @@ impl DynamicsDb {
         self.db.get(&id)
     }
 
+    /// Get a dynamic from the db behind `db`, fetching it with `f` on a miss.
+    /// Returns the item and whether it had to be fetched.
+    ///
+    /// Locking discipline: the read lock is released before `f` is awaited and the
+    /// write lock is only taken to insert the fetched item, so a slow ESI call never
+    /// blocks other readers. Two callers racing on the same id may both fetch it,
+    /// the first inserted item wins and the db is only marked updated once.
+    pub async fn get_or_insert_with<F, Fut, E>(
+        db: &RwLock<DynamicsDb>,
+        id: DynamicId,
+        f: F,
+    ) -> Result<(DynamicItem, bool), E>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<DynamicItem, E>>,
+    {
+        if let Some(item) = db.read().await.get(id) {
+            return Ok((item.clone(), false));
+        }
+
+        let item = f().await?;
+
+        let mut db = db.write().await;
+        if let Some(existing) = db.get(id) {
+            return Ok((existing.clone(), true));
+        }
+        db.add(id, item.clone());
+
+        Ok((item, true))
+    }
+
     pub fn len(&self) -> usize {
         self.db.len()
     }
@@ -114,3 +221,67 @@ impl DynamicsDb {
         format!("{}/dynamics/dynamics.cbor", dir)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir() -> String {
+        let dir = std::env::temp_dir().join(format!("dynamics-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir.to_str().unwrap().to_string()
+    }
+
+    fn dynamic(item_id: i64) -> (DynamicId, DynamicItem) {
+        let item = DynamicItem {
+            created_by: 0,
+            dogma_attributes: vec![],
+            dogma_effects: vec![],
+            mutator_type_id: 47408.into(),
+            source_type_id: 5945.into(),
+        };
+        ((47745.into(), item_id.into()), item)
+    }
+
+    #[test]
+    fn failed_write_is_retried() {
+        let dir = temp_dir();
+        let mut db = DynamicsDb::from_dir(&dir).unwrap();
+        let (id, item) = dynamic(1);
+        db.add(id, item);
+
+        // No dynamics dir yet, the write fails
+        let snapshot = db.snapshot().unwrap().unwrap();
+        assert!(snapshot.write().is_err());
+        assert!(db.needs_store());
+
+        std::fs::create_dir_all(format!("{dir}/dynamics")).unwrap();
+        db.store().unwrap();
+        assert!(!db.needs_store());
+
+        let reloaded = DynamicsDb::from_dir(&dir).unwrap();
+        assert!(reloaded.contain(id));
+        assert!(!reloaded.needs_store());
+    }
+
+    #[test]
+    fn older_snapshot_does_not_overwrite_a_newer_file() {
+        let dir = temp_dir();
+        std::fs::create_dir_all(format!("{dir}/dynamics")).unwrap();
+        let mut db = DynamicsDb::from_dir(&dir).unwrap();
+        let (first, item) = dynamic(1);
+        db.add(first, item);
+        let stale = db.snapshot().unwrap().unwrap();
+
+        let (second, item) = dynamic(2);
+        db.add(second, item);
+        db.store().unwrap();
+        // An autosave finishing after the shutdown store
+        let taken_at = stale.write().unwrap();
+        db.mark_stored(taken_at);
+        assert!(!db.needs_store());
+
+        let reloaded = DynamicsDb::from_dir(&dir).unwrap();
+        assert_eq!(reloaded.len(), 2);
+    }
+}
@@ -0,0 +1,55 @@
+use crate::mydb::persisted::PersistedMap;
+use crate::{MarketHistoryDay, RegionId, TypeId};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MarketHistoryEntry {
+    /// Oldest day first, as ESI sends them
+    pub days: Vec<MarketHistoryDay>,
+    pub updated_at: DateTime<Utc>,
+}
+
+/// Daily market aggregates per region and type
+pub struct MarketHistoryDb {
+    db: PersistedMap<(RegionId, TypeId), MarketHistoryEntry>,
+}
+
+impl MarketHistoryDb {
+    pub fn from_dir(dir: &str) -> Result<MarketHistoryDb, std::io::Error> {
+        Ok(MarketHistoryDb {
+            db: PersistedMap::from_dir(dir, "market_history.cbor", "Market history")?,
+        })
+    }
+
+    /// Replace the history of a region and type with a freshly fetched one. ESI keeps
+    /// about a year, older days already stored are kept.
+    pub fn replace(&mut self, region_id: RegionId, type_id: TypeId, days: Vec<MarketHistoryDay>) {
+        let mut merged: BTreeMap<_, _> = self
+            .db
+            .remove(&(region_id, type_id))
+            .map(|entry| entry.days)
+            .unwrap_or_default()
+            .into_iter()
+            .map(|day| (day.date, day))
+            .collect();
+        merged.extend(days.into_iter().map(|day| (day.date, day)));
+
+        self.db.insert(
+            (region_id, type_id),
+            MarketHistoryEntry {
+                days: merged.into_values().collect(),
+                updated_at: Utc::now(),
+            },
+        );
+    }
+
+    pub fn get(&self, region_id: RegionId, type_id: TypeId) -> Option<&MarketHistoryEntry> {
+        self.db.get(&(region_id, type_id))
+    }
+
+    pub fn store(&mut self) -> Result<(), std::io::Error> {
+        self.db.store()
+    }
+}
@@ -0,0 +1,60 @@
+use crate::mydb::persisted::PersistedMap;
+use crate::{MarketOrder, RegionId, TypeId};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+/// (region, type, is_buy)
+pub type MarketOrdersKey = (RegionId, TypeId, bool);
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MarketOrdersEntry {
+    pub orders: Vec<MarketOrder>,
+    pub updated_at: DateTime<Utc>,
+}
+
+/// Latest resolved market orders per region, type and side
+pub struct MarketOrdersDb {
+    db: PersistedMap<MarketOrdersKey, MarketOrdersEntry>,
+}
+
+impl MarketOrdersDb {
+    pub fn from_dir(dir: &str) -> Result<MarketOrdersDb, std::io::Error> {
+        Ok(MarketOrdersDb {
+            db: PersistedMap::from_dir(dir, "market_orders.cbor", "Market orders")?,
+        })
+    }
+
+    /// Replace all orders of a region, type and side with a freshly resolved set
+    pub fn replace(&mut self, key: MarketOrdersKey, orders: Vec<MarketOrder>) {
+        self.db.insert(
+            key,
+            MarketOrdersEntry {
+                orders,
+                updated_at: Utc::now(),
+            },
+        );
+    }
+
+    pub fn get(&self, key: MarketOrdersKey) -> Option<&MarketOrdersEntry> {
+        self.db.get(&key)
+    }
+
+    /// Lowest sell or highest buy price, None if there are no orders
+    pub fn best_price(&self, region_id: RegionId, type_id: TypeId, is_buy: bool) -> Option<f64> {
+        let prices = self
+            .get((region_id, type_id, is_buy))?
+            .orders
+            .iter()
+            .map(|order| order.price());
+
+        if is_buy {
+            prices.max_by(f64::total_cmp)
+        } else {
+            prices.min_by(f64::total_cmp)
+        }
+    }
+
+    pub fn store(&mut self) -> Result<(), std::io::Error> {
+        self.db.store()
+    }
+}
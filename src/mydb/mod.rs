@@ -1,5 +1,11 @@
 pub mod assets;
 pub mod dynamics;
+pub mod market_history;
+pub mod market_orders;
+pub(crate) mod persisted;
+pub(crate) mod versioned;
 
 pub use assets::{AllAssetsDb, AssetsDb};
 pub use dynamics::DynamicsDb;
+pub use market_history::MarketHistoryDb;
+pub use market_orders::MarketOrdersDb;
@@ -0,0 +1,177 @@
+// mydb/persisted.rs - BTreeMap kept in memory and stored as one versioned cbor file
+use crate::mydb::versioned;
+use chrono::{DateTime, Utc};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io;
+use std::path::Path;
+use tracing::info;
+
+/// Version of the files written by `PersistedMap`, see `versioned`
+const FORMAT_VERSION: u32 = 1;
+
+/// What goes to the file, the data dir isn't part of it so a moved data dir keeps working.
+/// Files from before the versioned envelope hold the same fields plus the old `dir`,
+/// which is ignored.
+#[derive(Deserialize)]
+struct Stored<K: Ord, V> {
+    db: BTreeMap<K, V>,
+    last_stored_at: DateTime<Utc>,
+    last_updated_at: DateTime<Utc>,
+}
+
+/// `Stored` borrowing the map, to encode it without a copy
+#[derive(Serialize)]
+struct StoredRef<'a, K: Ord, V> {
+    db: &'a BTreeMap<K, V>,
+    last_stored_at: DateTime<Utc>,
+    last_updated_at: DateTime<Utc>,
+}
+
+/// Map database stored to `{dir}/{file_name}`, written by `store` only if it changed
+pub struct PersistedMap<K: Ord, V> {
+    db: BTreeMap<K, V>,
+    file_path: String,
+    /// Name of the db in the logs
+    name: &'static str,
+    pub last_stored_at: DateTime<Utc>,
+    pub last_updated_at: DateTime<Utc>,
+}
+
+impl<K, V> PersistedMap<K, V>
+where
+    K: Ord + Serialize + DeserializeOwned,
+    V: Serialize + DeserializeOwned,
+{
+    /// Read the map from `{dir}/{file_name}`, an empty one if the file doesn't exist
+    pub fn from_dir(dir: &str, file_name: &str, name: &'static str) -> Result<Self, io::Error> {
+        let file_path = format!("{}/{}", dir, file_name);
+        if Path::new(&file_path).exists() {
+            let cbor_data = std::fs::read(&file_path)?;
+            let stored: Stored<K, V> = versioned::decode(&cbor_data, FORMAT_VERSION, |legacy| {
+                serde_cbor::from_slice(legacy).map_err(|e| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("failed to deserialize {file_path}: {e}"),
+                    )
+                })
+            })?;
+            info!("successfully deserialized {}", name);
+            return Ok(Self {
+                db: stored.db,
+                file_path,
+                name,
+                last_stored_at: stored.last_stored_at,
+                last_updated_at: stored.last_updated_at,
+            });
+        }
+
+        let now = Utc::now();
+        Ok(Self {
+            db: BTreeMap::new(),
+            file_path,
+            name,
+            last_stored_at: now,
+            last_updated_at: now,
+        })
+    }
+
+    pub fn get(&self, key: &K) -> Option<&V> {
+        self.db.get(key)
+    }
+
+    pub fn insert(&mut self, key: K, value: V) {
+        self.db.insert(key, value);
+        self.last_updated_at = Utc::now();
+    }
+
+    pub fn remove(&mut self, key: &K) -> Option<V> {
+        let value = self.db.remove(key);
+        if value.is_some() {
+            self.last_updated_at = Utc::now();
+        }
+        value
+    }
+
+    pub fn store(&mut self) -> Result<(), io::Error> {
+        if self.last_stored_at < self.last_updated_at {
+            self.last_stored_at = Utc::now();
+            let temp_path = format!("{}.tmp", self.file_path);
+            let stored = StoredRef {
+                db: &self.db,
+                last_stored_at: self.last_stored_at,
+                last_updated_at: self.last_updated_at,
+            };
+            let encoded = versioned::encode(FORMAT_VERSION, &stored)?;
+            std::fs::write(&temp_path, encoded)?;
+            std::fs::rename(temp_path, &self.file_path)?;
+            info!(
+                "✅ {} stored successfully for {} targets",
+                self.name,
+                self.db.len()
+            );
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir() -> String {
+        let dir = std::env::temp_dir().join(format!("persisted-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir.to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn stored_map_reads_back_from_another_dir() {
+        let dir = temp_dir();
+        let mut map: PersistedMap<(i64, i32), String> =
+            PersistedMap::from_dir(&dir, "map.cbor", "Test map").unwrap();
+        map.insert((10000002, 44992), "plex".to_string());
+        map.store().unwrap();
+
+        let moved = temp_dir();
+        std::fs::rename(format!("{dir}/map.cbor"), format!("{moved}/map.cbor")).unwrap();
+        let map: PersistedMap<(i64, i32), String> =
+            PersistedMap::from_dir(&moved, "map.cbor", "Test map").unwrap();
+
+        assert_eq!(
+            map.get(&(10000002, 44992)).map(String::as_str),
+            Some("plex")
+        );
+        assert_eq!(map.file_path, format!("{moved}/map.cbor"));
+    }
+
+    #[test]
+    fn unversioned_file_with_dir_is_migrated() {
+        #[derive(Serialize)]
+        struct Legacy {
+            db: BTreeMap<(i64, i32), String>,
+            dir: String,
+            last_stored_at: DateTime<Utc>,
+            last_updated_at: DateTime<Utc>,
+        }
+
+        let dir = temp_dir();
+        let legacy = Legacy {
+            db: BTreeMap::from([((10000002, 40520), "LSI".to_string())]),
+            dir: "/somewhere/else".to_string(),
+            last_stored_at: Utc::now(),
+            last_updated_at: Utc::now(),
+        };
+        std::fs::write(
+            format!("{dir}/map.cbor"),
+            serde_cbor::ser::to_vec(&legacy).unwrap(),
+        )
+        .unwrap();
+
+        let map: PersistedMap<(i64, i32), String> =
+            PersistedMap::from_dir(&dir, "map.cbor", "Test map").unwrap();
+        assert_eq!(map.get(&(10000002, 40520)).map(String::as_str), Some("LSI"));
+    }
+}
@@ -0,0 +1,67 @@
+// mydb/versioned.rs - Version tagged envelope of the cbor database files
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::io;
+use tracing::info;
+
+#[derive(Serialize)]
+struct Envelope<'a, T> {
+    version: u32,
+    payload: &'a T,
+}
+
+/// Payload of an envelope whose version was already checked
+#[derive(Deserialize)]
+struct EnvelopePayload<T> {
+    payload: T,
+}
+
+/// Reads just the version of an envelope, the payload is skipped as an unknown field
+#[derive(Deserialize)]
+struct EnvelopeProbe {
+    version: Option<u32>,
+}
+
+/// Encode `payload` as `{ version, payload }`
+pub(crate) fn encode<T: Serialize>(version: u32, payload: &T) -> Result<Vec<u8>, io::Error> {
+    serde_cbor::ser::to_vec(&Envelope { version, payload }).map_err(io::Error::other)
+}
+
+/// Version of an encoded file, None for files written before the envelope existed
+pub(crate) fn version_of(bytes: &[u8]) -> Option<u32> {
+    serde_cbor::from_slice::<EnvelopeProbe>(bytes)
+        .ok()
+        .and_then(|probe| probe.version)
+}
+
+/// Decode a file written by `encode` with the given `version`. Unversioned files are
+/// handed to `migrate_legacy`, files of another version are refused rather than
+/// guessed at.
+pub(crate) fn decode<T: DeserializeOwned>(
+    bytes: &[u8],
+    version: u32,
+    migrate_legacy: impl FnOnce(&[u8]) -> Result<T, io::Error>,
+) -> Result<T, io::Error> {
+    match version_of(bytes) {
+        None => {
+            info!(
+                "🔁 Migrating unversioned database file to version {}",
+                version
+            );
+            migrate_legacy(bytes)
+        }
+        Some(found) if found == version => {
+            let envelope: EnvelopePayload<T> = serde_cbor::from_slice(bytes).map_err(|e| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("failed to deserialize version {found}: {e}"),
+                )
+            })?;
+            Ok(envelope.payload)
+        }
+        Some(found) => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("unsupported database version {found}, expected {version}"),
+        )),
+    }
+}
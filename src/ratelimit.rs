@@ -14,16 +14,30 @@ impl RatelimitGroup {
         RatelimitGroup { ratelimits: data }
     }
 
+    /// Longest wait among the limits before a hit at `at` is allowed, without recording it
+    pub fn can_hit_at(&self, at: Duration) -> Option<Duration> {
+        self.ratelimits
+            .iter()
+            .filter_map(|v| v.can_hit_at(at))
+            .max()
+    }
+
+    /// Record a hit at `at` if every limit allows it, otherwise return the longest wait.
+    /// A group without limits always allows the hit.
     pub fn hit_at(&mut self, at: Duration) -> Option<Duration> {
-        let res = self.ratelimits.iter().map(|v| v.can_hit_at(at)).max()?;
+        if self.ratelimits.is_empty() {
+            return None;
+        }
+
+        let wait = self.can_hit_at(at);
 
-        if res == None {
+        if wait.is_none() {
             for r in self.ratelimits.iter_mut() {
                 r.hit_at(at);
             }
         }
 
-        res
+        wait
     }
 }
 
@@ -33,61 +47,331 @@ struct Slot {
     hits: usize,
 }
 
+#[derive(Debug)]
+enum Window {
+    /// Hits bucketed into `CAP` slots of `slot_size`, cheap but may overshoot at slot boundaries
+    Slotted {
+        data: RingBuffer<Slot>,
+        slot_size: Duration,
+    },
+    /// Exact timestamps of the last `limit` hits
+    Sliding { hits: RingBuffer<Duration> },
+}
+
 #[derive(Debug)]
 pub struct Ratelimit {
-    data: RingBuffer<Slot>,
+    window: Window,
     interval: Duration,
-    slot_size: Duration,
     limit: usize,
 }
 
 impl Ratelimit {
     pub fn new(interval: Duration, limit: usize) -> Self {
-        let slot_size = interval / CAP as u32;
+        // Rounded up so `CAP` slots always cover the whole interval, and never 0
+        let slot_size =
+            Duration::from_nanos(interval.as_nanos().div_ceil(CAP as u128).max(1) as u64);
 
         Ratelimit {
-            interval: interval,
-            slot_size: slot_size,
-            data: RingBuffer::with_capacity(CAP + 1),
-            limit: limit,
+            window: Window::Slotted {
+                data: RingBuffer::with_capacity(CAP + 1),
+                slot_size,
+            },
+            interval,
+            limit,
+        }
+    }
+
+    /// Never lets more than `limit` hits into any `interval`, at the cost of keeping
+    /// `limit` timestamps around. Meant for short bursty windows.
+    pub fn new_sliding(interval: Duration, limit: usize) -> Self {
+        Ratelimit {
+            window: Window::Sliding {
+                hits: RingBuffer::with_capacity(limit),
+            },
+            interval,
+            limit,
         }
     }
 
     fn can_hit_at(&self, at: Duration) -> Option<Duration> {
-        let slot_at = self.slot_at(at);
+        match &self.window {
+            Window::Slotted { data, slot_size } => {
+                let slot_at = slot_at(at, *slot_size);
 
-        let mut s = 0;
-        for slot in self.data.iter() {
-            if slot.from + self.interval < slot_at {
-                break;
+                let mut s = 0;
+                for slot in data.iter() {
+                    // A slot counts while its latest possible hit is less than `interval`
+                    // before some instant of the current slot
+                    let slot_end = slot.from + *slot_size + self.interval;
+                    if slot_end <= slot_at {
+                        break;
+                    }
+                    s += slot.hits;
+                    if s >= self.limit {
+                        // First slot start at which this slot no longer counts
+                        return Some(slot_ceil(slot_end, *slot_size) - at);
+                    }
+                }
+                None
             }
-            s += slot.hits;
-            if s >= self.limit {
-                return Some(slot.from + self.interval + self.slot_size - at)
+            Window::Sliding { hits } => {
+                if hits.iter().count() < self.limit {
+                    return None;
+                }
+
+                // The buffer holds exactly the last `limit` hits, the oldest one frees up next
+                let oldest = *hits.iter().last()?;
+                let free_at = oldest + self.interval;
+                if free_at > at {
+                    Some(free_at - at)
+                } else {
+                    None
+                }
             }
         }
-        None
     }
 
     fn hit_at(&mut self, at: Duration) {
-        let slot_from = self.slot_at(at);
+        match &mut self.window {
+            Window::Slotted { data, slot_size } => {
+                let slot_from = slot_at(at, *slot_size);
 
-        if let Some(v) = self.data.last() {
-            if v.from == slot_from {
-                self.data.set_last(Slot { from: slot_from, hits: v.hits + 1 });
-            } else {
-                self.data.push(Slot { from: slot_from, hits: 1 });
+                match data.last() {
+                    Some(v) if v.from == slot_from => {
+                        let hits = v.hits + 1;
+                        data.set_last(Slot {
+                            from: slot_from,
+                            hits,
+                        });
+                    }
+                    _ => data.push(Slot {
+                        from: slot_from,
+                        hits: 1,
+                    }),
+                }
             }
-        } else {
-            self.data.push(Slot { from: slot_from, hits: 1 });
+            Window::Sliding { hits } => hits.push(at),
         }
     }
+}
 
-    fn slot_at(&self, at: Duration) -> Duration {
-        let at = at.as_nanos();
-        let slot_size = self.slot_size.as_nanos();
+fn slot_at(at: Duration, slot_size: Duration) -> Duration {
+    let at = at.as_nanos();
+    let slot_size = slot_size.as_nanos();
 
-        let slot_from = at - at % slot_size;
-        Duration::new((slot_from / 1_000_000_000) as u64, (slot_from % 1_000_000_000) as u32)
+    let slot_from = at - at % slot_size;
+    Duration::new(
+        (slot_from / 1_000_000_000) as u64,
+        (slot_from % 1_000_000_000) as u32,
+    )
+}
+
+/// Smallest slot boundary at or after `at`
+fn slot_ceil(at: Duration, slot_size: Duration) -> Duration {
+    let slot_from = slot_at(at, slot_size);
+    if slot_from == at {
+        at
+    } else {
+        slot_from + slot_size
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    /// Hits as often as the limit allows on a simulated clock, stepping by a random
+    /// amount (up to `max_step`) or the returned wait, and returns the permitted hits
+    fn hammer(
+        ratelimit: &mut Ratelimit,
+        rng: &mut StdRng,
+        start: Duration,
+        max_step: Duration,
+        attempts: usize,
+    ) -> Vec<Duration> {
+        let mut now = start;
+        let mut permitted = Vec::new();
+        for _ in 0..attempts {
+            match ratelimit.can_hit_at(now) {
+                None => {
+                    ratelimit.hit_at(now);
+                    permitted.push(now);
+                    now += Duration::from_nanos(rng.random_range(0..=max_step.as_nanos() as u64));
+                }
+                Some(wait) => {
+                    assert!(wait > Duration::ZERO, "zero wait at {:?}", now);
+                    // Sometimes retry early, the limit must hold regardless
+                    if rng.random_bool(0.3) {
+                        now += Duration::from_nanos(rng.random_range(1..=wait.as_nanos() as u64));
+                    } else {
+                        now += wait;
+                    }
+                }
+            }
+        }
+        permitted
     }
-}
\ No newline at end of file
+
+    fn assert_within_limit(permitted: &[Duration], interval: Duration, limit: usize) {
+        for (i, from) in permitted.iter().enumerate() {
+            let in_window = permitted[i..]
+                .iter()
+                .take_while(|at| **at < *from + interval)
+                .count();
+            assert!(
+                in_window <= limit,
+                "{} hits in the {:?} window from {:?}, limit {}",
+                in_window,
+                interval,
+                from,
+                limit
+            );
+        }
+    }
+
+    fn cases() -> Vec<(Duration, usize)> {
+        vec![
+            (Duration::from_secs(1), 2),
+            (Duration::from_secs(60), 120),
+            (Duration::from_millis(1000), 1),
+            // Not a multiple of the slot count
+            (Duration::from_nanos(1_000_000_007), 5),
+            (Duration::from_nanos(999), 3),
+            // Shorter than one nanosecond per slot
+            (Duration::from_nanos(7), 2),
+        ]
+    }
+
+    #[test]
+    fn slotted_never_exceeds_limit() {
+        let mut rng = StdRng::seed_from_u64(0x5107);
+        for (interval, limit) in cases() {
+            for max_step in [interval / 50, interval / 3, interval * 2] {
+                let mut ratelimit = Ratelimit::new(interval, limit);
+                let permitted = hammer(&mut ratelimit, &mut rng, Duration::ZERO, max_step, 5_000);
+                assert!(!permitted.is_empty());
+                assert_within_limit(&permitted, interval, limit);
+            }
+        }
+    }
+
+    #[test]
+    fn sliding_never_exceeds_limit() {
+        let mut rng = StdRng::seed_from_u64(0x511d);
+        for (interval, limit) in cases() {
+            for max_step in [interval / 50, interval / 3, interval * 2] {
+                let mut ratelimit = Ratelimit::new_sliding(interval, limit);
+                let permitted = hammer(&mut ratelimit, &mut rng, Duration::ZERO, max_step, 5_000);
+                assert!(!permitted.is_empty());
+                assert_within_limit(&permitted, interval, limit);
+            }
+        }
+    }
+
+    #[test]
+    fn limit_holds_at_large_uptimes() {
+        let mut rng = StdRng::seed_from_u64(0x1a7e);
+        // A year and a bit, with a nanosecond offset so slots don't line up with seconds
+        let start = Duration::new(400 * 24 * 60 * 60, 123_456_789);
+        for (interval, limit) in cases() {
+            let mut ratelimit = Ratelimit::new(interval, limit);
+            let permitted = hammer(&mut ratelimit, &mut rng, start, interval / 7, 5_000);
+            assert_within_limit(&permitted, interval, limit);
+        }
+    }
+
+    #[test]
+    fn slotted_allows_limit_per_interval() {
+        let interval = Duration::from_secs(60);
+        let mut ratelimit = Ratelimit::new(interval, 120);
+        for _ in 0..120 {
+            assert_eq!(ratelimit.can_hit_at(Duration::ZERO), None);
+            ratelimit.hit_at(Duration::ZERO);
+        }
+        let wait = ratelimit.can_hit_at(Duration::ZERO).expect("limit reached");
+        // The burst frees up one slot after the interval, no later
+        assert_eq!(wait, interval + interval / CAP as u32);
+        assert_eq!(ratelimit.can_hit_at(wait), None);
+        assert!(
+            ratelimit
+                .can_hit_at(wait - Duration::from_nanos(1))
+                .is_some()
+        );
+    }
+
+    #[test]
+    fn slot_boundaries() {
+        let slot_size = Duration::from_millis(50);
+        assert_eq!(
+            slot_at(Duration::from_millis(149), slot_size),
+            Duration::from_millis(100)
+        );
+        assert_eq!(
+            slot_at(Duration::from_millis(150), slot_size),
+            Duration::from_millis(150)
+        );
+        assert_eq!(
+            slot_ceil(Duration::from_millis(150), slot_size),
+            Duration::from_millis(150)
+        );
+        assert_eq!(
+            slot_ceil(Duration::from_millis(151), slot_size),
+            Duration::from_millis(200)
+        );
+        let late = Duration::new(u32::MAX as u64, 999_999_999);
+        assert_eq!(slot_at(late, Duration::from_nanos(1)), late);
+    }
+
+    #[test]
+    fn group_applies_every_limit() {
+        let mut rng = StdRng::seed_from_u64(0x6a0b);
+        let mut group = RatelimitGroup::new(vec![
+            Ratelimit::new_sliding(Duration::from_secs(1), 2),
+            Ratelimit::new(Duration::from_secs(60), 30),
+        ]);
+        let mut now = Duration::ZERO;
+        let mut permitted = Vec::new();
+        for _ in 0..2_000 {
+            match group.hit_at(now) {
+                None => {
+                    permitted.push(now);
+                    now += Duration::from_millis(rng.random_range(0..300));
+                }
+                Some(wait) => now += wait,
+            }
+        }
+        assert_within_limit(&permitted, Duration::from_secs(1), 2);
+        assert_within_limit(&permitted, Duration::from_secs(60), 30);
+    }
+
+    #[test]
+    fn empty_group_never_waits() {
+        let mut group = RatelimitGroup::new(vec![]);
+        for _ in 0..1_000 {
+            assert_eq!(group.can_hit_at(Duration::ZERO), None);
+            assert_eq!(group.hit_at(Duration::ZERO), None);
+        }
+    }
+
+    #[test]
+    fn group_waits_for_the_saturated_limit() {
+        let mut group = RatelimitGroup::new(vec![
+            Ratelimit::new_sliding(Duration::from_secs(1), 1),
+            Ratelimit::new_sliding(Duration::from_secs(60), 1),
+        ]);
+        assert_eq!(group.hit_at(Duration::ZERO), None);
+
+        let at = Duration::from_secs(2);
+        // The per second limit is free again, the per minute one decides
+        assert_eq!(group.hit_at(at), Some(Duration::from_secs(58)));
+        assert_eq!(group.can_hit_at(at), Some(Duration::from_secs(58)));
+        // A refused hit is recorded by none of the limits
+        assert_eq!(
+            group.ratelimits[0].can_hit_at(at + Duration::from_millis(500)),
+            None
+        );
+        assert_eq!(group.hit_at(Duration::from_secs(60)), None);
+    }
+}
@@ -1,6 +1,10 @@
+/// Fixed size buffer overwriting its oldest element once full.
 #[derive(Debug, Clone)]
 pub(crate) struct RingBuffer<T> {
     data: Vec<T>,
+    // Vec::with_capacity may allocate more than asked for, so the size is kept explicitly
+    cap: usize,
+    // Index of the oldest element once the buffer is full, always 0 before that
     end: usize,
 }
 
@@ -8,33 +12,38 @@ impl<T> RingBuffer<T> {
     pub fn with_capacity(cap: usize) -> RingBuffer<T> {
         RingBuffer {
             data: Vec::with_capacity(cap),
+            cap,
             end: 0,
         }
     }
 
     pub fn push(&mut self, value: T) {
-        let cap = self.data.capacity();
+        if self.cap == 0 {
+            return;
+        }
 
-        if self.data.len() == cap {
+        if self.data.len() == self.cap {
             self.data[self.end] = value;
-            self.end = (self.end + 1) % cap;
+            self.end = (self.end + 1) % self.cap;
         } else {
             self.data.push(value);
         }
     }
 
     fn last_index(&self) -> Option<usize> {
-        let cap = self.data.capacity();
-        let len = self.data.len();
+        if self.data.is_empty() {
+            return None;
+        }
 
-        if len > 0 {
-            Some((len + self.end - 1) % cap)
+        if self.data.len() < self.cap {
+            Some(self.data.len() - 1)
         } else {
-            None
+            Some((self.end + self.cap - 1) % self.cap)
         }
     }
+
     pub fn last(&self) -> Option<&T> {
-        self.last_index().and_then(|i| Some(&self.data[i]))
+        self.last_index().map(|i| &self.data[i])
     }
 
     pub fn set_last(&mut self, value: T) {
@@ -44,8 +53,48 @@ impl<T> RingBuffer<T> {
         }
     }
 
-    pub fn iter(&self) -> impl Iterator<Item=&T> {
-        let d = self.data.split_at(self.end);
-        d.0.iter().rev().chain(d.1.iter().rev())
+    /// Elements from the newest to the oldest
+    pub fn iter(&self) -> impl Iterator<Item = &T> {
+        // Before wrapping `end` is 0 and the data is in push order. After wrapping
+        // everything before `end` is newer than everything from `end` on.
+        let (newer, older) = self.data.split_at(self.end);
+        newer.iter().rev().chain(older.iter().rev())
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CAP: usize = 5;
+
+    /// Buffer of capacity `CAP` after pushing 0, 1, .., count - 1
+    fn pushed(count: usize) -> Vec<usize> {
+        let mut buffer = RingBuffer::with_capacity(CAP);
+        for value in 0..count {
+            buffer.push(value);
+        }
+        buffer.iter().copied().collect()
+    }
+
+    #[test]
+    fn iter_goes_from_newest_to_oldest() {
+        assert_eq!(pushed(3), vec![2, 1, 0]);
+        assert_eq!(pushed(CAP), vec![4, 3, 2, 1, 0]);
+        // Wrapped, only the last CAP values are left
+        assert_eq!(pushed(CAP + 5), vec![9, 8, 7, 6, 5]);
+        assert_eq!(pushed(CAP + 2), vec![6, 5, 4, 3, 2]);
+    }
+
+    #[test]
+    fn last_is_the_newest() {
+        let mut buffer = RingBuffer::with_capacity(CAP);
+        assert_eq!(buffer.last(), None);
+        for value in 0..CAP + 2 {
+            buffer.push(value);
+            assert_eq!(buffer.last(), Some(&value));
+        }
+        buffer.set_last(42);
+        assert_eq!(buffer.iter().next(), Some(&42));
+    }
+}
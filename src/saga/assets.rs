@@ -1,15 +1,25 @@
 // saga/assets.rs - Assets saga implementation using the framework
+use oauth2::basic::BasicTokenResponse;
+use parking_lot::Mutex;
+use std::collections::{BTreeMap, HashMap};
 use std::sync::Arc;
+use std::time::Duration;
 use thiserror::Error;
+use tracing::{debug, info, warn};
 
-use crate::db::GetData;
+use crate::db::{AssetOwner, AssetsDiff, GetData};
 use crate::eve::{esi, hoboleaks, sde};
 use crate::saga::framework::{Saga, SagaError, SagaProcessor};
+use crate::saga::stats::{FetchCounter, FetchStats, FetchStatsSnapshot};
 use crate::{
-    AppContext, AssetItem, AssetName, CharacterId, DogmaAttribute, DogmaAttributeId, DynamicItem,
-    ItemId, ItemType, MarketGroup, MarketGroupId, Station, StationId, TypeId,
+    AppContext, AssetItem, AssetName, CharacterId, CorporationId, DogmaAttribute, DogmaAttributeId,
+    DynamicItem, DynamicsDb, ItemId, ItemType, MarketGroup, MarketGroupId, Station, StationId,
+    Structure, StructureId, TypeId,
 };
 
+/// Tokens expiring sooner than this are refreshed before the call rather than after a 401
+const TOKEN_REFRESH_MARGIN: Duration = Duration::from_secs(60);
+
 /// Assets-specific work types
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
 pub enum AssetsWorkType {
@@ -23,6 +33,16 @@ pub enum AssetsWorkType {
         page: usize,
         character_id: CharacterId,
     },
+    /// Corporation hangars, fetched with the token of the saga's character
+    GetCorpAssetsPage {
+        corporation_id: CorporationId,
+        page: usize,
+    },
+    GetCorpAssetsNames {
+        item_ids: Vec<ItemId>,
+        page: usize,
+        corporation_id: CorporationId,
+    },
     GetDynamic {
         type_id: TypeId,
         item_id: ItemId,
@@ -36,6 +56,9 @@ pub enum AssetsWorkType {
     GetStation {
         station_id: StationId,
     },
+    GetStructure {
+        structure_id: StructureId,
+    },
     GetDogmaAttribute {
         dogma_attribute_id: DogmaAttributeId,
     },
@@ -53,6 +76,14 @@ pub enum AssetsWorkKey {
         character_id: CharacterId,
         page: usize,
     },
+    CorpAssetsPage {
+        corporation_id: CorporationId,
+        page: usize,
+    },
+    CorpAssetsNames {
+        corporation_id: CorporationId,
+        page: usize,
+    },
     Dynamic {
         item_id: ItemId,
     },
@@ -65,6 +96,9 @@ pub enum AssetsWorkKey {
     Station {
         station_id: StationId,
     },
+    Structure {
+        structure_id: StructureId,
+    },
     DogmaAttribute {
         dogma_attribute_id: DogmaAttributeId,
     },
@@ -87,6 +121,17 @@ pub enum AssetsWorkResult {
         page: usize,
         assets_names: Vec<AssetName>,
     },
+    CorpAssetsPage {
+        corporation_id: CorporationId,
+        page: usize,
+        total_pages: usize,
+        assets: Vec<AssetItem>,
+    },
+    CorpAssetsNames {
+        corporation_id: CorporationId,
+        page: usize,
+        assets_names: Vec<AssetName>,
+    },
     Dynamic {
         type_id: TypeId,
         item_id: ItemId,
@@ -104,6 +149,11 @@ pub enum AssetsWorkResult {
         station_id: StationId,
         station: Station,
     },
+    /// `None` when the character has no docking access, the structure stays unnamed
+    Structure {
+        structure_id: StructureId,
+        structure: Option<Structure>,
+    },
     DogmaAttribute {
         dogma_attribute_id: DogmaAttributeId,
         dogma_attribute: DogmaAttribute,
@@ -120,11 +170,60 @@ pub enum AssetsError {
     DatabaseError(String),
     #[error("Consistency error: {0}")]
     ConsistencyError(String),
+    #[error("Hoboleaks error: {message}")]
+    HoboleaksError { message: String, temporary: bool },
+}
+
+impl AssetsError {
+    /// Whether trying again later may help
+    pub fn is_temporary(&self) -> bool {
+        match self {
+            AssetsError::HoboleaksError { temporary, .. } => *temporary,
+            _ => true,
+        }
+    }
+}
+
+impl From<hoboleaks::HoboleaksError> for AssetsError {
+    fn from(e: hoboleaks::HoboleaksError) -> Self {
+        AssetsError::HoboleaksError {
+            message: e.to_string(),
+            temporary: e.is_temporary(),
+        }
+    }
 }
 
 /// Initial event for assets saga
 pub struct AssetsInitialEvent {
     pub character_id: CharacterId,
+    /// Corporation whose assets are resolved along with the character's ones
+    pub corporation_id: Option<CorporationId>,
+}
+
+/// Shared state of a single assets saga run
+pub struct AssetsSagaContext {
+    pub app: Arc<AppContext>,
+    /// Character whose token is used for authenticated lookups like structures
+    pub character_id: CharacterId,
+    pub stats: FetchStats,
+    /// Assets listed so far per owner, swapped in for the stored ones once the run succeeds
+    pub listed_assets: Mutex<BTreeMap<AssetOwner, Vec<AssetItem>>>,
+}
+
+/// Summary of a finished assets saga run
+#[derive(Debug, Clone)]
+pub struct AssetsSagaOutcome {
+    /// Work items resolved, from assets pages down to single dogma attributes
+    pub resolved_count: usize,
+    pub elapsed: Duration,
+    /// Requests that went to ESI, served-from-cache lookups aside
+    pub esi_calls: u64,
+    pub fetch_stats: FetchStatsSnapshot,
+    /// Work that kept failing and was given up on
+    pub dead_letters: Vec<(AssetsWorkKey, String)>,
+    /// Assets added, removed and moved since the previous run, only of the owners this
+    /// run listed completely
+    pub changes: AssetsDiff,
 }
 
 /// Assets saga processor implementation
@@ -141,7 +240,7 @@ impl SagaProcessor for AssetsSagaProcessor {
     type WorkKey = AssetsWorkKey;
     type WorkResult = AssetsWorkResult;
     type Error = AssetsError;
-    type Context = AppContext;
+    type Context = AssetsSagaContext;
     type InitialEvent = AssetsInitialEvent;
 
     fn to_resolution_key(work_type: &Self::WorkType) -> Self::WorkKey {
@@ -157,6 +256,21 @@ impl SagaProcessor for AssetsSagaProcessor {
                 character_id: *character_id,
                 page: *page,
             },
+            AssetsWorkType::GetCorpAssetsPage {
+                corporation_id,
+                page,
+            } => AssetsWorkKey::CorpAssetsPage {
+                corporation_id: *corporation_id,
+                page: *page,
+            },
+            AssetsWorkType::GetCorpAssetsNames {
+                corporation_id,
+                page,
+                ..
+            } => AssetsWorkKey::CorpAssetsNames {
+                corporation_id: *corporation_id,
+                page: *page,
+            },
             AssetsWorkType::GetDynamic { item_id, .. } => {
                 AssetsWorkKey::Dynamic { item_id: *item_id }
             }
@@ -167,6 +281,9 @@ impl SagaProcessor for AssetsSagaProcessor {
             AssetsWorkType::GetStation { station_id } => AssetsWorkKey::Station {
                 station_id: *station_id,
             },
+            AssetsWorkType::GetStructure { structure_id } => AssetsWorkKey::Structure {
+                structure_id: *structure_id,
+            },
             AssetsWorkType::GetDogmaAttribute { dogma_attribute_id } => {
                 AssetsWorkKey::DogmaAttribute {
                     dogma_attribute_id: *dogma_attribute_id,
@@ -178,13 +295,20 @@ impl SagaProcessor for AssetsSagaProcessor {
     fn handle_initial_event(
         event: Self::InitialEvent,
     ) -> Result<Vec<Self::WorkType>, SagaError<Self::Error>> {
-        Ok(vec![
+        let mut work = vec![
             AssetsWorkType::GetHoboleaksMutators,
             AssetsWorkType::GetAssetsPage {
                 character_id: event.character_id,
                 page: 1,
             },
-        ])
+        ];
+        if let Some(corporation_id) = event.corporation_id {
+            work.push(AssetsWorkType::GetCorpAssetsPage {
+                corporation_id,
+                page: 1,
+            });
+        }
+        Ok(work)
     }
 
     async fn process(
@@ -193,29 +317,35 @@ impl SagaProcessor for AssetsSagaProcessor {
     ) -> Result<Self::WorkResult, Self::Error> {
         match work_type {
             AssetsWorkType::GetHoboleaksMutators => {
-                let data = hoboleaks::get_mutaplasmids(&context.http_client)
-                    .await
-                    .map_err(|e| AssetsError::EsiError(e.to_string()))?;
+                let data = match context.app.cached_hoboleaks_data().await {
+                    Some(data) => {
+                        context.stats.hoboleaks.hit();
+                        data
+                    }
+                    None => {
+                        context.stats.hoboleaks.fetch();
+                        context.app.fetch_hoboleaks_data().await?
+                    }
+                };
 
                 Ok(AssetsWorkResult::HoboleaksMutators { data })
             }
             AssetsWorkType::GetAssetsPage { character_id, page } => {
-                let characters_guard = context.characters.lock().await;
-                let character_client =
-                    characters_guard
-                        .get(*character_id)
-                        .ok_or(AssetsError::ConsistencyError(format!(
-                            "unknown character with id: {character_id}"
-                        )))?;
-
-                let (assets, total_pages) = esi::get_assets_chunk(
-                    &context.http_client,
-                    &character_client.oauth_token,
-                    *character_id,
-                    *page,
-                )
-                .await
-                .map_err(|e| AssetsError::EsiError(e.to_string()))?;
+                let (assets, total_pages) =
+                    with_character_token(context, *character_id, |token| async move {
+                        let _permit = context.app.acquire_esi_permit().await;
+                        let result = esi::get_assets_chunk(
+                            &context.app.http_client,
+                            &context.app.esi_expires,
+                            &token,
+                            *character_id,
+                            *page,
+                            false,
+                        )
+                        .await;
+                        empty_if_out_of_range(counted_page(&context.stats.assets_pages, result))
+                    })
+                    .await?;
 
                 Ok(AssetsWorkResult::AssetsPage {
                     character_id: *character_id,
@@ -229,22 +359,27 @@ impl SagaProcessor for AssetsSagaProcessor {
                 item_ids,
                 page,
             } => {
-                let characters_guard = context.characters.lock().await;
-                let character_client =
-                    characters_guard
-                        .get(*character_id)
-                        .ok_or(AssetsError::ConsistencyError(format!(
-                            "unknown character with id: {character_id}"
-                        )))?;
-
-                let assets_names = esi::get_assets_names(
-                    &context.http_client,
-                    &character_client.oauth_token,
-                    *character_id,
-                    &item_ids.iter().copied().map(Into::into).collect::<Vec<i64>>(),
-                )
-                .await
-                .map_err(|e| AssetsError::EsiError(e.to_string()))?;
+                let item_ids = item_ids
+                    .iter()
+                    .copied()
+                    .map(Into::into)
+                    .collect::<Vec<i64>>();
+
+                let assets_names = with_character_token(context, *character_id, |token| {
+                    let item_ids = &item_ids;
+                    async move {
+                        context.stats.assets_names.fetch();
+                        let _permit = context.app.acquire_esi_permit().await;
+                        esi::get_assets_names(
+                            &context.app.http_client,
+                            &token,
+                            *character_id,
+                            item_ids,
+                        )
+                        .await
+                    }
+                })
+                .await?;
 
                 Ok(AssetsWorkResult::AssetsNames {
                     assets_names,
@@ -252,31 +387,88 @@ impl SagaProcessor for AssetsSagaProcessor {
                     character_id: *character_id,
                 })
             }
-            AssetsWorkType::GetDynamic { type_id, item_id } => {
-                let cached_dynamic = {
-                    let dynamics_db = context.dynamics_db.read().await;
-                    dynamics_db.get((*type_id, *item_id)).cloned()
-                };
+            AssetsWorkType::GetCorpAssetsPage {
+                corporation_id,
+                page,
+            } => {
+                let (assets, total_pages) =
+                    with_character_token(context, context.character_id, |token| async move {
+                        let _permit = context.app.acquire_esi_permit().await;
+                        let result = esi::get_corp_assets_chunk(
+                            &context.app.http_client,
+                            &context.app.esi_expires,
+                            &token,
+                            *corporation_id,
+                            *page,
+                            false,
+                        )
+                        .await;
+                        empty_if_out_of_range(counted_page(&context.stats.assets_pages, result))
+                    })
+                    .await?;
 
-                let dynamic = match cached_dynamic {
-                    Some(d) => d,
-                    None => {
-                        let dynamic = esi::get_dynamic_item_attributes(
-                            &context.http_client,
+                Ok(AssetsWorkResult::CorpAssetsPage {
+                    corporation_id: *corporation_id,
+                    page: *page,
+                    assets,
+                    total_pages,
+                })
+            }
+            AssetsWorkType::GetCorpAssetsNames {
+                corporation_id,
+                item_ids,
+                page,
+            } => {
+                let item_ids = item_ids
+                    .iter()
+                    .copied()
+                    .map(Into::into)
+                    .collect::<Vec<i64>>();
+
+                let assets_names = with_character_token(context, context.character_id, |token| {
+                    let item_ids = &item_ids;
+                    async move {
+                        context.stats.assets_names.fetch();
+                        let _permit = context.app.acquire_esi_permit().await;
+                        esi::get_corp_assets_names(
+                            &context.app.http_client,
+                            &token,
+                            *corporation_id,
+                            item_ids,
+                        )
+                        .await
+                    }
+                })
+                .await?;
+
+                Ok(AssetsWorkResult::CorpAssetsNames {
+                    assets_names,
+                    page: *page,
+                    corporation_id: *corporation_id,
+                })
+            }
+            AssetsWorkType::GetDynamic { type_id, item_id } => {
+                let (dynamic, fetched) = DynamicsDb::get_or_insert_with(
+                    &context.app.dynamics_db,
+                    (*type_id, *item_id),
+                    || async {
+                        let _permit = context.app.acquire_esi_permit().await;
+                        esi::get_dynamic_item_attributes(
+                            &context.app.http_client,
                             (*item_id).into(),
                             (*type_id).into(),
                         )
                         .await
-                        .map_err(|e| AssetsError::EsiError(e.to_string()))?;
-
-                        {
-                            let mut dynamics_db = context.dynamics_db.write().await;
-                            dynamics_db.add((*type_id, *item_id), dynamic.clone());
-                        }
+                        .map_err(|e| AssetsError::EsiError(e.to_string()))
+                    },
+                )
+                .await?;
 
-                        dynamic
-                    }
-                };
+                if fetched {
+                    context.stats.dynamics.fetch();
+                } else {
+                    context.stats.dynamics.hit();
+                }
 
                 Ok(AssetsWorkResult::Dynamic {
                     type_id: *type_id,
@@ -285,22 +477,25 @@ impl SagaProcessor for AssetsSagaProcessor {
                 })
             }
             AssetsWorkType::GetType { type_id } => {
-                let cached_item_type = {
-                    let type_ids = vec![(*type_id).into()];
-                    let mut res = sde::get_types_by_ids(&context.sde_pool, &type_ids)
-                        .await
-                        .map_err(|e| AssetsError::SdeError(e.to_string()))?;
-                    res.pop()
-                };
+                let cached_item_type = context
+                    .app
+                    .get_sde_type(*type_id)
+                    .await
+                    .map_err(|e| AssetsError::SdeError(e.to_string()))?;
 
                 let item_type = match cached_item_type {
                     Some(item_type) => {
-                        println!("found type in sde: {}", type_id);
+                        debug!("found type in sde: {}", type_id);
+                        context.stats.types.hit();
                         item_type
                     }
-                    None => esi::get_type(&context.http_client, (*type_id).into())
-                        .await
-                        .map_err(|e| AssetsError::EsiError(e.to_string()))?,
+                    None => {
+                        context.stats.types.fetch();
+                        let _permit = context.app.acquire_esi_permit().await;
+                        esi::get_type(&context.app.http_client, (*type_id).into())
+                            .await
+                            .map_err(|e| AssetsError::EsiError(e.to_string()))?
+                    }
                 };
 
                 Ok(AssetsWorkResult::Type {
@@ -312,7 +507,7 @@ impl SagaProcessor for AssetsSagaProcessor {
                 let cached_market_group = {
                     let market_group_ids = vec![*market_group_id];
                     let mut res =
-                        sde::get_market_groups_by_ids(&context.sde_pool, &market_group_ids)
+                        sde::get_market_groups_by_ids(&context.app.sde_pool, &market_group_ids)
                             .await
                             .map_err(|e| AssetsError::SdeError(e.to_string()))?;
                     res.pop()
@@ -320,12 +515,17 @@ impl SagaProcessor for AssetsSagaProcessor {
 
                 let market_group = match cached_market_group {
                     Some(market_group) => {
-                        println!("found market group in sde: {}", market_group_id);
+                        debug!("found market group in sde: {}", market_group_id);
+                        context.stats.market_groups.hit();
                         market_group
                     }
-                    None => esi::get_market_group(&context.http_client, *market_group_id)
-                        .await
-                        .map_err(|e| AssetsError::EsiError(e.to_string()))?,
+                    None => {
+                        context.stats.market_groups.fetch();
+                        let _permit = context.app.acquire_esi_permit().await;
+                        esi::get_market_group(&context.app.http_client, *market_group_id)
+                            .await
+                            .map_err(|e| AssetsError::EsiError(e.to_string()))?
+                    }
                 };
 
                 Ok(AssetsWorkResult::MarketGroup {
@@ -334,7 +534,9 @@ impl SagaProcessor for AssetsSagaProcessor {
                 })
             }
             AssetsWorkType::GetStation { station_id } => {
-                let station = esi::get_station(&context.http_client, *station_id)
+                context.stats.stations.fetch();
+                let _permit = context.app.acquire_esi_permit().await;
+                let station = esi::get_station(&context.app.http_client, *station_id)
                     .await
                     .map_err(|e| AssetsError::EsiError(e.to_string()))?;
 
@@ -343,24 +545,60 @@ impl SagaProcessor for AssetsSagaProcessor {
                     station,
                 })
             }
+            AssetsWorkType::GetStructure { structure_id } => {
+                let structure =
+                    with_character_token(context, context.character_id, |token| async move {
+                        context.stats.structures.fetch();
+                        let _permit = context.app.acquire_esi_permit().await;
+                        let result =
+                            esi::get_structure(&context.app.http_client, &token, *structure_id)
+                                .await;
+                        // Structures without docking access are expected, they stay unnamed.
+                        // Anything else goes the regular retry path
+                        match result {
+                            Ok(structure) => Ok(Some(structure)),
+                            Err(
+                                e @ (esi::EsiError::Forbidden(_)
+                                | esi::EsiError::ApiError { status: 404, .. }),
+                            ) => {
+                                warn!("⚠️  Unable to resolve structure {}: {}", structure_id, e);
+                                Ok(None)
+                            }
+                            Err(e) => Err(e),
+                        }
+                    })
+                    .await?;
+
+                Ok(AssetsWorkResult::Structure {
+                    structure_id: *structure_id,
+                    structure,
+                })
+            }
             AssetsWorkType::GetDogmaAttribute { dogma_attribute_id } => {
                 let cached_dogma_attribute = {
                     let dogma_attribute_ids = vec![*dogma_attribute_id];
-                    let mut res =
-                        sde::get_dogma_attributes_by_ids(&context.sde_pool, &dogma_attribute_ids)
-                            .await
-                            .map_err(|e| AssetsError::SdeError(e.to_string()))?;
+                    let mut res = sde::get_dogma_attributes_by_ids(
+                        &context.app.sde_pool,
+                        &dogma_attribute_ids,
+                    )
+                    .await
+                    .map_err(|e| AssetsError::SdeError(e.to_string()))?;
                     res.pop()
                 };
 
                 let dogma_attribute = match cached_dogma_attribute {
                     Some(dogma_attribute) => {
-                        println!("found dogma attribute in sde: {}", dogma_attribute_id);
+                        debug!("found dogma attribute in sde: {}", dogma_attribute_id);
+                        context.stats.dogma_attributes.hit();
                         dogma_attribute
                     }
-                    None => esi::get_dogma_attribute(&context.http_client, *dogma_attribute_id)
-                        .await
-                        .map_err(|e| AssetsError::EsiError(e.to_string()))?,
+                    None => {
+                        context.stats.dogma_attributes.fetch();
+                        let _permit = context.app.acquire_esi_permit().await;
+                        esi::get_dogma_attribute(&context.app.http_client, *dogma_attribute_id)
+                            .await
+                            .map_err(|e| AssetsError::EsiError(e.to_string()))?
+                    }
                 };
 
                 Ok(AssetsWorkResult::DogmaAttribute {
@@ -371,6 +609,59 @@ impl SagaProcessor for AssetsSagaProcessor {
         }
     }
 
+    fn is_retryable(error: &Self::Error) -> bool {
+        error.is_temporary()
+    }
+
+    fn can_batch(a: &Self::WorkType, b: &Self::WorkType) -> bool {
+        matches!(
+            (a, b),
+            (
+                AssetsWorkType::GetType { .. },
+                AssetsWorkType::GetType { .. }
+            ) | (
+                AssetsWorkType::GetMarketGroup { .. },
+                AssetsWorkType::GetMarketGroup { .. }
+            ) | (
+                AssetsWorkType::GetDogmaAttribute { .. },
+                AssetsWorkType::GetDogmaAttribute { .. }
+            )
+        )
+    }
+
+    async fn process_batch(
+        context: &Arc<Self::Context>,
+        work_types: &[Self::WorkType],
+    ) -> Vec<Result<Self::WorkResult, Self::Error>> {
+        let mut found = match get_sde_batch(context, work_types).await {
+            Ok(found) => found,
+            Err(e) => {
+                return work_types
+                    .iter()
+                    .map(|_| Err(AssetsError::SdeError(e.to_string())))
+                    .collect();
+            }
+        };
+        debug!(
+            "found {} of {} batched items in sde",
+            found.len(),
+            work_types.len()
+        );
+
+        // Whatever the SDE doesn't know about goes through the regular ESI fallback, the
+        // misses are fetched concurrently, bounded by the ESI permits like any other request
+        futures::future::join_all(work_types.iter().map(|work_type| {
+            let found = found.remove(&Self::to_resolution_key(work_type));
+            async move {
+                match found {
+                    Some(work_result) => Ok(work_result),
+                    None => Self::process(context, work_type).await,
+                }
+            }
+        }))
+        .await
+    }
+
     async fn handle(
         context: &Arc<Self::Context>,
         work_result: Self::WorkResult,
@@ -380,27 +671,11 @@ impl SagaProcessor for AssetsSagaProcessor {
         match work_result {
             AssetsWorkResult::HoboleaksMutators { data } => {
                 for (mutator_type_id, mutator_data) in data {
-                    let attributes = mutator_data
-                        .attribute_i_ds
-                        .iter()
-                        .map(|(attribute_id, range)| (*attribute_id, range.min, range.max))
-                        .collect();
-
-                    let input_output = mutator_data
-                        .input_output_mapping
-                        .iter()
-                        .map(|i| (i.resulting_type, i.applicable_types.clone()))
-                        .collect();
-
-                    let new_data = context
-                        .character_assets_db
-                        .add_mutaplasmid_effects(mutator_type_id, attributes, input_output)
-                        .map_err(|e| {
-                            AssetsError::DatabaseError(format!(
-                                "Error adding mutaplasmid effects: {}",
-                                e
-                            ))
-                        })?;
+                    let new_data = context.app.character_assets_db.add_mutaplasmid_effects(
+                        mutator_type_id,
+                        mutator_data.attribute_ranges(),
+                        mutator_data.input_output(),
+                    );
 
                     for item in new_data {
                         new_items.push(get_data_to_work_type(&item));
@@ -413,40 +688,68 @@ impl SagaProcessor for AssetsSagaProcessor {
                 total_pages,
                 assets,
             } => {
-                for asset in &assets {
-                    let new_data = context
-                        .character_assets_db
-                        .add_asset(asset.clone())
-                        .map_err(|e| {
-                            AssetsError::DatabaseError(format!("unable to store asset {e}"))
-                        })?;
+                list_assets(
+                    context,
+                    AssetOwner::Character(character_id),
+                    &assets,
+                    &mut new_items,
+                );
 
-                    for item in new_data {
-                        new_items.push(get_data_to_work_type(&item));
+                if page == 1 {
+                    for page in 2..=total_pages {
+                        new_items.push(AssetsWorkType::GetAssetsPage { character_id, page });
                     }
                 }
 
+                // A page gone out of range comes back empty, there is nothing to name
+                if !assets.is_empty() {
+                    let item_ids = assets.iter().map(|asset| asset.item_id).collect();
+                    new_items.push(AssetsWorkType::GetAssetsNames {
+                        character_id,
+                        page,
+                        item_ids,
+                    });
+                }
+            }
+            AssetsWorkResult::CorpAssetsPage {
+                corporation_id,
+                page,
+                total_pages,
+                assets,
+            } => {
+                list_assets(
+                    context,
+                    AssetOwner::Corporation(corporation_id),
+                    &assets,
+                    &mut new_items,
+                );
+
                 if page == 1 {
                     for page in 2..=total_pages {
-                        new_items.push(AssetsWorkType::GetAssetsPage { character_id, page });
+                        new_items.push(AssetsWorkType::GetCorpAssetsPage {
+                            corporation_id,
+                            page,
+                        });
                     }
                 }
 
-                let item_ids = assets.iter().map(|asset| asset.item_id).collect();
-                new_items.push(AssetsWorkType::GetAssetsNames {
-                    character_id,
-                    page,
-                    item_ids,
-                });
+                // A page gone out of range comes back empty, there is nothing to name
+                if !assets.is_empty() {
+                    let item_ids = assets.iter().map(|asset| asset.item_id).collect();
+                    new_items.push(AssetsWorkType::GetCorpAssetsNames {
+                        corporation_id,
+                        page,
+                        item_ids,
+                    });
+                }
             }
-            AssetsWorkResult::AssetsNames { assets_names, .. } => {
+            AssetsWorkResult::AssetsNames { assets_names, .. }
+            | AssetsWorkResult::CorpAssetsNames { assets_names, .. } => {
                 for asset_name in assets_names {
                     context
+                        .app
                         .character_assets_db
-                        .add_asset_name(asset_name.item_id, asset_name.name.clone())
-                        .map_err(|e| {
-                            AssetsError::DatabaseError(format!("unable to store asset name {e}"))
-                        })?;
+                        .add_asset_name(asset_name.item_id, asset_name.name.clone());
                 }
             }
             AssetsWorkResult::Dynamic {
@@ -455,21 +758,16 @@ impl SagaProcessor for AssetsSagaProcessor {
                 dynamic,
             } => {
                 let new_data = context
+                    .app
                     .character_assets_db
-                    .add_dynamic(type_id, item_id, dynamic)
-                    .map_err(|e| {
-                        AssetsError::DatabaseError(format!("unable to store dynamic {e}"))
-                    })?;
+                    .add_dynamic(type_id, item_id, dynamic);
 
                 for item in new_data {
                     new_items.push(get_data_to_work_type(&item));
                 }
             }
             AssetsWorkResult::Type { item_type, .. } => {
-                let new_data = context
-                    .character_assets_db
-                    .add_type(item_type)
-                    .map_err(|e| AssetsError::DatabaseError(format!("unable to store type {e}")))?;
+                let new_data = context.app.character_assets_db.add_type(item_type);
 
                 for item in new_data {
                     new_items.push(get_data_to_work_type(&item));
@@ -477,11 +775,9 @@ impl SagaProcessor for AssetsSagaProcessor {
             }
             AssetsWorkResult::MarketGroup { market_group, .. } => {
                 let new_data = context
+                    .app
                     .character_assets_db
-                    .add_market_group(market_group)
-                    .map_err(|e| {
-                        AssetsError::DatabaseError(format!("unable to store market group {e}"))
-                    })?;
+                    .add_market_group(market_group);
 
                 for item in new_data {
                     new_items.push(get_data_to_work_type(&item));
@@ -492,25 +788,36 @@ impl SagaProcessor for AssetsSagaProcessor {
                 station,
             } => {
                 let new_data = context
+                    .app
                     .character_assets_db
-                    .add_station(station_id, station)
-                    .map_err(|e| {
-                        AssetsError::DatabaseError(format!("unable to store station {e}"))
-                    })?;
+                    .add_station(station_id, station);
 
                 for item in new_data {
                     new_items.push(get_data_to_work_type(&item));
                 }
             }
+            AssetsWorkResult::Structure {
+                structure_id,
+                structure,
+            } => {
+                if let Some(structure) = structure {
+                    let new_data = context
+                        .app
+                        .character_assets_db
+                        .add_structure(structure_id, structure);
+
+                    for item in new_data {
+                        new_items.push(get_data_to_work_type(&item));
+                    }
+                }
+            }
             AssetsWorkResult::DogmaAttribute {
                 dogma_attribute, ..
             } => {
                 let new_data = context
+                    .app
                     .character_assets_db
-                    .add_dogma_attribute(dogma_attribute)
-                    .map_err(|e| {
-                        AssetsError::DatabaseError(format!("unable to store dogma attribute {e}"))
-                    })?;
+                    .add_dogma_attribute(dogma_attribute);
 
                 for item in new_data {
                     new_items.push(get_data_to_work_type(&item));
@@ -522,6 +829,231 @@ impl SagaProcessor for AssetsSagaProcessor {
     }
 }
 
+/// A page past the last one is an empty page rather than a failure, the page count can
+/// shrink between fetching page 1 and the pages it fanned out
+/// Count an assets page as a cache hit or as a request sent to ESI
+fn counted_page(
+    counter: &FetchCounter,
+    result: Result<esi::Page<Vec<AssetItem>>, esi::EsiError>,
+) -> Result<(Vec<AssetItem>, usize), esi::EsiError> {
+    match &result {
+        Ok(page) if page.cached => counter.hit(),
+        _ => counter.fetch(),
+    }
+    result.map(|page| (page.items, page.total_pages))
+}
+
+fn empty_if_out_of_range(
+    result: Result<(Vec<AssetItem>, usize), esi::EsiError>,
+) -> Result<(Vec<AssetItem>, usize), esi::EsiError> {
+    match result {
+        Err(esi::EsiError::PageOutOfRange { page, total_pages }) => {
+            info!(
+                "📄 Assets page {} is gone (total pages now {:?}), treating it as empty",
+                page, total_pages
+            );
+            Ok((Vec::new(), total_pages.unwrap_or(page - 1)))
+        }
+        result => result,
+    }
+}
+
+/// Run an authenticated ESI call with the current token of the character. A token about
+/// to expire is refreshed up front; on an auth error the token is refreshed and the call
+/// retried once before giving up.
+async fn with_character_token<T, F, Fut>(
+    context: &Arc<AssetsSagaContext>,
+    character_id: CharacterId,
+    call: F,
+) -> Result<T, AssetsError>
+where
+    F: Fn(BasicTokenResponse) -> Fut,
+    Fut: Future<Output = Result<T, esi::EsiError>>,
+{
+    let (token, expiring) = {
+        let characters = context.app.characters.lock().await;
+        characters
+            .get(character_id)
+            .map(|character| {
+                (
+                    character.oauth_token.clone(),
+                    character.expires_within(TOKEN_REFRESH_MARGIN),
+                )
+            })
+            .ok_or(AssetsError::ConsistencyError(format!(
+                "unknown character with id: {character_id}"
+            )))?
+    };
+
+    // Falls back to the old token if the refresh fails, the 401 path retries it anyway
+    let token = if expiring {
+        match context
+            .app
+            .refresh_character_token(character_id, &token)
+            .await
+        {
+            Ok(token) => token,
+            Err(e) => {
+                warn!(
+                    "🔑 Failed to refresh the expiring token of character {}: {}",
+                    character_id, e
+                );
+                token
+            }
+        }
+    } else {
+        token
+    };
+
+    match call(token.clone()).await {
+        Err(esi::EsiError::AuthError(e)) => {
+            info!(
+                "🔑 ESI rejected the token of character {}, refreshing: {}",
+                character_id, e
+            );
+            let token = context
+                .app
+                .refresh_character_token(character_id, &token)
+                .await
+                .map_err(|e| AssetsError::EsiError(e.to_string()))?;
+
+            call(token)
+                .await
+                .map_err(|e| AssetsError::EsiError(e.to_string()))
+        }
+        res => res.map_err(|e| AssetsError::EsiError(e.to_string())),
+    }
+}
+
+/// Read the types, market groups and dogma attributes of a batch from the SDE with one
+/// query per kind. Items missing from the SDE are absent from the result.
+async fn get_sde_batch(
+    context: &Arc<AssetsSagaContext>,
+    work_types: &[AssetsWorkType],
+) -> Result<HashMap<AssetsWorkKey, AssetsWorkResult>, sqlx::Error> {
+    let mut type_ids = vec![];
+    let mut market_group_ids = vec![];
+    let mut dogma_attribute_ids = vec![];
+    for work_type in work_types {
+        match work_type {
+            AssetsWorkType::GetType { type_id } => type_ids.push(*type_id),
+            AssetsWorkType::GetMarketGroup { market_group_id } => {
+                market_group_ids.push(*market_group_id)
+            }
+            AssetsWorkType::GetDogmaAttribute { dogma_attribute_id } => {
+                dogma_attribute_ids.push(*dogma_attribute_id)
+            }
+            _ => {}
+        }
+    }
+
+    let mut found = HashMap::new();
+
+    for (type_id, item_type) in context.app.get_sde_types(&type_ids).await? {
+        context.stats.types.hit();
+        found.insert(
+            AssetsWorkKey::Type { type_id },
+            AssetsWorkResult::Type { type_id, item_type },
+        );
+    }
+
+    for market_group in
+        sde::get_market_groups_by_ids(&context.app.sde_pool, &market_group_ids).await?
+    {
+        context.stats.market_groups.hit();
+        let market_group_id = market_group.market_group_id;
+        found.insert(
+            AssetsWorkKey::MarketGroup { market_group_id },
+            AssetsWorkResult::MarketGroup {
+                market_group_id,
+                market_group,
+            },
+        );
+    }
+
+    for dogma_attribute in
+        sde::get_dogma_attributes_by_ids(&context.app.sde_pool, &dogma_attribute_ids).await?
+    {
+        context.stats.dogma_attributes.hit();
+        let dogma_attribute_id = dogma_attribute.attribute_id;
+        found.insert(
+            AssetsWorkKey::DogmaAttribute { dogma_attribute_id },
+            AssetsWorkResult::DogmaAttribute {
+                dogma_attribute_id,
+                dogma_attribute,
+            },
+        );
+    }
+
+    Ok(found)
+}
+
+/// Keep a page of character or corporation assets for the end of the run and queue
+/// whatever they reference
+fn list_assets(
+    context: &Arc<AssetsSagaContext>,
+    owner: AssetOwner,
+    assets: &[AssetItem],
+    new_items: &mut Vec<AssetsWorkType>,
+) {
+    let new_data = context.app.character_assets_db.missing_for_assets(assets);
+    debug!(
+        "listed {} assets, {} distinct items to resolve",
+        assets.len(),
+        new_data.len()
+    );
+    new_items.extend(new_data.iter().map(get_data_to_work_type));
+
+    // An empty page still creates the entry, an owner without assets is replaced too
+    context
+        .listed_assets
+        .lock()
+        .entry(owner)
+        .or_default()
+        .extend_from_slice(assets);
+}
+
+/// Swap the assets listed by a successful run in for the stored ones of the same owners.
+/// An owner with a page given up on keeps its stored assets, a partial list would drop
+/// every item of the missing pages
+fn replace_listed_assets(
+    context: &Arc<AssetsSagaContext>,
+    dead_letters: &[(AssetsWorkKey, String)],
+) -> AssetsDiff {
+    let listed_assets = std::mem::take(&mut *context.listed_assets.lock());
+
+    let mut previous = BTreeMap::new();
+    let mut current = BTreeMap::new();
+    for (owner, assets) in listed_assets {
+        let incomplete = dead_letters.iter().any(|(key, _)| match (key, owner) {
+            (AssetsWorkKey::AssetsPage { character_id, .. }, AssetOwner::Character(id)) => {
+                *character_id == id
+            }
+            (AssetsWorkKey::CorpAssetsPage { corporation_id, .. }, AssetOwner::Corporation(id)) => {
+                *corporation_id == id
+            }
+            _ => false,
+        });
+        if incomplete {
+            warn!(
+                "⚠️  Assets of {:?} are incomplete, keeping the stored ones",
+                owner
+            );
+            continue;
+        }
+
+        current.extend(assets.iter().map(|asset| (asset.item_id, asset.clone())));
+        previous.extend(
+            context
+                .app
+                .character_assets_db
+                .replace_owner_assets(owner, assets),
+        );
+    }
+
+    AssetsDiff::between(&previous, &current)
+}
+
 // Helper function to convert GetData to WorkType
 fn get_data_to_work_type(get_data: &GetData) -> AssetsWorkType {
     match get_data {
@@ -535,6 +1067,9 @@ fn get_data_to_work_type(get_data: &GetData) -> AssetsWorkType {
         GetData::Station(station_id) => AssetsWorkType::GetStation {
             station_id: *station_id,
         },
+        GetData::Structure(structure_id) => AssetsWorkType::GetStructure {
+            structure_id: *structure_id,
+        },
         GetData::Type(type_id) => AssetsWorkType::GetType { type_id: *type_id },
         GetData::DogmaAttribute(dogma_attribute_id) => AssetsWorkType::GetDogmaAttribute {
             dogma_attribute_id: *dogma_attribute_id,
@@ -546,12 +1081,62 @@ fn get_data_to_work_type(get_data: &GetData) -> AssetsWorkType {
 pub type AssetsSaga = Saga<AssetsSagaProcessor>;
 
 // Usage example:
+/// `corporation_id` adds the corporation's assets to the character's ones, the character
+/// needs the director role for that
 pub async fn run_assets_saga(
     context: Arc<AppContext>,
     character_id: CharacterId,
+    corporation_id: Option<CorporationId>,
     workers_count: usize,
-) -> Result<(), SagaError<AssetsError>> {
-    let saga = AssetsSaga::new(context, workers_count);
-    saga.start_with_event(AssetsInitialEvent { character_id })
+    deadline: Option<Duration>,
+) -> Result<AssetsSagaOutcome, SagaError<AssetsError>> {
+    let context = Arc::new(AssetsSagaContext {
+        app: context,
+        character_id,
+        stats: FetchStats::default(),
+        listed_assets: Mutex::new(BTreeMap::new()),
+    });
+
+    let saga = AssetsSaga::new(context.clone(), workers_count);
+    context
+        .app
+        .assets_progress
+        .write()
         .await
+        .insert(character_id, saga.subscribe_progress());
+    let cancellation = saga.cancellation_token();
+    context
+        .app
+        .assets_cancellation
+        .write()
+        .await
+        .insert(character_id, cancellation.clone());
+
+    let result = saga
+        .start_with_event(
+            AssetsInitialEvent {
+                character_id,
+                corporation_id,
+            },
+            deadline,
+        )
+        .await;
+
+    // Finished runs can't be cancelled anymore
+    cancellation.cancel();
+    // A failed, cancelled or timed out run leaves the stored assets as they were
+    let outcome = result?;
+    let changes = replace_listed_assets(&context, &outcome.dead_letters);
+
+    let fetch_stats = context.stats.snapshot();
+    *context.app.last_assets_fetch_stats.write().await = Some(fetch_stats);
+    Ok(AssetsSagaOutcome {
+        resolved_count: outcome.resolved,
+        elapsed: outcome.elapsed,
+        // Hoboleaks isn't ESI, everything else fetched is
+        esi_calls: fetch_stats.total().fetches - fetch_stats.hoboleaks.fetches,
+        fetch_stats,
+        dead_letters: outcome.dead_letters,
+        changes,
+    })
 }
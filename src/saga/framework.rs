@@ -4,11 +4,13 @@ use std::collections::{BTreeSet, HashMap};
 use std::fmt::Debug;
 use std::hash::Hash;
 use std::sync::Arc;
-use std::time::Instant;
+use std::time::{Duration, Instant};
 use thiserror::Error;
 use tokio::sync::Mutex;
-use tokio::sync::mpsc;
+use tokio::sync::{mpsc, watch};
 use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+use tracing::{Instrument, debug, debug_span, error, info, warn};
 use uuid::Uuid;
 
 /// Core trait that defines saga-specific behavior
@@ -45,6 +47,32 @@ pub trait SagaProcessor: Clone + Send + Sync + 'static {
         work_type: &Self::WorkType,
     ) -> impl std::future::Future<Output = Result<Self::WorkResult, Self::Error>> + Send;
 
+    /// Whether a failed work item is worth retrying, permanent failures become dead
+    /// letters right away
+    fn is_retryable(_error: &Self::Error) -> bool {
+        true
+    }
+
+    /// Whether `b` can be processed in the same `process_batch` call as `a`.
+    /// Batchable work must have adjacent keys, batches are taken in key order.
+    fn can_batch(_a: &Self::WorkType, _b: &Self::WorkType) -> bool {
+        false
+    }
+
+    /// Process work items grouped by `can_batch`, returns one result per item in order
+    fn process_batch(
+        context: &Arc<Self::Context>,
+        work_types: &[Self::WorkType],
+    ) -> impl std::future::Future<Output = Vec<Result<Self::WorkResult, Self::Error>>> + Send {
+        async move {
+            let mut results = Vec::with_capacity(work_types.len());
+            for work_type in work_types {
+                results.push(Self::process(context, work_type).await);
+            }
+            results
+        }
+    }
+
     /// Handle work result and return new work items
     fn handle(
         context: &Arc<Self::Context>,
@@ -58,15 +86,19 @@ pub struct WorkItem<P: SagaProcessor> {
     pub created_at: Instant,
     pub retry_count: u32,
     pub work_resolution_key: P::WorkKey,
+    /// Not handed to a worker before this, pushed back after every failure
+    pub next_attempt_at: Instant,
 }
 
 impl<P: SagaProcessor> WorkItem<P> {
     pub fn new(work_type: P::WorkType) -> Self {
+        let now = Instant::now();
         Self {
             work_resolution_key: P::to_resolution_key(&work_type),
             work_type,
-            created_at: Instant::now(),
+            created_at: now,
             retry_count: 0,
+            next_attempt_at: now,
         }
     }
 }
@@ -78,6 +110,7 @@ impl<P: SagaProcessor> Clone for WorkItem<P> {
             created_at: self.created_at,
             retry_count: self.retry_count,
             work_resolution_key: self.work_resolution_key.clone(),
+            next_attempt_at: self.next_attempt_at,
         }
     }
 }
@@ -89,6 +122,7 @@ impl<P: SagaProcessor> Debug for WorkItem<P> {
             .field("created_at", &self.created_at)
             .field("retry_count", &self.retry_count)
             .field("work_resolution_key", &self.work_resolution_key)
+            .field("next_attempt_at", &self.next_attempt_at)
             .finish()
     }
 }
@@ -119,6 +153,53 @@ pub struct WorkMessage<P: SagaProcessor> {
     pub work_result: Result<Vec<WorkItem<P>>, P::Error>,
 }
 
+/// What a worker sends back, an error stops the saga
+type WorkerMessage<P> = Result<WorkMessage<P>, SagaError<<P as SagaProcessor>::Error>>;
+
+/// Snapshot of how far a saga got
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Serialize, utoipa::ToSchema)]
+pub struct SagaProgress {
+    pub pending: usize,
+    pub in_flight: usize,
+    pub resolved: usize,
+    /// Distinct work items seen so far, grows as results discover new work
+    pub total_seen: usize,
+}
+
+/// How a saga deals with failing work
+#[derive(Debug, Clone, Copy)]
+pub struct SagaOptions {
+    pub max_retries: u32,
+    /// A failed item is retried after `retry_base_delay * 2^retry_count`
+    pub retry_base_delay: Duration,
+    /// Abort the whole saga once an item runs out of retries instead of
+    /// recording it as a dead letter and carrying on
+    pub fail_fast: bool,
+    /// Most work items handed to a worker at once, see `SagaProcessor::can_batch`
+    pub batch_size: usize,
+}
+
+impl Default for SagaOptions {
+    fn default() -> Self {
+        Self {
+            max_retries: MAX_RETRIES,
+            retry_base_delay: RETRY_BASE_DELAY,
+            fail_fast: false,
+            batch_size: BATCH_SIZE,
+        }
+    }
+}
+
+/// Result of a saga run to completion
+#[derive(Debug, Clone)]
+pub struct SagaOutcome<K> {
+    pub resolved: usize,
+    /// Wall-clock time from starting the workers to the last result
+    pub elapsed: Duration,
+    /// Work that failed permanently, with the last error
+    pub dead_letters: Vec<(K, String)>,
+}
+
 /// Generic saga orchestrator
 pub struct Saga<P: SagaProcessor> {
     pub workflow_id: Uuid,
@@ -130,24 +211,57 @@ pub struct Saga<P: SagaProcessor> {
 
     context: Arc<P::Context>,
     workers_count: usize,
-    work_sender: mpsc::UnboundedSender<WorkItem<P>>,
-    result_receiver: mpsc::UnboundedReceiver<WorkMessage<P>>,
-    shared_work_receiver: Arc<Mutex<mpsc::UnboundedReceiver<WorkItem<P>>>>,
-    result_sender: mpsc::UnboundedSender<WorkMessage<P>>,
-    max_retries: u32,
+    work_sender: mpsc::UnboundedSender<Vec<WorkItem<P>>>,
+    result_receiver: mpsc::UnboundedReceiver<WorkerMessage<P>>,
+    shared_work_receiver: Arc<Mutex<mpsc::UnboundedReceiver<Vec<WorkItem<P>>>>>,
+    result_sender: mpsc::UnboundedSender<WorkerMessage<P>>,
+    progress_sender: watch::Sender<SagaProgress>,
+    cancellation: CancellationToken,
+    /// Stops the workers in the middle of their work, only the deadline fires it
+    work_cancellation: CancellationToken,
+    options: SagaOptions,
+    dead_letters: Vec<(P::WorkKey, String)>,
 }
 
 const MAX_RETRIES: u32 = 3;
+const RETRY_BASE_DELAY: Duration = Duration::from_millis(500);
+const BATCH_SIZE: usize = 100;
 
 impl<P: SagaProcessor> Saga<P> {
     pub fn new(context: Arc<P::Context>, workers_count: usize) -> Self {
-        Self::with_max_retries(context, workers_count, MAX_RETRIES)
+        Self::with_options(context, workers_count, SagaOptions::default())
     }
 
     pub fn with_max_retries(
         context: Arc<P::Context>,
         workers_count: usize,
         max_retries: u32,
+    ) -> Self {
+        Self::with_retry_policy(context, workers_count, max_retries, RETRY_BASE_DELAY)
+    }
+
+    /// A failed item is retried after `retry_base_delay * 2^retry_count`
+    pub fn with_retry_policy(
+        context: Arc<P::Context>,
+        workers_count: usize,
+        max_retries: u32,
+        retry_base_delay: Duration,
+    ) -> Self {
+        Self::with_options(
+            context,
+            workers_count,
+            SagaOptions {
+                max_retries,
+                retry_base_delay,
+                ..SagaOptions::default()
+            },
+        )
+    }
+
+    pub fn with_options(
+        context: Arc<P::Context>,
+        workers_count: usize,
+        options: SagaOptions,
     ) -> Self {
         let (work_sender, work_receiver) = mpsc::unbounded_channel();
         let (result_sender, result_receiver) = mpsc::unbounded_channel();
@@ -165,13 +279,46 @@ impl<P: SagaProcessor> Saga<P> {
             result_receiver,
             shared_work_receiver,
             result_sender,
-            max_retries,
+            progress_sender: watch::Sender::new(SagaProgress::default()),
+            cancellation: CancellationToken::new(),
+            work_cancellation: CancellationToken::new(),
+            options,
+            dead_letters: vec![],
         }
     }
 
+    pub fn progress(&self) -> SagaProgress {
+        let pending = self.pending.len();
+        let in_flight = self.in_flight_work.len();
+        let resolved = self.resolved.len();
+
+        SagaProgress {
+            pending,
+            in_flight,
+            resolved,
+            total_seen: pending + in_flight + resolved,
+        }
+    }
+
+    /// Receive a fresh `SagaProgress` after every handled result. Subscribe before
+    /// starting the saga, the receiver sees the last snapshot once the saga is gone.
+    pub fn subscribe_progress(&self) -> watch::Receiver<SagaProgress> {
+        self.progress_sender.subscribe()
+    }
+
+    /// Token stopping the saga when cancelled: no new work is sent, in-flight work is
+    /// awaited and `start_with_event` returns `SagaError::Cancelled`
+    pub fn cancellation_token(&self) -> CancellationToken {
+        self.cancellation.clone()
+    }
+
+    fn publish_progress(&self) {
+        self.progress_sender.send_replace(self.progress());
+    }
+
     pub fn print_pending_summary(&self, count: usize) {
         let first_pending: Vec<&WorkItem<P>> = self.pending.iter().take(count).collect();
-        println!(
+        debug!(
             "First {} / {} pending: {:?}",
             count.min(self.pending.len()),
             self.pending.len(),
@@ -179,10 +326,17 @@ impl<P: SagaProcessor> Saga<P> {
         );
     }
 
+    /// Run the saga to completion. With a `deadline` the saga gives up once the
+    /// wall-clock limit is hit: nothing more is dispatched, the in-flight work is
+    /// cancelled and the partial progress is reported.
     pub async fn start_with_event(
         mut self,
         initial_event: P::InitialEvent,
-    ) -> Result<(), SagaError<P::Error>> {
+        deadline: Option<Duration>,
+    ) -> Result<SagaOutcome<P::WorkKey>, SagaError<P::Error>> {
+        let started_at = Instant::now();
+        let deadline_at = deadline.map(|d| tokio::time::Instant::now() + d);
+
         // Start workers
         let mut worker_handles: Vec<JoinHandle<()>> = vec![];
 
@@ -191,9 +345,11 @@ impl<P: SagaProcessor> Saga<P> {
                 self.context.clone(),
                 self.shared_work_receiver.clone(),
                 self.result_sender.clone(),
+                self.work_cancellation.clone(),
             );
 
-            let handle = tokio::spawn(async move { worker.start().await });
+            let span = debug_span!("saga_worker", workflow_id = %self.workflow_id, worker_id = %worker.worker_id);
+            let handle = tokio::spawn(async move { worker.start().await }.instrument(span));
             worker_handles.push(handle);
         }
 
@@ -204,20 +360,55 @@ impl<P: SagaProcessor> Saga<P> {
         }
 
         self.status = SagaStatus::Processing;
+        self.publish_progress();
 
         // Main processing loop
         loop {
+            if let Some(deadline_at) = deadline_at
+                && deadline_at <= tokio::time::Instant::now()
+            {
+                warn!(
+                    "Saga deadline exceeded, resolved: {}, in flight: {}, pending: {}",
+                    self.resolved.len(),
+                    self.in_flight_work.len(),
+                    self.pending.len()
+                );
+                self.cancellation.cancel();
+                self.work_cancellation.cancel();
+                return self.drain_cancelled(worker_handles, deadline).await;
+            }
+
+            if self.cancellation.is_cancelled() {
+                return self.drain_cancelled(worker_handles, None).await;
+            }
+
             self.print_pending_summary(6);
 
             // Send work if available
-            if let Some(work_item) = self.get_work() {
-                if let Err(e) = self.work_sender.send(work_item) {
-                    eprintln!("Unable to send work item: {}", e);
+            if let Some(batch) = self.get_work() {
+                if let Err(e) = self.work_sender.send(batch) {
+                    error!("Unable to send work item: {}", e);
                 }
             }
 
-            // Receive results
-            if let Some(message) = self.result_receiver.recv().await {
+            // Receive results, waking up early to hand out retries once their backoff passed
+            let wake_at = [deadline_at, self.next_retry_at()]
+                .into_iter()
+                .flatten()
+                .min();
+            let maybe_message = match wake_at {
+                Some(wake_at) => {
+                    match tokio::time::timeout_at(wake_at, self.result_receiver.recv()).await {
+                        Ok(maybe_message) => maybe_message,
+                        // A retry is due or the deadline passed, the top of the loop sorts it out
+                        Err(_) => continue,
+                    }
+                }
+                None => self.result_receiver.recv().await,
+            };
+
+            if let Some(message) = maybe_message {
+                let message = message?;
                 let work_resolution_key = message.work_resolution_key;
 
                 match message.work_result {
@@ -228,14 +419,15 @@ impl<P: SagaProcessor> Saga<P> {
                         self.handle_work_failed(work_resolution_key, e)?;
                     }
                 }
+                self.publish_progress();
 
                 if self.is_complete() {
-                    println!("Saga completed successfully");
+                    info!(workflow_id = %self.workflow_id, "Saga completed successfully");
                     self.status = SagaStatus::Completed;
                     break;
                 }
             } else {
-                println!("Result channel closed");
+                warn!("Result channel closed");
                 break;
             }
         }
@@ -245,11 +437,76 @@ impl<P: SagaProcessor> Saga<P> {
 
         for handle in worker_handles {
             if let Err(e) = handle.await {
-                eprintln!("Worker task failed: {}", e);
+                error!("Worker task failed: {}", e);
             }
         }
 
-        Ok(())
+        if !self.dead_letters.is_empty() {
+            warn!(
+                "Saga finished with {} dead letters",
+                self.dead_letters.len()
+            );
+        }
+
+        Ok(SagaOutcome {
+            resolved: self.resolved.len(),
+            elapsed: started_at.elapsed(),
+            dead_letters: self.dead_letters,
+        })
+    }
+
+    /// Wait for the in-flight work of a cancelled saga, then shut the workers down.
+    /// `deadline` is the limit that cancelled the saga, None for an external cancellation.
+    /// Work the deadline cancelled is never reported and stays in flight.
+    async fn drain_cancelled(
+        mut self,
+        worker_handles: Vec<JoinHandle<()>>,
+        deadline: Option<Duration>,
+    ) -> Result<SagaOutcome<P::WorkKey>, SagaError<P::Error>> {
+        info!(
+            "Saga cancelled, draining {} in flight items",
+            self.in_flight_work.len()
+        );
+
+        self.close_channels();
+        while let Some(message) = self.result_receiver.recv().await {
+            // A failed batch never reports its items
+            let Ok(message) = message else {
+                continue;
+            };
+            if let Some(work_item) = self.in_flight_work.remove(&message.work_resolution_key)
+                && message.work_result.is_ok()
+            {
+                self.resolved.insert(work_item.work_resolution_key);
+            }
+        }
+        self.publish_progress();
+
+        for handle in worker_handles {
+            if let Err(e) = handle.await {
+                error!("Worker task failed: {}", e);
+            }
+        }
+
+        match deadline {
+            Some(deadline) => Err(SagaError::DeadlineExceeded {
+                deadline,
+                resolved: self.resolved.len(),
+                in_flight: self.in_flight_work.len(),
+                pending: self.pending.len(),
+            }),
+            None => Err(SagaError::Cancelled {
+                resolved: self.resolved.len(),
+                pending: self.pending.len(),
+            }),
+        }
+    }
+
+    /// Swap the work and results senders for dead ones: the workers exit once the work
+    /// sent to them is done, and the results channel closes with the last of them
+    fn close_channels(&mut self) {
+        (self.work_sender, _) = mpsc::unbounded_channel();
+        (self.result_sender, _) = mpsc::unbounded_channel();
     }
 
     fn handle_work_completed(
@@ -257,10 +514,10 @@ impl<P: SagaProcessor> Saga<P> {
         work_resolution_key: P::WorkKey,
         new_work_items: Vec<WorkItem<P>>,
     ) -> Result<(), SagaError<P::Error>> {
-        println!(
-            "Work completed: {:?}, new items: {}",
-            work_resolution_key,
-            new_work_items.len()
+        debug!(
+            key = ?work_resolution_key,
+            new_items = new_work_items.len(),
+            "work completed"
         );
 
         if let Some(work_item) = self.in_flight_work.remove(&work_resolution_key) {
@@ -273,7 +530,7 @@ impl<P: SagaProcessor> Saga<P> {
                 }
             }
         } else {
-            eprintln!(
+            warn!(
                 "Unable to find work item for key: {:?}",
                 work_resolution_key
             );
@@ -288,26 +545,43 @@ impl<P: SagaProcessor> Saga<P> {
         error: P::Error,
     ) -> Result<(), SagaError<P::Error>> {
         if let Some(mut work_item) = self.in_flight_work.remove(&work_resolution_key) {
-            work_item.retry_count += 1;
-            if work_item.retry_count < self.max_retries {
-                println!(
-                    "Retrying work item (attempt {}): {:?}",
+            // Including the one that just failed
+            let attempts = work_item.retry_count + 1;
+            work_item.retry_count = attempts;
+            if attempts < self.options.max_retries && P::is_retryable(&error) {
+                let backoff = self
+                    .options
+                    .retry_base_delay
+                    .saturating_mul(2u32.saturating_pow(work_item.retry_count));
+                work_item.next_attempt_at = Instant::now() + backoff;
+                info!(
+                    "Retrying work item (attempt {}) in {:?}: {:?}",
                     work_item.retry_count + 1,
+                    backoff,
                     work_resolution_key
                 );
                 self.pending.insert(work_item);
             } else {
-                eprintln!(
-                    "Work item failed after {} retries: {:?}, error: {}",
-                    self.max_retries, work_resolution_key, error
+                warn!(
+                    "Work item failed after {} attempts: {:?}, error: {}",
+                    attempts, work_resolution_key, error
                 );
-                return Err(SagaError::ProcessingError(error));
+                if self.options.fail_fast {
+                    return Err(SagaError::ProcessingError(error));
+                }
+                self.dead_letters
+                    .push((work_resolution_key, error.to_string()));
             }
         }
         Ok(())
     }
 
-    fn get_work(&mut self) -> Option<WorkItem<P>> {
+    /// Next ready work item, along with the following ones it can be batched with
+    fn get_work(&mut self) -> Option<Vec<WorkItem<P>>> {
+        let now = Instant::now();
+        let mut backing_off = vec![];
+        let mut batch: Vec<WorkItem<P>> = vec![];
+
         while let Some(work_item) = self.pending.pop_first() {
             if self.is_resolved(&work_item.work_resolution_key) {
                 continue;
@@ -320,13 +594,38 @@ impl<P: SagaProcessor> Saga<P> {
                 continue;
             }
 
+            if let Some(first) = batch.first()
+                && (batch.len() >= self.options.batch_size
+                    || !P::can_batch(&first.work_type, &work_item.work_type))
+            {
+                self.pending.insert(work_item);
+                break;
+            }
+
+            if work_item.next_attempt_at > now {
+                backing_off.push(work_item);
+                continue;
+            }
+
             self.in_flight_work
                 .insert(work_item.work_resolution_key.clone(), work_item.clone());
 
-            return Some(work_item);
+            batch.push(work_item);
         }
 
-        None
+        self.pending.extend(backing_off);
+        (!batch.is_empty()).then_some(batch)
+    }
+
+    /// Earliest moment a pending retry becomes due, None if nothing is backing off
+    fn next_retry_at(&self) -> Option<tokio::time::Instant> {
+        let now = Instant::now();
+        self.pending
+            .iter()
+            .map(|work_item| work_item.next_attempt_at)
+            .filter(|at| *at > now)
+            .min()
+            .map(tokio::time::Instant::from_std)
     }
 
     fn is_complete(&self) -> bool {
@@ -334,7 +633,9 @@ impl<P: SagaProcessor> Saga<P> {
     }
 
     fn is_resolved(&self, key: &P::WorkKey) -> bool {
-        self.in_flight_work.contains_key(key) || self.resolved.contains(key)
+        self.in_flight_work.contains_key(key)
+            || self.resolved.contains(key)
+            || self.dead_letters.iter().any(|(dead, _)| dead == key)
     }
 }
 
@@ -342,65 +643,104 @@ impl<P: SagaProcessor> Saga<P> {
 struct Worker<P: SagaProcessor> {
     worker_id: Uuid,
     context: Arc<P::Context>,
-    work_receiver: Arc<Mutex<mpsc::UnboundedReceiver<WorkItem<P>>>>,
-    result_sender: mpsc::UnboundedSender<WorkMessage<P>>,
+    work_receiver: Arc<Mutex<mpsc::UnboundedReceiver<Vec<WorkItem<P>>>>>,
+    result_sender: mpsc::UnboundedSender<WorkerMessage<P>>,
+    work_cancellation: CancellationToken,
 }
 
 impl<P: SagaProcessor> Worker<P> {
     fn new(
         context: Arc<P::Context>,
-        work_receiver: Arc<Mutex<mpsc::UnboundedReceiver<WorkItem<P>>>>,
-        result_sender: mpsc::UnboundedSender<WorkMessage<P>>,
+        work_receiver: Arc<Mutex<mpsc::UnboundedReceiver<Vec<WorkItem<P>>>>>,
+        result_sender: mpsc::UnboundedSender<WorkerMessage<P>>,
+        work_cancellation: CancellationToken,
     ) -> Self {
         Self {
             worker_id: Uuid::new_v4(),
             context,
             work_receiver,
             result_sender,
+            work_cancellation,
         }
     }
 
     async fn start(&self) {
         loop {
-            let maybe_work_item = {
+            let maybe_batch = {
                 let mut receiver = self.work_receiver.lock().await;
                 receiver.recv().await
             };
 
-            if let Some(work_item) = maybe_work_item {
-                println!(
-                    "Worker {} processing: {:?}",
-                    self.worker_id, work_item.work_type
-                );
+            if let Some(batch) = maybe_batch {
+                // Cancelled work is dropped without a result, the saga has given up on it
+                tokio::select! {
+                    biased;
+                    _ = self.work_cancellation.cancelled() => {
+                        debug!("work cancelled, worker shutting down");
+                        break;
+                    }
+                    _ = self.process(batch) => {}
+                }
+            } else {
+                debug!("worker shutting down");
+                break;
+            }
+        }
+    }
+
+    /// Process a batch and send its results to the saga
+    async fn process(&self, batch: Vec<WorkItem<P>>) {
+        let work_types: Vec<P::WorkType> = batch
+            .iter()
+            .map(|work_item| work_item.work_type.clone())
+            .collect();
+        debug!(work = ?work_types, "processing");
+
+        let results = match work_types.as_slice() {
+            [work_type] => vec![P::process(&self.context, work_type).await],
+            _ => P::process_batch(&self.context, &work_types).await,
+        };
+        if results.len() != batch.len() {
+            error!(
+                "process_batch returned {} results for {} work items",
+                results.len(),
+                batch.len()
+            );
+            let mismatch = SagaError::BatchResultMismatch {
+                work_items: batch.len(),
+                results: results.len(),
+            };
+            if let Err(e) = self.result_sender.send(Err(mismatch)) {
+                error!("Error sending work message: {}", e);
+            }
+            return;
+        }
 
-                let work_resolution_key = work_item.work_resolution_key.clone();
+        for (work_item, result) in batch.into_iter().zip(results) {
+            let work_resolution_key = work_item.work_resolution_key;
 
-                let work_message = match P::process(&self.context, &work_item.work_type).await {
-                    Ok(work_result) => match P::handle(&self.context, work_result).await {
-                        Ok(new_work_types) => {
-                            let new_items = new_work_types.into_iter().map(WorkItem::new).collect();
-                            WorkMessage {
-                                work_resolution_key,
-                                work_result: Ok(new_items),
-                            }
-                        }
-                        Err(e) => WorkMessage {
+            let work_message = match result {
+                Ok(work_result) => match P::handle(&self.context, work_result).await {
+                    Ok(new_work_types) => {
+                        let new_items = new_work_types.into_iter().map(WorkItem::new).collect();
+                        WorkMessage {
                             work_resolution_key,
-                            work_result: Err(e),
-                        },
-                    },
+                            work_result: Ok(new_items),
+                        }
+                    }
                     Err(e) => WorkMessage {
                         work_resolution_key,
                         work_result: Err(e),
                     },
-                };
+                },
+                Err(e) => WorkMessage {
+                    work_resolution_key,
+                    work_result: Err(e),
+                },
+            };
 
-                if let Err(e) = self.result_sender.send(work_message) {
-                    eprintln!("Error sending work message: {}", e);
-                }
-            } else {
-                println!("Worker {} shutting down", self.worker_id);
-                break;
+            if let Err(e) = self.result_sender.send(Ok(work_message)) {
+                error!("Error sending work message: {}", e);
             }
         }
     }
@@ -419,4 +759,178 @@ pub enum SagaError<E: std::error::Error> {
     InvalidState,
     #[error("Processing error: {0}")]
     ProcessingError(E),
+    #[error(
+        "Deadline of {deadline:?} exceeded ({resolved} resolved, {in_flight} in flight, {pending} pending)"
+    )]
+    DeadlineExceeded {
+        deadline: Duration,
+        resolved: usize,
+        in_flight: usize,
+        pending: usize,
+    },
+    #[error("Cancelled ({resolved} resolved, {pending} pending)")]
+    Cancelled { resolved: usize, pending: usize },
+    #[error("process_batch returned {results} results for {work_items} work items")]
+    BatchResultMismatch { work_items: usize, results: usize },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Work is the number of milliseconds to sleep for
+    #[derive(Clone)]
+    struct Sleeper;
+
+    impl SagaProcessor for Sleeper {
+        type WorkType = u64;
+        type WorkKey = u64;
+        type WorkResult = ();
+        type Error = std::io::Error;
+        type Context = ();
+        type InitialEvent = Vec<u64>;
+
+        fn to_resolution_key(work_type: &u64) -> u64 {
+            *work_type
+        }
+
+        fn handle_initial_event(event: Vec<u64>) -> Result<Vec<u64>, SagaError<Self::Error>> {
+            Ok(event)
+        }
+
+        async fn process(_context: &Arc<()>, work_type: &u64) -> Result<(), Self::Error> {
+            tokio::time::sleep(Duration::from_millis(*work_type)).await;
+            Ok(())
+        }
+
+        async fn handle(_context: &Arc<()>, _work_result: ()) -> Result<Vec<u64>, Self::Error> {
+            Ok(vec![])
+        }
+    }
+
+    /// Fails every item, counting the attempts. Work 0 fails permanently.
+    #[derive(Clone)]
+    struct Failing;
+
+    impl SagaProcessor for Failing {
+        type WorkType = u64;
+        type WorkKey = u64;
+        type WorkResult = ();
+        type Error = std::io::Error;
+        type Context = std::sync::atomic::AtomicUsize;
+        type InitialEvent = u64;
+
+        fn to_resolution_key(work_type: &u64) -> u64 {
+            *work_type
+        }
+
+        fn handle_initial_event(event: u64) -> Result<Vec<u64>, SagaError<Self::Error>> {
+            Ok(vec![event])
+        }
+
+        async fn process(
+            attempts: &Arc<Self::Context>,
+            work_type: &u64,
+        ) -> Result<(), Self::Error> {
+            attempts.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            let kind = match work_type {
+                0 => std::io::ErrorKind::InvalidData,
+                _ => std::io::ErrorKind::TimedOut,
+            };
+            Err(std::io::Error::from(kind))
+        }
+
+        fn is_retryable(error: &Self::Error) -> bool {
+            error.kind() == std::io::ErrorKind::TimedOut
+        }
+
+        async fn handle(
+            _context: &Arc<Self::Context>,
+            _work_result: (),
+        ) -> Result<Vec<u64>, Self::Error> {
+            Ok(vec![])
+        }
+    }
+
+    async fn failing_attempts(work: u64) -> (usize, SagaOutcome<u64>) {
+        let attempts = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let saga = Saga::<Failing>::with_retry_policy(attempts.clone(), 1, 3, Duration::ZERO);
+        let outcome = saga.start_with_event(work, None).await.unwrap();
+        (attempts.load(std::sync::atomic::Ordering::SeqCst), outcome)
+    }
+
+    #[tokio::test]
+    async fn temporary_failure_is_dead_after_max_retries_attempts() {
+        let (attempts, outcome) = failing_attempts(1).await;
+        assert_eq!(attempts, 3);
+        assert_eq!(outcome.resolved, 0);
+        assert_eq!(outcome.dead_letters.len(), 1);
+        assert_eq!(outcome.dead_letters[0].0, 1);
+    }
+
+    #[tokio::test]
+    async fn permanent_failure_is_dead_after_one_attempt() {
+        let (attempts, outcome) = failing_attempts(0).await;
+        assert_eq!(attempts, 1);
+        assert_eq!(outcome.dead_letters.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn slow_work_hits_the_deadline() {
+        let saga = Saga::<Sleeper>::new(Arc::new(()), 1);
+        let started_at = Instant::now();
+        let result = saga
+            .start_with_event(vec![1, 2, 60_000], Some(Duration::from_millis(200)))
+            .await;
+
+        assert!(started_at.elapsed() < Duration::from_secs(5));
+        match result {
+            Err(SagaError::DeadlineExceeded {
+                deadline,
+                resolved,
+                in_flight,
+                pending,
+            }) => {
+                assert_eq!(deadline, Duration::from_millis(200));
+                assert_eq!((resolved, in_flight, pending), (2, 1, 0));
+            }
+            other => panic!(
+                "expected DeadlineExceeded, got {:?}",
+                other.map(|o| o.resolved)
+            ),
+        }
+    }
+
+    #[tokio::test]
+    async fn nothing_is_dispatched_past_the_deadline() {
+        let saga = Saga::<Sleeper>::new(Arc::new(()), 1);
+        let result = saga
+            .start_with_event(vec![0, 1, 2], Some(Duration::ZERO))
+            .await;
+
+        match result {
+            Err(SagaError::DeadlineExceeded {
+                resolved,
+                in_flight,
+                pending,
+                ..
+            }) => assert_eq!((resolved, in_flight, pending), (0, 0, 3)),
+            other => panic!(
+                "expected DeadlineExceeded, got {:?}",
+                other.map(|o| o.resolved)
+            ),
+        }
+    }
+
+    #[tokio::test]
+    async fn fast_work_finishes_before_the_deadline() {
+        let saga = Saga::<Sleeper>::new(Arc::new(()), 2);
+        let outcome = saga
+            .start_with_event(vec![1, 2, 3], Some(Duration::from_secs(10)))
+            .await
+            .unwrap();
+
+        assert_eq!(outcome.resolved, 3);
+        assert!(outcome.dead_letters.is_empty());
+    }
 }
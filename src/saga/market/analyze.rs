@@ -0,0 +1,52 @@
+// saga/market/analyze.rs - Views derived from resolved market orders
+use serde::Serialize;
+use utoipa::ToSchema;
+
+use crate::MarketOrder;
+
+/// Best prices of both sides of a market and the margin between them
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, ToSchema)]
+pub struct Spread {
+    /// Highest buy order, None without buy orders
+    pub best_buy: Option<f64>,
+    /// Lowest sell order, None without sell orders
+    pub best_sell: Option<f64>,
+    /// `(best_sell - best_buy) / best_sell` in percent, the margin of buying at the best
+    /// buy price and reselling at the best sell price. None unless both sides have orders
+    pub spread_pct: Option<f64>,
+    /// Remaining volume of all buy orders
+    pub buy_volume: i64,
+    /// Remaining volume of all sell orders
+    pub sell_volume: i64,
+}
+
+/// Spread of the buy and sell orders of one market, None if there are no orders at all
+pub fn spread(orders: &[MarketOrder]) -> Option<Spread> {
+    if orders.is_empty() {
+        return None;
+    }
+
+    let (buys, sells): (Vec<&MarketOrder>, Vec<&MarketOrder>) =
+        orders.iter().partition(|order| order.is_buy_order());
+
+    let best_buy = buys
+        .iter()
+        .map(|order| order.price())
+        .max_by(f64::total_cmp);
+    let best_sell = sells
+        .iter()
+        .map(|order| order.price())
+        .min_by(f64::total_cmp);
+    let spread_pct = match (best_buy, best_sell) {
+        (Some(buy), Some(sell)) if sell > 0.0 => Some((sell - buy) / sell * 100.0),
+        _ => None,
+    };
+
+    Some(Spread {
+        best_buy,
+        best_sell,
+        spread_pct,
+        buy_volume: buys.iter().map(|order| order.volume_remain()).sum(),
+        sell_volume: sells.iter().map(|order| order.volume_remain()).sum(),
+    })
+}
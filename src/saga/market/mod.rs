@@ -1,6 +1,8 @@
 use crate::AppContext;
 use crate::esi;
+use crate::mydb::market_orders::MarketOrdersKey;
 use crate::{MarketOrder, RegionId, TypeId};
+use tracing::{debug, error, warn};
 
 use std::collections::{BTreeSet, HashMap};
 use std::sync::Arc;
@@ -9,6 +11,9 @@ use thiserror::Error;
 use tokio::sync::RwLock;
 use uuid::Uuid;
 
+pub mod analyze;
+pub mod refresher;
+
 #[derive(Clone, Debug)]
 pub struct WorkItem {
     pub id: Uuid,
@@ -32,12 +37,20 @@ impl PartialOrd for WorkItem {
     }
 }
 
+/// Higher priority first, so queues pop page 1 requests (which discover the page count)
+/// before the fan-out of deeper pages
 impl Ord for WorkItem {
     fn cmp(&self, other: &Self) -> std::cmp::Ordering {
-        self.work_type.cmp(&other.work_type)
+        other
+            .priority
+            .cmp(&self.priority)
+            .then_with(|| self.work_type.cmp(&other.work_type))
     }
 }
 
+const FIRST_PAGE_PRIORITY: u8 = 10;
+const PAGE_PRIORITY: u8 = 5;
+
 #[derive(Clone, Eq, PartialEq, PartialOrd, Ord, Debug)]
 pub enum WorkType {
     MarketOrderSell {
@@ -77,10 +90,32 @@ pub struct MarketResolutionSaga {
 
     pub resolved_market_orders_sell: BTreeSet<(RegionId, TypeId, usize)>,
     pub resolved_market_orders_buy: BTreeSet<(RegionId, TypeId, usize)>,
+
+    pub targets: Vec<(RegionId, TypeId)>,
+
+    // Orders of all resolved pages, written to the market orders db once the saga completes
+    pub orders: HashMap<MarketOrdersKey, Vec<MarketOrder>>,
+}
+
+// (region_id = 10000002, type_id = 44992) - plex
+// (region_id = 10000002, type_id = 40520) - LSI
+// (region_id = 10000002, type_id = 40519) - Skill Extractor
+pub const DEFAULT_TARGETS: [(RegionId, i32); 3] =
+    [(10000002, 44992), (10000002, 40520), (10000002, 40519)];
+
+pub fn default_targets() -> Vec<(RegionId, TypeId)> {
+    DEFAULT_TARGETS
+        .iter()
+        .map(|(region_id, type_id)| (*region_id, (*type_id).into()))
+        .collect()
 }
 
 impl MarketResolutionSaga {
     pub fn new(context: Arc<AppContext>) -> Self {
+        Self::new_with_targets(context, default_targets())
+    }
+
+    pub fn new_with_targets(context: Arc<AppContext>, targets: Vec<(RegionId, TypeId)>) -> Self {
         MarketResolutionSaga {
             workflow_id: Uuid::new_v4(),
             status: SagaStatus::Started,
@@ -90,6 +125,8 @@ impl MarketResolutionSaga {
             in_flight_work: HashMap::new(),
             resolved_market_orders_sell: BTreeSet::new(),
             resolved_market_orders_buy: BTreeSet::new(),
+            targets,
+            orders: HashMap::new(),
         }
     }
 
@@ -113,18 +150,8 @@ impl MarketResolutionSaga {
             SagaEvent::SagaStarted => {
                 self.status = SagaStatus::Processing;
 
-                // (region_id = 10000002, type_id = 44992, page = 1) - plex
-                // (region_id = 10000002, type_id = 40520, page = 1) - LSI
-                // (region_id = 10000002, type_id = 40519, page = 1) - Skill Extractor
-                let data = vec![
-                    (10000002, 44992, 1),
-                    (10000002, 40520, 1),
-                    (10000002, 40519, 1),
-                ];
-
-                for (region_id, type_id, page) in data {
-                    let type_id = type_id.into();
-                    
+                let page = 1;
+                for (region_id, type_id) in self.targets.clone() {
                     self.market_orders_buy_queue.insert(WorkItem {
                         id: Uuid::new_v4(),
                         work_type: WorkType::MarketOrderBuy {
@@ -132,7 +159,7 @@ impl MarketResolutionSaga {
                             type_id,
                             page,
                         },
-                        priority: 5,
+                        priority: FIRST_PAGE_PRIORITY,
                         created_at: Instant::now(),
                         retry_count: 0,
                     });
@@ -144,7 +171,7 @@ impl MarketResolutionSaga {
                             type_id,
                             page,
                         },
-                        priority: 5,
+                        priority: FIRST_PAGE_PRIORITY,
                         created_at: Instant::now(),
                         retry_count: 0,
                     });
@@ -163,7 +190,10 @@ impl MarketResolutionSaga {
                             self.resolved_market_orders_sell
                                 .insert((region_id, type_id, page));
 
-                            println!("market orders: {:?}", orders);
+                            self.orders
+                                .entry((region_id, type_id, false))
+                                .or_default()
+                                .extend(orders);
 
                             if page == 1 {
                                 for page in 2..=total_pages {
@@ -174,7 +204,7 @@ impl MarketResolutionSaga {
                                             type_id,
                                             page,
                                         },
-                                        priority: 5,
+                                        priority: PAGE_PRIORITY,
                                         created_at: Instant::now(),
                                         retry_count: 0,
                                     };
@@ -192,7 +222,10 @@ impl MarketResolutionSaga {
                             self.resolved_market_orders_buy
                                 .insert((region_id, type_id, page));
 
-                            println!("market orders: {:?}", orders);
+                            self.orders
+                                .entry((region_id, type_id, true))
+                                .or_default()
+                                .extend(orders);
 
                             if page == 1 {
                                 for page in 2..=total_pages {
@@ -203,7 +236,7 @@ impl MarketResolutionSaga {
                                             type_id,
                                             page,
                                         },
-                                        priority: 5,
+                                        priority: PAGE_PRIORITY,
                                         created_at: Instant::now(),
                                         retry_count: 0,
                                     };
@@ -228,7 +261,7 @@ impl MarketResolutionSaga {
                             }
                         }
                     } else {
-                        eprintln!(
+                        warn!(
                             "Work item failed permanently: {:?}, error: {}",
                             work_item, error
                         );
@@ -250,6 +283,65 @@ impl MarketResolutionSaga {
             && self.market_orders_buy_queue.is_empty()
     }
 }
+/// Resolve market orders of the targets with `workers_count` workers and wait for completion
+pub async fn run_market_saga(
+    context: Arc<AppContext>,
+    targets: Vec<(RegionId, TypeId)>,
+    workers_count: usize,
+) -> Result<(), SagaError> {
+    let saga = Arc::new(RwLock::new(MarketResolutionSaga::new_with_targets(
+        context.clone(),
+        targets.clone(),
+    )));
+
+    let mut worker_handles = Vec::new();
+    for _ in 0..workers_count {
+        let worker = Worker::new(WorkerType::MarketOrders, saga.clone(), context.clone());
+
+        let handle = tokio::spawn(async move { worker.start().await });
+        worker_handles.push(handle);
+    }
+
+    {
+        let mut saga = saga.write().await;
+        saga.handle_event(SagaEvent::SagaStarted).await?;
+    }
+
+    for handle in worker_handles {
+        handle.await.map_err(|e| {
+            SagaError::ProcessingError(format!("failed to join worker task: {e}"))
+        })??;
+    }
+
+    let orders = std::mem::take(&mut saga.write().await.orders);
+    {
+        let mut market_orders_db = context.market_orders_db.write().await;
+        for (key, orders) in orders {
+            market_orders_db.replace(key, orders);
+        }
+        if let Err(e) = market_orders_db.store() {
+            error!("❌ Failed to store market orders: {}", e);
+        }
+
+        let mut market_spreads = context.market_spreads.write().await;
+        for &(region_id, type_id) in &targets {
+            let orders: Vec<MarketOrder> = [true, false]
+                .into_iter()
+                .filter_map(|is_buy| market_orders_db.get((region_id, type_id, is_buy)))
+                .flat_map(|entry| entry.orders.iter().cloned())
+                .collect();
+            match analyze::spread(&orders) {
+                Some(spread) => market_spreads.insert((region_id, type_id), spread),
+                None => market_spreads.remove(&(region_id, type_id)),
+            };
+        }
+    }
+
+    context.mark_market_targets_updated(&targets).await;
+
+    Ok(())
+}
+
 #[derive(Debug, Error)]
 pub enum SagaError {
     #[error("Invalid saga state")]
@@ -321,22 +413,30 @@ impl Worker {
             }
         }
 
-        println!("worker finished, id: {}", self.worker_id);
+        debug!("worker finished, id: {}", self.worker_id);
 
         Ok(())
     }
 
     async fn process_work_item(&self, work_item: WorkItem) -> Result<WorkResult, WorkerError> {
+        let _permit = self.context.acquire_esi_permit().await;
+
         let result = match work_item.work_type {
             WorkType::MarketOrderSell {
                 region_id,
                 type_id,
                 page,
             } => {
-                let (orders, total_pages) =
-                    esi::get_sell_orders(&self.context.http_client, region_id, type_id, page)
-                        .await
-                        .map_err(|e| WorkerError::EsiError(e.to_string()))?;
+                let (orders, total_pages) = esi::get_sell_orders(
+                    &self.context.http_client,
+                    &self.context.esi_expires,
+                    region_id,
+                    type_id,
+                    page,
+                    false,
+                )
+                .await
+                .map_err(|e| WorkerError::EsiError(e.to_string()))?;
 
                 Ok(WorkResult::MarketOrdersSell {
                     region_id,
@@ -351,10 +451,16 @@ impl Worker {
                 type_id,
                 page,
             } => {
-                let (orders, total_pages) =
-                    esi::get_buy_orders(&self.context.http_client, region_id, type_id, page)
-                        .await
-                        .map_err(|e| WorkerError::EsiError(e.to_string()))?;
+                let (orders, total_pages) = esi::get_buy_orders(
+                    &self.context.http_client,
+                    &self.context.esi_expires,
+                    region_id,
+                    type_id,
+                    page,
+                    false,
+                )
+                .await
+                .map_err(|e| WorkerError::EsiError(e.to_string()))?;
 
                 Ok(WorkResult::MarketOrdersBuy {
                     region_id,
@@ -394,3 +500,51 @@ pub enum WorkerError {
     #[error("Saga error: {0}")]
     SagaError(String),
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sell(type_id: i32, page: usize) -> WorkItem {
+        WorkItem {
+            id: Uuid::new_v4(),
+            work_type: WorkType::MarketOrderSell {
+                region_id: 10000002,
+                type_id: type_id.into(),
+                page,
+            },
+            priority: if page == 1 {
+                FIRST_PAGE_PRIORITY
+            } else {
+                PAGE_PRIORITY
+            },
+            created_at: Instant::now(),
+            retry_count: 0,
+        }
+    }
+
+    #[test]
+    fn queue_pops_first_pages_before_the_fan_out() {
+        // Pages of a target already fanned out, queued before the first page of another
+        let mut queue = BTreeSet::from([
+            sell(44992, 3),
+            sell(44992, 2),
+            sell(40520, 1),
+            sell(40519, 1),
+        ]);
+
+        let popped: Vec<WorkType> = std::iter::from_fn(|| queue.pop_first())
+            .map(|work_item| work_item.work_type)
+            .collect();
+
+        assert_eq!(
+            popped,
+            vec![
+                sell(40519, 1).work_type,
+                sell(40520, 1).work_type,
+                sell(44992, 2).work_type,
+                sell(44992, 3).work_type,
+            ]
+        );
+    }
+}
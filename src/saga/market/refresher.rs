@@ -0,0 +1,61 @@
+// saga/market/refresher.rs - Periodic re-runs of the market saga
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use tokio::task::JoinHandle;
+use tracing::{error, info};
+
+use super::run_market_saga;
+use crate::{AppContext, RegionId, TypeId};
+
+#[derive(Clone, Debug)]
+pub struct MarketRefresherConfig {
+    pub interval: Duration,
+    /// Random delay of up to `jitter` added to every cycle
+    pub jitter: Duration,
+    pub targets: Vec<(RegionId, TypeId)>,
+    pub workers_count: usize,
+}
+
+/// Re-run the market saga for the configured targets every `interval`.
+/// A cycle is skipped if the previous one is still running.
+pub fn spawn_market_refresher(
+    context: Arc<AppContext>,
+    config: MarketRefresherConfig,
+) -> JoinHandle<()> {
+    let running = Arc::new(AtomicBool::new(false));
+
+    tokio::spawn(async move {
+        loop {
+            if running.swap(true, Ordering::AcqRel) {
+                info!("⏭️ Previous market refresh still running, skipping cycle");
+            } else {
+                let context = context.clone();
+                let config = config.clone();
+                let running = running.clone();
+                tokio::spawn(async move {
+                    info!(
+                        "🔄 Refreshing market orders for {} targets",
+                        config.targets.len()
+                    );
+                    match run_market_saga(context, config.targets, config.workers_count).await {
+                        Ok(_) => info!("market orders refresh completed"),
+                        Err(e) => error!("market orders refresh failed: {}", e),
+                    }
+                    running.store(false, Ordering::Release);
+                });
+            }
+
+            tokio::time::sleep(config.interval + jitter(config.jitter)).await;
+        }
+    })
+}
+
+fn jitter(max: Duration) -> Duration {
+    let max_ms = max.as_millis() as u64;
+    if max_ms == 0 {
+        return Duration::ZERO;
+    }
+
+    Duration::from_millis(rand::random_range(0..max_ms))
+}
@@ -1,3 +1,4 @@
 pub mod assets;
 pub mod framework;
 pub mod market;
+pub mod stats;
@@ -0,0 +1,122 @@
+// saga/stats.rs - Cache hit vs network fetch counters for saga runs
+use serde::Serialize;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use utoipa::ToSchema;
+
+/// Counts how often a piece of data was served locally (SDE, on-disk db) vs fetched over the network
+#[derive(Default)]
+pub struct FetchCounter {
+    hits: AtomicU64,
+    fetches: AtomicU64,
+}
+
+impl FetchCounter {
+    pub fn hit(&self) {
+        self.hits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn fetch(&self) {
+        self.fetches.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> FetchCount {
+        FetchCount {
+            hits: self.hits.load(Ordering::Relaxed),
+            fetches: self.fetches.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[derive(Serialize, ToSchema, Debug, Clone, Copy, Default)]
+pub struct FetchCount {
+    pub hits: u64,
+    pub fetches: u64,
+}
+
+impl FetchCount {
+    pub fn total(&self) -> u64 {
+        self.hits + self.fetches
+    }
+}
+
+/// Per data category counters of a single saga run
+#[derive(Default)]
+pub struct FetchStats {
+    pub hoboleaks: FetchCounter,
+    pub assets_pages: FetchCounter,
+    pub assets_names: FetchCounter,
+    pub dynamics: FetchCounter,
+    pub types: FetchCounter,
+    pub market_groups: FetchCounter,
+    pub stations: FetchCounter,
+    pub structures: FetchCounter,
+    pub dogma_attributes: FetchCounter,
+}
+
+impl FetchStats {
+    pub fn snapshot(&self) -> FetchStatsSnapshot {
+        FetchStatsSnapshot {
+            hoboleaks: self.hoboleaks.snapshot(),
+            assets_pages: self.assets_pages.snapshot(),
+            assets_names: self.assets_names.snapshot(),
+            dynamics: self.dynamics.snapshot(),
+            types: self.types.snapshot(),
+            market_groups: self.market_groups.snapshot(),
+            stations: self.stations.snapshot(),
+            structures: self.structures.snapshot(),
+            dogma_attributes: self.dogma_attributes.snapshot(),
+        }
+    }
+}
+
+#[derive(Serialize, ToSchema, Debug, Clone, Copy, Default)]
+pub struct FetchStatsSnapshot {
+    pub hoboleaks: FetchCount,
+    pub assets_pages: FetchCount,
+    pub assets_names: FetchCount,
+    pub dynamics: FetchCount,
+    pub types: FetchCount,
+    pub market_groups: FetchCount,
+    pub stations: FetchCount,
+    pub structures: FetchCount,
+    pub dogma_attributes: FetchCount,
+}
+
+impl FetchStatsSnapshot {
+    pub fn categories(&self) -> [(&'static str, FetchCount); 9] {
+        [
+            ("hoboleaks", self.hoboleaks),
+            ("assets_pages", self.assets_pages),
+            ("assets_names", self.assets_names),
+            ("dynamics", self.dynamics),
+            ("types", self.types),
+            ("market_groups", self.market_groups),
+            ("stations", self.stations),
+            ("structures", self.structures),
+            ("dogma_attributes", self.dogma_attributes),
+        ]
+    }
+
+    pub fn total(&self) -> FetchCount {
+        self.categories()
+            .iter()
+            .fold(FetchCount::default(), |acc, (_, c)| FetchCount {
+                hits: acc.hits + c.hits,
+                fetches: acc.fetches + c.fetches,
+            })
+    }
+}
+
+impl fmt::Display for FetchStatsSnapshot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{:<18} {:>8} {:>8}", "category", "hits", "fetches")?;
+        for (name, count) in self.categories() {
+            if count.total() > 0 {
+                writeln!(f, "{:<18} {:>8} {:>8}", name, count.hits, count.fetches)?;
+            }
+        }
+        let total = self.total();
+        write!(f, "{:<18} {:>8} {:>8}", "total", total.hits, total.fetches)
+    }
+}